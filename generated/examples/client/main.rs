@@ -6,6 +6,7 @@ use futures::{future, Stream, stream};
 #[allow(unused_imports)]
 use reposync_lib::{Api, ApiNoContext, Client, ContextWrapperExt, models,
                       HealthGetResponse,
+                      ReloadPostResponse,
                       RepositoryRepoGetResponse,
                       RepositoryRepoSyncPostResponse,
                      };
@@ -30,6 +31,7 @@ fn main() {
             .help("Sets the operation to run")
             .possible_values(&[
                 "HealthGet",
+                "ReloadPost",
                 "RepositoryRepoGet",
                 "RepositoryRepoSyncPost",
             ])
@@ -80,6 +82,11 @@ fn main() {
             ));
             info!("{:?} (X-Span-ID: {:?})", result, (client.context() as &dyn Has<XSpanIdString>).get().clone());
         },
+        Some("ReloadPost") => {
+            let result = rt.block_on(client.reload_post(
+            ));
+            info!("{:?} (X-Span-ID: {:?})", result, (client.context() as &dyn Has<XSpanIdString>).get().clone());
+        },
         Some("RepositoryRepoGet") => {
             let result = rt.block_on(client.repository_repo_get(
                   ""centos8-prod"".to_string()
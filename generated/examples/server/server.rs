@@ -97,6 +97,7 @@ impl<C> Server<C> {
 use reposync_lib::{
     Api,
     HealthGetResponse,
+    ReloadPostResponse,
     RepositoryRepoGetResponse,
     RepositoryRepoSyncPostResponse,
 };
@@ -117,6 +118,16 @@ impl<C> Api<C> for Server<C> where C: Has<XSpanIdString> + Send + Sync
         Err("Generic failuare".into())
     }
 
+    /// Reload the configuration
+    async fn reload_post(
+        &self,
+        context: &C) -> Result<ReloadPostResponse, ApiError>
+    {
+        let context = context.clone();
+        info!("reload_post() - X-Span-ID: {:?}", context.get().0.clone());
+        Err("Generic failuare".into())
+    }
+
     /// status of repository
     async fn repository_repo_get(
         &self,
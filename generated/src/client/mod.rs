@@ -37,6 +37,7 @@ const ID_ENCODE_SET: &AsciiSet = &FRAGMENT_ENCODE_SET.add(b'|');
 
 use crate::{Api,
      HealthGetResponse,
+     ReloadPostResponse,
      RepositoryRepoGetResponse,
      RepositoryRepoSyncPostResponse
      };
@@ -425,16 +426,115 @@ impl<S, C> Api<C> for Client<S, C> where
             .map_err(|e| ApiError(format!("No response received: {}", e))).await?;
 
         match response.status().as_u16() {
-            204 => {
+            200 => {
                 let body = response.into_body();
-                Ok(
-                    HealthGetResponse::EmptyResponseWhenEverythingIsOk
+                let body = body
+                        .to_raw()
+                        .map_err(|e| ApiError(format!("Failed to read response: {}", e))).await?;
+                let body = str::from_utf8(&body)
+                    .map_err(|e| ApiError(format!("Response was not valid UTF8: {}", e)))?;
+                let body = serde_json::from_str::<models::Health>(body)?;
+                Ok(HealthGetResponse::EverythingIsOk
+                    (body)
                 )
             }
             503 => {
                 let body = response.into_body();
-                Ok(
-                    HealthGetResponse::ServiceUnavailableWhenServiceHasKnownIssues
+                let body = body
+                        .to_raw()
+                        .map_err(|e| ApiError(format!("Failed to read response: {}", e))).await?;
+                let body = str::from_utf8(&body)
+                    .map_err(|e| ApiError(format!("Response was not valid UTF8: {}", e)))?;
+                let body = serde_json::from_str::<models::Health>(body)?;
+                Ok(HealthGetResponse::ServiceUnavailableWhenServiceHasKnownIssues
+                    (body)
+                )
+            }
+            code => {
+                let headers = response.headers().clone();
+                let body = response.into_body()
+                       .take(100)
+                       .to_raw().await;
+                Err(ApiError(format!("Unexpected response code {}:\n{:?}\n\n{}",
+                    code,
+                    headers,
+                    match body {
+                        Ok(body) => match String::from_utf8(body) {
+                            Ok(body) => body,
+                            Err(e) => format!("<Body was not UTF8: {:?}>", e),
+                        },
+                        Err(e) => format!("<Failed to read body: {}>", e),
+                    }
+                )))
+            }
+        }
+    }
+
+    async fn reload_post(
+        &self,
+        context: &C) -> Result<ReloadPostResponse, ApiError>
+    {
+        let mut client_service = self.client_service.clone();
+        let mut uri = format!(
+            "{}/reload",
+            self.base_path
+        );
+
+        // Query parameters
+        let query_string = {
+            let mut query_string = form_urlencoded::Serializer::new("".to_owned());
+            query_string.finish()
+        };
+        if !query_string.is_empty() {
+            uri += "?";
+            uri += &query_string;
+        }
+
+        let uri = match Uri::from_str(&uri) {
+            Ok(uri) => uri,
+            Err(err) => return Err(ApiError(format!("Unable to build URI: {}", err))),
+        };
+
+        let mut request = match Request::builder()
+            .method("POST")
+            .uri(uri)
+            .body(Body::empty()) {
+                Ok(req) => req,
+                Err(e) => return Err(ApiError(format!("Unable to create request: {}", e)))
+        };
+
+        let header = HeaderValue::from_str(Has::<XSpanIdString>::get(context).0.clone().to_string().as_str());
+        request.headers_mut().insert(HeaderName::from_static("x-span-id"), match header {
+            Ok(h) => h,
+            Err(e) => return Err(ApiError(format!("Unable to create X-Span ID header value: {}", e)))
+        });
+
+        let mut response = client_service.call((request, context.clone()))
+            .map_err(|e| ApiError(format!("No response received: {}", e))).await?;
+
+        match response.status().as_u16() {
+            200 => {
+                let body = response.into_body();
+                let body = body
+                        .to_raw()
+                        .map_err(|e| ApiError(format!("Failed to read response: {}", e))).await?;
+                let body = str::from_utf8(&body)
+                    .map_err(|e| ApiError(format!("Response was not valid UTF8: {}", e)))?;
+                let body = serde_json::from_str::<Vec<String>>(body)?;
+                Ok(ReloadPostResponse::TheConfigurationHasBeenReloaded
+                    (body)
+                )
+            }
+            400 => {
+                let body = response.into_body();
+                let body = body
+                        .to_raw()
+                        .map_err(|e| ApiError(format!("Failed to read response: {}", e))).await?;
+                let body = str::from_utf8(&body)
+                    .map_err(|e| ApiError(format!("Response was not valid UTF8: {}", e)))?;
+                let body = serde_json::from_str::<String>(body)?;
+                Ok(ReloadPostResponse::InvalidConfiguration
+                    (body)
                 )
             }
             code => {
@@ -520,6 +620,24 @@ impl<S, C> Api<C> for Client<S, C> where
                     RepositoryRepoGetResponse::RepositoryNotFound
                 )
             }
+            409 => {
+                let body = response.into_body();
+                Ok(
+                    RepositoryRepoGetResponse::TheRepositoryIsLockedByASynchronization
+                )
+            }
+            403 => {
+                let body = response.into_body();
+                let body = body
+                        .to_raw()
+                        .map_err(|e| ApiError(format!("Failed to read response: {}", e))).await?;
+                let body = str::from_utf8(&body)
+                    .map_err(|e| ApiError(format!("Response was not valid UTF8: {}", e)))?;
+                let body = serde_json::from_str::<String>(body)?;
+                Ok(RepositoryRepoGetResponse::TheRequestHasBeenRefused
+                    (body)
+                )
+            }
             code => {
                 let headers = response.headers().clone();
                 let body = response.into_body()
@@ -603,6 +721,24 @@ impl<S, C> Api<C> for Client<S, C> where
                     RepositoryRepoSyncPostResponse::RepositoryNotFound
                 )
             }
+            409 => {
+                let body = response.into_body();
+                Ok(
+                    RepositoryRepoSyncPostResponse::TheRepositoryIsLockedByASynchronization
+                )
+            }
+            403 => {
+                let body = response.into_body();
+                let body = body
+                        .to_raw()
+                        .map_err(|e| ApiError(format!("Failed to read response: {}", e))).await?;
+                let body = str::from_utf8(&body)
+                    .map_err(|e| ApiError(format!("Response was not valid UTF8: {}", e)))?;
+                let body = serde_json::from_str::<String>(body)?;
+                Ok(RepositoryRepoSyncPostResponse::TheRequestHasBeenRefused
+                    (body)
+                )
+            }
             code => {
                 let headers = response.headers().clone();
                 let body = response.into_body()
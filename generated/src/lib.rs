@@ -15,11 +15,25 @@ pub const API_VERSION: &'static str = "1.0.0";
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[must_use]
 pub enum HealthGetResponse {
-    /// Empty response when everything is ok.
-    EmptyResponseWhenEverythingIsOk
+    /// Everything is ok.
+    EverythingIsOk
+    (models::Health)
     ,
     /// Service unavailable when service has known issues, such as a full disk.
     ServiceUnavailableWhenServiceHasKnownIssues
+    (models::Health)
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[must_use]
+pub enum ReloadPostResponse {
+    /// The configuration has been reloaded.
+    TheConfigurationHasBeenReloaded
+    (Vec<String>)
+    ,
+    /// Invalid configuration, the previous one is still in use.
+    InvalidConfiguration
+    (String)
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -31,6 +45,13 @@ pub enum RepositoryRepoGetResponse {
     ,
     /// Repository not found.
     RepositoryNotFound
+    ,
+    /// The repository is locked by a synchronization.
+    TheRepositoryIsLockedByASynchronization
+    ,
+    /// The request has been refused.
+    TheRequestHasBeenRefused
+    (String)
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -42,6 +63,13 @@ pub enum RepositoryRepoSyncPostResponse {
     ,
     /// Repository not found.
     RepositoryNotFound
+    ,
+    /// The repository is locked by a synchronization.
+    TheRepositoryIsLockedByASynchronization
+    ,
+    /// The request has been refused.
+    TheRequestHasBeenRefused
+    (String)
 }
 
 /// API
@@ -56,6 +84,11 @@ pub trait Api<C: Send + Sync> {
         &self,
         context: &C) -> Result<HealthGetResponse, ApiError>;
 
+    /// Reload the configuration
+    async fn reload_post(
+        &self,
+        context: &C) -> Result<ReloadPostResponse, ApiError>;
+
     /// status of repository
     async fn repository_repo_get(
         &self,
@@ -83,6 +116,11 @@ pub trait ApiNoContext<C: Send + Sync> {
         &self,
         ) -> Result<HealthGetResponse, ApiError>;
 
+    /// Reload the configuration
+    async fn reload_post(
+        &self,
+        ) -> Result<ReloadPostResponse, ApiError>;
+
     /// status of repository
     async fn repository_repo_get(
         &self,
@@ -129,6 +167,15 @@ impl<T: Api<C> + Send + Sync, C: Clone + Send + Sync> ApiNoContext<C> for Contex
         self.api().health_get(&context).await
     }
 
+    /// Reload the configuration
+    async fn reload_post(
+        &self,
+        ) -> Result<ReloadPostResponse, ApiError>
+    {
+        let context = self.context().clone();
+        self.api().reload_post(&context).await
+    }
+
     /// status of repository
     async fn repository_repo_get(
         &self,
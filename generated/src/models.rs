@@ -4,6 +4,268 @@ use crate::models;
 #[cfg(any(feature = "client", feature = "server"))]
 use crate::header;
 
+/// Details of every health check
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "conversion", derive(frunk::LabelledGeneric))]
+pub struct Health {
+    /// Every path checked for write access
+    #[serde(rename = "paths")]
+    pub paths: Vec<models::PathStatus>,
+
+    /// False when the scheduler did not tick recently and no synchronization is running
+    #[serde(rename = "scheduler_alive")]
+    pub scheduler_alive: bool,
+
+    /// UTC timestamp of the last scheduler tick, 0 if never performed
+    #[serde(rename = "last_tick")]
+    pub last_tick: i64,
+
+}
+
+impl Health {
+    pub fn new(paths: Vec<models::PathStatus>, scheduler_alive: bool, last_tick: i64, ) -> Health {
+        Health {
+            paths: paths,
+            scheduler_alive: scheduler_alive,
+            last_tick: last_tick,
+        }
+    }
+}
+
+/// Converts the Health value to the Query Parameters representation (style=form, explode=false)
+/// specified in https://swagger.io/docs/specification/serialization/
+/// Should be implemented in a serde serializer
+impl std::string::ToString for Health {
+    fn to_string(&self) -> String {
+        let mut params: Vec<String> = vec![];
+        // Skipping paths in query parameter serialization
+
+
+        params.push("scheduler_alive".to_string());
+        params.push(self.scheduler_alive.to_string());
+
+
+        params.push("last_tick".to_string());
+        params.push(self.last_tick.to_string());
+
+        params.join(",").to_string()
+    }
+}
+
+/// Converts Query Parameters representation (style=form, explode=false) to a Health value
+/// as specified in https://swagger.io/docs/specification/serialization/
+/// Should be implemented in a serde deserializer
+impl std::str::FromStr for Health {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        #[derive(Default)]
+        // An intermediate representation of the struct to use for parsing.
+        struct IntermediateRep {
+            pub paths: Vec<Vec<models::PathStatus>>,
+            pub scheduler_alive: Vec<bool>,
+            pub last_tick: Vec<i64>,
+        }
+
+        let mut intermediate_rep = IntermediateRep::default();
+
+        // Parse into intermediate representation
+        let mut string_iter = s.split(',').into_iter();
+        let mut key_result = string_iter.next();
+
+        while key_result.is_some() {
+            let val = match string_iter.next() {
+                Some(x) => x,
+                None => return std::result::Result::Err("Missing value while parsing Health".to_string())
+            };
+
+            if let Some(key) = key_result {
+                match key {
+                    "paths" => return std::result::Result::Err("Parsing a container in this style is not supported in Health".to_string()),
+                    "scheduler_alive" => intermediate_rep.scheduler_alive.push(<bool as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
+                    "last_tick" => intermediate_rep.last_tick.push(<i64 as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
+                    _ => return std::result::Result::Err("Unexpected key while parsing Health".to_string())
+                }
+            }
+
+            // Get the next key
+            key_result = string_iter.next();
+        }
+
+        // Use the intermediate representation to return the struct
+        std::result::Result::Ok(Health {
+            paths: intermediate_rep.paths.into_iter().next().ok_or("paths missing in Health".to_string())?,
+            scheduler_alive: intermediate_rep.scheduler_alive.into_iter().next().ok_or("scheduler_alive missing in Health".to_string())?,
+            last_tick: intermediate_rep.last_tick.into_iter().next().ok_or("last_tick missing in Health".to_string())?,
+        })
+    }
+}
+
+// Methods for converting between header::IntoHeaderValue<Health> and hyper::header::HeaderValue
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl std::convert::TryFrom<header::IntoHeaderValue<Health>> for hyper::header::HeaderValue {
+    type Error = String;
+
+    fn try_from(hdr_value: header::IntoHeaderValue<Health>) -> std::result::Result<Self, Self::Error> {
+        let hdr_value = hdr_value.to_string();
+        match hyper::header::HeaderValue::from_str(&hdr_value) {
+             std::result::Result::Ok(value) => std::result::Result::Ok(value),
+             std::result::Result::Err(e) => std::result::Result::Err(
+                 format!("Invalid header value for Health - value: {} is invalid {}",
+                     hdr_value, e))
+        }
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl std::convert::TryFrom<hyper::header::HeaderValue> for header::IntoHeaderValue<Health> {
+    type Error = String;
+
+    fn try_from(hdr_value: hyper::header::HeaderValue) -> std::result::Result<Self, Self::Error> {
+        match hdr_value.to_str() {
+             std::result::Result::Ok(value) => {
+                    match <Health as std::str::FromStr>::from_str(value) {
+                        std::result::Result::Ok(value) => std::result::Result::Ok(header::IntoHeaderValue(value)),
+                        std::result::Result::Err(err) => std::result::Result::Err(
+                            format!("Unable to convert header value '{}' into Health - {}",
+                                value, err))
+                    }
+             },
+             std::result::Result::Err(e) => std::result::Result::Err(
+                 format!("Unable to convert header: {:?} to string: {}",
+                     hdr_value, e))
+        }
+    }
+}
+
+
+/// Result of the write access check of a path
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "conversion", derive(frunk::LabelledGeneric))]
+pub struct PathStatus {
+    /// Checked path
+    #[serde(rename = "path")]
+    pub path: String,
+
+    /// Either \"ok\" or the reason of the failure
+    #[serde(rename = "status")]
+    pub status: String,
+
+}
+
+impl PathStatus {
+    pub fn new(path: String, status: String, ) -> PathStatus {
+        PathStatus {
+            path: path,
+            status: status,
+        }
+    }
+}
+
+/// Converts the PathStatus value to the Query Parameters representation (style=form, explode=false)
+/// specified in https://swagger.io/docs/specification/serialization/
+/// Should be implemented in a serde serializer
+impl std::string::ToString for PathStatus {
+    fn to_string(&self) -> String {
+        let mut params: Vec<String> = vec![];
+
+        params.push("path".to_string());
+        params.push(self.path.to_string());
+
+
+        params.push("status".to_string());
+        params.push(self.status.to_string());
+
+        params.join(",").to_string()
+    }
+}
+
+/// Converts Query Parameters representation (style=form, explode=false) to a PathStatus value
+/// as specified in https://swagger.io/docs/specification/serialization/
+/// Should be implemented in a serde deserializer
+impl std::str::FromStr for PathStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        #[derive(Default)]
+        // An intermediate representation of the struct to use for parsing.
+        struct IntermediateRep {
+            pub path: Vec<String>,
+            pub status: Vec<String>,
+        }
+
+        let mut intermediate_rep = IntermediateRep::default();
+
+        // Parse into intermediate representation
+        let mut string_iter = s.split(',').into_iter();
+        let mut key_result = string_iter.next();
+
+        while key_result.is_some() {
+            let val = match string_iter.next() {
+                Some(x) => x,
+                None => return std::result::Result::Err("Missing value while parsing PathStatus".to_string())
+            };
+
+            if let Some(key) = key_result {
+                match key {
+                    "path" => intermediate_rep.path.push(<String as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
+                    "status" => intermediate_rep.status.push(<String as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
+                    _ => return std::result::Result::Err("Unexpected key while parsing PathStatus".to_string())
+                }
+            }
+
+            // Get the next key
+            key_result = string_iter.next();
+        }
+
+        // Use the intermediate representation to return the struct
+        std::result::Result::Ok(PathStatus {
+            path: intermediate_rep.path.into_iter().next().ok_or("path missing in PathStatus".to_string())?,
+            status: intermediate_rep.status.into_iter().next().ok_or("status missing in PathStatus".to_string())?,
+        })
+    }
+}
+
+// Methods for converting between header::IntoHeaderValue<PathStatus> and hyper::header::HeaderValue
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl std::convert::TryFrom<header::IntoHeaderValue<PathStatus>> for hyper::header::HeaderValue {
+    type Error = String;
+
+    fn try_from(hdr_value: header::IntoHeaderValue<PathStatus>) -> std::result::Result<Self, Self::Error> {
+        let hdr_value = hdr_value.to_string();
+        match hyper::header::HeaderValue::from_str(&hdr_value) {
+             std::result::Result::Ok(value) => std::result::Result::Ok(value),
+             std::result::Result::Err(e) => std::result::Result::Err(
+                 format!("Invalid header value for PathStatus - value: {} is invalid {}",
+                     hdr_value, e))
+        }
+    }
+}
+
+#[cfg(any(feature = "client", feature = "server"))]
+impl std::convert::TryFrom<hyper::header::HeaderValue> for header::IntoHeaderValue<PathStatus> {
+    type Error = String;
+
+    fn try_from(hdr_value: hyper::header::HeaderValue) -> std::result::Result<Self, Self::Error> {
+        match hdr_value.to_str() {
+             std::result::Result::Ok(value) => {
+                    match <PathStatus as std::str::FromStr>::from_str(value) {
+                        std::result::Result::Ok(value) => std::result::Result::Ok(header::IntoHeaderValue(value)),
+                        std::result::Result::Err(err) => std::result::Result::Err(
+                            format!("Unable to convert header value '{}' into PathStatus - {}",
+                                value, err))
+                    }
+             },
+             std::result::Result::Err(e) => std::result::Result::Err(
+                 format!("Unable to convert header: {:?} to string: {}",
+                     hdr_value, e))
+        }
+    }
+}
+
+
 /// Status of a repository
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 #[cfg_attr(feature = "conversion", derive(frunk::LabelledGeneric))]
@@ -37,6 +299,26 @@ pub struct Status {
     #[serde(rename = "packages")]
     pub packages: isize,
 
+    /// Files already copied or skipped by the synchronization in progress, missing when not copying
+    #[serde(rename = "files_done")]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub files_done: Option<i64>,
+
+    /// Files to copy by the synchronization in progress, missing when not copying
+    #[serde(rename = "files_total")]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub files_total: Option<i64>,
+
+    /// Bytes already copied or skipped by the synchronization in progress, missing when not copying
+    #[serde(rename = "bytes_done")]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub bytes_done: Option<i64>,
+
+    /// Bytes to copy by the synchronization in progress, missing when not copying
+    #[serde(rename = "bytes_total")]
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub bytes_total: Option<i64>,
+
 }
 
 impl Status {
@@ -49,6 +331,10 @@ impl Status {
             last_result: last_result,
             size: size,
             packages: packages,
+            files_done: None,
+            files_total: None,
+            bytes_done: None,
+            bytes_total: None,
         }
     }
 }
@@ -87,6 +373,30 @@ impl std::string::ToString for Status {
         params.push("packages".to_string());
         params.push(self.packages.to_string());
 
+
+        if let Some(ref files_done) = self.files_done {
+            params.push("files_done".to_string());
+            params.push(files_done.to_string());
+        }
+
+
+        if let Some(ref files_total) = self.files_total {
+            params.push("files_total".to_string());
+            params.push(files_total.to_string());
+        }
+
+
+        if let Some(ref bytes_done) = self.bytes_done {
+            params.push("bytes_done".to_string());
+            params.push(bytes_done.to_string());
+        }
+
+
+        if let Some(ref bytes_total) = self.bytes_total {
+            params.push("bytes_total".to_string());
+            params.push(bytes_total.to_string());
+        }
+
         params.join(",").to_string()
     }
 }
@@ -108,6 +418,10 @@ impl std::str::FromStr for Status {
             pub last_result: Vec<String>,
             pub size: Vec<i64>,
             pub packages: Vec<isize>,
+            pub files_done: Vec<i64>,
+            pub files_total: Vec<i64>,
+            pub bytes_done: Vec<i64>,
+            pub bytes_total: Vec<i64>,
         }
 
         let mut intermediate_rep = IntermediateRep::default();
@@ -131,6 +445,10 @@ impl std::str::FromStr for Status {
                     "last_result" => intermediate_rep.last_result.push(<String as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
                     "size" => intermediate_rep.size.push(<i64 as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
                     "packages" => intermediate_rep.packages.push(<isize as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
+                    "files_done" => intermediate_rep.files_done.push(<i64 as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
+                    "files_total" => intermediate_rep.files_total.push(<i64 as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
+                    "bytes_done" => intermediate_rep.bytes_done.push(<i64 as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
+                    "bytes_total" => intermediate_rep.bytes_total.push(<i64 as std::str::FromStr>::from_str(val).map_err(|x| format!("{}", x))?),
                     _ => return std::result::Result::Err("Unexpected key while parsing Status".to_string())
                 }
             }
@@ -148,6 +466,10 @@ impl std::str::FromStr for Status {
             last_result: intermediate_rep.last_result.into_iter().next().ok_or("last_result missing in Status".to_string())?,
             size: intermediate_rep.size.into_iter().next().ok_or("size missing in Status".to_string())?,
             packages: intermediate_rep.packages.into_iter().next().ok_or("packages missing in Status".to_string())?,
+            files_done: intermediate_rep.files_done.into_iter().next(),
+            files_total: intermediate_rep.files_total.into_iter().next(),
+            bytes_done: intermediate_rep.bytes_done.into_iter().next(),
+            bytes_total: intermediate_rep.bytes_total.into_iter().next(),
         })
     }
 }
@@ -23,6 +23,7 @@ type ServiceFuture = BoxFuture<'static, Result<Response<Body>, crate::ServiceErr
 
 use crate::{Api,
      HealthGetResponse,
+     ReloadPostResponse,
      RepositoryRepoGetResponse,
      RepositoryRepoSyncPostResponse
 };
@@ -33,19 +34,21 @@ mod paths {
     lazy_static! {
         pub static ref GLOBAL_REGEX_SET: regex::RegexSet = regex::RegexSet::new(vec![
             r"^/health$",
+            r"^/reload$",
             r"^/repository/(?P<repo>[^/?#]*)/$",
             r"^/repository/(?P<repo>[^/?#]*)/sync$"
         ])
         .expect("Unable to create global regex set");
     }
     pub(crate) static ID_HEALTH: usize = 0;
-    pub(crate) static ID_REPOSITORY_REPO_: usize = 1;
+    pub(crate) static ID_RELOAD: usize = 1;
+    pub(crate) static ID_REPOSITORY_REPO_: usize = 2;
     lazy_static! {
         pub static ref REGEX_REPOSITORY_REPO_: regex::Regex =
             regex::Regex::new(r"^/repository/(?P<repo>[^/?#]*)/$")
                 .expect("Unable to create regex for REPOSITORY_REPO_");
     }
-    pub(crate) static ID_REPOSITORY_REPO_SYNC: usize = 2;
+    pub(crate) static ID_REPOSITORY_REPO_SYNC: usize = 3;
     lazy_static! {
         pub static ref REGEX_REPOSITORY_REPO_SYNC: regex::Regex =
             regex::Regex::new(r"^/repository/(?P<repo>[^/?#]*)/sync$")
@@ -168,13 +171,74 @@ impl<T, C> hyper::service::Service<(Request<Body>, C)> for Service<T, C> where
 
                                         match result {
                                             Ok(rsp) => match rsp {
-                                                HealthGetResponse::EmptyResponseWhenEverythingIsOk
+                                                HealthGetResponse::EverythingIsOk
+                                                    (body)
                                                 => {
-                                                    *response.status_mut() = StatusCode::from_u16(204).expect("Unable to turn 204 into a StatusCode");
+                                                    *response.status_mut() = StatusCode::from_u16(200).expect("Unable to turn 200 into a StatusCode");
+                                                    response.headers_mut().insert(
+                                                        CONTENT_TYPE,
+                                                        HeaderValue::from_str("application/json")
+                                                            .expect("Unable to create Content-Type header for HEALTH_GET_EVERYTHING_IS_OK"));
+                                                    let body = serde_json::to_string(&body).expect("impossible to fail to serialize");
+                                                    *response.body_mut() = Body::from(body);
                                                 },
                                                 HealthGetResponse::ServiceUnavailableWhenServiceHasKnownIssues
+                                                    (body)
                                                 => {
                                                     *response.status_mut() = StatusCode::from_u16(503).expect("Unable to turn 503 into a StatusCode");
+                                                    response.headers_mut().insert(
+                                                        CONTENT_TYPE,
+                                                        HeaderValue::from_str("application/json")
+                                                            .expect("Unable to create Content-Type header for HEALTH_GET_SERVICE_UNAVAILABLE_WHEN_SERVICE_HAS_KNOWN_ISSUES"));
+                                                    let body = serde_json::to_string(&body).expect("impossible to fail to serialize");
+                                                    *response.body_mut() = Body::from(body);
+                                                },
+                                            },
+                                            Err(_) => {
+                                                // Application code returned an error. This should not happen, as the implementation should
+                                                // return a valid response.
+                                                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                                *response.body_mut() = Body::from("An internal error occurred");
+                                            },
+                                        }
+
+                                        Ok(response)
+            },
+
+            // ReloadPost - POST /reload
+            &hyper::Method::POST if path.matched(paths::ID_RELOAD) => {
+                                let result = api_impl.reload_post(
+                                        &context
+                                    ).await;
+                                let mut response = Response::new(Body::empty());
+                                response.headers_mut().insert(
+                                            HeaderName::from_static("x-span-id"),
+                                            HeaderValue::from_str((&context as &dyn Has<XSpanIdString>).get().0.clone().to_string().as_str())
+                                                .expect("Unable to create X-Span-ID header value"));
+
+                                        match result {
+                                            Ok(rsp) => match rsp {
+                                                ReloadPostResponse::TheConfigurationHasBeenReloaded
+                                                    (body)
+                                                => {
+                                                    *response.status_mut() = StatusCode::from_u16(200).expect("Unable to turn 200 into a StatusCode");
+                                                    response.headers_mut().insert(
+                                                        CONTENT_TYPE,
+                                                        HeaderValue::from_str("application/json")
+                                                            .expect("Unable to create Content-Type header for RELOAD_POST_THE_CONFIGURATION_HAS_BEEN_RELOADED"));
+                                                    let body = serde_json::to_string(&body).expect("impossible to fail to serialize");
+                                                    *response.body_mut() = Body::from(body);
+                                                },
+                                                ReloadPostResponse::InvalidConfiguration
+                                                    (body)
+                                                => {
+                                                    *response.status_mut() = StatusCode::from_u16(400).expect("Unable to turn 400 into a StatusCode");
+                                                    response.headers_mut().insert(
+                                                        CONTENT_TYPE,
+                                                        HeaderValue::from_str("application/json")
+                                                            .expect("Unable to create Content-Type header for RELOAD_POST_INVALID_CONFIGURATION"));
+                                                    let body = serde_json::to_string(&body).expect("impossible to fail to serialize");
+                                                    *response.body_mut() = Body::from(body);
                                                 },
                                             },
                                             Err(_) => {
@@ -240,6 +304,21 @@ impl<T, C> hyper::service::Service<(Request<Body>, C)> for Service<T, C> where
                                                 => {
                                                     *response.status_mut() = StatusCode::from_u16(404).expect("Unable to turn 404 into a StatusCode");
                                                 },
+                                                RepositoryRepoGetResponse::TheRepositoryIsLockedByASynchronization
+                                                => {
+                                                    *response.status_mut() = StatusCode::from_u16(409).expect("Unable to turn 409 into a StatusCode");
+                                                },
+                                                RepositoryRepoGetResponse::TheRequestHasBeenRefused
+                                                    (body)
+                                                => {
+                                                    *response.status_mut() = StatusCode::from_u16(403).expect("Unable to turn 403 into a StatusCode");
+                                                    response.headers_mut().insert(
+                                                        CONTENT_TYPE,
+                                                        HeaderValue::from_str("application/json")
+                                                            .expect("Unable to create Content-Type header for REPOSITORY_REPO_GET_THE_REQUEST_HAS_BEEN_REFUSED"));
+                                                    let body = serde_json::to_string(&body).expect("impossible to fail to serialize");
+                                                    *response.body_mut() = Body::from(body);
+                                                },
                                             },
                                             Err(_) => {
                                                 // Application code returned an error. This should not happen, as the implementation should
@@ -304,6 +383,21 @@ impl<T, C> hyper::service::Service<(Request<Body>, C)> for Service<T, C> where
                                                 => {
                                                     *response.status_mut() = StatusCode::from_u16(404).expect("Unable to turn 404 into a StatusCode");
                                                 },
+                                                RepositoryRepoSyncPostResponse::TheRepositoryIsLockedByASynchronization
+                                                => {
+                                                    *response.status_mut() = StatusCode::from_u16(409).expect("Unable to turn 409 into a StatusCode");
+                                                },
+                                                RepositoryRepoSyncPostResponse::TheRequestHasBeenRefused
+                                                    (body)
+                                                => {
+                                                    *response.status_mut() = StatusCode::from_u16(403).expect("Unable to turn 403 into a StatusCode");
+                                                    response.headers_mut().insert(
+                                                        CONTENT_TYPE,
+                                                        HeaderValue::from_str("application/json")
+                                                            .expect("Unable to create Content-Type header for REPOSITORY_REPO_SYNC_POST_THE_REQUEST_HAS_BEEN_REFUSED"));
+                                                    let body = serde_json::to_string(&body).expect("impossible to fail to serialize");
+                                                    *response.body_mut() = Body::from(body);
+                                                },
                                             },
                                             Err(_) => {
                                                 // Application code returned an error. This should not happen, as the implementation should
@@ -317,6 +411,7 @@ impl<T, C> hyper::service::Service<(Request<Body>, C)> for Service<T, C> where
             },
 
             _ if path.matched(paths::ID_HEALTH) => method_not_allowed(),
+            _ if path.matched(paths::ID_RELOAD) => method_not_allowed(),
             _ if path.matched(paths::ID_REPOSITORY_REPO_) => method_not_allowed(),
             _ if path.matched(paths::ID_REPOSITORY_REPO_SYNC) => method_not_allowed(),
             _ => Ok(Response::builder().status(StatusCode::NOT_FOUND)
@@ -334,6 +429,8 @@ impl<T> RequestParser<T> for ApiRequestParser {
         match request.method() {
             // HealthGet - GET /health
             &hyper::Method::GET if path.matched(paths::ID_HEALTH) => Ok("HealthGet"),
+            // ReloadPost - POST /reload
+            &hyper::Method::POST if path.matched(paths::ID_RELOAD) => Ok("ReloadPost"),
             // RepositoryRepoGet - GET /repository/{repo}/
             &hyper::Method::GET if path.matched(paths::ID_REPOSITORY_REPO_) => Ok("RepositoryRepoGet"),
             // RepositoryRepoSyncPost - POST /repository/{repo}/sync
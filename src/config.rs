@@ -1,5 +1,10 @@
+use crate::destination::validate_path;
+use crate::fetcher::request_headers;
+use crate::packages::{Hash, Signature, SIGNATURE_HASH_ALGORITHMS};
+use chrono::{DateTime, Duration, Utc};
 use pgp::{Deserializable, SignedPublicKey};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
 use std::io::{Error, ErrorKind, Read};
@@ -12,9 +17,51 @@ pub struct SourceConfig {
     pub username: Option<String>,
     pub password: Option<String>,
     pub authorization_file: Option<String>,
+    pub packages_base: Option<String>,
+    #[serde(default)]
+    pub strict_signatures: bool,
+    ///hash algorithms a verified signature may be made with, any when empty
+    #[serde(default)]
+    pub signature_hash_algorithms: Vec<String>,
+    ///file names of optional indexes which must exist, e.g. 'InRelease' or 'repomd.xml.asc'
+    #[serde(default)]
+    pub required_indexes: Vec<String>,
+    ///sent with every request, e.g. a token required by a firewall
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    ///redhat only, directories under the endpoint with their own repodata, e.g. 'os' and 'updates'
+    #[serde(default)]
+    pub channels: Vec<String>,
+    pub metadata_path: Option<String>,
+    ///leading directory of upstream paths which is not mirrored, e.g. 'Packages'
+    pub strip_prefix: Option<String>,
 }
 
 impl SourceConfig {
+    ///packages location is relative to the repository root, which is the endpoint unless
+    ///packages_base is specified
+    pub fn get_packages_endpoint(&self) -> &str {
+        self.packages_base.as_ref().unwrap_or(&self.endpoint)
+    }
+
+    ///where an upstream path is stored in the destination
+    pub fn destination_path<'a>(&self, path: &'a str) -> &'a str {
+        match &self.strip_prefix {
+            Some(prefix) => path
+                .strip_prefix(prefix.as_str())
+                .and_then(|x| x.strip_prefix('/'))
+                .unwrap_or(path),
+            None => path,
+        }
+    }
+
+    ///entry point of redhat repositories, relative to the endpoint
+    pub fn get_metadata_path(&self) -> &str {
+        self.metadata_path
+            .as_deref()
+            .unwrap_or("repodata/repomd.xml")
+    }
+
     pub fn get_authorization_secret(&self) -> Result<Option<String>, std::io::Error> {
         if self.username.is_some() && self.password.is_some() {
             return Ok(Some(format!(
@@ -60,23 +107,90 @@ pub struct S3Destination {
     pub cloudfront_endpoint: Option<String>,
     pub cloudfront_distribution_id: Option<String>,
     pub region_name: String,
+    ///region of the SigV4 signature, some S3 compatible stores expect e.g. us-east-1
+    pub signing_region: Option<String>,
     pub access_key_id: Option<String>,
     pub access_key_secret: Option<String>,
     pub aws_credential_file: Option<String>,
+    #[serde(default)]
+    pub append_repo_name: bool,
+    #[serde(default)]
+    pub verify_uploads: bool,
+    pub key_encoding: Option<String>,
+    ///bounds the connections kept alive per host
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    ///false keeps cloudfront_distribution_id without creating invalidations
+    #[serde(default = "default_invalidate")]
+    pub invalidate: bool,
+    ///false omits the Content-MD5 header, for backends which reject it
+    #[serde(default = "default_content_md5")]
+    pub content_md5: bool,
+}
+
+fn default_max_concurrent_requests() -> usize {
+    16
+}
+
+fn default_invalidate() -> bool {
+    true
+}
+
+fn default_content_md5() -> bool {
+    true
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct LocalDestination {
     pub path: String,
+    #[serde(default)]
+    pub gzip_indexes: bool,
+    #[serde(default)]
+    pub verify_uploads: bool,
+    //in server mode, serve path under this url prefix
+    pub serve_prefix: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DestinationConfig {
     pub s3: Option<S3Destination>,
     pub local: Option<LocalDestination>,
+    ///packages are stored under a directory per architecture, set by a path ending with {arch}
+    #[serde(skip)]
+    pub arch_prefix: bool,
+}
+
+const ARCH_PLACEHOLDER: &str = "{arch}";
+
+///the destination root of a path ending with the {arch} placeholder
+fn strip_arch_placeholder(path: &str) -> Option<String> {
+    if path == ARCH_PLACEHOLDER {
+        return Some(String::new());
+    }
+    path.strip_suffix(ARCH_PLACEHOLDER)
+        .and_then(|x| x.strip_suffix('/'))
+        .map(|x| x.into())
+}
+
+impl DestinationConfig {
+    ///where files are written, to tell whether repositories share or nest their destinations
+    pub fn location(&self) -> String {
+        match (&self.s3, &self.local) {
+            (Some(s3), _) => format!("{}/{}/{}", s3.s3_endpoint, s3.s3_bucket, s3.path),
+            (None, Some(local)) => local.path.clone(),
+            (None, None) => String::new(),
+        }
+        .trim_end_matches('/')
+        .into()
+    }
 }
 
 impl S3Destination {
+    ///the requests are signed for region_name unless signing_region is set
+    pub fn get_signing_region(&self) -> &str {
+        self.signing_region.as_deref().unwrap_or(&self.region_name)
+    }
+
     ///returns (access_key_id,access_key_secret)
     pub fn get_aws_credentials(&self) -> Result<(String, String), std::io::Error> {
         if self.access_key_id.is_some() && self.access_key_secret.is_some() {
@@ -89,20 +203,28 @@ impl S3Destination {
                 let mut text = String::new();
                 File::open(&self.aws_credential_file.clone().unwrap())?
                     .read_to_string(&mut text)?;
-                let vec: Vec<&str> = text.splitn(2, "\n").collect();
+                let vec: Vec<&str> = text
+                    .trim_start_matches('\u{feff}')
+                    .splitn(2, "\n")
+                    .collect();
                 if vec.len() == 2 {
-                    Ok((
-                        vec.get(0)
-                            .unwrap()
-                            .to_string()
-                            .replace('\n', "")
-                            .replace('\r', ""),
-                        vec.get(1)
-                            .unwrap()
-                            .to_string()
-                            .replace('\n', "")
-                            .replace('\r', ""),
-                    ))
+                    let access_key_id = vec.first().unwrap().trim();
+                    let access_key_secret = vec.get(1).unwrap().trim();
+                    for (name, value) in &[
+                        ("access_key_id", access_key_id),
+                        ("access_key_secret", access_key_secret),
+                    ] {
+                        if value.is_empty() || value.contains(char::is_whitespace) {
+                            return Err(Error::new(
+                                ErrorKind::InvalidInput,
+                                format!(
+                                    "invalid aws credential file, {} is empty or contains whitespaces",
+                                    name
+                                ),
+                            ));
+                        }
+                    }
+                    Ok((access_key_id.into(), access_key_secret.into()))
                 } else {
                     Err(Error::new(
                         ErrorKind::InvalidInput,
@@ -126,6 +248,58 @@ pub struct RepositoryConfig {
     pub destination: DestinationConfig,
     #[serde(default)]
     pub versions: Vec<String>,
+    pub tmp_path: Option<String>,
+    #[serde(default)]
+    pub pin: bool,
+    #[serde(default)]
+    pub best_effort: bool,
+    #[serde(default)]
+    pub metadata_only: bool,
+    pub publish_public_key: Option<String>,
+    ///lists every published package and index with its size and hash, e.g. 'MANIFEST'
+    pub manifest_path: Option<String>,
+    pub publish_variants: Option<Vec<String>>,
+    ///redhat only, reported instead of the architectures found in the packages, e.g. of a noarch repository
+    pub architectures: Option<Vec<String>>,
+    pub max_requests_per_sec: Option<f64>,
+    pub max_download_bytes_per_sec: Option<u64>,
+    ///debian only, Packages indexes of a version downloaded at the same time
+    pub max_concurrent_index_fetches: Option<usize>,
+    #[serde(default)]
+    pub wildcard_invalidation: bool,
+    ///never delete anything from the destination, files dropped upstream are kept forever
+    #[serde(default)]
+    pub append_only: bool,
+    ///skip the sync when every Release or repomd.xml matches the last synchronization
+    #[serde(default)]
+    pub skip_unchanged: bool,
+    ///read back the uploaded Release or repomd.xml and check its hash before deleting anything
+    #[serde(default)]
+    pub verify_indexes: bool,
+    ///debian only, tolerate a pool path listed with different hashes, one of them is mirrored
+    #[serde(default)]
+    pub allow_pool_conflicts: bool,
+    ///evict the least recently referenced packages beyond this size, for caching mirrors
+    pub max_total_size_bytes: Option<u64>,
+    ///packages missing upstream are deleted once they stayed missing this long, 0 deletes at once
+    #[serde(default)]
+    pub delete_grace_secs: u64,
+    ///uploaded on every sync, e.g. a keyring referenced by signed-by or a README
+    #[serde(default)]
+    pub extra_files: Vec<String>,
+}
+
+impl RepositoryConfig {
+    ///returns the repository tmp_path, falling back to the general one
+    pub fn get_tmp_path(&self, general: &GeneralConfig) -> String {
+        self.tmp_path
+            .clone()
+            .unwrap_or_else(|| general.tmp_path.clone())
+    }
+
+    pub fn get_max_concurrent_index_fetches(&self) -> usize {
+        self.max_concurrent_index_fetches.unwrap_or(4)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -134,10 +308,82 @@ pub struct GeneralConfig {
     pub tmp_path: String,
     pub bind_address: String,
     pub timeout: u32,
+    pub connect_timeout: Option<u32>,
+    ///only for upstreams known to support http2, requests fail otherwise
+    #[serde(default)]
+    pub http2_prior_knowledge: bool,
+    ///seconds an idle upstream connection is kept open
+    pub pool_idle_timeout: Option<u64>,
+    pub pool_max_idle_per_host: Option<usize>,
+    #[serde(default)]
+    pub rebase_redirects: bool,
     pub max_retries: u32,
     pub retry_sleep: u64,
     pub min_sync_delay: u32,
     pub max_sync_delay: u32,
+    #[serde(default)]
+    pub initial_sync_jitter: bool,
+    #[serde(default = "default_scheduler_tick_secs")]
+    pub scheduler_tick_secs: u64,
+    #[serde(default = "default_max_delete_ratio")]
+    pub max_delete_ratio: f64,
+    #[serde(default = "default_max_concurrent_invalidations")]
+    pub max_concurrent_invalidations: usize,
+    #[serde(default = "default_verify_size")]
+    pub verify_size: bool,
+    pub min_hash_strength: Option<String>,
+    ///hash large packages while another thread reads them, useful on slow disks
+    #[serde(default)]
+    pub parallel_hashing: bool,
+    ///downloads failing hash or size validation are fetched again this many times
+    #[serde(default = "default_copy_retries")]
+    pub copy_retries: u32,
+    ///warn when a public_pgp_key expires within this many days
+    #[serde(default = "default_key_expiry_warning_days")]
+    pub key_expiry_warning_days: u32,
+    ///saved states of every repository including the current one, older ones allow a rollback
+    #[serde(default = "default_metadata_generations")]
+    pub metadata_generations: usize,
+    ///where sync reports are written, {data_path}/{name}.reports of every repository by default
+    pub report_path: Option<String>,
+    ///sync reports kept for every repository
+    #[serde(default = "default_report_retention")]
+    pub report_retention: usize,
+    ///fail the health check and refuse to sync when data_path or tmp_path has fewer free inodes
+    pub min_free_inodes: Option<u64>,
+}
+
+fn default_scheduler_tick_secs() -> u64 {
+    10
+}
+
+fn default_max_delete_ratio() -> f64 {
+    0.5
+}
+
+//cloudfront allows up to 15 wildcard invalidation paths in progress at the same time
+fn default_max_concurrent_invalidations() -> usize {
+    15
+}
+
+fn default_copy_retries() -> u32 {
+    2
+}
+
+fn default_key_expiry_warning_days() -> u32 {
+    30
+}
+
+fn default_metadata_generations() -> usize {
+    1
+}
+
+fn default_report_retention() -> usize {
+    10
+}
+
+fn default_verify_size() -> bool {
+    true
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -167,12 +413,34 @@ pub fn load_config(path: &str) -> Result<Config, String> {
 
     //normalize slashes
     let mut config: Config = config_result.unwrap();
+    if let Some(report_path) = &config.general.report_path {
+        config.general.report_path = Some(remove_trailing_slash(report_path));
+    }
     for repo in &mut config.repo {
+        //names are directories in data_path, so incidental whitespace or slashes must not matter
+        repo.name = repo.name.trim().trim_end_matches('/').into();
         repo.source.endpoint = remove_trailing_slash(&repo.source.endpoint);
+        if let Some(packages_base) = &repo.source.packages_base {
+            repo.source.packages_base = Some(remove_trailing_slash(packages_base));
+        }
+        if let Some(strip_prefix) = &repo.source.strip_prefix {
+            repo.source.strip_prefix = Some(strip_prefix.trim_matches('/').into());
+        }
         if repo.destination.s3.is_some() {
             let mut s3 = repo.destination.s3.clone().unwrap();
             s3.s3_endpoint = remove_trailing_slash(&s3.s3_endpoint);
             s3.path = remove_initial_slash(&remove_trailing_slash(&s3.path));
+            if let Some(path) = strip_arch_placeholder(&s3.path) {
+                s3.path = path;
+                repo.destination.arch_prefix = true;
+            }
+            if s3.append_repo_name {
+                s3.path = if s3.path.is_empty() {
+                    repo.name.clone()
+                } else {
+                    format!("{}/{}", s3.path, repo.name)
+                };
+            }
             if s3.cloudfront_endpoint.is_some() {
                 s3.cloudfront_endpoint = Some(remove_trailing_slash(
                     &s3.cloudfront_endpoint.clone().unwrap(),
@@ -184,16 +452,53 @@ pub fn load_config(path: &str) -> Result<Config, String> {
         if repo.destination.local.is_some() {
             let mut local = repo.destination.local.clone().unwrap();
             local.path = remove_trailing_slash(&local.path);
+            if let Some(path) = strip_arch_placeholder(&local.path) {
+                local.path = path;
+                repo.destination.arch_prefix = true;
+            }
+            if let Some(serve_prefix) = &local.serve_prefix {
+                local.serve_prefix = Some(remove_trailing_slash(serve_prefix));
+            }
             repo.destination.local = Some(local);
         }
     }
 
     //verify
+    if config.general.scheduler_tick_secs == 0 {
+        return Result::Err("scheduler_tick_secs must be greater than 0".into());
+    }
+    if !(0.0..=1.0).contains(&config.general.max_delete_ratio) {
+        return Result::Err("max_delete_ratio must be between 0 and 1".into());
+    }
+    if config.general.max_concurrent_invalidations == 0 {
+        return Result::Err("max_concurrent_invalidations must be greater than 0".into());
+    }
+    if config.general.metadata_generations == 0 {
+        return Result::Err("metadata_generations must be greater than 0".into());
+    }
+    if config.general.report_retention == 0 {
+        return Result::Err("report_retention must be greater than 0".into());
+    }
+    if let Some(min_hash_strength) = &config.general.min_hash_strength {
+        if Hash::strength_of(min_hash_strength).is_none() {
+            return Result::Err(format!(
+                "unknown min_hash_strength '{}', only 'md5', 'sha1' and 'sha256' are supported",
+                min_hash_strength
+            ));
+        }
+    }
+
+    let mut used_s3_locations: Vec<(String, &String)> = vec![];
+    let mut used_prefixes: Vec<(&String, &String)> = vec![];
     let mut used_names: Vec<&String> = vec![];
     for repo in &config.repo {
-        if repo.name == "all" {
+        if repo.name.is_empty() {
+            return Result::Err("empty repository name".into());
+        }
+        if repo.name.eq_ignore_ascii_case("all") {
             return Result::Err(format!(
-                "'all' was used as repository name, but it's a reserved word'",
+                "'{}' was used as repository name, but 'all' is a reserved word'",
+                &repo.name
             ));
         }
         if used_names.contains(&&repo.name) {
@@ -202,19 +507,40 @@ pub fn load_config(path: &str) -> Result<Config, String> {
                 &repo.name
             ));
         }
+        //the same name on a case insensitive filesystem, and ambiguous for --repo
+        if let Some(used_name) = used_names
+            .iter()
+            .find(|x| x.eq_ignore_ascii_case(&repo.name))
+        {
+            return Result::Err(format!(
+                "'{}' and '{}' repository names differ only by case",
+                used_name, &repo.name
+            ));
+        }
         used_names.push(&repo.name);
 
-        if !["debian", "redhat"].contains(&repo.source.kind.as_str()) {
+        if !["debian", "debian-flat", "redhat"].contains(&repo.source.kind.as_str()) {
             return Result::Err(format!(
-                "unknown repository type '{}', only 'debian' and 'redhat' are supported",
+                "unknown repository type '{}', only 'debian', 'debian-flat' and 'redhat' are supported",
                 &repo.source.kind
             ));
         }
 
+        if repo.source.kind == "debian-flat" && !repo.versions.is_empty() {
+            return Result::Err(format!(
+                "'{}' is a debian-flat repository, its single Release is at the endpoint and versions must be empty",
+                &repo.name
+            ));
+        }
+
         if let Err(err) = repo.source.get_authorization_secret() {
             return Result::Err(format!("cannot parse authorization: {}", err.to_string()));
         }
 
+        if let Err(err) = request_headers(&repo.source.headers) {
+            return Result::Err(err.to_string());
+        }
+
         let result = repo.source.parse_public_key();
         if result.is_err() {
             return Result::Err(result.err().unwrap().to_string());
@@ -227,6 +553,161 @@ pub fn load_config(path: &str) -> Result<Config, String> {
                     result.err().unwrap().to_string()
                 ));
             }
+            let warnings = check_key_expiry(
+                &repo.name,
+                &public_key,
+                Utc::now(),
+                config.general.key_expiry_warning_days,
+            )?;
+            for warning in warnings {
+                eprintln!("warning: {}", warning);
+            }
+        }
+
+        if repo.architectures.is_some() && repo.source.kind != "redhat" {
+            return Result::Err(format!(
+                "'{}' uses architectures, which is only supported for redhat repositories",
+                &repo.name
+            ));
+        }
+
+        if repo.publish_variants.is_some() && !repo.source.kind.starts_with("debian") {
+            return Result::Err(format!(
+                "'{}' uses publish_variants, which is only supported for debian repositories",
+                &repo.name
+            ));
+        }
+
+        if let Some(max_requests_per_sec) = repo.max_requests_per_sec {
+            if max_requests_per_sec <= 0.0 {
+                return Result::Err(format!(
+                    "'{}' max_requests_per_sec must be greater than 0",
+                    &repo.name
+                ));
+            }
+        }
+        if repo.max_total_size_bytes == Some(0) {
+            return Result::Err(format!(
+                "'{}' max_total_size_bytes must be greater than 0",
+                &repo.name
+            ));
+        }
+        if repo.max_download_bytes_per_sec == Some(0) {
+            return Result::Err(format!(
+                "'{}' max_download_bytes_per_sec must be greater than 0",
+                &repo.name
+            ));
+        }
+        if repo.max_concurrent_index_fetches == Some(0) {
+            return Result::Err(format!(
+                "'{}' max_concurrent_index_fetches must be greater than 0",
+                &repo.name
+            ));
+        }
+
+        if repo.source.strict_signatures && repo.source.public_pgp_key.is_none() {
+            return Result::Err(format!(
+                "'{}' requires strict signatures, but public_pgp_key is missing",
+                &repo.name
+            ));
+        }
+        if let Some(name) = repo
+            .source
+            .signature_hash_algorithms
+            .iter()
+            .find(|x| !SIGNATURE_HASH_ALGORITHMS.contains(&x.as_str()))
+        {
+            return Result::Err(format!(
+                "'{}' signature_hash_algorithms contains unknown '{}', expected one of {}",
+                &repo.name,
+                name,
+                SIGNATURE_HASH_ALGORITHMS.join(", ")
+            ));
+        }
+
+        if let Some(name) = repo
+            .source
+            .required_indexes
+            .iter()
+            .find(|x| x.is_empty() || x.contains('/'))
+        {
+            return Result::Err(format!(
+                "'{}' required_indexes must be file names, found '{}'",
+                &repo.name, name
+            ));
+        }
+
+        if repo.source.metadata_path.is_some() && repo.source.kind != "redhat" {
+            return Result::Err(format!(
+                "'{}' uses metadata_path, which is only supported for redhat repositories",
+                &repo.name
+            ));
+        }
+
+        if !repo.source.channels.is_empty() && repo.source.kind != "redhat" {
+            return Result::Err(format!(
+                "'{}' uses channels, which are only supported for redhat repositories",
+                &repo.name
+            ));
+        }
+        let mut used_channels: Vec<&str> = vec![];
+        for channel in &repo.source.channels {
+            let channel = channel.trim_end_matches('/');
+            if channel.is_empty() || used_channels.contains(&channel) {
+                return Result::Err(format!(
+                    "'{}' channels must be distinct directories, found '{}'",
+                    &repo.name, channel
+                ));
+            }
+            if let Err(err) = validate_path(channel) {
+                return Result::Err(format!("'{}' invalid channels: {}", &repo.name, err));
+            }
+            used_channels.push(channel);
+        }
+
+        if let Some(strip_prefix) = &repo.source.strip_prefix {
+            if strip_prefix.is_empty() {
+                return Result::Err(format!("'{}' has an empty strip_prefix", &repo.name));
+            }
+            if let Err(err) = validate_path(strip_prefix) {
+                return Result::Err(format!("'{}' invalid strip_prefix: {}", &repo.name, err));
+            }
+        }
+
+        for path in &repo.extra_files {
+            if let Err(err) = validate_path(path) {
+                return Result::Err(format!("'{}' invalid extra_files: {}", &repo.name, err));
+            }
+        }
+
+        if let Some(path) = &repo.manifest_path {
+            if let Err(err) = validate_path(path) {
+                return Result::Err(format!("'{}' invalid manifest_path: {}", &repo.name, err));
+            }
+        }
+
+        if repo.publish_public_key.is_some() && repo.source.public_pgp_key.is_none() {
+            return Result::Err(format!(
+                "'{}' publishes the public key, but public_pgp_key is missing",
+                &repo.name
+            ));
+        }
+
+        if repo.destination.location().contains(ARCH_PLACEHOLDER) {
+            return Err(format!(
+                "'{}' can only be the last segment of the destination path of '{}'",
+                ARCH_PLACEHOLDER, &repo.name
+            ));
+        }
+        if repo.destination.arch_prefix
+            && (repo.source.strip_prefix.is_some()
+                || repo.append_only
+                || repo.max_total_size_bytes.is_some())
+        {
+            return Err(format!(
+                "'{}' cannot be combined with strip_prefix, append_only or max_total_size_bytes in '{}'",
+                ARCH_PLACEHOLDER, &repo.name
+            ));
         }
 
         if repo.destination.s3.is_some() && repo.destination.local.is_some() {
@@ -245,6 +726,37 @@ pub fn load_config(path: &str) -> Result<Config, String> {
             }
         }
 
+        if let Some(s3) = &repo.destination.s3 {
+            if let Some(key_encoding) = &s3.key_encoding {
+                if !["percent", "plus_as_space"].contains(&key_encoding.as_str()) {
+                    return Result::Err(format!(
+                        "unknown key_encoding '{}' for '{}', only 'percent' and 'plus_as_space' are supported",
+                        key_encoding, &repo.name
+                    ));
+                }
+            }
+            if s3.cloudfront_endpoint.is_some() != s3.cloudfront_distribution_id.is_some() {
+                return Result::Err(format!(
+                    "'{}' must set both cloudfront_endpoint and cloudfront_distribution_id, or neither",
+                    &repo.name
+                ));
+            }
+            if s3.max_concurrent_requests == 0 {
+                return Result::Err(format!(
+                    "max_concurrent_requests for '{}' must be greater than 0",
+                    &repo.name
+                ));
+            }
+            let location = format!("{}/{}/{}", s3.s3_endpoint, s3.s3_bucket, s3.path);
+            if let Some((_, other_name)) = used_s3_locations.iter().find(|(x, _)| x == &location) {
+                return Err(format!(
+                    "'{}' and '{}' would both synchronize to '{}'",
+                    other_name, &repo.name, location
+                ));
+            }
+            used_s3_locations.push((location, &repo.name));
+        }
+
         if repo.destination.local.is_some() {
             if !repo
                 .destination
@@ -257,11 +769,52 @@ pub fn load_config(path: &str) -> Result<Config, String> {
                 return Err(format!("local destination path must be absolute"));
             }
         }
+
+        if let Some(serve_prefix) = repo
+            .destination
+            .local
+            .as_ref()
+            .and_then(|x| x.serve_prefix.as_ref())
+        {
+            if !serve_prefix.starts_with('/') {
+                return Err(format!(
+                    "'{}' serve_prefix must start with '/' and cannot be the root",
+                    &repo.name
+                ));
+            }
+            for api_path in API_PATHS.iter() {
+                if overlaps(serve_prefix, api_path) {
+                    return Err(format!(
+                        "'{}' serve_prefix '{}' overlaps the api path '{}'",
+                        &repo.name, serve_prefix, api_path
+                    ));
+                }
+            }
+            for (used_prefix, used_name) in &used_prefixes {
+                if overlaps(serve_prefix, used_prefix) {
+                    return Err(format!(
+                        "'{}' and '{}' serve_prefix overlap",
+                        used_name, &repo.name
+                    ));
+                }
+            }
+            used_prefixes.push((serve_prefix, &repo.name));
+        }
     }
 
     Result::Ok(config)
 }
 
+//served files cannot shadow the http apis
+const API_PATHS: [&str; 3] = ["/health", "/reload", "/repository"];
+
+///true when a path is the same as or nested in the other
+fn overlaps(a: &str, b: &str) -> bool {
+    let nested =
+        |path: &str, prefix: &str| path == prefix || path.starts_with(&format!("{}/", prefix));
+    nested(a, b) || nested(b, a)
+}
+
 fn remove_initial_slash(s: &str) -> String {
     if s.starts_with("/") {
         let len = s.len();
@@ -279,9 +832,56 @@ fn remove_trailing_slash(s: &str) -> String {
     }
 }
 
+///an expired primary key cannot verify anything, expired subkeys may not be the signing ones
+fn check_key_expiry(
+    repo_name: &str,
+    public_key: &SignedPublicKey,
+    now: DateTime<Utc>,
+    warning_days: u32,
+) -> Result<Vec<String>, String> {
+    let primary = Signature::fingerprint(public_key);
+    let mut warnings = vec![];
+    for (fingerprint, expires_at) in Signature::expiries(public_key) {
+        let key = if fingerprint == primary {
+            "public key"
+        } else {
+            "public subkey"
+        };
+        if expires_at <= now {
+            if fingerprint == primary {
+                return Err(format!(
+                    "'{}' public key {} expired on {}",
+                    repo_name,
+                    fingerprint,
+                    expires_at.to_rfc3339()
+                ));
+            }
+            warnings.push(format!(
+                "'{}' {} {} expired on {}",
+                repo_name,
+                key,
+                fingerprint,
+                expires_at.to_rfc3339()
+            ));
+        } else if expires_at <= now + Duration::days(warning_days.into()) {
+            warnings.push(format!(
+                "'{}' {} {} expires on {}, in {} days",
+                repo_name,
+                key,
+                fingerprint,
+                expires_at.to_rfc3339(),
+                (expires_at - now).num_days()
+            ));
+        }
+    }
+    Ok(warnings)
+}
+
 #[cfg(test)]
 pub mod tests {
-    use crate::config::{load_config, SourceConfig};
+    use crate::config::{check_key_expiry, load_config, S3Destination, SourceConfig};
+    use chrono::{Duration, TimeZone, Utc};
+    use std::collections::BTreeMap;
     use std::fs;
 
     #[test]
@@ -293,6 +893,8 @@ pub mod tests {
         assert_eq!("centos8", repo0.name);
         let repo1 = config.repo.get(1).unwrap();
         assert_eq!("ubuntu", repo1.name);
+        let s3 = config.repo.iter().find_map(|x| x.destination.s3.as_ref());
+        assert_eq!(16, s3.unwrap().max_concurrent_requests);
         serde_yaml::to_string(&config).expect("cannot convert back to toml");
     }
 
@@ -308,8 +910,379 @@ pub mod tests {
             username: None,
             password: None,
             authorization_file: None,
+            packages_base: None,
+            strict_signatures: false,
+            signature_hash_algorithms: vec![],
+            metadata_path: None,
+            strip_prefix: None,
+            required_indexes: vec![],
+            headers: BTreeMap::new(),
+            channels: vec![],
         };
 
         source_config.parse_public_key().unwrap().unwrap();
     }
+
+    ///writes the repositories after a minimal general section
+    fn write_config(path: &str, repo_yaml: &str) {
+        fs::write(
+            path,
+            format!(
+                "general:
+  data_path: /data/repo/
+  tmp_path: /tmp/
+  bind_address: 127.0.0.1:8080
+  timeout: 60
+  max_retries: 3
+  retry_sleep: 5
+  min_sync_delay: 5
+  max_sync_delay: 30
+repo:
+{}",
+                repo_yaml
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn s3_locations_must_be_unique() {
+        let repo = |name: &str, path: &str, append_repo_name: bool| {
+            format!(
+                "  - name: {}
+    source:
+      kind: debian
+      endpoint: https://example.com
+    destination:
+      s3:
+        s3_endpoint: https://s3.example.com/
+        s3_bucket: my-bucket
+        path: {}
+        region_name: custom
+        access_key_id: key
+        access_key_secret: secret
+        append_repo_name: {}
+",
+                name, path, append_repo_name
+            )
+        };
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/config.yaml", tmp_dir.path().to_str().unwrap());
+
+        write_config(
+            &path,
+            &format!(
+                "{}{}",
+                repo("first", "/mirror/", true),
+                repo("second", "/mirror", true)
+            ),
+        );
+        let config = load_config(&path).unwrap();
+        assert_eq!(
+            "mirror/second",
+            config.repo[1].destination.s3.as_ref().unwrap().path
+        );
+
+        write_config(
+            &path,
+            &format!(
+                "{}{}",
+                repo("first", "/mirror/first", false),
+                repo("second", "/mirror", true)
+            ),
+        );
+        let config = load_config(&path);
+        assert!(config.is_ok());
+
+        write_config(
+            &path,
+            &format!(
+                "{}{}",
+                repo("first", "/mirror/second", false),
+                repo("second", "/mirror", true)
+            ),
+        );
+        assert_eq!(
+            "'first' and 'second' would both synchronize to 'https://s3.example.com/my-bucket/mirror/second'",
+            load_config(&path).err().unwrap()
+        );
+    }
+
+    #[test]
+    fn key_expiry_is_checked() {
+        let source_config = |path: &str| SourceConfig {
+            endpoint: "".to_string(),
+            kind: "".to_string(),
+            public_pgp_key: Some(fs::read_to_string(path).unwrap()),
+            username: None,
+            password: None,
+            authorization_file: None,
+            packages_base: None,
+            strict_signatures: false,
+            signature_hash_algorithms: vec![],
+            metadata_path: None,
+            strip_prefix: None,
+            required_indexes: vec![],
+            headers: BTreeMap::new(),
+            channels: vec![],
+        };
+        let never_expiring = source_config("samples/public-key")
+            .parse_public_key()
+            .unwrap()
+            .unwrap();
+        let far_future = Utc.timestamp_opt(4_000_000_000, 0).unwrap();
+        assert!(check_key_expiry("repo", &never_expiring, far_future, 30)
+            .unwrap()
+            .is_empty());
+
+        //the signing subkey expires a year before the primary key
+        let public_key = source_config("samples/expiring-public-key")
+            .parse_public_key()
+            .unwrap()
+            .unwrap();
+        let subkey_expiry = Utc.timestamp_opt(1_823_711_622, 0).unwrap();
+        let primary_expiry = Utc.timestamp_opt(1_855_247_622, 0).unwrap();
+        let check = |now| check_key_expiry("repo", &public_key, now, 30);
+        assert!(check(subkey_expiry - Duration::days(31))
+            .unwrap()
+            .is_empty());
+
+        let warnings = check(subkey_expiry - Duration::days(10)).unwrap();
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("public subkey"));
+        assert!(warnings[0].ends_with("in 10 days"));
+
+        let warnings = check(primary_expiry - Duration::days(1)).unwrap();
+        assert_eq!(2, warnings.len());
+        assert!(warnings[0].contains("public key"));
+
+        let err = check(primary_expiry).err().unwrap();
+        assert!(err.starts_with("'repo' public key "));
+        assert!(err.contains(" expired on "));
+    }
+
+    #[test]
+    fn cloudfront_settings_go_together() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/config.yaml", tmp_dir.path().to_str().unwrap());
+        let repo = |cloudfront: &str| {
+            format!(
+                "  - name: first
+    source:
+      kind: debian
+      endpoint: https://example.com
+    destination:
+      s3:
+        s3_endpoint: https://s3.example.com/
+        s3_bucket: my-bucket
+        path: /mirror
+        region_name: custom
+        access_key_id: key
+        access_key_secret: secret
+{}",
+                cloudfront
+            )
+        };
+
+        write_config(&path, &repo("        cloudfront_distribution_id: E123\n"));
+        assert_eq!(
+            "'first' must set both cloudfront_endpoint and cloudfront_distribution_id, or neither",
+            load_config(&path).err().unwrap()
+        );
+
+        write_config(
+            &path,
+            &repo("        cloudfront_endpoint: https://cloudfront.amazonaws.com/\n"),
+        );
+        assert!(load_config(&path).is_err());
+
+        write_config(
+            &path,
+            &repo("        cloudfront_endpoint: https://cloudfront.amazonaws.com/\n        cloudfront_distribution_id: E123\n"),
+        );
+        assert!(load_config(&path).is_ok());
+    }
+
+    #[test]
+    fn aws_credential_file_is_trimmed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/aws_credential", tmp_dir.path().to_str().unwrap());
+        let destination = S3Destination {
+            s3_endpoint: "".to_string(),
+            s3_bucket: "".to_string(),
+            path: "".to_string(),
+            cloudfront_endpoint: None,
+            cloudfront_distribution_id: None,
+            region_name: "".to_string(),
+            signing_region: None,
+            access_key_id: None,
+            access_key_secret: None,
+            aws_credential_file: Some(path.clone()),
+            append_repo_name: false,
+            verify_uploads: false,
+            key_encoding: None,
+            max_concurrent_requests: 16,
+            invalidate: true,
+            content_md5: true,
+        };
+
+        fs::write(&path, "\u{feff}key \r\n secret\t\n").unwrap();
+        let (access_key_id, access_key_secret) = destination.get_aws_credentials().unwrap();
+        assert_eq!("key", access_key_id);
+        assert_eq!("secret", access_key_secret);
+
+        fs::write(&path, "key\nsec ret\n").unwrap();
+        assert!(destination.get_aws_credentials().is_err());
+
+        fs::write(&path, "\nsecret\n").unwrap();
+        assert!(destination.get_aws_credentials().is_err());
+    }
+
+    #[test]
+    fn serve_prefixes_must_not_overlap() {
+        let repo = |name: &str, serve_prefix: &str| {
+            format!(
+                "  - name: {}
+    source:
+      kind: debian
+      endpoint: https://example.com
+    destination:
+      local:
+        path: /mirror/{}
+        serve_prefix: {}
+",
+                name, name, serve_prefix
+            )
+        };
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/config.yaml", tmp_dir.path().to_str().unwrap());
+        let load = |first: &str, second: &str| {
+            write_config(
+                &path,
+                &format!("{}{}", repo("first", first), repo("second", second)),
+            );
+            load_config(&path)
+        };
+
+        let config = load("/mirror/first/", "/mirror/firstly").unwrap();
+        assert_eq!(
+            Some("/mirror/first"),
+            config.repo[0]
+                .destination
+                .local
+                .as_ref()
+                .unwrap()
+                .serve_prefix
+                .as_deref()
+        );
+        assert_eq!(
+            "'first' and 'second' serve_prefix overlap",
+            load("/mirror", "/mirror/second").err().unwrap()
+        );
+        assert_eq!(
+            "'second' serve_prefix '/repository/x' overlaps the api path '/repository'",
+            load("/first", "/repository/x").err().unwrap()
+        );
+        assert_eq!(
+            "'first' serve_prefix must start with '/' and cannot be the root",
+            load("/", "/second").err().unwrap()
+        );
+    }
+
+    #[test]
+    fn repository_names_are_normalized() {
+        let repo = |name: &str| {
+            format!(
+                "  - name: \"{}\"
+    source:
+      kind: debian
+      endpoint: https://example.com
+    destination:
+      local:
+        path: /mirror
+",
+                name
+            )
+        };
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/config.yaml", tmp_dir.path().to_str().unwrap());
+        let load = |first: &str, second: &str| {
+            write_config(&path, &format!("{}{}", repo(first), repo(second)));
+            load_config(&path)
+        };
+
+        let config = load(" ubuntu/", "centos").unwrap();
+        assert_eq!("ubuntu", config.repo[0].name);
+        assert_eq!(
+            "'ubuntu' was used as repository name twice",
+            load("ubuntu", "ubuntu/ ").err().unwrap()
+        );
+        assert_eq!(
+            "'ubuntu' and 'Ubuntu' repository names differ only by case",
+            load("ubuntu", "Ubuntu").err().unwrap()
+        );
+        assert_eq!(
+            "'All' was used as repository name, but 'all' is a reserved word'",
+            load("ubuntu", "All").err().unwrap()
+        );
+        assert_eq!(
+            "empty repository name",
+            load("ubuntu", " / ").err().unwrap()
+        );
+    }
+
+    #[test]
+    fn arch_placeholder_ends_the_destination_path() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/config.yaml", tmp_dir.path().to_str().unwrap());
+        let load = |destination_path: &str| {
+            write_config(
+                &path,
+                &format!(
+                    "  - name: centos
+    source:
+      kind: redhat
+      endpoint: https://example.com
+    destination:
+      local:
+        path: {}
+",
+                    destination_path
+                ),
+            );
+            load_config(&path)
+        };
+
+        let config = load("/mirror/{arch}/").unwrap();
+        assert!(config.repo[0].destination.arch_prefix);
+        assert_eq!(
+            "/mirror",
+            config.repo[0].destination.local.as_ref().unwrap().path
+        );
+        assert!(!load("/mirror").unwrap().repo[0].destination.arch_prefix);
+        assert_eq!(
+            "'{arch}' can only be the last segment of the destination path of 'centos'",
+            load("/mirror/{arch}/centos").err().unwrap()
+        );
+    }
+
+    #[test]
+    fn signing_region_defaults_to_region_name() {
+        let s3 = |extra: &str| -> S3Destination {
+            serde_yaml::from_str(&format!(
+                "s3_endpoint: https://s3.example.com/
+s3_bucket: my-bucket
+path: /mirror
+region_name: custom
+{}",
+                extra
+            ))
+            .unwrap()
+        };
+        assert_eq!("custom", s3("").get_signing_region());
+        assert_eq!(
+            "us-east-1",
+            s3("signing_region: us-east-1").get_signing_region()
+        );
+    }
 }
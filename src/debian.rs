@@ -1,12 +1,17 @@
 use crate::config::RepositoryConfig;
+use crate::error::SyncError;
 use crate::fetcher::Fetcher;
 use crate::packages::{Collection, Hash, IndexFile, Package, Repository, Signature, Target};
 use crate::state::{LiveRepoMetadataStore, RepoMetadataStore, SavedRepoMetadataStore};
-use crate::utils::add_optional_index;
+use crate::utils::{add_optional_index, human_println};
+use flate2::read::GzDecoder;
 use regex::Regex;
-use std::io::{BufRead, BufReader, ErrorKind, Read};
-use std::rc::Rc;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 struct PackagesReference {
@@ -24,35 +29,77 @@ pub struct Release {
 }
 
 pub fn fetch_repository(
-    fetcher: Rc<dyn Fetcher>,
+    fetcher: Arc<dyn Fetcher>,
     tmp_path: &str,
     config: &RepositoryConfig,
-) -> Result<(Repository, LiveRepoMetadataStore), std::io::Error> {
+) -> Result<(Repository, LiveRepoMetadataStore), SyncError> {
     let repo_metadata = LiveRepoMetadataStore::new(&config.source.endpoint, tmp_path, fetcher)?;
-    let result = fetch_repository_internal(&repo_metadata, config, false);
-    if let Err(err) = result {
-        return Err(std::io::Error::new(
-            err.kind(),
-            format!("cannot fetch repo state: {}", &err.to_string()),
-        ));
-    }
-    Ok((result.unwrap(), repo_metadata))
+    let repo = fetch_repository_internal(&repo_metadata, config, false)
+        .map_err(|err| err.context("cannot fetch repo state"))?;
+    Ok((repo, repo_metadata))
 }
 
 pub fn load_repository(
     data_path: &str,
     config: &RepositoryConfig,
-) -> Result<(Repository, SavedRepoMetadataStore), std::io::Error> {
+) -> Result<(Repository, SavedRepoMetadataStore), SyncError> {
     let repo_metadata = SavedRepoMetadataStore::new(data_path);
-    let result = fetch_repository_internal(&repo_metadata, config, true);
-    if let Err(err) = result {
-        return Err(std::io::Error::new(
-            err.kind(),
-            format!("cannot load current repo state: {}", &err.to_string()),
-        ));
+    let repo = fetch_repository_internal(&repo_metadata, config, true)
+        .map_err(|err| err.context("cannot load current repo state"))?;
+    Ok((repo, repo_metadata))
+}
+
+const POCKET_SUFFIXES: [&str; 4] = ["", "-updates", "-security", "-backports"];
+
+///expands 'focal*' into every standard pocket, returns (codename, expanded)
+fn expand_versions(versions: &[String]) -> Vec<(String, bool)> {
+    let mut expanded_versions = vec![];
+    for version in versions {
+        if let Some(codename) = version.strip_suffix('*') {
+            for suffix in &POCKET_SUFFIXES {
+                expanded_versions.push((format!("{}{}", codename, suffix), true));
+            }
+        } else {
+            expanded_versions.push((version.clone(), false));
+        }
+    }
+    expanded_versions
+}
+
+///a flat repository has a single Release at the root of the endpoint, without dists
+fn is_flat(config: &RepositoryConfig) -> bool {
+    config.source.kind == "debian-flat"
+}
+
+///(release name, directory of the Release, expanded), the directory is empty for flat repositories
+fn releases(config: &RepositoryConfig) -> Vec<(String, String, bool)> {
+    if is_flat(config) {
+        return vec![(String::new(), String::new(), false)];
     }
+    expand_versions(&config.versions)
+        .into_iter()
+        .map(|(version, expanded)| {
+            let directory = format!("dists/{}", version);
+            (version, directory, expanded)
+        })
+        .collect()
+}
+
+///path of a file listed by the Release in the given directory
+fn release_file(directory: &str, file: &str) -> String {
+    if directory.is_empty() {
+        file.into()
+    } else {
+        format!("{}/{}", directory, file)
+    }
+}
 
-    Ok((result.unwrap(), repo_metadata))
+///every Release file, the entry points of the repository
+pub fn entry_points(config: &RepositoryConfig) -> Vec<String> {
+    releases(config)
+        .iter()
+        .map(|(_, directory, _)| release_file(directory, "Release"))
+        .collect()
 }
 
 //internal function for dependency injection
@@ -60,23 +107,24 @@ fn fetch_repository_internal<T>(
     state: &T,
     config: &RepositoryConfig,
     allow_empty: bool,
-) -> Result<Repository, std::io::Error>
+) -> Result<Repository, SyncError>
 where
-    T: RepoMetadataStore,
+    T: RepoMetadataStore + Sync,
 {
     let mut repo = Repository {
         name: config.name.clone(),
         collections: vec![],
     };
+    //the pool is shared by every version and component, path -> (package, Packages index)
+    let mut pool: HashMap<String, (Package, String)> = HashMap::new();
 
-    for version_codename in &config.versions {
-        let version_path = format!("dists/{}", version_codename);
-        let path = format!("{}/Release", &version_path);
+    for (version_codename, version_path, expanded) in releases(config) {
+        let path = release_file(&version_path, "Release");
         let result = state.fetch(&path);
-        if allow_empty {
+        if allow_empty || expanded {
             if let Err(err) = result {
-                //mostly useful when adding a new distribution
-                if err.kind() == ErrorKind::NotFound {
+                //mostly useful when adding a new distribution or a pocket missing upstream
+                if err.is_not_found() {
                     continue;
                 } else {
                     return Err(err);
@@ -95,13 +143,13 @@ where
         //this index file is optional
         add_optional_index(
             state,
-            &format!("{}/InRelease", version_path),
+            &release_file(&version_path, "InRelease"),
             &mut indexes,
             Signature::PGPEmbedded,
         )?;
         let signature = add_optional_index(
             state,
-            &format!("{}/Release.gpg", version_path),
+            &release_file(&version_path, "Release.gpg"),
             &mut indexes,
             Signature::None,
         )?;
@@ -136,25 +184,103 @@ where
         }
 
         let mut packages: Vec<Package> = Vec::new();
+        let packages_paths: Vec<String> = release
+            .indexes
+            .iter()
+            .filter(|index| index.path.ends_with("Packages"))
+            .map(|index| index.path.clone())
+            .collect();
+
+        let mut dropped: Vec<String> = vec![];
+        if let Some(publish_variants) = &config.publish_variants {
+            //the rewritten Release cannot be signed again
+            if indexes.len() > 1 {
+                return Err(SyncError::Refused(format!(
+                    "publish_variants would invalidate the signature of '{}', it's only supported for unsigned repositories",
+                    &indexes[0].path
+                )));
+            }
+            dropped = release
+                .indexes
+                .iter()
+                .filter(|index| !is_published_variant(&index.path, publish_variants))
+                .map(|index| index.path.clone())
+                .collect();
+            //read_packages parses the plain variants, which are fetched even when unpublished
+            release.indexes.retain(|index| {
+                !dropped.contains(&index.path) || packages_paths.contains(&index.path)
+            });
+
+            let release_index = &mut indexes[0];
+            let mut text = String::new();
+            state
+                .read(&release_index.path)?
+                .unwrap()
+                .read_to_string(&mut text)?;
+            let text = rewrite_release(&text, &version_path, &dropped);
+            release_index.file_path = format!("{}.published", release_index.file_path);
+            std::fs::write(&release_index.file_path, &text)?;
+            release_index.size = text.len() as u64;
+            release_index.hash = Hash::create_sha256_hash(&mut text.as_bytes())?;
+        }
 
-        for index in &mut release.indexes {
-            let (disk_path, reader, size) = state.fetch(&index.path)?;
+        //a stale size on a single compression variant is tolerated, as long as
+        //a readable variant of the same index is valid
+        let mut valid_indexes: Vec<IndexFile> = vec![];
+        let mut invalid_indexes: Vec<(String, SyncError)> = vec![];
+        let fetched = fetch_indexes(
+            state,
+            &release.indexes,
+            config.get_max_concurrent_index_fetches(),
+        )?;
+        for (mut index, (disk_path, size)) in release.indexes.drain(..).zip(fetched) {
             index.file_path = disk_path;
             if index.size != size {
-                return Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!(
-                        "wrong file size for '{}', expected: {} found {}",
-                        index.path, index.size, size
-                    ),
-                ));
+                let err = SyncError::SizeMismatch {
+                    path: index.path.clone(),
+                    expected: index.size,
+                    found: size,
+                };
+                human_println!("{}", err);
+                invalid_indexes.push((logical_index_path(&index.path).into(), err));
+            } else {
+                valid_indexes.push(index);
+            }
+        }
+
+        //only the plain and gzip variants are read, see read_packages
+        for (logical_path, err) in invalid_indexes {
+            let gz_path = format!("{}.gz", logical_path);
+            let variant = valid_indexes
+                .iter()
+                .find(|index| index.path == logical_path || index.path == gz_path);
+            match variant {
+                Some(variant) => human_println!("using '{}' for '{}'", variant.path, logical_path),
+                None => return Err(err),
+            }
+        }
+
+        for path in &packages_paths {
+            let mut parsed = parse_packages(read_packages(state, path, &valid_indexes)?)?;
+            //flat indexes usually list packages as './name.deb'
+            if is_flat(config) {
+                for package in parsed.iter_mut() {
+                    if let Some(stripped) = package.path.strip_prefix("./") {
+                        package.path = stripped.into();
+                    }
+                }
             }
-            if index.path.ends_with("Packages") {
-                packages.append(&mut parse_packages(reader)?);
+            if !config.allow_pool_conflicts {
+                check_pool_paths(&mut pool, &parsed, path)?;
             }
+            packages.append(&mut parsed);
         }
+        //'Architecture: all' packages are listed in the Packages of every architecture
+        let mut paths: HashSet<String> = HashSet::new();
+        packages.retain(|package| paths.insert(package.path.clone()));
 
-        indexes.append(&mut release.indexes);
+        valid_indexes.retain(|index| !dropped.contains(&index.path));
+        indexes.append(&mut valid_indexes);
 
         repo.collections.push(Collection {
             target: Target {
@@ -169,7 +295,136 @@ where
     Ok(repo)
 }
 
-pub fn parse_release<R>(input_read: R, base_path: &str) -> Result<Release, std::io::Error>
+///fails when a path is already listed by another index with a different file
+fn check_pool_paths(
+    pool: &mut HashMap<String, (Package, String)>,
+    packages: &[Package],
+    index_path: &str,
+) -> Result<(), SyncError> {
+    for package in packages {
+        match pool.get(&package.path) {
+            Some((listed, listed_by)) if !listed.same_file(package) => {
+                return Err(SyncError::Parse(format!(
+                    "'{}' is listed as {} ({} bytes) by '{}' and as {} ({} bytes) by '{}'",
+                    package.path,
+                    listed.hash,
+                    listed.size,
+                    listed_by,
+                    package.hash,
+                    package.size,
+                    index_path
+                )));
+            }
+            Some(_) => {}
+            None => {
+                pool.insert(
+                    package.path.clone(),
+                    (package.clone(), index_path.to_string()),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+type FetchedIndex = Result<(String, u64), SyncError>;
+
+///fetches the indexes with up to max_concurrent threads, returns (file path, size) in the
+///order of indexes, or the error of the first failed one
+fn fetch_indexes<T>(
+    state: &T,
+    indexes: &[IndexFile],
+    max_concurrent: usize,
+) -> Result<Vec<(String, u64)>, SyncError>
+where
+    T: RepoMetadataStore + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<FetchedIndex>>> =
+        Mutex::new(indexes.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..max_concurrent.min(indexes.len()) {
+            scope.spawn(|| loop {
+                let n = next.fetch_add(1, Ordering::SeqCst);
+                if n >= indexes.len() {
+                    break;
+                }
+                let result = state
+                    .fetch(&indexes[n].path)
+                    .map(|(disk_path, _, size)| (disk_path, size));
+                results.lock().unwrap()[n] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every index is fetched"))
+        .collect()
+}
+
+///path of the uncompressed index, e.g. 'main/binary-amd64/Packages' for 'main/binary-amd64/Packages.gz'
+fn logical_index_path(path: &str) -> &str {
+    for extension in &[".gz", ".bz2", ".xz", ".lzma"] {
+        if path.ends_with(extension) {
+            return &path[0..path.len() - extension.len()];
+        }
+    }
+    path
+}
+
+///only Packages variants are filtered, every other index is always published
+fn is_published_variant(path: &str, publish_variants: &[String]) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    logical_index_path(file_name) != "Packages" || publish_variants.iter().any(|x| x == file_name)
+}
+
+///removes the dropped indexes from the checksum lists, by-hash is disabled as it's not mirrored
+fn rewrite_release(text: &str, base_path: &str, dropped: &[String]) -> String {
+    text.split_inclusive('\n')
+        .filter(|line| match line.split_whitespace().last() {
+            Some(path) if line.starts_with(' ') => {
+                !dropped.contains(&release_file(base_path, path))
+            }
+            _ => true,
+        })
+        .map(|line| {
+            if line.starts_with("Acquire-By-Hash:") {
+                line.replace("yes", "no")
+            } else {
+                line.to_string()
+            }
+        })
+        .collect()
+}
+
+///reads the Packages index, falling back to the gzip variant when the plain one is invalid
+fn read_packages<T>(
+    state: &T,
+    path: &str,
+    valid_indexes: &[IndexFile],
+) -> Result<Box<dyn Read>, SyncError>
+where
+    T: RepoMetadataStore,
+{
+    let is_valid = |path: &str| valid_indexes.iter().any(|index| index.path == path);
+    if is_valid(path) {
+        return Ok(state.read(path)?.unwrap());
+    }
+
+    let gz_path = format!("{}.gz", path);
+    if is_valid(&gz_path) {
+        return Ok(Box::new(GzDecoder::new(state.read(&gz_path)?.unwrap())));
+    }
+
+    Err(SyncError::Parse(format!(
+        "no readable variant for '{}'",
+        path
+    )))
+}
+
+pub fn parse_release<R>(input_read: R, base_path: &str) -> Result<Release, SyncError>
 where
     R: Read,
 {
@@ -190,7 +445,11 @@ where
             break;
         }
 
-        let line = String::from_utf8(buffer).unwrap().replace("\n", "");
+        //CRLF line endings are accepted as well
+        let line = String::from_utf8(buffer)
+            .unwrap()
+            .trim_end_matches(&['\r', '\n'][..])
+            .to_string();
         if line.starts_with(" ") {
             if parsing_sha256 {
                 let re = Regex::new(" *([a-z0-9]+) *([0-9]+) *(.*)").unwrap();
@@ -198,17 +457,14 @@ where
                     if group.len() == 4 {
                         let size = u64::from_str(group.get(2).unwrap().as_str());
                         if size.is_err() {
-                            return Result::Err(std::io::Error::new(
-                                ErrorKind::InvalidData,
-                                format!(
-                                    "cannot parse release file, invalid number in line: {}",
-                                    line
-                                ),
-                            ));
+                            return Result::Err(SyncError::Parse(format!(
+                                "cannot parse release file, invalid number in line: {}",
+                                line
+                            )));
                         }
                         release.indexes.push(IndexFile {
                             file_path: "".into(),
-                            path: format!("{}/{}", base_path, group.get(3).unwrap().as_str()),
+                            path: release_file(base_path, group.get(3).unwrap().as_str()),
                             size: size.unwrap(),
                             hash: Hash::Sha256 {
                                 hex: group.get(1).unwrap().as_str().to_string(),
@@ -216,16 +472,16 @@ where
                             signature: Signature::None,
                         })
                     } else {
-                        return Result::Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            format!("cannot parse release file, invalid line: {}", line),
-                        ));
+                        return Result::Err(SyncError::Parse(format!(
+                            "cannot parse release file, invalid line: {}",
+                            line
+                        )));
                     }
                 } else {
-                    return Result::Err(std::io::Error::new(
-                        ErrorKind::InvalidData,
-                        format!("cannot parse release file, invalid line: {}", line),
-                    ));
+                    return Result::Err(SyncError::Parse(format!(
+                        "cannot parse release file, invalid line: {}",
+                        line
+                    )));
                 }
             }
             continue;
@@ -234,10 +490,10 @@ where
 
         let tokens: Vec<&str> = line.splitn(2, ":").collect();
         if tokens.len() != 2 {
-            return Result::Err(std::io::Error::new(
-                ErrorKind::InvalidData,
-                format!("cannot parse release file, invalid line: {}", line),
-            ));
+            return Result::Err(SyncError::Parse(format!(
+                "cannot parse release file, invalid line: {}",
+                line
+            )));
         }
 
         let key = *tokens.get(0).unwrap();
@@ -255,11 +511,22 @@ where
     Result::Ok(release)
 }
 
+fn warn_weak_hash(package: &Package) {
+    match package.hash {
+        Hash::Md5 { .. } | Hash::Sha1 { .. } => human_println!(
+            "{} has no SHA256 checksum, falling back to {}",
+            package.path,
+            package.hash
+        ),
+        _ => {}
+    }
+}
+
 /**
     Parse packages filename streaming line per line, line which starts with an empty space(' ')
     are a continuation of the previous line.
 */
-pub fn parse_packages<R>(input_read: R) -> Result<Vec<Package>, std::io::Error>
+pub fn parse_packages<R>(input_read: R) -> Result<Vec<Package>, SyncError>
 where
     R: Read,
 {
@@ -270,52 +537,66 @@ where
     let mut packages: Vec<Package> = Vec::new();
     let mut current = Package::empty();
 
-    let check_and_add = |key: &mut String,
-                         value: &mut String,
-                         current: &mut Package|
-     -> Result<(), std::io::Error> {
-        if key.len() > 0 {
-            match key.as_str() {
-                "Package" => current.name = value.clone(),
-                "Version" => current.version = value.clone(),
-                "Architecture" => current.architecture = value.clone(),
-                "Filename" => current.path = value.clone(),
-                "SHA256" => current.hash = Hash::Sha256 { hex: value.clone() },
-                "Size" => {
-                    let clean_value = value.trim();
-                    let result = u64::from_str(clean_value);
-                    if result.is_err() {
-                        return Result::Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            format!("invalid number {}", clean_value),
-                        ));
+    let check_and_add =
+        |key: &mut String, value: &mut String, current: &mut Package| -> Result<(), SyncError> {
+            if key.len() > 0 {
+                match key.as_str() {
+                    "Package" => current.name = value.clone(),
+                    "Version" => current.version = value.clone(),
+                    "Architecture" => current.architecture = value.clone(),
+                    "Filename" => current.path = value.clone(),
+                    //prefer the strongest checksum, whatever the order of the fields
+                    "SHA256" | "SHA1" | "MD5sum" => {
+                        let hash = match key.as_str() {
+                            "SHA256" => Hash::Sha256 { hex: value.clone() },
+                            "SHA1" => Hash::Sha1 { hex: value.clone() },
+                            _ => Hash::Md5 { hex: value.clone() },
+                        };
+                        if hash.strength() > current.hash.strength() {
+                            current.hash = hash;
+                        }
+                    }
+                    "Size" => {
+                        let clean_value = value.trim();
+                        let result = u64::from_str(clean_value);
+                        if result.is_err() {
+                            return Result::Err(SyncError::Parse(format!(
+                                "invalid number {}",
+                                clean_value
+                            )));
+                        }
+                        current.size = result.unwrap();
                     }
-                    current.size = result.unwrap();
+                    _ => {}
                 }
-                _ => {}
+                key.clear();
+                value.clear();
             }
-            key.clear();
-            value.clear();
-        }
 
-        Result::Ok(())
-    };
+            Result::Ok(())
+        };
 
     loop {
         let mut buffer: Vec<u8> = Vec::new();
         if input.read_until(b'\n', &mut buffer)? == 0 {
             check_and_add(&mut key, &mut value, &mut current)?;
             if current.name.len() > 0 {
+                warn_weak_hash(&current);
                 packages.push(current);
             }
             break;
         }
 
-        let line = String::from_utf8(buffer).unwrap().replace("\n", "");
+        //CRLF line endings are accepted as well
+        let line = String::from_utf8(buffer)
+            .unwrap()
+            .trim_end_matches(&['\r', '\n'][..])
+            .to_string();
 
         if line.is_empty() {
             check_and_add(&mut key, &mut value, &mut current)?;
             if current.name.len() > 0 {
+                warn_weak_hash(&current);
                 packages.push(current);
                 current = Package::empty();
             }
@@ -329,10 +610,7 @@ where
             check_and_add(&mut key, &mut value, &mut current)?;
             let tokens: Vec<&str> = line.splitn(2, ":").collect();
             if tokens.len() != 2 || tokens.get(1).unwrap().is_empty() {
-                return Result::Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!("invalid line {}", line),
-                ));
+                return Result::Err(SyncError::Parse(format!("invalid line {}", line)));
             }
 
             key = (*tokens.get(0).unwrap()).into();
@@ -348,14 +626,65 @@ where
 pub mod tests {
     use crate::config::{DestinationConfig, RepositoryConfig, SourceConfig};
     use crate::debian::{
-        fetch_repository_internal, parse_packages, parse_release, LiveRepoMetadataStore, Package,
+        check_pool_paths, expand_versions, fetch_indexes, fetch_repository_internal,
+        parse_packages, parse_release, LiveRepoMetadataStore, Package,
     };
-    use crate::fetcher::MockFetcher;
+    use crate::error::SyncError;
+    use crate::fetcher::{FetchError, MockFetcher};
     use crate::packages::{Hash, IndexFile, Signature};
-    use crate::state::RepoMetadataStore;
+    use crate::state::{MemoryRepoMetadataStore, RepoMetadataStore};
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs;
     use std::fs::File;
     use std::io::Read;
-    use std::rc::Rc;
+    use std::sync::Arc;
+
+    fn create_config() -> RepositoryConfig {
+        RepositoryConfig {
+            name: "test-repo".to_string(),
+            source: SourceConfig {
+                endpoint: "http://fake-url".to_string(),
+                kind: "debian".to_string(),
+                public_pgp_key: None,
+                username: None,
+                password: None,
+                authorization_file: None,
+                packages_base: None,
+                strict_signatures: false,
+                signature_hash_algorithms: vec![],
+                metadata_path: None,
+                strip_prefix: None,
+                required_indexes: vec![],
+                headers: BTreeMap::new(),
+                channels: vec![],
+            },
+            destination: DestinationConfig {
+                s3: None,
+                local: None,
+                arch_prefix: false,
+            },
+            versions: vec!["focal".into()],
+            tmp_path: None,
+            pin: false,
+            best_effort: false,
+            metadata_only: false,
+            publish_public_key: None,
+            manifest_path: None,
+            publish_variants: None,
+            architectures: None,
+            max_requests_per_sec: None,
+            max_download_bytes_per_sec: None,
+            max_concurrent_index_fetches: None,
+            wildcard_invalidation: false,
+            append_only: false,
+            skip_unchanged: false,
+            verify_indexes: false,
+            allow_pool_conflicts: false,
+            max_total_size_bytes: None,
+            delete_grace_secs: 0,
+            extra_files: vec![],
+        }
+    }
 
     #[test]
     fn fetch_repository_state() {
@@ -399,31 +728,11 @@ pub mod tests {
         let state = LiveRepoMetadataStore::new(
             "http://fake-url/rc",
             tmp_dir.path().to_str().unwrap(),
-            Rc::new(mock_fetcher),
+            Arc::new(mock_fetcher),
         )
         .unwrap();
 
-        let repository = fetch_repository_internal(
-            &state,
-            &RepositoryConfig {
-                name: "test-repo".to_string(),
-                source: SourceConfig {
-                    endpoint: "http://fake-url".to_string(),
-                    kind: "".to_string(),
-                    public_pgp_key: None,
-                    username: None,
-                    password: None,
-                    authorization_file: None,
-                },
-                destination: DestinationConfig {
-                    s3: None,
-                    local: None,
-                },
-                versions: vec!["focal".into()],
-            },
-            false,
-        )
-        .unwrap();
+        let repository = fetch_repository_internal(&state, &create_config(), false).unwrap();
 
         assert_eq!("test-repo", repository.name);
         assert_eq!(1, repository.collections.len());
@@ -431,7 +740,8 @@ pub mod tests {
         let collection0 = repository.collections.get(0).unwrap();
         assert_eq!(vec!["amd64", "i386"], collection0.target.architectures);
         assert_eq!("focal", collection0.target.release_name);
-        assert_eq!(4, collection0.packages.len());
+        //both architectures list the same packages, they are kept once
+        assert_eq!(2, collection0.packages.len());
         assert_eq!(9, collection0.indexes.len());
 
         let mut text = String::new();
@@ -446,6 +756,292 @@ pub mod tests {
         assert!(state.read("un-existing-file").unwrap().is_none())
     }
 
+    fn fetch_with_stale_indexes(
+        stale_urls: &'static [&'static str],
+    ) -> Result<crate::packages::Repository, crate::error::SyncError> {
+        let mut mock_fetcher = MockFetcher::new();
+
+        mock_fetcher
+            .expect_fetch()
+            .times(9)
+            .returning(move |url: &str| {
+                Result::Ok(Box::new(if stale_urls.contains(&url) {
+                    File::open("samples/debian/Packages.2").unwrap()
+                } else if url.ends_with("/Release") || url.ends_with("/InRelease") {
+                    File::open("samples/debian/Release").unwrap()
+                } else if url.ends_with("/Release.gpg") {
+                    File::open("samples/fake-signature").unwrap()
+                } else {
+                    File::open("samples/debian/Packages").unwrap()
+                }))
+            });
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            tmp_dir.path().to_str().unwrap(),
+            Arc::new(mock_fetcher),
+        )
+        .unwrap();
+
+        fetch_repository_internal(&state, &create_config(), false)
+    }
+
+    #[test]
+    fn fallback_on_stale_index_variant() {
+        let repository = fetch_with_stale_indexes(&[
+            "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.bz2",
+        ])
+        .unwrap();
+        let collection0 = repository.collections.first().unwrap();
+        assert_eq!(2, collection0.packages.len());
+        assert_eq!(8, collection0.indexes.len());
+        assert!(!collection0
+            .indexes
+            .iter()
+            .any(|index| index.path == "dists/focal/main/binary-amd64/Packages.bz2"));
+
+        let result = fetch_with_stale_indexes(&[
+            "http://fake-url/rc/dists/focal/main/binary-amd64/Packages",
+            "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.bz2",
+            "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.gz",
+        ]);
+        assert!(result.is_err());
+
+        //a valid variant which cannot be read is no fallback
+        let result = fetch_with_stale_indexes(&[
+            "http://fake-url/rc/dists/focal/main/binary-amd64/Packages",
+            "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.gz",
+        ]);
+        match result.err().unwrap() {
+            SyncError::SizeMismatch { path, .. } => {
+                assert_eq!("dists/focal/main/binary-amd64/Packages", path)
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn expand_version_pockets() {
+        assert_eq!(
+            vec![
+                ("bionic".to_string(), false),
+                ("focal".to_string(), true),
+                ("focal-updates".to_string(), true),
+                ("focal-security".to_string(), true),
+                ("focal-backports".to_string(), true),
+            ],
+            expand_versions(&["bionic".into(), "focal*".into()])
+        );
+    }
+
+    #[test]
+    fn missing_pockets_are_skipped() {
+        let mut mock_fetcher = MockFetcher::new();
+
+        mock_fetcher
+            .expect_fetch()
+            .times(12)
+            .returning(|url: &str| {
+                if url.starts_with("http://fake-url/rc/dists/focal-") {
+                    return Err(FetchError {
+                        code: 404,
+                        error: "not found".into(),
+                    });
+                }
+                Result::Ok(Box::new(
+                    if url.ends_with("/Release") || url.ends_with("/InRelease") {
+                        File::open("samples/debian/Release").unwrap()
+                    } else if url.ends_with("/Release.gpg") {
+                        File::open("samples/fake-signature").unwrap()
+                    } else {
+                        File::open("samples/debian/Packages").unwrap()
+                    },
+                ))
+            });
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            tmp_dir.path().to_str().unwrap(),
+            Arc::new(mock_fetcher),
+        )
+        .unwrap();
+
+        let repository = fetch_repository_internal(
+            &state,
+            &RepositoryConfig {
+                versions: vec!["focal*".into()],
+                ..create_config()
+            },
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(1, repository.collections.len());
+        assert_eq!("focal", repository.collections[0].target.release_name);
+    }
+
+    #[test]
+    fn parse_crlf_indexes() {
+        let text = fs::read_to_string("samples/debian/Packages").unwrap();
+        assert_eq!(
+            parse_packages(text.as_bytes()).unwrap(),
+            parse_packages(text.replace('\n', "\r\n").as_bytes()).unwrap()
+        );
+
+        let text = fs::read_to_string("samples/debian/Release").unwrap();
+        assert_eq!(
+            parse_release(text.as_bytes(), "dists/focal").unwrap(),
+            parse_release(text.replace('\n', "\r\n").as_bytes(), "dists/focal").unwrap()
+        );
+    }
+
+    #[test]
+    fn prefer_strongest_package_checksum() {
+        let text =
+            "Package: strong\nFilename: strong.deb\nSize: 1\nMD5sum: aa\nSHA256: cc\nSHA1: bb\n\n\
+                    Package: weak\nFilename: weak.deb\nSize: 1\nMD5sum: aa\nSHA1: bb\n\n\
+                    Package: weakest\nFilename: weakest.deb\nSize: 1\nMD5sum: aa\n";
+        let packages = parse_packages(text.as_bytes()).unwrap();
+        assert_eq!(
+            vec![
+                Hash::Sha256 { hex: "cc".into() },
+                Hash::Sha1 { hex: "bb".into() },
+                Hash::Md5 { hex: "aa".into() },
+            ],
+            packages
+                .into_iter()
+                .map(|package| package.hash)
+                .collect::<Vec<Hash>>()
+        );
+    }
+
+    #[test]
+    fn publish_only_selected_variants() {
+        let mut mock_fetcher = MockFetcher::new();
+
+        mock_fetcher
+            .expect_fetch()
+            .times(5)
+            .returning(|url: &str| match url {
+                "http://fake-url/rc/dists/focal/Release" => {
+                    Ok(Box::new(File::open("samples/debian/Release").unwrap()))
+                }
+                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages"
+                | "http://fake-url/rc/dists/focal/main/binary-i386/Packages" => {
+                    Ok(Box::new(File::open("samples/debian/Packages").unwrap()))
+                }
+                _ => Err(FetchError {
+                    code: 404,
+                    error: "not found".into(),
+                }),
+            });
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            tmp_dir.path().to_str().unwrap(),
+            Arc::new(mock_fetcher),
+        )
+        .unwrap();
+
+        let repository = fetch_repository_internal(
+            &state,
+            &RepositoryConfig {
+                publish_variants: Some(vec!["Packages".into()]),
+                ..create_config()
+            },
+            false,
+        )
+        .unwrap();
+
+        let collection0 = repository.collections.first().unwrap();
+        assert_eq!(2, collection0.packages.len());
+        assert_eq!(
+            vec![
+                "dists/focal/Release",
+                "dists/focal/main/binary-amd64/Packages",
+                "dists/focal/main/binary-i386/Packages"
+            ],
+            collection0
+                .indexes
+                .iter()
+                .map(|index| index.path.as_str())
+                .collect::<Vec<&str>>()
+        );
+
+        let release = fs::read_to_string(&collection0.indexes[0].file_path).unwrap();
+        assert_eq!(release.len() as u64, collection0.indexes[0].size);
+        assert!(release.contains("Acquire-By-Hash: no"));
+        assert!(release.contains(" main/binary-amd64/Packages\n"));
+        assert!(!release.contains("Packages.gz"));
+        assert!(!release.contains("Packages.bz2"));
+    }
+
+    #[test]
+    fn publish_only_unreadable_variants() {
+        let mut mock_fetcher = MockFetcher::new();
+
+        mock_fetcher
+            .expect_fetch()
+            .times(7)
+            .returning(|url: &str| match url {
+                "http://fake-url/rc/dists/focal/Release" => {
+                    Ok(Box::new(File::open("samples/debian/Release").unwrap()))
+                }
+                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages"
+                | "http://fake-url/rc/dists/focal/main/binary-i386/Packages"
+                | "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.bz2"
+                | "http://fake-url/rc/dists/focal/main/binary-i386/Packages.bz2" => {
+                    Ok(Box::new(File::open("samples/debian/Packages").unwrap()))
+                }
+                _ => Err(FetchError {
+                    code: 404,
+                    error: "not found".into(),
+                }),
+            });
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            tmp_dir.path().to_str().unwrap(),
+            Arc::new(mock_fetcher),
+        )
+        .unwrap();
+
+        //the plain Packages are parsed but neither published nor listed
+        let repository = fetch_repository_internal(
+            &state,
+            &RepositoryConfig {
+                publish_variants: Some(vec!["Packages.bz2".into()]),
+                ..create_config()
+            },
+            false,
+        )
+        .unwrap();
+
+        let collection0 = repository.collections.first().unwrap();
+        assert_eq!(2, collection0.packages.len());
+        assert_eq!(
+            vec![
+                "dists/focal/Release",
+                "dists/focal/main/binary-amd64/Packages.bz2",
+                "dists/focal/main/binary-i386/Packages.bz2"
+            ],
+            collection0
+                .indexes
+                .iter()
+                .map(|index| index.path.as_str())
+                .collect::<Vec<&str>>()
+        );
+
+        let release = fs::read_to_string(&collection0.indexes[0].file_path).unwrap();
+        assert!(release.contains(" main/binary-amd64/Packages.bz2\n"));
+        assert!(!release.contains(" main/binary-amd64/Packages\n"));
+        assert!(!release.contains("Packages.gz"));
+    }
+
     #[test]
     fn load_sample_release() {
         let reader = File::open("samples/debian/Release").unwrap();
@@ -536,6 +1132,8 @@ pub mod tests {
                             .into()
                     },
                     size: 20,
+                    file_time: 0,
+                    build_time: 0,
                 },
                 Package {
                     name: "service-discover-agent".to_string(),
@@ -546,10 +1144,125 @@ pub mod tests {
                         hex: "9ed5e5312df1aa047aa64799960b281e56b724bbbb457b5114bde9a829f17af2"
                             .into()
                     },
-                    size: 20
+                    size: 20,
+                    file_time: 0,
+                    build_time: 0,
                 }
             ],
             packages,
         );
     }
+
+    #[test]
+    fn conflicting_pool_paths_are_refused() {
+        let package = |hex: &str| Package {
+            path: "pool/main/h/hello/hello_1.0_amd64.deb".into(),
+            hash: Hash::Sha256 { hex: hex.into() },
+            size: 100,
+            ..Package::empty()
+        };
+        let mut pool = HashMap::new();
+        check_pool_paths(
+            &mut pool,
+            &[package("aa")],
+            "dists/focal/main/binary-amd64/Packages",
+        )
+        .unwrap();
+        //the same file listed again, e.g. by another architecture
+        check_pool_paths(
+            &mut pool,
+            &[package("aa")],
+            "dists/focal/main/binary-i386/Packages",
+        )
+        .unwrap();
+
+        let err = check_pool_paths(
+            &mut pool,
+            &[package("bb")],
+            "dists/focal/contrib/binary-amd64/Packages",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            "'pool/main/h/hello/hello_1.0_amd64.deb' is listed as sha256:aa (100 bytes) by \
+             'dists/focal/main/binary-amd64/Packages' and as sha256:bb (100 bytes) by \
+             'dists/focal/contrib/binary-amd64/Packages'",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn flat_repository_is_read_from_the_endpoint() {
+        let packages = fs::read_to_string("samples/debian/Packages")
+            .unwrap()
+            .replace("Filename: pool/", "Filename: ./");
+        let hash = match Hash::create_sha256_hash(&mut packages.as_bytes()).unwrap() {
+            Hash::Sha256 { hex } => hex,
+            _ => panic!("expected a sha256 hash"),
+        };
+        let release = format!(
+            "Architectures: amd64\nSHA256:\n {} {} Packages\n",
+            hash,
+            packages.len()
+        );
+        let mut state = MemoryRepoMetadataStore::new();
+        state.insert("Release", release.into_bytes());
+        state.insert("Packages", packages.into_bytes());
+
+        let mut config = create_config();
+        config.source.kind = "debian-flat".into();
+        config.versions = vec![];
+        let repository = fetch_repository_internal(&state, &config, false).unwrap();
+
+        assert_eq!(1, repository.collections.len());
+        let collection = &repository.collections[0];
+        let index_paths: Vec<&str> = collection
+            .indexes
+            .iter()
+            .map(|index| index.path.as_str())
+            .collect();
+        assert_eq!(vec!["Release", "Packages"], index_paths);
+        let package_paths: Vec<&str> = collection
+            .packages
+            .iter()
+            .map(|package| package.path.as_str())
+            .collect();
+        assert_eq!(
+            vec![
+                "service-discover-daemon_0.1.0_amd64.deb",
+                "service-discover-agent_0.1.0_amd64.deb"
+            ],
+            package_paths
+        );
+    }
+
+    #[test]
+    fn concurrent_index_fetches_keep_the_order() {
+        let paths = ["a/Packages", "b/Packages.gz", "c/Packages", "d/Packages.xz"];
+        let mut state = MemoryRepoMetadataStore::new();
+        for (n, path) in paths.iter().enumerate() {
+            state.insert(path, vec![0; n]);
+        }
+        let indexes: Vec<IndexFile> = paths
+            .iter()
+            .map(|path| IndexFile {
+                file_path: "".into(),
+                path: path.to_string(),
+                size: 0,
+                hash: Hash::Sha256 { hex: "".into() },
+                signature: Signature::None,
+            })
+            .collect();
+
+        for max_concurrent in [1, 3, 8] {
+            let fetched = fetch_indexes(&state, &indexes, max_concurrent).unwrap();
+            let sizes: Vec<u64> = fetched.iter().map(|(_, size)| *size).collect();
+            assert_eq!(vec![0, 1, 2, 3], sizes);
+        }
+
+        let mut indexes = indexes;
+        indexes[1].path = "missing/Packages".into();
+        let err = fetch_indexes(&state, &indexes, 3).err().unwrap();
+        assert!(err.is_not_found());
+    }
 }
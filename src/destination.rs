@@ -1,181 +1,683 @@
+use crate::packages::Hash;
 use bytes::Bytes;
+use data_encoding::{BASE64, HEXLOWER_PERMISSIVE};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use futures::future::Future;
 use futures::stream::Stream;
+use futures::TryStreamExt;
+use hyper_tls::HttpsConnector;
 use rusoto_cloudfront::{
-    CloudFront, CloudFrontClient, CreateInvalidationRequest, InvalidationBatch, Paths,
+    CloudFront, CloudFrontClient, CreateInvalidationError, CreateInvalidationRequest,
+    CreateInvalidationResult, GetInvalidationRequest, GetInvalidationResult, InvalidationBatch,
+    Paths,
 };
 use rusoto_core::credential::StaticProvider;
-use rusoto_core::{region, HttpClient, Region};
-use rusoto_s3::{DeleteObjectRequest, PutObjectRequest, S3Client, StreamingBody, S3};
+use rusoto_core::{region, HttpClient, Region, RusotoError};
+use rusoto_s3::{
+    Delete, DeleteObjectRequest, DeleteObjectsRequest, GetObjectRequest, HeadObjectRequest,
+    ListObjectsV2Request, ObjectIdentifier, PutObjectRequest, S3Client, StreamingBody, S3,
+};
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use tokio::runtime::Runtime;
 
 pub trait Destination {
     fn upload(&mut self, path: &str, file: File) -> Result<(), std::io::Error>;
     fn delete(&mut self, path: &str) -> Result<(), std::io::Error>;
+    ///deletes every path, one by one unless the destination supports batches
+    fn delete_many(&mut self, paths: &[String]) -> Result<(), std::io::Error> {
+        for path in paths {
+            self.delete(path)?;
+        }
+        Ok(())
+    }
     fn invalidate(&mut self, paths: Vec<String>) -> Result<(), std::io::Error>;
+    ///every stored path starting with prefix, relative to the destination like upload paths
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, std::io::Error>;
+    ///content of a stored file, e.g. to verify an upload
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, std::io::Error>;
     fn name(&self) -> String;
 }
 
 pub fn create_destination(
     general: &GeneralConfig,
     destination: &DestinationConfig,
+    invalidations: &Arc<InvalidationsInProgress>,
 ) -> Result<Box<dyn Destination>, std::io::Error> {
     if destination.s3.is_some() {
         let s3 = destination.s3.clone().unwrap();
 
-        let (access_key, access_key_secret) = s3
-            .get_aws_credentials()
-            .expect("cannot read aws cred, should be already validated");
-
         Ok(Box::new(S3Destination::new(
-            &s3.path,
-            &s3.s3_endpoint,
-            &s3.s3_bucket,
-            s3.cloudfront_endpoint.clone(),
-            s3.cloudfront_distribution_id.clone(),
-            &s3.region_name,
-            &access_key,
-            &access_key_secret,
+            &s3,
             general.max_retries,
             Duration::from_secs(general.retry_sleep),
-        )))
+            invalidations.clone(),
+        )?))
     } else {
+        let local = destination.local.clone().unwrap();
         Ok(Box::new(LocalDestination::new(
-            &destination.local.clone().unwrap().path,
+            &local.path,
+            local.gzip_indexes,
+            local.verify_uploads,
         )?))
     }
 }
 
+///rejects paths which would escape the destination root, e.g. a malicious '../' href
+pub fn validate_path(path: &str) -> Result<(), Error> {
+    if path.starts_with('/') || path.starts_with('\\') {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("absolute path '{}' is not allowed", path),
+        ));
+    }
+    if path.split(&['/', '\\'][..]).any(|x| x == "..") {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("path '{}' would escape the destination", path),
+        ));
+    }
+    Ok(())
+}
+
+const PROBE_PATH: &str = ".reposync-probe";
+
+///verifies the destination is writable by uploading and deleting a tiny marker file
+pub fn probe(destination: &mut dyn Destination, tmp_path: &str) -> Result<(), std::io::Error> {
+    std::fs::create_dir_all(tmp_path)?;
+    let mut file = tempfile::tempfile_in(tmp_path)?;
+    file.write_all(b"reposync")?;
+    file.seek(SeekFrom::Start(0))?;
+    destination.upload(PROBE_PATH, file)?;
+    destination.delete(PROBE_PATH)
+}
+
 pub struct LocalDestination {
     pub path: String,
+    pub gzip_indexes: bool,
+    //read back the size of every written file
+    verify_uploads: bool,
+    //paths uploaded by this instance, to avoid overwriting an upstream .gz with our own
+    uploaded: HashSet<String>,
+    //paths whose .gz copy was written by this instance, deleted together with them
+    gzipped: HashSet<String>,
 }
 
+//uncompressed indexes which get a .gz copy when gzip_indexes is enabled
+const GZIP_INDEXES: [&str; 5] = ["Release", "InRelease", "Packages", "Sources", "repomd.xml"];
+
 impl LocalDestination {
-    pub fn new(path: &str) -> Result<Self, std::io::Error> {
+    pub fn new(
+        path: &str,
+        gzip_indexes: bool,
+        verify_uploads: bool,
+    ) -> Result<Self, std::io::Error> {
         std::fs::create_dir_all(&path)?;
-        Ok(LocalDestination { path: path.into() })
+        Ok(LocalDestination {
+            path: path.into(),
+            gzip_indexes,
+            verify_uploads,
+            uploaded: HashSet::new(),
+            gzipped: HashSet::new(),
+        })
+    }
+
+    fn should_gzip(&self, path: &str) -> bool {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        self.gzip_indexes
+            && GZIP_INDEXES.contains(&file_name)
+            && !self.uploaded.contains(&format!("{}.gz", path))
     }
 }
 
 impl Destination for LocalDestination {
     fn upload(&mut self, path: &str, mut file: File) -> Result<(), Error> {
+        validate_path(path)?;
         let s_path = format!("{}/{}", self.path, path);
-        let path = Path::new(&s_path);
-        println!("writing {}", &s_path);
-        std::fs::create_dir_all(path.parent().unwrap())?;
-        let mut writer = File::create(&path)?;
+        human_println!("writing {}", &s_path);
+        std::fs::create_dir_all(Path::new(&s_path).parent().unwrap())?;
+        let mut writer = File::create(&s_path)?;
         std::io::copy(&mut file, &mut writer)?;
+        if self.verify_uploads {
+            writer.sync_all()?;
+            if let Err(err) = verify_written(&s_path, file.metadata()?.len()) {
+                //a truncated file must not be served
+                let _ = std::fs::remove_file(&s_path);
+                return Err(err);
+            }
+        }
+
+        //not tracked in the repository state, it's only a convenience for web servers
+        if self.should_gzip(path) {
+            human_println!("writing {}.gz", &s_path);
+            file.seek(SeekFrom::Start(0))?;
+            let mut encoder = GzEncoder::new(
+                File::create(format!("{}.gz", &s_path))?,
+                Compression::default(),
+            );
+            std::io::copy(&mut file, &mut encoder)?;
+            encoder.finish()?;
+            self.gzipped.insert(path.into());
+        }
+        self.uploaded.insert(path.into());
         Ok(())
     }
 
     fn delete(&mut self, path: &str) -> Result<(), Error> {
-        let path = format!("{}/{}", self.path, path);
-        println!("deleting {}", &path);
-        std::fs::remove_file(&path)
+        validate_path(path)?;
+        let s_path = format!("{}/{}", self.path, path);
+        human_println!("deleting {}", &s_path);
+        std::fs::remove_file(&s_path)?;
+        if self.gzipped.remove(path) {
+            human_println!("deleting {}.gz", &s_path);
+            match std::fs::remove_file(format!("{}.gz", &s_path)) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
     }
 
     fn invalidate(&mut self, _paths: Vec<String>) -> Result<(), Error> {
         Ok(())
     }
 
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, Error> {
+        validate_path(prefix)?;
+        let mut paths = vec![];
+        list_files(Path::new(&self.path), "", &mut paths)?;
+        paths.retain(|x| x.starts_with(prefix));
+        Ok(paths)
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        validate_path(path)?;
+        std::fs::read(format!("{}/{}", self.path, path))
+    }
+
     fn name(&self) -> String {
         "local".into()
     }
 }
 
+///reads back the size of a written file, a crash or a full disk may leave it truncated
+fn verify_written(path: &str, size: u64) -> Result<(), Error> {
+    let written = std::fs::metadata(path)?.len();
+    if written != size {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "upload verification failed for '{}': expected size {} found {}",
+                path, size, written
+            ),
+        ));
+    }
+    Ok(())
+}
+
+///stores upstream paths without the strip_prefix of the source, fetches are not affected
+pub struct StripPrefixDestination<'a> {
+    inner: &'a mut dyn Destination,
+    source: &'a SourceConfig,
+}
+
+impl<'a> StripPrefixDestination<'a> {
+    pub fn new(inner: &'a mut dyn Destination, source: &'a SourceConfig) -> Self {
+        StripPrefixDestination { inner, source }
+    }
+}
+
+impl Destination for StripPrefixDestination<'_> {
+    fn upload(&mut self, path: &str, file: File) -> Result<(), Error> {
+        self.inner.upload(self.source.destination_path(path), file)
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), Error> {
+        self.inner.delete(self.source.destination_path(path))
+    }
+
+    fn delete_many(&mut self, paths: &[String]) -> Result<(), Error> {
+        let paths: Vec<String> = paths
+            .iter()
+            .map(|x| self.source.destination_path(x).to_string())
+            .collect();
+        self.inner.delete_many(&paths)
+    }
+
+    fn invalidate(&mut self, paths: Vec<String>) -> Result<(), Error> {
+        let paths = paths
+            .iter()
+            .map(|x| self.source.destination_path(x).to_string())
+            .collect();
+        self.inner.invalidate(paths)
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, Error> {
+        self.inner.list(prefix)
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        self.inner.read(self.source.destination_path(path))
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+}
+
+///stores every uploaded path under prefix
+pub struct PrefixDestination<'a> {
+    inner: &'a mut dyn Destination,
+    prefix: &'a str,
+}
+
+impl<'a> PrefixDestination<'a> {
+    pub fn new(inner: &'a mut dyn Destination, prefix: &'a str) -> Self {
+        PrefixDestination { inner, prefix }
+    }
+}
+
+impl Destination for PrefixDestination<'_> {
+    fn upload(&mut self, path: &str, file: File) -> Result<(), Error> {
+        self.inner
+            .upload(&format!("{}/{}", self.prefix, path), file)
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), Error> {
+        self.inner.delete(&format!("{}/{}", self.prefix, path))
+    }
+
+    fn invalidate(&mut self, paths: Vec<String>) -> Result<(), Error> {
+        let paths = paths
+            .iter()
+            .map(|x| format!("{}/{}", self.prefix, x))
+            .collect();
+        self.inner.invalidate(paths)
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, Error> {
+        self.inner.list(&format!("{}/{}", self.prefix, prefix))
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        self.inner.read(&format!("{}/{}", self.prefix, path))
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+}
+
+///emits a LogEvent for every operation, with paths as given to the destination
+pub struct EventDestination<'a> {
+    inner: &'a mut dyn Destination,
+    repository: &'a str,
+}
+
+impl<'a> EventDestination<'a> {
+    pub fn new(inner: &'a mut dyn Destination, repository: &'a str) -> Self {
+        EventDestination { inner, repository }
+    }
+
+    fn emit(&self, event: &str, path: &str, bytes: Option<u64>) {
+        LogEvent {
+            repo: self.repository,
+            event,
+            path: Some(path),
+            bytes,
+            ..Default::default()
+        }
+        .emit();
+    }
+}
+
+impl Destination for EventDestination<'_> {
+    fn upload(&mut self, path: &str, file: File) -> Result<(), Error> {
+        let bytes = file.metadata().map(|x| x.len()).ok();
+        self.inner.upload(path, file)?;
+        self.emit("upload", path, bytes);
+        Ok(())
+    }
+
+    fn delete(&mut self, path: &str) -> Result<(), Error> {
+        self.inner.delete(path)?;
+        self.emit("delete", path, None);
+        Ok(())
+    }
+
+    fn delete_many(&mut self, paths: &[String]) -> Result<(), Error> {
+        self.inner.delete_many(paths)?;
+        for path in paths {
+            self.emit("delete", path, None);
+        }
+        Ok(())
+    }
+
+    fn invalidate(&mut self, paths: Vec<String>) -> Result<(), Error> {
+        for path in &paths {
+            self.emit("invalidate", path, None);
+        }
+        self.inner.invalidate(paths)
+    }
+
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, Error> {
+        self.inner.list(prefix)
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        self.inner.read(path)
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+}
+
+fn list_files(root: &Path, relative: &str, paths: &mut Vec<String>) -> Result<(), Error> {
+    let entries = match std::fs::read_dir(root.join(relative)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        if entry.file_type()?.is_dir() {
+            list_files(root, &path, paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+//throttled invalidations are retried with doubling sleeps, up to 5 minutes each
+const MAX_THROTTLED_RETRIES: u32 = 12;
+const MAX_THROTTLE_SLEEP: Duration = Duration::from_secs(300);
+//the most keys a single DeleteObjects request accepts
+const MAX_DELETE_OBJECTS: usize = 1000;
+
+///cloudfront invalidations created by every repository and not completed yet, as cloudfront
+///limits the ones in progress
+pub struct InvalidationsInProgress {
+    //changed by a reload
+    limit: AtomicUsize,
+    //distribution and id of each invalidation
+    ids: Mutex<Vec<(String, String)>>,
+}
+
+impl InvalidationsInProgress {
+    pub fn new(limit: usize) -> Self {
+        InvalidationsInProgress {
+            limit: AtomicUsize::new(limit),
+            ids: Mutex::new(vec![]),
+        }
+    }
+
+    ///applies to the next invalidations, the ones in progress are still tracked
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::SeqCst);
+    }
+
+    ///waits until fewer than limit invalidations are in progress, asking is_completed about
+    ///each one, and stops waiting after a while, e.g. when other tools fill the distribution.
+    ///the lock is released while asking and sleeping, so other repositories are not blocked
+    fn wait<F>(
+        &self,
+        mut is_completed: F,
+        wait_sleep: Duration,
+    ) -> MutexGuard<'_, Vec<(String, String)>>
+    where
+        F: FnMut(&str, &str) -> bool,
+    {
+        let mut wait_sleep = wait_sleep;
+        let mut waits = 0;
+        let limit = self.limit.load(Ordering::SeqCst);
+        loop {
+            let ids = self.ids.lock().unwrap();
+            if ids.len() < limit {
+                return ids;
+            }
+            let in_progress = ids.clone();
+            drop(ids);
+            let completed: Vec<(String, String)> = in_progress
+                .into_iter()
+                .filter(|(distribution_id, id)| is_completed(distribution_id, id))
+                .collect();
+            let mut ids = self.ids.lock().unwrap();
+            ids.retain(|x| !completed.contains(x));
+            if ids.len() < limit || waits == MAX_THROTTLED_RETRIES {
+                return ids;
+            }
+            human_println!(
+                "{} invalidations in progress, waiting {}s...",
+                ids.len(),
+                wait_sleep.as_secs()
+            );
+            drop(ids);
+            sleep(wait_sleep);
+            wait_sleep = (wait_sleep * 2).min(MAX_THROTTLE_SLEEP);
+            waits += 1;
+        }
+    }
+}
+
 pub struct S3Destination {
     pub path: String,
     pub s3_endpoint: String,
     pub s3_bucket: String,
-    pub cloudfront_endpoint: Option<String>,
     pub cloudfront_arn: Option<String>,
-    pub region_name: String,
-    pub access_key_id: String,
-    pub access_key_secret: String,
     pub max_retries: u32,
     pub retry_sleep: Duration,
+    pub verify_uploads: bool,
+    pub key_encoding: Option<String>,
+    //false skips the invalidations, even with a cloudfront distribution
+    invalidate: bool,
+    //S3 rejects an upload whose body doesn't match the Content-MD5 header
+    content_md5: bool,
+    //built once, so connections are kept alive between requests
+    s3_client: S3Client,
+    cloudfront_client: Option<CloudFrontClient>,
+    runtime: Runtime,
+    invalidations: Arc<InvalidationsInProgress>,
 }
 
 impl S3Destination {
+    ///fails when the credentials cannot be read
     pub fn new(
-        path: &str,
-        s3_endpoint: &str,
-        s3_bucket: &str,
-        cloudfront_endpoint: Option<String>,
-        cloudfront_arn: Option<String>,
-        region_name: &str,
-        access_key_id: &str,
-        access_key_secret: &str,
+        config: &S3DestinationConfig,
         max_retries: u32,
         retry_sleep: Duration,
-    ) -> S3Destination {
-        Self {
-            path: path.into(),
-            s3_endpoint: s3_endpoint.into(),
-            s3_bucket: s3_bucket.into(),
-            cloudfront_endpoint: cloudfront_endpoint.clone(),
-            cloudfront_arn: cloudfront_arn.clone(),
-            region_name: region_name.into(),
-            access_key_id: access_key_id.into(),
-            access_key_secret: access_key_secret.into(),
+        invalidations: Arc<InvalidationsInProgress>,
+    ) -> Result<S3Destination, Error> {
+        let (access_key_id, access_key_secret) = config.get_aws_credentials()?;
+        let credential_provider = StaticProvider::new(access_key_id, access_key_secret, None, None);
+        let s3_client = S3Client::new_with(
+            Self::request_dispatcher(config.max_concurrent_requests),
+            credential_provider.clone(),
+            Self::region(config.get_signing_region(), &config.s3_endpoint),
+        );
+        let cloudfront_arn = config.cloudfront_distribution_id.clone();
+        let cloudfront_client = cloudfront_arn.as_ref().map(|_| {
+            CloudFrontClient::new_with(
+                Self::request_dispatcher(config.max_concurrent_requests),
+                credential_provider,
+                Self::region("us-east-1", config.cloudfront_endpoint.as_ref().unwrap()),
+            )
+        });
+        let runtime = tokio::runtime::Builder::new()
+            .threaded_scheduler()
+            .core_threads(1)
+            .enable_all()
+            .build()
+            .expect("failed to create runtime");
+
+        Ok(Self {
+            path: config.path.clone(),
+            s3_endpoint: config.s3_endpoint.clone(),
+            s3_bucket: config.s3_bucket.clone(),
+            cloudfront_arn,
             max_retries,
             retry_sleep,
-        }
+            verify_uploads: config.verify_uploads,
+            key_encoding: config.key_encoding.clone(),
+            invalidate: config.invalidate,
+            content_md5: config.content_md5,
+            s3_client,
+            cloudfront_client,
+            runtime,
+            invalidations,
+        })
     }
 
-    fn s3_client(&mut self) -> S3Client {
-        let request_dispatcher = HttpClient::new().expect("failed to create request dispatcher");
-        let credential_provider = StaticProvider::new(
-            self.access_key_id.clone(),
-            self.access_key_secret.clone(),
-            None,
-            None,
-        );
-        rusoto_s3::S3Client::new_with(
-            request_dispatcher,
-            credential_provider,
-            self.region(&self.region_name, &self.s3_endpoint),
-        )
+    ///keeps at most max_concurrent_requests idle connections per host
+    fn request_dispatcher(max_concurrent_requests: usize) -> HttpClient {
+        let mut builder = hyper::Client::builder();
+        builder.pool_max_idle_per_host(max_concurrent_requests);
+        HttpClient::from_builder(builder, HttpsConnector::new())
     }
 
-    fn cloudfront_client(&mut self) -> Option<CloudFrontClient> {
-        if self.cloudfront_arn.is_none() {
-            return None;
+    ///an invalidation which cannot be checked is not tracked anymore, a throttled one is retried
+    fn invalidation_completed(
+        &self,
+        client: &CloudFrontClient,
+        distribution_id: &str,
+        id: &str,
+    ) -> bool {
+        let result = self.block_on(client.get_invalidation(GetInvalidationRequest {
+            distribution_id: distribution_id.into(),
+            id: id.into(),
+        }));
+        match result {
+            Ok(GetInvalidationResult {
+                invalidation: Some(invalidation),
+            }) => invalidation.status == "Completed",
+            _ => true,
         }
+    }
 
-        let request_dispatcher = HttpClient::new().expect("failed to create request dispatcher");
-        let credential_provider = StaticProvider::new(
-            self.access_key_id.clone(),
-            self.access_key_secret.clone(),
-            None,
-            None,
-        );
-        Some(rusoto_cloudfront::CloudFrontClient::new_with(
-            request_dispatcher,
-            credential_provider,
-            self.region("us-east-1", &self.cloudfront_endpoint.clone().unwrap()),
-        ))
+    ///runs a request on the runtime of this destination
+    fn block_on<F, T>(&self, future: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        self.runtime.handle().block_on(future)
     }
 
-    fn region(&self, name: &str, endpoint: &str) -> Region {
+    fn region(name: &str, endpoint: &str) -> Region {
         region::Region::Custom {
             name: name.into(),
             endpoint: endpoint.into(),
         }
     }
 
+    ///an overwritten object may be briefly missing or stale, so it's checked a few times
+    fn verify_upload(&self, client: &S3Client, path: &str, size: u64) -> Result<(), Error> {
+        let mut err: Option<Error> = None;
+        for n in 0..self.max_retries {
+            if n > 0 {
+                sleep(self.retry_sleep);
+                human_println!(
+                    "'{}' not visible yet, retrying in {}s...",
+                    path,
+                    self.retry_sleep.as_secs()
+                );
+            }
+            let result = self.block_on(client.head_object(HeadObjectRequest {
+                bucket: self.s3_bucket.clone(),
+                key: self.s3_path(path),
+                ..Default::default()
+            }));
+
+            match result {
+                Ok(output) if output.content_length == Some(size as i64) => return Ok(()),
+                Ok(output) => {
+                    err = Some(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "upload verification failed for '{}': expected size {} found {}",
+                            path,
+                            size,
+                            output.content_length.unwrap_or_default()
+                        ),
+                    ))
+                }
+                Err(result_err) => {
+                    err = Some(std::io::Error::new(
+                        ErrorKind::NotFound,
+                        format!("upload verification failed for '{}': {}", path, result_err),
+                    ))
+                }
+            }
+        }
+
+        Err(err.unwrap())
+    }
+
     fn s3_path(&self, path: &str) -> String {
+        let path = encode_key(path, self.key_encoding.as_deref());
         if self.path.is_empty() {
-            path.into()
+            path
         } else {
             format!("{}/{}", &self.path, path)
         }
     }
 }
 
+///reverts encode_key, a space is ambiguous with plus_as_space so it's always a '+'
+fn decode_key(key: &str, key_encoding: Option<&str>) -> String {
+    match key_encoding {
+        Some("percent") => percent_encoding::percent_decode_str(key)
+            .decode_utf8_lossy()
+            .to_string(),
+        Some("plus_as_space") => key.replace(' ', "+"),
+        _ => key.into(),
+    }
+}
+
+///rewrites the key for S3 compatible backends which mangle some characters of the url,
+///only the object key changes, reposync keeps recording the upstream paths
+fn encode_key(path: &str, key_encoding: Option<&str>) -> String {
+    match key_encoding {
+        //everything outside the RFC 3986 unreserved characters, except the separator
+        Some("percent") => path
+            .bytes()
+            .map(|x| {
+                if x.is_ascii_alphanumeric() || b"-._~/".contains(&x) {
+                    (x as char).to_string()
+                } else {
+                    format!("%{:02X}", x)
+                }
+            })
+            .collect(),
+        //S3 decodes '+' in the url as a space, like a query string
+        Some("plus_as_space") => path.replace('+', " "),
+        _ => path.into(),
+    }
+}
+
+///base64 md5 of the file, as expected by the Content-MD5 header
+fn content_md5(file: &File) -> Result<String, Error> {
+    let mut file = file.try_clone()?;
+    file.seek(SeekFrom::Start(0))?;
+    match (Hash::Md5 { hex: String::new() }).compute_read_ahead(&file)? {
+        Hash::Md5 { hex } => HEXLOWER_PERMISSIVE
+            .decode(hex.as_bytes())
+            .map(|digest| BASE64.encode(&digest))
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err)),
+        _ => unreachable!(),
+    }
+}
+
 #[tokio::main]
 async fn await_for<F, T>(future: F) -> T
 where
@@ -186,31 +688,40 @@ where
 
 impl Destination for S3Destination {
     fn upload(&mut self, path: &str, file: File) -> Result<(), Error> {
+        validate_path(path)?;
         let mut err: Option<Error> = None;
 
-        let client = self.s3_client();
-        let len = Some(file.metadata()?.len() as i64);
+        let client = &self.s3_client;
+        let size = file.metadata()?.len();
+        let len = Some(size as i64);
+        //computed once, every retry sends the same body
+        let md5 = if self.content_md5 {
+            Some(content_md5(&file)?)
+        } else {
+            None
+        };
 
         for n in 0..self.max_retries {
             if n > 0 {
                 sleep(self.retry_sleep);
-                println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
+                human_println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
             }
             let mut file = file.try_clone().expect("cannot duplicate file descriptor");
             file.seek(SeekFrom::Start(0))?;
             let body = StreamingBody::new(FileAdapter { file });
 
-            println!(
+            human_println!(
                 "uploading {}/{}/{}",
                 &self.s3_endpoint,
                 self.s3_bucket,
                 &self.s3_path(path)
             );
-            let result = await_for(client.put_object(PutObjectRequest {
+            let result = self.block_on(client.put_object(PutObjectRequest {
                 bucket: self.s3_bucket.clone(),
                 key: self.s3_path(path),
                 body: Some(body),
                 content_length: len,
+                content_md5: md5.clone(),
                 ..Default::default()
             }));
 
@@ -219,6 +730,8 @@ impl Destination for S3Destination {
                     ErrorKind::Other,
                     format!("upload failed: {}", result.err().unwrap().to_string()),
                 ));
+            } else if self.verify_uploads {
+                return self.verify_upload(client, path, size);
             } else {
                 return Ok(());
             }
@@ -228,15 +741,16 @@ impl Destination for S3Destination {
     }
 
     fn delete(&mut self, path: &str) -> Result<(), Error> {
+        validate_path(path)?;
         let mut err: Option<Error> = None;
-        let client = self.s3_client();
+        let client = &self.s3_client;
 
         for n in 0..self.max_retries {
             if n > 0 {
                 sleep(self.retry_sleep);
-                println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
+                human_println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
             }
-            println!(
+            human_println!(
                 "deleting {}/{}/{}",
                 &self.s3_endpoint,
                 self.s3_bucket,
@@ -248,7 +762,7 @@ impl Destination for S3Destination {
                 ..Default::default()
             });
 
-            let result = await_for(future);
+            let result = self.block_on(future);
             if result.is_err() {
                 err = Some(std::io::Error::new(
                     ErrorKind::Other,
@@ -262,20 +776,86 @@ impl Destination for S3Destination {
         Err(err.unwrap())
     }
 
+    fn delete_many(&mut self, paths: &[String]) -> Result<(), Error> {
+        for path in paths {
+            validate_path(path)?;
+        }
+        let client = &self.s3_client;
+
+        for chunk in paths.chunks(MAX_DELETE_OBJECTS) {
+            //only keys failing in the last attempt are sent again
+            let mut keys: Vec<String> = chunk.iter().map(|x| self.s3_path(x)).collect();
+            let mut err: Option<Error> = None;
+            for n in 0..self.max_retries {
+                if n > 0 {
+                    sleep(self.retry_sleep);
+                    human_println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
+                }
+                for key in &keys {
+                    human_println!("deleting {}/{}/{}", &self.s3_endpoint, self.s3_bucket, key);
+                }
+                let result = self.block_on(
+                    client.delete_objects(DeleteObjectsRequest {
+                        bucket: self.s3_bucket.clone(),
+                        delete: Delete {
+                            objects: keys
+                                .iter()
+                                .map(|key| ObjectIdentifier {
+                                    key: key.clone(),
+                                    version_id: None,
+                                })
+                                .collect(),
+                            quiet: Some(true),
+                        },
+                        ..Default::default()
+                    }),
+                );
+                match result {
+                    Ok(output) => {
+                        let errors = output.errors.unwrap_or_default();
+                        if errors.is_empty() {
+                            keys.clear();
+                            break;
+                        }
+                        err = Some(std::io::Error::other(format!(
+                            "delete failed for {} of {} objects, first error on '{}': {}",
+                            errors.len(),
+                            keys.len(),
+                            errors[0].key.as_deref().unwrap_or_default(),
+                            errors[0].message.as_deref().unwrap_or_default()
+                        )));
+                        keys = errors.into_iter().filter_map(|x| x.key).collect();
+                    }
+                    Err(result_err) => {
+                        err = Some(std::io::Error::other(format!(
+                            "delete failed: {}",
+                            result_err
+                        )))
+                    }
+                }
+            }
+            if !keys.is_empty() {
+                return Err(err.unwrap());
+            }
+        }
+
+        Ok(())
+    }
+
     fn invalidate(&mut self, paths: Vec<String>) -> Result<(), Error> {
-        if let Some(client) = self.cloudfront_client() {
+        if let Some(client) = self.cloudfront_client.as_ref().filter(|_| self.invalidate) {
             let mut err: Option<Error> = None;
             if !paths.is_empty() {
                 for n in 0..self.max_retries {
                     if n > 0 {
                         sleep(self.retry_sleep);
-                        println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
+                        human_println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
                     }
                     for path in &paths {
-                        println!("invalidating {}", path);
+                        human_println!("invalidating {}", path);
                     }
 
-                    let future = client.create_invalidation(CreateInvalidationRequest {
+                    let request = || CreateInvalidationRequest {
                         distribution_id: self.cloudfront_arn.clone().unwrap(),
                         invalidation_batch: InvalidationBatch {
                             caller_reference: SystemTime::now()
@@ -293,9 +873,43 @@ impl Destination for S3Destination {
                                 ),
                             },
                         },
-                    });
+                    };
 
-                    let result = await_for(future);
+                    //held until the new invalidation is tracked, so the limit is never exceeded
+                    let distribution_id = self.cloudfront_arn.clone().unwrap();
+                    let mut in_progress = self.invalidations.wait(
+                        |distribution_id, id| {
+                            self.invalidation_completed(client, distribution_id, id)
+                        },
+                        self.retry_sleep.max(Duration::from_secs(1)),
+                    );
+                    let mut result = self.block_on(client.create_invalidation(request()));
+                    //throttling is not a failure, wait longer and longer for other invalidations to complete
+                    let mut throttle_sleep = self.retry_sleep.max(Duration::from_secs(1));
+                    for _ in 0..MAX_THROTTLED_RETRIES {
+                        match &result {
+                            Err(RusotoError::Service(
+                                CreateInvalidationError::TooManyInvalidationsInProgress(_),
+                            )) => {
+                                human_println!(
+                                    "too many invalidations in progress, retrying in {}s...",
+                                    throttle_sleep.as_secs()
+                                );
+                                sleep(throttle_sleep);
+                                throttle_sleep = (throttle_sleep * 2).min(MAX_THROTTLE_SLEEP);
+                                result = self.block_on(client.create_invalidation(request()));
+                            }
+                            _ => break,
+                        }
+                    }
+                    if let Ok(CreateInvalidationResult {
+                        invalidation: Some(invalidation),
+                        ..
+                    }) = &result
+                    {
+                        in_progress.push((distribution_id, invalidation.id.clone()));
+                    }
+                    drop(in_progress);
                     if result.is_err() {
                         err = Some(std::io::Error::new(
                             ErrorKind::Other,
@@ -315,13 +929,100 @@ impl Destination for S3Destination {
             }
         } else {
             for path in paths {
-                println!("skipping cloudfront invalidation for {}", path);
+                human_println!("skipping cloudfront invalidation for {}", path);
             }
 
             Ok(())
         }
     }
 
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, Error> {
+        validate_path(prefix)?;
+        let client = &self.s3_client;
+        let root = self.s3_path("");
+        let mut paths = vec![];
+        let mut continuation_token = None;
+        loop {
+            let mut err: Option<Error> = None;
+            let mut output = None;
+            for n in 0..self.max_retries {
+                if n > 0 {
+                    sleep(self.retry_sleep);
+                    human_println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
+                }
+                let result = self.block_on(client.list_objects_v2(ListObjectsV2Request {
+                    bucket: self.s3_bucket.clone(),
+                    prefix: Some(self.s3_path(prefix)),
+                    continuation_token: continuation_token.clone(),
+                    ..Default::default()
+                }));
+                match result {
+                    Ok(result) => {
+                        output = Some(result);
+                        break;
+                    }
+                    Err(result_err) => {
+                        err = Some(std::io::Error::other(format!(
+                            "list failed: {}",
+                            result_err
+                        )))
+                    }
+                }
+            }
+            let output = match output {
+                Some(output) => output,
+                None => return Err(err.unwrap()),
+            };
+            for object in output.contents.unwrap_or_default() {
+                if let Some(key) = object.key.as_deref().and_then(|x| x.strip_prefix(&root)) {
+                    paths.push(decode_key(key, self.key_encoding.as_deref()));
+                }
+            }
+            continuation_token = output.next_continuation_token;
+            if output.is_truncated != Some(true) || continuation_token.is_none() {
+                return Ok(paths);
+            }
+        }
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        validate_path(path)?;
+        let client = &self.s3_client;
+        let mut err: Option<Error> = None;
+        for n in 0..self.max_retries {
+            if n > 0 {
+                sleep(self.retry_sleep);
+                human_println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
+            }
+            let result = self.block_on(async {
+                let output = client
+                    .get_object(GetObjectRequest {
+                        bucket: self.s3_bucket.clone(),
+                        key: self.s3_path(path),
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(|err| std::io::Error::other(format!("read failed: {}", err)))?;
+                match output.body {
+                    Some(body) => {
+                        body.try_fold(vec![], |mut content, chunk| async move {
+                            content.extend_from_slice(&chunk);
+                            Ok(content)
+                        })
+                        .await
+                    }
+                    None => Ok(vec![]),
+                }
+            });
+            match result {
+                Ok(content) => return Ok(content),
+                Err(result_err) => err = Some(result_err),
+            }
+        }
+
+        Err(err.unwrap())
+    }
+
     fn name(&self) -> String {
         format!("{}/{}", self.s3_endpoint, self.s3_bucket)
     }
@@ -357,10 +1058,15 @@ impl Stream for FileAdapter {
     }
 }
 
-use crate::config::{DestinationConfig, GeneralConfig};
+use crate::config::{
+    DestinationConfig, GeneralConfig, S3Destination as S3DestinationConfig, SourceConfig,
+};
+use crate::utils::{human_println, LogEvent};
 #[cfg(test)]
 use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread::sleep;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -398,15 +1104,15 @@ impl MemoryDestination {
 
     pub fn print(&self) {
         self.map.iter().for_each(|(k, v)| {
-            println!("file[{:04}]: {}", v.len(), k);
+            human_println!("file[{:04}]: {}", v.len(), k);
         });
 
         self.delete_set.iter().for_each(|k| {
-            println!("deletion: {}", k);
+            human_println!("deletion: {}", k);
         });
 
         self.invalidation_set.iter().for_each(|k| {
-            println!("invalidation: {}", k);
+            human_println!("invalidation: {}", k);
         });
     }
 }
@@ -433,7 +1139,319 @@ impl Destination for MemoryDestination {
         Ok(())
     }
 
+    fn list(&mut self, prefix: &str) -> Result<Vec<String>, Error> {
+        let root = format!("{}/", &self.path);
+        Ok(self
+            .map
+            .keys()
+            .filter(|x| !self.delete_set.contains(*x))
+            .filter_map(|x| x.strip_prefix(&root))
+            .filter(|x| x.starts_with(prefix))
+            .map(|x| x.to_string())
+            .collect())
+    }
+
+    fn read(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        let path = format!("{}/{}", &self.path, path);
+        match self.map.get(&path) {
+            Some(content) if !self.delete_set.contains(&path) => Ok(content.clone()),
+            _ => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found", path),
+            )),
+        }
+    }
+
     fn name(&self) -> String {
         "memory".into()
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use crate::destination::{
+        content_md5, decode_key, encode_key, probe, validate_path, verify_written, Destination,
+        InvalidationsInProgress, LocalDestination, MemoryDestination, S3Destination, PROBE_PATH,
+    };
+    use flate2::read::GzDecoder;
+    use std::fs;
+    use std::fs::File;
+    use std::io::{ErrorKind, Read, Write};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    ///an S3 destination of the mirror path with these extra options, nothing is contacted
+    fn s3_destination(options: &str) -> S3Destination {
+        let config = serde_yaml::from_str(&format!(
+            "s3_endpoint: https://s3.example.com\n\
+             s3_bucket: bucket\n\
+             path: mirror\n\
+             region_name: custom\n\
+             access_key_id: key\n\
+             access_key_secret: secret\n\
+             {}",
+            options
+        ))
+        .unwrap();
+        S3Destination::new(
+            &config,
+            1,
+            Duration::from_secs(0),
+            Arc::new(InvalidationsInProgress::new(1)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn local_destination_gzips_indexes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().to_str().unwrap();
+        let mut destination = LocalDestination::new(path, true, false).unwrap();
+
+        destination
+            .upload(
+                "dists/focal/main/binary-amd64/Packages",
+                File::open("samples/debian/Packages").unwrap(),
+            )
+            .unwrap();
+        let mut text = String::new();
+        GzDecoder::new(
+            File::open(format!(
+                "{}/dists/focal/main/binary-amd64/Packages.gz",
+                path
+            ))
+            .unwrap(),
+        )
+        .read_to_string(&mut text)
+        .unwrap();
+        assert_eq!(fs::read_to_string("samples/debian/Packages").unwrap(), text);
+
+        //an upstream .gz is never overwritten
+        destination
+            .upload(
+                "dists/focal/main/binary-i386/Packages.gz",
+                File::open("samples/fake-package").unwrap(),
+            )
+            .unwrap();
+        destination
+            .upload(
+                "dists/focal/main/binary-i386/Packages",
+                File::open("samples/debian/Packages").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            fs::read("samples/fake-package").unwrap(),
+            fs::read(format!("{}/dists/focal/main/binary-i386/Packages.gz", path)).unwrap()
+        );
+
+        destination
+            .upload("pool/fake.deb", File::open("samples/fake-package").unwrap())
+            .unwrap();
+        assert!(!Path::new(&format!("{}/pool/fake.deb.gz", path)).exists());
+
+        //the copy is deleted with its index, an upstream .gz is left alone
+        destination
+            .delete("dists/focal/main/binary-amd64/Packages")
+            .unwrap();
+        assert!(!Path::new(&format!(
+            "{}/dists/focal/main/binary-amd64/Packages.gz",
+            path
+        ))
+        .exists());
+        destination
+            .delete("dists/focal/main/binary-i386/Packages")
+            .unwrap();
+        assert!(Path::new(&format!(
+            "{}/dists/focal/main/binary-i386/Packages.gz",
+            path
+        ))
+        .exists());
+    }
+
+    #[test]
+    fn paths_cannot_escape_destination() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let root = tmp_dir.path().join("repo");
+        let mut destination = LocalDestination::new(root.to_str().unwrap(), false, false).unwrap();
+
+        for path in &[
+            "../escape",
+            "pool/../../escape",
+            "/escape",
+            "pool\\..\\..\\escape",
+        ] {
+            let err = destination
+                .upload(path, File::open("samples/fake-package").unwrap())
+                .unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, err.kind());
+            let err = destination.delete(path).unwrap_err();
+            assert_eq!(ErrorKind::InvalidData, err.kind());
+        }
+        assert!(!tmp_dir.path().join("escape").exists());
+
+        assert!(validate_path("pool/main/..deb").is_ok());
+        assert!(validate_path("dists/focal/Release").is_ok());
+    }
+
+    #[test]
+    fn probe_uploads_and_deletes_marker() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut destination = MemoryDestination::new("repo");
+        probe(&mut destination, tmp_dir.path().to_str().unwrap()).unwrap();
+
+        let (contents, deletions, _) = destination.explode();
+        let path = format!("repo/{}", PROBE_PATH);
+        assert_eq!(b"reposync".to_vec(), *contents.get(&path).unwrap());
+        assert!(deletions.contains(&path));
+    }
+
+    #[test]
+    fn s3_key_encoding() {
+        let destination = |key_encoding: Option<&str>| match key_encoding {
+            Some(key_encoding) => s3_destination(&format!("key_encoding: {}", key_encoding)),
+            None => s3_destination(""),
+        };
+        let path = "pool/main/libf/libfoo_1.0+dfsg_amd64.deb";
+        assert_eq!(
+            "mirror/pool/main/libf/libfoo_1.0+dfsg_amd64.deb",
+            destination(None).s3_path(path)
+        );
+        assert_eq!(
+            "mirror/pool/main/libf/libfoo_1.0%2Bdfsg_amd64.deb",
+            destination(Some("percent")).s3_path(path)
+        );
+        assert_eq!(
+            "mirror/pool/main/libf/libfoo_1.0 dfsg_amd64.deb",
+            destination(Some("plus_as_space")).s3_path(path)
+        );
+        assert_eq!(
+            "mirror/pool/my%20package~1_amd64.deb",
+            destination(Some("percent")).s3_path("pool/my package~1_amd64.deb")
+        );
+        for key_encoding in &[None, Some("percent"), Some("plus_as_space")] {
+            let key = encode_key(path, *key_encoding);
+            assert_eq!(path, decode_key(&key, *key_encoding));
+        }
+    }
+
+    #[test]
+    fn local_destination_lists_files() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("repo");
+        let mut destination = LocalDestination::new(path.to_str().unwrap(), false, false).unwrap();
+        assert!(destination.list("").unwrap().is_empty());
+
+        for file in &["dists/focal/Release", "pool/a.deb", "pool/main/b.deb"] {
+            destination
+                .upload(file, File::open("samples/fake-package").unwrap())
+                .unwrap();
+        }
+        let mut paths = destination.list("").unwrap();
+        paths.sort();
+        assert_eq!(
+            vec!["dists/focal/Release", "pool/a.deb", "pool/main/b.deb"],
+            paths
+        );
+        let mut paths = destination.list("pool/").unwrap();
+        paths.sort();
+        assert_eq!(vec!["pool/a.deb", "pool/main/b.deb"], paths);
+        assert!(destination.list("../").is_err());
+    }
+
+    #[test]
+    fn delete_many_deletes_every_path() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("repo");
+        let mut destination = LocalDestination::new(path.to_str().unwrap(), false, false).unwrap();
+        for file in &["dists/focal/Release", "pool/a.deb", "pool/main/b.deb"] {
+            destination
+                .upload(file, File::open("samples/fake-package").unwrap())
+                .unwrap();
+        }
+
+        destination
+            .delete_many(&["pool/a.deb".into(), "pool/main/b.deb".into()])
+            .unwrap();
+        assert_eq!(vec!["dists/focal/Release"], destination.list("").unwrap());
+
+        //a failing delete surfaces
+        assert!(destination
+            .delete_many(&["dists/focal/Release".into(), "../escape".into()])
+            .is_err());
+    }
+
+    #[test]
+    fn truncated_writes_are_detected() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("repo");
+        let mut destination = LocalDestination::new(path.to_str().unwrap(), false, true).unwrap();
+        destination
+            .upload("pool/a.deb", File::open("samples/fake-package").unwrap())
+            .unwrap();
+        assert_eq!(
+            fs::read("samples/fake-package").unwrap(),
+            fs::read(path.join("pool/a.deb")).unwrap()
+        );
+
+        let written = path.join("pool/a.deb");
+        let size = fs::metadata(&written).unwrap().len();
+        assert!(verify_written(written.to_str().unwrap(), size).is_ok());
+        let err = verify_written(written.to_str().unwrap(), size + 10)
+            .err()
+            .unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn disabled_invalidations_are_skipped() {
+        let mut destination = s3_destination(
+            "cloudfront_endpoint: https://cloudfront.example.com\n\
+             cloudfront_distribution_id: distribution\n\
+             invalidate: false",
+        );
+        //no request is sent to the unreachable endpoint
+        destination
+            .invalidate(vec!["dists/focal/Release".into()])
+            .unwrap();
+    }
+
+    #[test]
+    fn invalidations_wait_for_completed_ones() {
+        let invalidations = InvalidationsInProgress::new(2);
+        let mut asked = 0;
+        {
+            let mut ids = invalidations.wait(|_, _| panic!("nothing to ask"), Duration::ZERO);
+            ids.push(("E1".into(), "I1".into()));
+            ids.push(("E2".into(), "I2".into()));
+        }
+        //the first completes at the second check, nothing is locked meanwhile
+        let ids = invalidations.wait(
+            |_, id| {
+                assert!(invalidations.ids.try_lock().is_ok());
+                asked += 1;
+                id == "I1" && asked > 2
+            },
+            Duration::ZERO,
+        );
+        assert_eq!(vec![("E2".to_string(), "I2".to_string())], *ids);
+        drop(ids);
+        assert_eq!(4, asked);
+
+        //not waiting forever, e.g. for invalidations which never complete
+        let invalidations = InvalidationsInProgress::new(1);
+        invalidations
+            .wait(|_, _| false, Duration::ZERO)
+            .push(("E1".into(), "I1".into()));
+        assert_eq!(1, invalidations.wait(|_, _| false, Duration::ZERO).len());
+    }
+
+    #[test]
+    fn content_md5_is_base64() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"hello\n").unwrap();
+        assert_eq!("sZRqySSS0jR8YjW00mERhA==", content_md5(&file).unwrap());
+        //the whole file is hashed, wherever its offset is
+        assert_eq!("sZRqySSS0jR8YjW00mERhA==", content_md5(&file).unwrap());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::fetcher::FetchError;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::ErrorKind;
+
+///every failure of a synchronization, so callers can tell a corrupted upstream from a
+///network or a local issue
+#[derive(Debug)]
+pub enum SyncError {
+    ///a downloaded file doesn't match the checksum declared in the index
+    HashMismatch {
+        path: String,
+        expected: String,
+        found: String,
+    },
+    ///a downloaded file doesn't match the size declared in the index
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        found: u64,
+    },
+    ///an index signature cannot be verified with the configured key
+    SignatureInvalid {
+        path: String,
+        reason: String,
+    },
+    ///the upstream request failed, code is the HTTP status
+    Fetch {
+        path: String,
+        code: u16,
+        reason: String,
+    },
+    ///upstream metadata is malformed or inconsistent
+    Parse(String),
+    ///the repository is not configured
+    NotFound(String),
+    ///another synchronization of the same repository is running
+    Busy(String),
+    ///a safety check refused to go on, e.g. mass deletes or missing signatures
+    Refused(String),
+    ///the configuration cannot be applied, e.g. an unreadable authorization_file
+    Config(String),
+    Io(std::io::Error),
+}
+
+impl SyncError {
+    ///true when the requested file doesn't exist, either upstream or locally
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            SyncError::Fetch { code, .. } => *code == 404,
+            SyncError::Io(err) => err.kind() == ErrorKind::NotFound,
+            _ => false,
+        }
+    }
+
+    pub fn fetch(path: &str, err: FetchError) -> Self {
+        SyncError::Fetch {
+            path: path.into(),
+            code: err.code,
+            reason: err.error,
+        }
+    }
+
+    ///prefixes free text errors, structured ones already tell what failed
+    pub fn context(self, context: &str) -> Self {
+        match self {
+            SyncError::Parse(message) => SyncError::Parse(format!("{}: {}", context, message)),
+            SyncError::Io(err) => SyncError::Io(std::io::Error::new(
+                err.kind(),
+                format!("{}: {}", context, err),
+            )),
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            SyncError::HashMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "failed hash validation for '{}', expected {} found {}",
+                path, expected, found
+            ),
+            SyncError::SizeMismatch {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "invalid file size for '{}', expected {} found {}",
+                path, expected, found
+            ),
+            SyncError::SignatureInvalid { path, reason } => {
+                write!(f, "cannot validate signature of '{}': {}", path, reason)
+            }
+            SyncError::Fetch { path, code, reason } if *code == 404 => {
+                write!(f, "file not found '{}': {}", path, reason)
+            }
+            SyncError::Fetch { path, reason, .. } => {
+                write!(f, "cannot fetch file '{}': {}", path, reason)
+            }
+            SyncError::Parse(message) => write!(f, "{}", message),
+            SyncError::NotFound(name) => write!(f, "repository {} not found", name),
+            SyncError::Busy(name) => write!(f, "sync of {} already in progress", name),
+            SyncError::Refused(message) => write!(f, "{}", message),
+            SyncError::Config(message) => write!(f, "invalid configuration: {}", message),
+            SyncError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SyncError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SyncError {
+    fn from(err: std::io::Error) -> Self {
+        SyncError::Io(err)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::error::SyncError;
+    use crate::fetcher::FetchError;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn not_found_errors() {
+        let err = SyncError::fetch(
+            "dists/focal/Release",
+            FetchError {
+                code: 404,
+                error: "request failed: 404 Not Found".into(),
+            },
+        );
+        assert!(err.is_not_found());
+        assert_eq!(
+            "file not found 'dists/focal/Release': request failed: 404 Not Found",
+            err.to_string()
+        );
+
+        let err = SyncError::from(std::io::Error::new(ErrorKind::NotFound, "missing"));
+        assert!(err.is_not_found());
+        assert_eq!("loading: missing", err.context("loading").to_string());
+
+        assert!(!SyncError::Parse("invalid line".into()).is_not_found());
+    }
+}
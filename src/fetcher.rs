@@ -1,11 +1,17 @@
+use crate::sync::{RealTimeProvider, TimeProvider};
+use crate::utils::{human_println, LogEvent};
 use data_encoding::BASE64;
 #[cfg(test)]
-use mockall::automock;
-use reqwest::blocking::Client;
+use mockall::mock;
+use reqwest::blocking::{Client, RequestBuilder};
 use reqwest::{header, StatusCode};
-use std::io::Read;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 #[derive(PartialEq, PartialOrd, Eq, Ord, Debug, Hash, Clone)]
 pub struct FetchError {
@@ -13,9 +19,65 @@ pub struct FetchError {
     pub error: String,
 }
 
-#[cfg_attr(test, automock)]
-pub trait Fetcher {
+//shared by the threads fetching metadata concurrently
+pub trait Fetcher: Send + Sync {
     fn fetch(&self, url: &str) -> Result<Box<dyn Read>, FetchError>;
+
+    ///requests the content from offset on, returns the offset where the content actually
+    ///starts, which is 0 when the server ignores the range
+    fn fetch_range(&self, url: &str, offset: u64) -> Result<(Box<dyn Read>, u64), FetchError>;
+
+    ///downloads url to path, continuing the partial content already there when possible,
+    ///returns the size of the complete file
+    fn download(&self, url: &str, path: &Path) -> Result<u64, FetchError> {
+        download_partial(self, url, path)
+    }
+}
+
+#[cfg(test)]
+mock! {
+    pub Fetcher {}
+    impl Fetcher for Fetcher {
+        fn fetch(&self, url: &str) -> Result<Box<dyn Read>, FetchError>;
+        fn fetch_range(&self, url: &str, offset: u64) -> Result<(Box<dyn Read>, u64), FetchError>;
+    }
+}
+
+fn download_partial<F>(fetcher: &F, url: &str, path: &Path) -> Result<u64, FetchError>
+where
+    F: Fetcher + ?Sized,
+{
+    let local_error = |err: std::io::Error| FetchError {
+        code: 500,
+        error: format!("cannot write {}: {}", path.display(), err),
+    };
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(local_error)?;
+    let offset = file.metadata().map_err(local_error)?.len();
+    let (mut reader, start) = if offset > 0 {
+        human_println!("resuming {} from byte {}", url, offset);
+        match fetcher.fetch_range(url, offset) {
+            //the partial content is longer than the file, it changed upstream
+            Err(err) if err.code == 416 => (fetcher.fetch(url)?, 0),
+            result => result?,
+        }
+    } else {
+        (fetcher.fetch(url)?, 0)
+    };
+    if start != offset {
+        human_println!("{} cannot be resumed, downloading from scratch", url);
+        file.set_len(0).map_err(local_error)?;
+    }
+    match std::io::copy(&mut reader, &mut file) {
+        Ok(size) => Ok(start + size),
+        Err(err) => Err(FetchError {
+            code: StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+            error: format!("download interrupted: {}", err),
+        }),
+    }
 }
 
 struct RetryFetcher {
@@ -24,15 +86,18 @@ struct RetryFetcher {
     retry_sleep: Duration,
 }
 
-impl Fetcher for RetryFetcher {
-    fn fetch(&self, url: &str) -> Result<Box<dyn Read>, FetchError> {
+impl RetryFetcher {
+    fn retry<T, F>(&self, request: F) -> Result<T, FetchError>
+    where
+        F: Fn() -> Result<T, FetchError>,
+    {
         let mut err: Option<FetchError> = None;
         for n in 0..self.max_retries {
             if n > 0 {
                 sleep(self.retry_sleep);
-                println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
+                human_println!("Failed, retrying in {}s...", self.retry_sleep.as_secs());
             }
-            let result = self.fetcher.fetch(url);
+            let result = request();
             if result.is_ok() {
                 return result;
             }
@@ -47,35 +112,279 @@ impl Fetcher for RetryFetcher {
     }
 }
 
+impl Fetcher for RetryFetcher {
+    fn fetch(&self, url: &str) -> Result<Box<dyn Read>, FetchError> {
+        self.retry(|| self.fetcher.fetch(url))
+    }
+
+    fn fetch_range(&self, url: &str, offset: u64) -> Result<(Box<dyn Read>, u64), FetchError> {
+        self.retry(|| self.fetcher.fetch_range(url, offset))
+    }
+
+    ///every attempt continues from the content stored by the previous ones
+    fn download(&self, url: &str, path: &Path) -> Result<u64, FetchError> {
+        self.retry(|| self.fetcher.download(url, path))
+    }
+}
+
+pub struct RateLimit {
+    pub max_requests_per_sec: Option<f64>,
+    pub max_download_bytes_per_sec: Option<u64>,
+}
+
+///settings of the http client, unset pool options keep the reqwest defaults
+pub struct ClientOptions {
+    pub timeout: Duration,
+    pub connect_timeout: Option<Duration>,
+    pub http2_prior_knowledge: bool,
+    pub pool_idle_timeout: Option<Duration>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub headers: BTreeMap<String, String>,
+}
+
+///spaces requests and throttles downloads, to be gentle with fragile upstreams
+struct RateLimitFetcher {
+    fetcher: Box<dyn Fetcher>,
+    min_interval: Option<Duration>,
+    max_bytes_per_sec: Option<u64>,
+    time_provider: Arc<dyn TimeProvider>,
+    sleep: Arc<dyn Fn(Duration) + Send + Sync>,
+    last_request: Mutex<Option<SystemTime>>,
+}
+
+impl RateLimitFetcher {
+    fn wait(&self) {
+        //held while sleeping, so concurrent requests are spaced too
+        let mut last_request = self.last_request.lock().unwrap();
+        if let (Some(min_interval), Some(last_request)) = (self.min_interval, *last_request) {
+            let elapsed = self
+                .time_provider
+                .now()
+                .duration_since(last_request)
+                .unwrap_or_default();
+            if elapsed < min_interval {
+                (self.sleep)(min_interval - elapsed);
+            }
+        }
+        *last_request = Some(self.time_provider.now());
+    }
+
+    fn throttle(&self, reader: Box<dyn Read>) -> Box<dyn Read> {
+        match self.max_bytes_per_sec {
+            Some(max_bytes_per_sec) => Box::new(ThrottledReader {
+                reader,
+                max_bytes_per_sec,
+                time_provider: self.time_provider.clone(),
+                sleep: self.sleep.clone(),
+                started: self.time_provider.now(),
+                bytes: 0,
+            }),
+            None => reader,
+        }
+    }
+}
+
+impl Fetcher for RateLimitFetcher {
+    fn fetch(&self, url: &str) -> Result<Box<dyn Read>, FetchError> {
+        self.wait();
+        Ok(self.throttle(self.fetcher.fetch(url)?))
+    }
+
+    fn fetch_range(&self, url: &str, offset: u64) -> Result<(Box<dyn Read>, u64), FetchError> {
+        self.wait();
+        let (reader, start) = self.fetcher.fetch_range(url, offset)?;
+        Ok((self.throttle(reader), start))
+    }
+}
+
+///emits a LogEvent for every request, downloads include their size
+pub struct EventFetcher {
+    fetcher: Box<dyn Fetcher>,
+    repository: String,
+}
+
+impl EventFetcher {
+    pub fn new(fetcher: Box<dyn Fetcher>, repository: &str) -> Self {
+        EventFetcher {
+            fetcher,
+            repository: repository.into(),
+        }
+    }
+
+    fn emit(&self, url: &str, bytes: Option<u64>) {
+        LogEvent {
+            repo: &self.repository,
+            event: "fetch",
+            path: Some(url),
+            bytes,
+            ..Default::default()
+        }
+        .emit();
+    }
+}
+
+impl Fetcher for EventFetcher {
+    fn fetch(&self, url: &str) -> Result<Box<dyn Read>, FetchError> {
+        let reader = self.fetcher.fetch(url)?;
+        self.emit(url, None);
+        Ok(reader)
+    }
+
+    fn fetch_range(&self, url: &str, offset: u64) -> Result<(Box<dyn Read>, u64), FetchError> {
+        let result = self.fetcher.fetch_range(url, offset)?;
+        self.emit(url, None);
+        Ok(result)
+    }
+
+    fn download(&self, url: &str, path: &Path) -> Result<u64, FetchError> {
+        let size = self.fetcher.download(url, path)?;
+        self.emit(url, Some(size));
+        Ok(size)
+    }
+}
+
+struct ThrottledReader {
+    reader: Box<dyn Read>,
+    max_bytes_per_sec: u64,
+    time_provider: Arc<dyn TimeProvider>,
+    sleep: Arc<dyn Fn(Duration) + Send + Sync>,
+    started: SystemTime,
+    bytes: u64,
+}
+
+impl Read for ThrottledReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.bytes += size as u64;
+        //sleep until the average speed is back under the limit
+        let expected = Duration::from_secs_f64(self.bytes as f64 / self.max_bytes_per_sec as f64);
+        let elapsed = self
+            .time_provider
+            .now()
+            .duration_since(self.started)
+            .unwrap_or_default();
+        if elapsed < expected {
+            (self.sleep)(expected - elapsed);
+        }
+        Ok(size)
+    }
+}
+
 struct DirectFetcher {
-    secret: Option<String>,
-    timeout: Duration,
+    //built once, so connections are kept alive between requests
+    client: Client,
+    //added to every request
+    headers: header::HeaderMap,
+    rebase_redirects: bool,
+    //(original base, redirected base) detected so far
+    redirected_bases: Mutex<Vec<(String, String)>>,
+}
+
+impl DirectFetcher {
+    fn request(&self, url: &str, offset: u64) -> RequestBuilder {
+        let mut request = self.client.get(url).headers(self.headers.clone());
+        if offset > 0 {
+            request = request.header(header::RANGE, format!("bytes={}-", offset));
+        }
+        request
+    }
+
+    fn rebase(&self, url: &str) -> String {
+        for (base, redirected_base) in self.redirected_bases.lock().unwrap().iter() {
+            if let Some(path) = url.strip_prefix(base.as_str()) {
+                return format!("{}{}", redirected_base, path);
+            }
+        }
+        url.into()
+    }
+
+    fn track_redirect(&self, url: &str, final_url: &str) {
+        human_println!("redirected: {} -> {}", url, final_url);
+        if !self.rebase_redirects {
+            return;
+        }
+        if let Some((base, redirected_base)) = redirected_base(url, final_url) {
+            human_println!(
+                "using '{}' instead of '{}' for the next requests",
+                redirected_base,
+                base
+            );
+            let mut redirected_bases = self.redirected_bases.lock().unwrap();
+            redirected_bases.retain(|(x, _)| x != &base);
+            redirected_bases.push((base, redirected_base));
+        }
+    }
 }
+
+///when only the base of the url changed returns (original base, redirected base),
+///so 'http://a/repo/pool/x.deb' to 'http://b/mirror/pool/x.deb' is ('http://a/repo', 'http://b/mirror')
+fn redirected_base(url: &str, final_url: &str) -> Option<(String, String)> {
+    let common_suffix = url
+        .bytes()
+        .rev()
+        .zip(final_url.bytes().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = &url[url.len() - common_suffix..];
+    //the base must end at a path separator, the file name alone is not enough
+    let suffix = &suffix[suffix.find('/')?..];
+    if suffix.len() == url.len() || suffix.rfind('/')? == 0 {
+        return None;
+    }
+    Some((
+        url[..url.len() - suffix.len()].into(),
+        final_url[..final_url.len() - suffix.len()].into(),
+    ))
+}
+
+///reads a file:// url from disk, e.g. a repository mounted in an air-gapped network
+fn fetch_file(url: &str, offset: u64) -> Result<(Box<dyn Read>, u64), FetchError> {
+    let path = &url[FILE_SCHEME.len()..];
+    human_println!("reading: {}", path);
+    let result = File::open(path).and_then(|mut file| {
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(file)
+    });
+    match result {
+        Ok(file) => Ok((Box::new(file), offset)),
+        Err(err) => Err(FetchError {
+            code: match err.kind() {
+                ErrorKind::NotFound => 404,
+                ErrorKind::PermissionDenied => 403,
+                _ => 500,
+            },
+            error: format!("cannot read {}: {}", path, err),
+        }),
+    }
+}
+
+const FILE_SCHEME: &str = "file://";
+
 impl Fetcher for DirectFetcher {
     fn fetch(&self, url: &str) -> Result<Box<dyn Read>, FetchError> {
-        println!("requesting: {}", url);
-        let builder = Client::builder();
-        let mut headers = header::HeaderMap::new();
-        if self.secret.is_some() {
-            let mut auth_value = header::HeaderValue::from_str(&format!(
-                "Basic {}",
-                BASE64.encode(self.secret.clone().unwrap().as_bytes(),)
-            ))
-            .expect("cannot crate authorization header");
-            auth_value.set_sensitive(true);
-            headers.insert(header::AUTHORIZATION, auth_value);
-        }
-        let client = builder
-            .default_headers(headers)
-            .timeout(self.timeout)
-            .build()
-            .expect("cannot create http client");
+        self.fetch_range(url, 0).map(|(reader, _)| reader)
+    }
 
-        let result = client.get(url).send();
+    fn fetch_range(&self, url: &str, offset: u64) -> Result<(Box<dyn Read>, u64), FetchError> {
+        if url.starts_with(FILE_SCHEME) {
+            return fetch_file(url, offset);
+        }
+        let url = &self.rebase(url);
+        human_println!("requesting: {}", url);
+        let result = self.request(url, offset).send();
         if result.is_ok() {
             let response = result.unwrap();
+            if response.url().as_str() != url {
+                self.track_redirect(url, response.url().as_str());
+            }
             if response.status().is_success() {
-                Result::Ok(Box::new(response))
+                //a plain 200 means the range was ignored and the content starts from 0
+                let start = if response.status() == StatusCode::PARTIAL_CONTENT {
+                    offset
+                } else {
+                    0
+                };
+                Result::Ok((Box::new(response), start))
             } else {
                 Result::Err(FetchError {
                     code: response.status().as_u16(),
@@ -95,25 +404,117 @@ impl Fetcher for DirectFetcher {
     }
 }
 
+///names which look like credentials, their values are hidden from logs
+const SENSITIVE_HEADERS: [&str; 6] = ["auth", "token", "key", "secret", "cookie", "password"];
+
+///configured headers, the sensitive looking ones are marked as such
+pub fn request_headers(
+    headers: &BTreeMap<String, String>,
+) -> Result<header::HeaderMap, std::io::Error> {
+    let mut header_map = header::HeaderMap::new();
+    for (name, value) in headers {
+        let invalid = |err: String| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid header '{}': {}", name, err),
+            )
+        };
+        let header_name = header::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|err| invalid(err.to_string()))?;
+        let mut header_value =
+            header::HeaderValue::from_str(value).map_err(|err| invalid(err.to_string()))?;
+        if SENSITIVE_HEADERS
+            .iter()
+            .any(|x| header_name.as_str().contains(x))
+        {
+            header_value.set_sensitive(true);
+        }
+        header_map.insert(header_name, header_value);
+    }
+    Ok(header_map)
+}
+
+fn create_client(
+    secret: Option<String>,
+    options: &ClientOptions,
+) -> Result<Client, std::io::Error> {
+    let mut builder = Client::builder();
+    if let Some(connect_timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    if options.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(pool_idle_timeout) = options.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(pool_idle_timeout);
+    }
+    if let Some(pool_max_idle_per_host) = options.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+    }
+    let mut headers = header::HeaderMap::new();
+    if let Some(secret) = secret {
+        let mut auth_value =
+            header::HeaderValue::from_str(&format!("Basic {}", BASE64.encode(secret.as_bytes())))
+                .expect("cannot crate authorization header");
+        auth_value.set_sensitive(true);
+        headers.insert(header::AUTHORIZATION, auth_value);
+    }
+    builder
+        .default_headers(headers)
+        .timeout(options.timeout)
+        .build()
+        .map_err(|err| std::io::Error::other(format!("cannot create http client: {}", err)))
+}
+
 pub fn create_chain(
     max_retries: u32,
     retry_sleep: Duration,
     secret: Option<String>,
-    timeout: Duration,
+    client_options: ClientOptions,
+    rebase_redirects: bool,
+    rate_limit: RateLimit,
 ) -> Result<Box<dyn Fetcher>, std::io::Error> {
+    let mut fetcher: Box<dyn Fetcher> = Box::new(DirectFetcher {
+        client: create_client(secret, &client_options)?,
+        headers: request_headers(&client_options.headers)?,
+        rebase_redirects,
+        redirected_bases: Mutex::new(vec![]),
+    });
+    if rate_limit.max_requests_per_sec.is_some() || rate_limit.max_download_bytes_per_sec.is_some()
+    {
+        fetcher = Box::new(RateLimitFetcher {
+            fetcher,
+            min_interval: rate_limit
+                .max_requests_per_sec
+                .map(|x| Duration::from_secs_f64(1.0 / x)),
+            max_bytes_per_sec: rate_limit.max_download_bytes_per_sec,
+            time_provider: Arc::new(RealTimeProvider {}),
+            sleep: Arc::new(sleep),
+            last_request: Mutex::new(None),
+        });
+    }
     Ok(Box::new(RetryFetcher {
         max_retries,
         retry_sleep,
-        fetcher: Box::new(DirectFetcher { secret, timeout }),
+        fetcher,
     }))
 }
 
 #[cfg(test)]
 pub mod test {
-    use crate::fetcher::{FetchError, Fetcher, MockFetcher, RetryFetcher};
+    use crate::fetcher::{
+        create_chain, redirected_base, request_headers, ClientOptions, DirectFetcher, FetchError,
+        Fetcher, MockFetcher, RateLimit, RateLimitFetcher, RetryFetcher,
+    };
+    use crate::sync::MockTimeProvider;
     use mockall::predicate;
-    use std::io::Read;
-    use std::time::Duration;
+    use reqwest::blocking::Client;
+    use std::collections::BTreeMap;
+    use std::io::{ErrorKind, Read};
+    use std::ops::Add;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
 
     #[test]
     fn retry_fail() {
@@ -160,4 +561,186 @@ pub mod test {
 
         assert_eq!("hello", content);
     }
+
+    #[test]
+    fn detect_redirected_base() {
+        assert_eq!(
+            Some(("http://a/repo".into(), "https://b/mirror".into())),
+            redirected_base("http://a/repo/pool/x.deb", "https://b/mirror/pool/x.deb")
+        );
+        assert_eq!(
+            None,
+            redirected_base("http://a/repo/pool/x.deb", "https://cdn/3fa7c1")
+        );
+        assert_eq!(
+            None,
+            redirected_base("http://a/repo/x.deb", "https://b/x.deb")
+        );
+    }
+
+    #[test]
+    fn rate_limit_spacing() {
+        //the mock clock only advances when the fetcher sleeps
+        let clock = Arc::new(AtomicU64::new(0));
+        let mut time_provider = MockTimeProvider::new();
+        let time_clock = clock.clone();
+        time_provider.expect_now().returning(move || {
+            UNIX_EPOCH.add(Duration::from_millis(time_clock.load(Ordering::SeqCst)))
+        });
+
+        let requests = Arc::new(Mutex::new(vec![]));
+        let mut mock = MockFetcher::new();
+        let request_clock = clock.clone();
+        let request_times = requests.clone();
+        mock.expect_fetch().times(3).returning(move |_| {
+            request_times
+                .lock()
+                .unwrap()
+                .push(request_clock.load(Ordering::SeqCst));
+            Result::Ok(Box::new(&[0u8; 1000][..]))
+        });
+
+        let sleep_clock = clock.clone();
+        let fetcher = RateLimitFetcher {
+            fetcher: Box::new(mock),
+            min_interval: Some(Duration::from_millis(500)),
+            max_bytes_per_sec: Some(500),
+            time_provider: Arc::new(time_provider),
+            sleep: Arc::new(move |duration: Duration| {
+                sleep_clock.fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+            }),
+            last_request: Mutex::new(None),
+        };
+
+        fetcher.fetch("https://url/1").unwrap();
+        fetcher.fetch("https://url/2").unwrap();
+        let mut reader = fetcher.fetch("https://url/3").unwrap();
+        //responses were not read yet, so only the request spacing applies
+        assert_eq!(vec![0, 500, 1000], *requests.lock().unwrap());
+
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(1000, content.len());
+        //1000 bytes at 500 bytes/s
+        assert_eq!(3000, clock.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn fetch_local_files() {
+        let fetcher = create_chain(
+            3,
+            Duration::from_millis(0),
+            None,
+            ClientOptions {
+                timeout: Duration::from_secs(1),
+                connect_timeout: None,
+                http2_prior_knowledge: false,
+                pool_idle_timeout: None,
+                pool_max_idle_per_host: None,
+                headers: BTreeMap::new(),
+            },
+            false,
+            RateLimit {
+                max_requests_per_sec: None,
+                max_download_bytes_per_sec: None,
+            },
+        )
+        .unwrap();
+        let root = std::env::current_dir().unwrap();
+
+        let mut content = String::new();
+        fetcher
+            .fetch(&format!("file://{}/samples/debian/Release", root.display()))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string("samples/debian/Release").unwrap(),
+            content
+        );
+
+        let err = fetcher
+            .fetch(&format!("file://{}/samples/missing", root.display()))
+            .err()
+            .unwrap();
+        assert_eq!(404, err.code);
+    }
+
+    ///returns the content, then fails as a dropped connection would
+    struct BrokenReader {
+        content: &'static [u8],
+    }
+
+    impl Read for BrokenReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.content.is_empty() {
+                return Err(std::io::Error::new(ErrorKind::ConnectionReset, "reset"));
+            }
+            let size = self.content.read(buf)?;
+            Ok(size)
+        }
+    }
+
+    #[test]
+    fn resume_interrupted_download() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("package.partial");
+
+        let mut mock = MockFetcher::new();
+        mock.expect_fetch()
+            .with(predicate::eq("https://url"))
+            .times(1)
+            .returning(|_| Result::Ok(Box::new(BrokenReader { content: b"hello" })));
+        mock.expect_fetch_range()
+            .with(predicate::eq("https://url"), predicate::eq(5))
+            .times(1)
+            .returning(|_, offset| Result::Ok((Box::new(" world".as_bytes()), offset)));
+        let fetcher = RetryFetcher {
+            fetcher: Box::new(mock),
+            max_retries: 3,
+            retry_sleep: Duration::from_millis(0),
+        };
+        assert_eq!(11, fetcher.download("https://url", &path).unwrap());
+        assert_eq!("hello world", std::fs::read_to_string(&path).unwrap());
+
+        //servers ignoring the range send the whole content again
+        std::fs::write(&path, "hello").unwrap();
+        let mut mock = MockFetcher::new();
+        mock.expect_fetch_range()
+            .with(predicate::eq("https://url"), predicate::eq(5))
+            .times(1)
+            .returning(|_, _| Result::Ok((Box::new("hello world".as_bytes()), 0)));
+        let fetcher = RetryFetcher {
+            fetcher: Box::new(mock),
+            max_retries: 3,
+            retry_sleep: Duration::from_millis(0),
+        };
+        assert_eq!(11, fetcher.download("https://url", &path).unwrap());
+        assert_eq!("hello world", std::fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn configured_headers_are_sent() {
+        let mut headers = BTreeMap::new();
+        headers.insert("Referer".to_string(), "https://example.com/".to_string());
+        headers.insert("X-Mirror-Token".to_string(), "s3cr3t".to_string());
+        let fetcher = DirectFetcher {
+            client: Client::new(),
+            headers: request_headers(&headers).unwrap(),
+            rebase_redirects: false,
+            redirected_bases: Mutex::new(vec![]),
+        };
+
+        let request = fetcher.request("https://url", 10).build().unwrap();
+        let headers = request.headers();
+        assert_eq!("https://example.com/", headers["referer"]);
+        assert!(!headers["referer"].is_sensitive());
+        assert_eq!("s3cr3t", headers["x-mirror-token"]);
+        assert!(headers["x-mirror-token"].is_sensitive());
+        assert_eq!("bytes=10-", headers["range"]);
+
+        let mut invalid = BTreeMap::new();
+        invalid.insert("X-Token".to_string(), "line\nbreak".to_string());
+        assert!(request_headers(&invalid).is_err());
+    }
 }
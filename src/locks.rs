@@ -1,16 +1,75 @@
+use crate::utils::human_println;
+use chrono::Utc;
 use std::collections::BTreeMap;
+use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 pub struct LockHolder {
     atomic: Arc<AtomicBool>,
+    //a file recording who holds the lock, removed on drop
+    marker: Option<String>,
 }
 impl Drop for LockHolder {
     fn drop(&mut self) {
+        if let Some(marker) = &self.marker {
+            let _ = std::fs::remove_file(marker);
+        }
         self.atomic.store(false, Ordering::SeqCst);
     }
 }
 
+impl LockHolder {
+    ///writes the pid, hostname and start time to path while the lock is held
+    pub fn with_marker(mut self, path: &str) -> Self {
+        let content = format!(
+            "pid: {}\nhostname: {}\nstarted: {}\n",
+            std::process::id(),
+            hostname(),
+            Utc::now().to_rfc3339()
+        );
+        match std::fs::write(path, content) {
+            Ok(()) => self.marker = Some(path.into()),
+            Err(err) => human_println!("warning: cannot write lock file {}: {}", path, err),
+        }
+        self
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|x| x.trim().to_string())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "unknown".into())
+}
+
+///the content of a lock file left by a process of this host which is no longer running, e.g.
+///after a crash. Processes of other hosts cannot be checked
+pub fn stale_marker(path: &str, held: bool) -> Option<String> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let field = |name: &str| {
+        content
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|x| x.trim().to_string())
+    };
+    let pid: u32 = field("pid:")?.parse().ok()?;
+    if field("hostname:")? != hostname() || !Path::new("/proc/self").exists() {
+        return None;
+    }
+    //after a restart, e.g. in a container, the pid may be the same of this process
+    let running = if pid == std::process::id() {
+        held
+    } else {
+        Path::new(&format!("/proc/{}", pid)).exists()
+    };
+    if running {
+        None
+    } else {
+        Some(content)
+    }
+}
+
 pub struct Lock {
     sync_locks: Arc<Mutex<BTreeMap<String, Arc<AtomicBool>>>>,
     write_locks: Arc<Mutex<BTreeMap<String, Mutex<()>>>>,
@@ -40,7 +99,8 @@ impl Lock {
         //allow to move out the lock guard without dropping it
         //we are telling the compiler: ignore the lock guard lifetime
         //this only holds as long as no entry is removed from the map
-        unsafe { std::mem::transmute(lock.lock().unwrap()) }
+        //a sync which panicked while holding it doesn't block the next ones
+        unsafe { std::mem::transmute(lock.lock().unwrap_or_else(PoisonError::into_inner)) }
     }
 
     fn try_lock(
@@ -55,6 +115,7 @@ impl Lock {
             {
                 Some(LockHolder {
                     atomic: atomic.clone(),
+                    marker: None,
                 })
             } else {
                 None
@@ -63,6 +124,7 @@ impl Lock {
             let new_mutex = Arc::new(AtomicBool::new(true));
             let holder = LockHolder {
                 atomic: new_mutex.clone(),
+                marker: None,
             };
             map.insert(repo_name.into(), new_mutex);
             Some(holder)
@@ -73,6 +135,14 @@ impl Lock {
         Lock::try_lock(&self.sync_locks, repo_name)
     }
 
+    ///true while a holder of lock_sync exists in this process
+    pub fn is_sync_held(&self, repo_name: &str) -> bool {
+        let map = self.sync_locks.lock().unwrap();
+        map.get(repo_name)
+            .map(|atomic| atomic.load(Ordering::SeqCst))
+            .unwrap_or(false)
+    }
+
     pub fn lock_write(&self, repo_name: &str) -> MutexGuard<()> {
         Lock::lock(&self.write_locks, repo_name)
     }
@@ -93,7 +163,7 @@ impl Lock {
 
 #[cfg(test)]
 pub mod test {
-    use crate::locks::Lock;
+    use crate::locks::{stale_marker, Lock};
     use std::sync::Arc;
     use std::thread;
 
@@ -123,4 +193,43 @@ pub mod test {
         }
         let _guard = lock.lock_write("repo");
     }
+
+    #[test]
+    fn lock_of_a_panicked_holder() {
+        let lock = Arc::new(Lock::new());
+        let panicking = lock.clone();
+        let result = thread::spawn(move || {
+            let _guard = panicking.lock_write("repo");
+            panic!("holder panicked");
+        })
+        .join();
+        assert!(result.is_err());
+        let _guard = lock.lock_write("repo");
+    }
+
+    #[test]
+    fn lock_file_exists_while_syncing() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = format!("{}/repo.syncing", tmp_dir.path().to_str().unwrap());
+        let lock = Lock::new();
+        {
+            let _holder = lock.lock_sync("repo").unwrap().with_marker(&path);
+            let content = std::fs::read_to_string(&path).unwrap();
+            assert!(content.starts_with(&format!("pid: {}\n", std::process::id())));
+            assert_eq!(None, stale_marker(&path, lock.is_sync_held("repo")));
+        }
+        assert!(!std::path::Path::new(&path).exists());
+
+        //left behind by a crash of a process which had the same pid
+        std::fs::write(
+            &path,
+            format!(
+                "pid: {}\nhostname: {}\nstarted: 2020-01-01T00:00:00+00:00\n",
+                std::process::id(),
+                super::hostname()
+            ),
+        )
+        .unwrap();
+        assert!(stale_marker(&path, lock.is_sync_held("repo")).is_some());
+    }
 }
@@ -2,6 +2,7 @@
 mod config;
 mod debian;
 mod destination;
+mod error;
 mod fetcher;
 mod locks;
 mod packages;
@@ -11,18 +12,56 @@ mod state;
 mod sync;
 mod utils;
 
+use crate::destination::{create_destination, InvalidationsInProgress};
 use crate::sync::SyncManager;
+use crate::utils::{human_println, LogEvent};
 use clap::{App, Arg};
 use std::process::exit;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const EXIT_SUCCESS: i32 = 0;
+//invalid config file or arguments
+const EXIT_CONFIG_ERROR: i32 = 2;
+//the action failed, for 'all' every repository failed
+const EXIT_FAILURE: i32 = 3;
+//only some repositories of 'all' failed
+const EXIT_PARTIAL_FAILURE: i32 = 4;
+
+///a number of seconds, minutes, hours or days, e.g. '90m' or '30d'
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "invalid duration '{}', expected e.g. 3600s, 90m, 12h or 30d",
+            text
+        )
+    };
+    if text.len() < 2 {
+        return Err(invalid());
+    }
+    let (amount, unit) = text.split_at(text.len() - 1);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
 
 fn main() {
     env_logger::init();
 
     let action_validator = |x: String| -> Result<(), String> {
-        if vec!["check", "sync", "server"].contains(&x.as_str()) {
+        if ["check", "sync", "server", "list", "prune", "rollback"].contains(&x.as_str()) {
             Ok(())
         } else {
-            Err("only check, sync, server are valid actions".into())
+            Err("only check, sync, server, list, prune, rollback are valid actions".into())
         }
     };
 
@@ -40,7 +79,7 @@ fn main() {
             Arg::with_name("action")
                 .long("action")
                 .value_name("ACTION")
-                .help("action to perform, 'check', 'sync' or 'server'")
+                .help("action to perform, 'check', 'sync', 'server', 'list', 'prune' or 'rollback'")
                 .takes_value(true)
                 .required(true)
                 .validator(action_validator)
@@ -48,9 +87,60 @@ fn main() {
             Arg::with_name("repository")
                 .long("repo")
                 .value_name("REPO")
-                .help("which repo to synchronize, check, sync, or server")
+                .help("which repo to synchronize, check, sync, prune, rollback or server")
+                .takes_value(true)
+                .required(false),
+            Arg::with_name("probe")
+                .long("probe")
+                .help("during check, upload and delete a probe file in every destination")
+                .takes_value(false)
+                .required(false),
+            Arg::with_name("verbose")
+                .long("verbose")
+                .help("during sync or server, print why every file is copied or deleted")
+                .takes_value(false)
+                .required(false),
+            Arg::with_name("confirm")
+                .long("confirm")
+                .help("during prune, delete orphans instead of only listing them")
+                .takes_value(false)
+                .required(false),
+            Arg::with_name("generation")
+                .long("generation")
+                .value_name("GENERATION")
+                .help("during rollback, which saved generation to restore, defaults to the latest")
                 .takes_value(true)
                 .required(false),
+            Arg::with_name("print-diff")
+                .long("print-diff")
+                .help("during sync, print the planned operations as json without performing them")
+                .takes_value(false)
+                .required(false),
+            Arg::with_name("print-deletes")
+                .long("print-deletes")
+                .help("during sync, print the files which would be deleted with their sizes without performing anything")
+                .takes_value(false)
+                .required(false),
+            Arg::with_name("since")
+                .long("since")
+                .value_name("DURATION")
+                .help("during sync, only copy packages built in the last DURATION, e.g. 30d, when upstream metadata has build times")
+                .takes_value(true)
+                .validator(|x| parse_duration(&x).map(|_| ()))
+                .required(false),
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("during sync, 'json' prints one json object per fetch, upload, delete, invalidation and repository summary instead of the human output")
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .default_value("human")
+                .required(false),
+            Arg::with_name("allow-mass-delete")
+                .long("allow-mass-delete")
+                .help("during sync or server, ignore max_delete_ratio")
+                .takes_value(false)
+                .required(false),
         ])
         .get_matches();
 
@@ -59,15 +149,52 @@ fn main() {
     let result = config::load_config(config_file);
     if result.is_err() {
         println!("{}", result.err().unwrap().to_string());
-        exit(1);
+        exit(EXIT_CONFIG_ERROR);
     }
     let config = result.unwrap();
 
     let action = matches.value_of("action").unwrap();
+    if let Some(repo_name) = matches.value_of("repository") {
+        if repo_name != "all" && !config.repo.iter().any(|repo| repo.name == repo_name) {
+            println!("unknown repository {}", repo_name);
+            exit(EXIT_CONFIG_ERROR);
+        }
+    }
     match action {
         "check" => {
+            if matches.is_present("probe") {
+                for repo in &config.repo {
+                    let tmp_path = repo.get_tmp_path(&config.general);
+                    let invalidations = Arc::new(InvalidationsInProgress::new(
+                        config.general.max_concurrent_invalidations,
+                    ));
+                    let result =
+                        create_destination(&config.general, &repo.destination, &invalidations)
+                            .and_then(|mut destination| {
+                                destination::probe(destination.as_mut(), &tmp_path)
+                            });
+                    if let Err(err) = result {
+                        println!("cannot write to destination of {}: {}", repo.name, err);
+                        exit(EXIT_FAILURE);
+                    }
+                }
+            }
             println!("config file is correct");
-            exit(0);
+            exit(EXIT_SUCCESS);
+        }
+        "list" => {
+            for repo in &config.repo {
+                let destination_kind = if repo.destination.s3.is_some() {
+                    "s3"
+                } else {
+                    "local"
+                };
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    repo.name, repo.source.kind, repo.source.endpoint, destination_kind
+                );
+            }
+            exit(EXIT_SUCCESS);
         }
         "sync" => {
             if let Some(repo_name) = matches.value_of("repository") {
@@ -77,31 +204,158 @@ fn main() {
                 } else {
                     repo_names = vec![repo_name.into()]
                 }
-                let sync_manager = SyncManager::new(config);
-                for repo_name in repo_names {
-                    let result = sync_manager.sync_repo(&repo_name);
+                utils::set_json_log(matches.value_of("log-format") == Some("json"));
+                let mut sync_manager = SyncManager::new(config);
+                sync_manager.set_allow_mass_delete(matches.is_present("allow-mass-delete"));
+                sync_manager.set_verbose(matches.is_present("verbose"));
+                let print_deletes = matches.is_present("print-deletes");
+                sync_manager.set_print_diff(matches.is_present("print-diff") || print_deletes);
+                //stdout is left to the plan, so that it can be piped
+                utils::set_progress_to_stderr(matches.is_present("print-diff") || print_deletes);
+                if let Some(since) = matches.value_of("since") {
+                    let since = parse_duration(since).unwrap();
+                    sync_manager.set_since(Some(
+                        SystemTime::now()
+                            .checked_sub(since)
+                            .unwrap_or(SystemTime::UNIX_EPOCH),
+                    ));
+                }
+                //one line per repository, printed at the end
+                let mut summary: Vec<String> = vec![];
+                let mut failed = 0;
+                for repo_name in &repo_names {
+                    let result = sync_manager.sync_repo(repo_name);
+                    LogEvent {
+                        repo: repo_name,
+                        event: "summary",
+                        bytes: result.as_ref().ok().map(|x| x.bytes_copied),
+                        files: result.as_ref().ok().map(|x| x.files_copied),
+                        failed: result.as_ref().ok().map(|x| x.failures.len()),
+                        error: result.as_ref().err().map(|x| x.to_string()),
+                        ..Default::default()
+                    }
+                    .emit();
                     if let Err(err) = result {
-                        println!("failed to synchronize {}: {}", repo_name, err.to_string());
-                        exit(1);
+                        human_println!("failed to synchronize {}: {}", repo_name, err);
+                        summary.push(format!("{}\tfailed: {}", repo_name, err));
+                        failed += 1;
+                        continue;
+                    }
+                    let report = result.unwrap();
+                    if let Some(plan) = &report.plan {
+                        if print_deletes {
+                            for (path, size) in plan.delete.iter().zip(&plan.delete_sizes) {
+                                println!("{}\t{}\t{}", repo_name, size, path);
+                            }
+                            println!(
+                                "{}\t{} files to delete for a total of {:.2} MB",
+                                repo_name,
+                                plan.delete.len(),
+                                plan.delete_sizes.iter().sum::<u64>() as f64 / (1024f64 * 1024f64)
+                            );
+                        } else {
+                            println!("{}", serde_json::to_string(plan).unwrap());
+                        }
+                        summary.push(format!("{}\tplanned", repo_name));
+                    } else if report.failures.is_empty() {
+                        human_println!("{} fully synchronized", repo_name);
+                        summary.push(format!("{}\tsynchronized", repo_name));
+                    } else {
+                        for failure in &report.failures {
+                            human_println!("skipped {}", failure);
+                        }
+                        human_println!(
+                            "{} synchronized, {} packages skipped",
+                            repo_name,
+                            report.failures.len()
+                        );
+                        summary.push(format!(
+                            "{}\tsynchronized, {} packages skipped",
+                            repo_name,
+                            report.failures.len()
+                        ));
+                    }
+                }
+                if repo_names.len() > 1 {
+                    human_println!("summary:");
+                    for line in &summary {
+                        human_println!("{}", line);
+                    }
+                }
+                if failed == 0 {
+                    exit(EXIT_SUCCESS);
+                } else if failed == repo_names.len() {
+                    exit(EXIT_FAILURE);
+                } else {
+                    exit(EXIT_PARTIAL_FAILURE);
+                }
+            } else {
+                println!("missing argument repo");
+                exit(EXIT_CONFIG_ERROR);
+            }
+        }
+        "prune" => {
+            if let Some(repo_name) = matches.value_of("repository") {
+                let confirm = matches.is_present("confirm");
+                let sync_manager = SyncManager::new(config);
+                match sync_manager.prune(repo_name, confirm) {
+                    Ok(orphans) => {
+                        for orphan in &orphans {
+                            println!("orphan {}", orphan);
+                        }
+                        if confirm {
+                            println!("{} orphans deleted from {}", orphans.len(), repo_name);
+                        } else {
+                            println!(
+                                "{} orphans found in {}, use --confirm to delete them",
+                                orphans.len(),
+                                repo_name
+                            );
+                        }
+                        exit(EXIT_SUCCESS);
+                    }
+                    Err(err) => {
+                        println!("failed to prune {}: {}", repo_name, err);
+                        exit(EXIT_FAILURE);
+                    }
+                }
+            } else {
+                println!("missing argument repo");
+                exit(EXIT_CONFIG_ERROR);
+            }
+        }
+        "rollback" => {
+            if let Some(repo_name) = matches.value_of("repository") {
+                let sync_manager = SyncManager::new(config);
+                match sync_manager.rollback(repo_name, matches.value_of("generation")) {
+                    Ok(generation) => {
+                        println!(
+                            "{} restored to generation {}, the next sync uploads the differences",
+                            repo_name, generation
+                        );
+                        exit(EXIT_SUCCESS);
+                    }
+                    Err(err) => {
+                        println!("failed to roll back {}: {}", repo_name, err);
+                        exit(EXIT_FAILURE);
                     }
-                    println!("{} fully synchronized", repo_name);
                 }
-                exit(0);
             } else {
                 println!("missing argument repo");
-                exit(1);
+                exit(EXIT_CONFIG_ERROR);
             }
         }
         "server" => {
-            let result = start_server(
-                &config.general.bind_address.clone(),
-                SyncManager::new(config),
-            );
+            let bind_address = config.general.bind_address.clone();
+            let mut sync_manager = SyncManager::new(config);
+            sync_manager.set_allow_mass_delete(matches.is_present("allow-mass-delete"));
+            sync_manager.set_verbose(matches.is_present("verbose"));
+            let result = start_server(&bind_address, config_file, sync_manager);
             if let Err(err) = result {
                 println!("cannot start http server: {}", err);
-                exit(1);
+                exit(EXIT_FAILURE);
             } else {
-                exit(0);
+                exit(EXIT_SUCCESS);
             }
         }
         _ => {
@@ -111,6 +365,10 @@ fn main() {
 }
 
 #[tokio::main]
-async fn start_server(bind_address: &str, sync_manager: SyncManager) -> hyper::Result<()> {
-    server::create(sync_manager, &bind_address).await
+async fn start_server(
+    bind_address: &str,
+    config_file: &str,
+    sync_manager: SyncManager,
+) -> hyper::Result<()> {
+    server::create(sync_manager, &bind_address, config_file).await
 }
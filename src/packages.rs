@@ -1,28 +1,58 @@
-use data_encoding::HEXLOWER_PERMISSIVE;
+use chrono::{DateTime, Duration, Utc};
+use data_encoding::{HEXLOWER_PERMISSIVE, HEXUPPER};
+use md5::Md5;
+use pgp::crypto::HashAlgorithm;
+use pgp::types::KeyTrait;
 use pgp::{Deserializable, SignedPublicKey, StandaloneSignature};
 use sha1::digest::{FixedOutput, Update};
 use sha1::{Digest, Sha1};
 use sha2::Sha256;
-use std::io::{Cursor, Error, ErrorKind, Read, Seek};
+use std::fmt;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{Cursor, Error, ErrorKind, Read};
+use std::sync::mpsc::{sync_channel, Receiver};
+
+//large reads amortize the per call overhead of the hashers
+const HASH_BUFFER_SIZE: usize = 256 * 1024;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Hash {
+    Md5 { hex: String },
     Sha1 { hex: String },
     Sha256 { hex: String },
     None,
 }
 
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Hash::Md5 { hex } => write!(f, "md5:{}", hex),
+            Hash::Sha1 { hex } => write!(f, "sha1:{}", hex),
+            Hash::Sha256 { hex } => write!(f, "sha256:{}", hex),
+            Hash::None => write!(f, "none"),
+        }
+    }
+}
+
 impl Hash {
     /**
-        returns an error when hash doesn't match
+        computes the hash of the reader content with the same algorithm
     */
-    pub fn matches<T>(&self, reader: &mut T) -> Result<bool, std::io::Error>
+    pub fn compute<T>(&self, reader: &mut T) -> Result<Hash, std::io::Error>
     where
         T: Read,
     {
         match self {
-            Hash::Sha1 { hex } => Hash::verify(reader, Sha1::new(), hex),
-            Hash::Sha256 { hex } => Hash::verify(reader, Sha256::new(), hex),
+            Hash::Md5 { .. } => Ok(Hash::Md5 {
+                hex: Hash::digest(reader, Md5::new())?,
+            }),
+            Hash::Sha1 { .. } => Ok(Hash::Sha1 {
+                hex: Hash::digest(reader, Sha1::new())?,
+            }),
+            Hash::Sha256 { .. } => Ok(Hash::Sha256 {
+                hex: Hash::digest(reader, Sha256::new())?,
+            }),
             Hash::None => {
                 //just a safeguard against bugs
                 panic!("file has an empty hash!")
@@ -30,16 +60,75 @@ impl Hash {
         }
     }
 
-    fn verify<T, D>(
-        reader: &mut T,
-        mut hasher: D,
-        expected_hash: &str,
-    ) -> Result<bool, std::io::Error>
+    ///like compute, but the file is read on another thread while hashing. md5 and sha digests
+    ///cannot be split in chunks, so the gain comes from overlapping disk reads with hashing
+    pub fn compute_read_ahead(&self, file: &File) -> Result<Hash, std::io::Error> {
+        let mut file = file.try_clone()?;
+        //at most two chunks waiting, to bound memory
+        let (sender, receiver) = sync_channel(2);
+        std::thread::spawn(move || loop {
+            let mut chunk = vec![0u8; HASH_BUFFER_SIZE];
+            let result = file.read(&mut chunk).map(|size| {
+                chunk.truncate(size);
+                chunk
+            });
+            let done = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+            if sender.send(result).is_err() || done {
+                break;
+            }
+        });
+        self.compute(&mut ReadAhead {
+            receiver,
+            chunk: vec![],
+            offset: 0,
+        })
+    }
+
+    ///higher is stronger, used to pick the best checksum when an index lists several
+    pub fn strength(&self) -> u8 {
+        match self {
+            Hash::None => 0,
+            Hash::Md5 { .. } => 1,
+            Hash::Sha1 { .. } => 2,
+            Hash::Sha256 { .. } => 3,
+        }
+    }
+
+    ///strength of an algorithm as named by min_hash_strength
+    pub fn strength_of(algorithm: &str) -> Option<u8> {
+        match algorithm {
+            "md5" => Some(1),
+            "sha1" => Some(2),
+            "sha256" => Some(3),
+            _ => None,
+        }
+    }
+
+    ///maps the checksum type of redhat metadata, where 'sha' is an alias of sha1
+    pub fn from_type(type_: &str, hex: String) -> Option<Hash> {
+        match type_ {
+            "md5" => Some(Hash::Md5 { hex }),
+            "sha" | "sha1" => Some(Hash::Sha1 { hex }),
+            "sha256" => Some(Hash::Sha256 { hex }),
+            _ => None,
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Hash::None => "none",
+            Hash::Md5 { .. } => "md5",
+            Hash::Sha1 { .. } => "sha1",
+            Hash::Sha256 { .. } => "sha256",
+        }
+    }
+
+    fn digest<T, D>(reader: &mut T, mut hasher: D) -> Result<String, std::io::Error>
     where
         T: Read,
         D: Update + FixedOutput,
     {
-        let mut buffer: [u8; 4096] = [0u8; 4096];
+        let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
         loop {
             let size = reader.read(&mut buffer)?;
             if size == 0 {
@@ -48,29 +137,45 @@ impl Hash {
             hasher.update(&buffer[0..size]);
         }
 
-        let hash = HEXLOWER_PERMISSIVE.encode(hasher.finalize_fixed().as_slice());
-        Ok(hash == expected_hash)
+        Ok(HEXLOWER_PERMISSIVE.encode(hasher.finalize_fixed().as_slice()))
     }
 
     pub fn create_sha256_hash<T>(reader: &mut T) -> Result<Hash, std::io::Error>
     where
         T: Read,
     {
-        let mut hasher = Sha256::new();
-        let mut buffer: [u8; 4096] = [0u8; 4096];
-        loop {
-            let size = reader.read(&mut buffer)?;
-            if size == 0 {
-                break;
-            }
-            Digest::update(&mut hasher, &buffer[0..size]);
-        }
         Ok(Hash::Sha256 {
-            hex: HEXLOWER_PERMISSIVE.encode(hasher.finalize_fixed().as_slice()),
+            hex: Hash::digest(reader, Sha256::new())?,
         })
     }
 }
 
+///chunks read by the thread of compute_read_ahead
+struct ReadAhead {
+    receiver: Receiver<Result<Vec<u8>, std::io::Error>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+        if self.offset == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.offset = 0;
+                }
+                //the reading thread stops after the end of the file
+                Err(_) => return Ok(0),
+            }
+        }
+        let size = buf.len().min(self.chunk.len() - self.offset);
+        buf[..size].copy_from_slice(&self.chunk[self.offset..self.offset + size]);
+        self.offset += size;
+        Ok(size)
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Signature {
     PGPEmbedded,
@@ -84,42 +189,64 @@ impl Default for Signature {
     }
 }
 
+///names of the hash algorithms a signature can be made with, for signature_hash_algorithms
+pub const SIGNATURE_HASH_ALGORITHMS: [&str; 9] = [
+    "md5",
+    "sha1",
+    "ripemd160",
+    "sha224",
+    "sha256",
+    "sha384",
+    "sha512",
+    "sha3-256",
+    "sha3-512",
+];
+
+fn signature_hash_name(algorithm: HashAlgorithm) -> &'static str {
+    match algorithm {
+        HashAlgorithm::MD5 => "md5",
+        HashAlgorithm::SHA1 => "sha1",
+        HashAlgorithm::RIPEMD160 => "ripemd160",
+        HashAlgorithm::SHA2_224 => "sha224",
+        HashAlgorithm::SHA2_256 => "sha256",
+        HashAlgorithm::SHA2_384 => "sha384",
+        HashAlgorithm::SHA2_512 => "sha512",
+        HashAlgorithm::SHA3_256 => "sha3-256",
+        HashAlgorithm::SHA3_512 => "sha3-512",
+        HashAlgorithm::None | HashAlgorithm::Private10 => "unknown",
+    }
+}
+
 impl Signature {
-    fn extract_body_and_signature(text: &str) -> Option<(String, String)> {
+    ///a message signed by multiple keys may contain multiple signature blocks
+    fn extract_body_and_signatures(text: &str) -> Option<(String, Vec<String>)> {
         let mut split = text.splitn(2, "\n\n");
         split.next();
 
-        let second_token = split.next();
-        if second_token.is_none() {
-            return None;
-        }
-
-        let mut split = second_token
-            .unwrap()
-            .splitn(2, "\n-----BEGIN PGP SIGNATURE-----");
-
-        let third_token = split.next();
-        if third_token.is_none() {
-            return None;
-        }
+        let second_token = split.next()?;
+        let mut split = second_token.split("\n-----BEGIN PGP SIGNATURE-----");
 
-        let forth_token = split.next();
-        if forth_token.is_none() {
+        let body = split.next()?.to_string();
+        let signatures: Vec<String> = split
+            .map(|block| format!("-----BEGIN PGP SIGNATURE-----{}", block))
+            .collect();
+        if signatures.is_empty() {
             return None;
         }
-        let signature = format!("-----BEGIN PGP SIGNATURE-----{}", forth_token.unwrap());
-        let body = third_token.unwrap().to_string();
 
-        Some((body, signature))
+        Some((body, signatures))
     }
 
+    ///returns the fingerprint of the verifying key, none when there is no signature,
+    ///signatures made with a hash missing from accepted_hashes are invalid unless it's empty
     pub fn matches<T>(
         &self,
         public_key: &SignedPublicKey,
+        accepted_hashes: &[String],
         reader: &mut T,
-    ) -> Result<(), std::io::Error>
+    ) -> Result<Option<String>, std::io::Error>
     where
-        T: Read + Seek,
+        T: Read + ?Sized,
     {
         match self {
             Signature::PGPEmbedded => {
@@ -129,9 +256,22 @@ impl Signature {
                 //files, which are currently the only use-case.
                 let mut text = String::new();
                 reader.read_to_string(&mut text)?;
-                let result = Signature::extract_body_and_signature(&text);
-                if let Some((data, signature)) = result {
-                    Signature::match_internal(public_key, &signature, data.as_bytes())
+                let result = Signature::extract_body_and_signatures(&text);
+                if let Some((data, signatures)) = result {
+                    //any matching signature is enough
+                    let mut last_err = None;
+                    for signature in &signatures {
+                        match Signature::match_internal(
+                            public_key,
+                            accepted_hashes,
+                            signature,
+                            data.as_bytes(),
+                        ) {
+                            Ok(fingerprint) => return Ok(Some(fingerprint)),
+                            Err(err) => last_err = Some(err),
+                        }
+                    }
+                    Err(last_err.unwrap())
                 } else {
                     return Err(std::io::Error::new(
                         ErrorKind::InvalidData,
@@ -142,25 +282,42 @@ impl Signature {
             Signature::PGPExternal { signature } => {
                 let mut data = Vec::new();
                 reader.read_to_end(&mut data)?;
-                Signature::match_internal(public_key, signature, data.as_slice())
+                Signature::match_internal(public_key, accepted_hashes, signature, data.as_slice())
+                    .map(Some)
             }
-            Signature::None => Ok(()),
+            Signature::None => Ok(None),
         }
     }
 
     fn match_internal(
         public_key: &SignedPublicKey,
+        accepted_hashes: &[String],
         signature: &String,
         data: &[u8],
-    ) -> Result<(), Error> {
+    ) -> Result<String, Error> {
+        let fingerprint = Signature::fingerprint(public_key);
         let result = StandaloneSignature::from_armor_single(Cursor::new(signature.as_bytes()));
 
         if let Ok((signature, _)) = result {
+            let hash = signature_hash_name(signature.signature.config.hash_alg);
+            if !accepted_hashes.is_empty() && !accepted_hashes.iter().any(|x| x == hash) {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "signature made with {}, which is not in signature_hash_algorithms",
+                        hash
+                    ),
+                ));
+            }
             let result = signature.verify(&public_key, data);
             if let Err(err) = result {
                 return Err(std::io::Error::new(
                     ErrorKind::InvalidData,
-                    format!("validation failed: {}", err.to_string()),
+                    format!(
+                        "validation failed with key {}: {}",
+                        fingerprint,
+                        err.to_string()
+                    ),
                 ));
             }
         } else {
@@ -169,7 +326,34 @@ impl Signature {
                 "cannot parse signature".to_string(),
             ));
         }
-        Ok(())
+        Ok(fingerprint)
+    }
+
+    pub fn fingerprint(public_key: &SignedPublicKey) -> String {
+        HEXUPPER.encode(&public_key.fingerprint())
+    }
+
+    ///fingerprint and expiry of the primary key and of its subkeys, skipping keys which never expire
+    pub fn expiries(public_key: &SignedPublicKey) -> Vec<(String, DateTime<Utc>)> {
+        let mut expiries = vec![];
+        if let Some(expires_at) = public_key.expires_at() {
+            expiries.push((Signature::fingerprint(public_key), expires_at));
+        }
+        for subkey in &public_key.public_subkeys {
+            //the expiration is stored as an offset from the creation of the subkey
+            let offset = subkey
+                .signatures
+                .iter()
+                .filter_map(|x| x.key_expiration_time())
+                .max();
+            if let Some(offset) = offset {
+                expiries.push((
+                    HEXUPPER.encode(&subkey.key.fingerprint()),
+                    *subkey.key.created_at() + Duration::seconds(offset.timestamp()),
+                ));
+            }
+        }
+        expiries
     }
 }
 
@@ -181,6 +365,9 @@ pub struct Package {
     pub path: String,
     pub hash: Hash,
     pub size: u64,
+    ///seconds since the epoch, zero when upstream metadata doesn't have it
+    pub file_time: u64,
+    pub build_time: u64,
 }
 
 impl Package {
@@ -192,8 +379,15 @@ impl Package {
             path: "".to_string(),
             hash: Hash::None,
             size: 0,
+            file_time: 0,
+            build_time: 0,
         }
     }
+
+    ///the same stored file, metadata like times may change without a new upload
+    pub fn same_file(&self, other: &Package) -> bool {
+        self.path == other.path && self.hash == other.hash && self.size == other.size
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -261,11 +455,171 @@ impl Repository {
             .iter()
             .fold(0, |count, c| count + c.packages.len() as u64)
     }
+
+    ///true when every collection has exactly the same indexes of the other repository
+    pub fn same_indexes(&self, other: &Repository) -> bool {
+        self.collections.len() == other.collections.len()
+            && self.collections.iter().all(|collection| {
+                other.collections.iter().any(|other_collection| {
+                    collection.target == other_collection.target
+                        && collection.indexes.len() == other_collection.indexes.len()
+                        && collection.indexes.iter().all(|index| {
+                            other_collection
+                                .indexes
+                                .iter()
+                                .any(|other_index| index.same_content(other_index))
+                        })
+                })
+            })
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use crate::packages::Signature;
+    use crate::packages::{Hash, Signature, HASH_BUFFER_SIZE};
+    use pgp::{Deserializable, SignedPublicKey};
+    use std::fs;
+    use std::fs::File;
+
+    #[test]
+    fn public_key_fingerprint() {
+        let (public_key, _) =
+            SignedPublicKey::from_string(&fs::read_to_string("samples/public-key").unwrap())
+                .unwrap();
+        assert_eq!(
+            "E8A032E094D8EB4EA189D270DA418C88A3219F7B",
+            Signature::fingerprint(&public_key)
+        );
+        assert_eq!(
+            None,
+            Signature::None
+                .matches(&public_key, &[], &mut std::io::Cursor::new(b"data"))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn signature_hash_policy() {
+        let (public_key, _) = SignedPublicKey::from_string(
+            &fs::read_to_string("samples/signing-public-key").unwrap(),
+        )
+        .unwrap();
+        let data = fs::read("samples/signed-data").unwrap();
+        let signature = |sample: &str| Signature::PGPExternal {
+            signature: fs::read_to_string(sample).unwrap(),
+        };
+        let sha1 = signature("samples/signed-data.sha1.asc");
+        let sha256 = signature("samples/signed-data.sha256.asc");
+        let accepted = vec!["sha256".to_string(), "sha512".to_string()];
+
+        let fingerprint = Signature::fingerprint(&public_key);
+        let verify = |signature: &Signature, accepted: &[String]| {
+            signature.matches(&public_key, accepted, &mut data.as_slice())
+        };
+        assert_eq!(Some(fingerprint.clone()), verify(&sha1, &[]).unwrap());
+        assert_eq!(Some(fingerprint), verify(&sha256, &accepted).unwrap());
+        let err = verify(&sha1, &accepted).err().unwrap();
+        assert!(err.to_string().contains("made with sha1"));
+    }
+
+    #[test]
+    fn compute_hash() {
+        let expected = Hash::Sha256 {
+            hex: "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".into(),
+        };
+        let computed = expected.compute(&mut "hello".as_bytes()).unwrap();
+        assert_eq!(expected, computed);
+
+        let expected = Hash::Sha1 { hex: "".into() };
+        let computed = expected.compute(&mut "hello".as_bytes()).unwrap();
+        assert_eq!(
+            Hash::Sha1 {
+                hex: "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d".into()
+            },
+            computed
+        );
+
+        let expected = Hash::Md5 {
+            hex: "5d41402abc4b2a76b9719d911017c592".into(),
+        };
+        let computed = expected.compute(&mut "hello".as_bytes()).unwrap();
+        assert_eq!(expected, computed);
+    }
+
+    #[test]
+    fn read_ahead_hash_matches_serial() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("large");
+        //not a multiple of the buffer size, so the last chunk is partial
+        let data: Vec<u8> = (0..3 * HASH_BUFFER_SIZE + 1234)
+            .map(|x| (x * 31 % 251) as u8)
+            .collect();
+        for content in &[&data[..], &[]] {
+            std::fs::write(&path, content).unwrap();
+            for hash in &[
+                Hash::Md5 { hex: "".into() },
+                Hash::Sha1 { hex: "".into() },
+                Hash::Sha256 { hex: "".into() },
+            ] {
+                let serial = hash.compute(&mut File::open(&path).unwrap()).unwrap();
+                let read_ahead = hash
+                    .compute_read_ahead(&File::open(&path).unwrap())
+                    .unwrap();
+                assert_eq!(serial, read_ahead);
+            }
+        }
+    }
+
+    ///reads at most 4 KB per call, like the hashing buffer did before
+    struct SmallReads(File);
+
+    impl std::io::Read for SmallReads {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let size = buf.len().min(4096);
+            self.0.read(&mut buf[..size])
+        }
+    }
+
+    ///a benchmark, run with 'cargo test --release -- --ignored --nocapture hashing_throughput'
+    #[test]
+    #[ignore]
+    fn hashing_throughput() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("large");
+        let data: Vec<u8> = (0..256 * 1024 * 1024)
+            .map(|x| (x * 31 % 251) as u8)
+            .collect();
+        std::fs::write(&path, &data).unwrap();
+        let megabytes = data.len() as f64 / (1024f64 * 1024f64);
+        for hash in &[
+            Hash::Md5 { hex: "".into() },
+            Hash::Sha1 { hex: "".into() },
+            Hash::Sha256 { hex: "".into() },
+        ] {
+            let measure = |compute: &dyn Fn() -> Hash| {
+                let start = std::time::Instant::now();
+                compute();
+                megabytes / start.elapsed().as_secs_f64()
+            };
+            let small = measure(&|| {
+                hash.compute(&mut SmallReads(File::open(&path).unwrap()))
+                    .unwrap()
+            });
+            let large = measure(&|| hash.compute(&mut File::open(&path).unwrap()).unwrap());
+            let read_ahead = measure(&|| {
+                hash.compute_read_ahead(&File::open(&path).unwrap())
+                    .unwrap()
+            });
+            println!(
+                "{}: 4 KB reads {:.0} MB/s, {} KB reads {:.0} MB/s, read ahead {:.0} MB/s",
+                hash.algorithm(),
+                small,
+                HASH_BUFFER_SIZE / 1024,
+                large,
+                read_ahead
+            );
+        }
+    }
 
     #[test]
     fn pgp_signature() {
@@ -404,9 +758,39 @@ lEq9/6sr7HPHcpDquH2n
 =yLRq
 -----END PGP SIGNATURE-----";
 
-        let (body, signature) = Signature::extract_body_and_signature(text).unwrap();
+        let (body, signatures) = Signature::extract_body_and_signatures(text).unwrap();
 
         assert_eq!(body, expected_body);
-        assert_eq!(signature, expected_signature);
+        assert_eq!(signatures, vec![expected_signature.to_string()]);
+    }
+
+    #[test]
+    fn pgp_multiple_signatures() {
+        let text = "-----BEGIN PGP SIGNED MESSAGE-----
+Hash: SHA256
+
+Origin: Artifactory
+Codename: bionic
+-----BEGIN PGP SIGNATURE-----
+
+first
+-----END PGP SIGNATURE-----
+-----BEGIN PGP SIGNATURE-----
+
+second
+-----END PGP SIGNATURE-----
+";
+
+        let (body, signatures) = Signature::extract_body_and_signatures(text).unwrap();
+
+        assert_eq!(body, "Origin: Artifactory\nCodename: bionic");
+        assert_eq!(
+            signatures,
+            vec![
+                "-----BEGIN PGP SIGNATURE-----\n\nfirst\n-----END PGP SIGNATURE-----".to_string(),
+                "-----BEGIN PGP SIGNATURE-----\n\nsecond\n-----END PGP SIGNATURE-----\n"
+                    .to_string(),
+            ]
+        );
     }
 }
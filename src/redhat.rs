@@ -1,83 +1,142 @@
 use crate::config::RepositoryConfig;
+use crate::error::SyncError;
 use crate::fetcher::Fetcher;
 use crate::packages::{Collection, Hash, IndexFile, Package, Repository, Signature, Target};
 use crate::state::{LiveRepoMetadataStore, RepoMetadataStore, SavedRepoMetadataStore};
-use crate::utils::add_optional_index;
+use crate::utils::{add_optional_index, human_println};
 use flate2::read::GzDecoder;
-use std::io::{ErrorKind, Read};
-use std::rc::Rc;
+use std::io::Read;
 use std::str::FromStr;
+use std::sync::Arc;
+use xml::attribute::OwnedAttribute;
 use xml::reader::{Events, XmlEvent};
 
 pub fn load_repository(
     data_path: &str,
     config: &RepositoryConfig,
-) -> Result<(Repository, SavedRepoMetadataStore), std::io::Error> {
+) -> Result<(Repository, SavedRepoMetadataStore), SyncError> {
     let repo_metadata = SavedRepoMetadataStore::new(data_path);
-    let result = fetch_repository_internal(&repo_metadata, config);
-    if result.is_err() {
-        let err = result.err().unwrap();
-        return Err(std::io::Error::new(
-            err.kind(),
-            format!("cannot load current repo state: {}", &err.to_string()),
-        ));
-    }
-
-    Ok((result.unwrap(), repo_metadata))
+    let repo = fetch_repository_internal(&repo_metadata, config)
+        .map_err(|err| err.context("cannot load current repo state"))?;
+    Ok((repo, repo_metadata))
 }
 
 pub fn fetch_repository(
-    fetcher: Rc<dyn Fetcher>,
+    fetcher: Arc<dyn Fetcher>,
     tmp_path: &str,
     config: &RepositoryConfig,
-) -> Result<(Repository, LiveRepoMetadataStore), std::io::Error> {
+) -> Result<(Repository, LiveRepoMetadataStore), SyncError> {
     let repo_metadata = LiveRepoMetadataStore::new(&config.source.endpoint, tmp_path, fetcher)?;
-    let result = fetch_repository_internal(&repo_metadata, config);
-    if result.is_err() {
-        let err = result.err().unwrap();
-        return Err(std::io::Error::new(
-            err.kind(),
-            format!("cannot fetch repo state: {}", &err.to_string()),
-        ));
+    let repo = fetch_repository_internal(&repo_metadata, config)
+        .map_err(|err| err.context("cannot fetch repo state"))?;
+    Ok((repo, repo_metadata))
+}
+
+//a mirror running createrepo while we fetch may delete the files referenced by the repomd.xml
+//we just fetched, which is fixed by reading the new repomd.xml
+const MAX_METADATA_ATTEMPTS: u32 = 3;
+
+///every channel is a directory with its own repodata, the endpoint itself is the only
+///channel when none is configured
+fn channels(config: &RepositoryConfig) -> Vec<String> {
+    if config.source.channels.is_empty() {
+        return vec![String::new()];
+    }
+    config
+        .source
+        .channels
+        .iter()
+        .map(|version| version.trim_end_matches('/').to_string())
+        .collect()
+}
+
+///path relative to the endpoint of a file relative to the channel
+fn channel_path(channel: &str, path: &str) -> String {
+    if channel.is_empty() {
+        path.into()
+    } else {
+        format!("{}/{}", channel, path)
     }
-    Ok((result.unwrap(), repo_metadata))
+}
+
+///every repomd.xml, the entry points of the repository
+pub fn entry_points(config: &RepositoryConfig) -> Vec<String> {
+    channels(config)
+        .iter()
+        .map(|channel| channel_path(channel, config.source.get_metadata_path()))
+        .collect()
 }
 
 fn fetch_repository_internal<T>(
     state: &T,
     config: &RepositoryConfig,
-) -> Result<Repository, std::io::Error>
+) -> Result<Repository, SyncError>
+where
+    T: RepoMetadataStore,
+{
+    let mut repository = Repository {
+        name: config.name.clone(),
+        collections: vec![],
+    };
+    for channel in channels(config) {
+        repository
+            .collections
+            .push(fetch_collection(state, config, &channel)?);
+    }
+    Ok(repository)
+}
+
+fn fetch_collection<T>(
+    state: &T,
+    config: &RepositoryConfig,
+    channel: &str,
+) -> Result<Collection, SyncError>
+where
+    T: RepoMetadataStore,
+{
+    let repo_mod_path = channel_path(channel, config.source.get_metadata_path());
+    for attempt in 1..=MAX_METADATA_ATTEMPTS {
+        if let Some(collection) = parse_collection(state, config, channel)? {
+            return Ok(collection);
+        }
+        human_println!(
+            "{} changed while fetching, attempt {} of {}",
+            repo_mod_path,
+            attempt,
+            MAX_METADATA_ATTEMPTS
+        );
+    }
+    Err(SyncError::Parse(format!(
+        "{} kept referencing missing files after {} attempts",
+        repo_mod_path, MAX_METADATA_ATTEMPTS
+    )))
+}
+
+///returns None when a file referenced by the repomd.xml is missing
+fn parse_collection<T>(
+    state: &T,
+    config: &RepositoryConfig,
+    channel: &str,
+) -> Result<Option<Collection>, SyncError>
 where
     T: RepoMetadataStore,
 {
     let mut collection = Collection {
         target: Target {
-            release_name: "".to_string(),
+            release_name: channel.to_string(),
             architectures: vec![],
         },
         indexes: vec![],
         packages: vec![],
     };
 
-    let repo_mod_path = "repodata/repomd.xml";
-    let result = state.fetch(repo_mod_path);
-    if result.is_err() {
-        let err = result.err().unwrap();
-        return Result::Err(std::io::Error::new(
-            err.kind(),
-            format!("cannot fetch repomod.xml: {}", err.to_string()),
-        ));
-    }
-
-    let (disk_path, mut reader, size) = result.unwrap();
-    let result = parse_repomod(&mut reader);
-    if result.is_err() {
-        let err = result.err().unwrap();
-        return Result::Err(std::io::Error::new(
-            err.kind(),
-            format!("cannot parse repomod.xml: {}", err.to_string()),
-        ));
-    }
+    let repo_mod_path = channel_path(channel, config.source.get_metadata_path());
+    let repo_mod_path = repo_mod_path.as_str();
+    let (disk_path, mut reader, size) = state
+        .fetch_entry_point(repo_mod_path)
+        .map_err(|err| err.context("cannot fetch repomod.xml"))?;
+    let result =
+        parse_repomod(&mut reader).map_err(|err| err.context("cannot parse repomod.xml"))?;
 
     let signature = add_optional_index(
         state,
@@ -86,6 +145,14 @@ where
         Signature::None,
     )?;
 
+    //published verbatim, so clients can import the key from the mirror
+    add_optional_index(
+        state,
+        &format!("{}.key", repo_mod_path),
+        &mut collection.indexes,
+        Signature::None,
+    )?;
+
     let mut reader = state.read(&repo_mod_path)?.unwrap();
     if signature.is_some() {
         let mut text_signature = String::new();
@@ -109,22 +176,48 @@ where
         });
     }
 
-    for data in result.unwrap() {
-        let (disk_path, mut reader, size) = state.fetch(&data.location).unwrap();
+    for mut data in result {
+        //locations are relative to the channel, like the packages they list
+        data.location = channel_path(channel, &data.location);
+        let (disk_path, mut reader, size) = match state.fetch(&data.location) {
+            Ok(result) => result,
+            Err(err) if err.is_not_found() => {
+                human_println!("{}, referenced by {}", err, repo_mod_path);
+                return Ok(None);
+            }
+            Err(err) => return Err(err),
+        };
+
+        //some repositories sign every metadata file, only useful when we can verify it
+        let signature = if config.source.public_pgp_key.is_some() {
+            add_optional_index(
+                state,
+                &format!("{}.asc", data.location),
+                &mut collection.indexes,
+                Signature::None,
+            )?
+        } else {
+            None
+        };
+        let signature = if let Some(mut signature) = signature {
+            let mut text_signature = String::new();
+            signature.read_to_string(&mut text_signature)?;
+            Signature::PGPExternal {
+                signature: text_signature,
+            }
+        } else {
+            Signature::None
+        };
 
         if data.type_ == "primary" {
             if data.location.ends_with(".gz") {
                 reader = Box::new(GzDecoder::new(reader));
             }
-            let result = parse_packages(&mut reader);
-            if result.is_err() {
-                let err = result.err().unwrap();
-                return Result::Err(std::io::Error::new(
-                    err.kind(),
-                    format!("cannot parse primary.xml: {}", err.to_string()),
-                ));
+            let mut packages = parse_packages(&mut reader)
+                .map_err(|err| err.context("cannot parse primary.xml"))?;
+            for package in packages.iter_mut() {
+                package.path = channel_path(channel, &package.path);
             }
-            let mut packages = result.unwrap();
             collection.packages.append(&mut packages);
         }
 
@@ -133,12 +226,13 @@ where
             path: data.location.clone(),
             size,
             hash: data.hash.clone(),
-            signature: Signature::None,
+            signature,
         });
     }
 
-    collection.target.architectures =
-        collection
+    collection.target.architectures = match &config.architectures {
+        Some(architectures) => architectures.clone(),
+        None => collection
             .packages
             .iter()
             .fold(vec![], |mut acc: Vec<String>, x: &Package| {
@@ -146,12 +240,10 @@ where
                     acc.push(x.architecture.clone());
                 }
                 acc
-            });
+            }),
+    };
 
-    Ok(Repository {
-        name: config.name.clone(),
-        collections: vec![collection],
-    })
+    Ok(Some(collection))
 }
 
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -162,7 +254,7 @@ struct RepomodData {
     size: usize,
 }
 
-fn next_event<R>(iterator: &mut Events<&mut R>) -> Result<Option<XmlEvent>, std::io::Error>
+fn next_event<R>(iterator: &mut Events<&mut R>) -> Result<Option<XmlEvent>, SyncError>
 where
     R: Read,
 {
@@ -172,15 +264,15 @@ where
     }
     let event = event.unwrap();
     if event.is_err() {
-        return Result::Err(std::io::Error::new(
-            ErrorKind::InvalidData,
-            format!("invalid xml {}", event.err().unwrap().msg()),
-        ));
+        return Result::Err(SyncError::Parse(format!(
+            "invalid xml {}",
+            event.err().unwrap().msg()
+        )));
     }
     Result::Ok(Some(event.unwrap()))
 }
 
-pub fn parse_packages<R>(source: &mut R) -> Result<Vec<Package>, std::io::Error>
+pub fn parse_packages<R>(source: &mut R) -> Result<Vec<Package>, SyncError>
 where
     R: Read,
 {
@@ -203,7 +295,35 @@ where
     Result::Ok(packages)
 }
 
-fn parse_package<R>(iterator: &mut Events<&mut R>) -> Result<Package, std::io::Error>
+//old createrepo versions write 'sha' without a type, both mean sha1
+fn checksum_type_of(attributes: &[OwnedAttribute]) -> String {
+    attributes
+        .iter()
+        .find(|x| x.name.local_name == "type")
+        .map(|x| x.value.clone())
+        .unwrap_or_else(|| "sha".into())
+}
+
+fn parse_checksum(checksum_type: &str, hex: String) -> Result<Hash, SyncError> {
+    match Hash::from_type(checksum_type, hex) {
+        Some(hash) => Ok(hash),
+        None => Err(SyncError::Parse(format!(
+            "unsupported checksum type '{}'",
+            checksum_type
+        ))),
+    }
+}
+
+///seconds since the epoch of a time attribute, zero when missing
+fn parse_time(attributes: &[OwnedAttribute], name: &str) -> Result<u64, SyncError> {
+    match attributes.iter().find(|x| x.name.local_name == name) {
+        Some(attribute) => u64::from_str(&attribute.value)
+            .map_err(|err| SyncError::Parse(format!("invalid {} time: {}", name, err))),
+        None => Ok(0),
+    }
+}
+
+fn parse_package<R>(iterator: &mut Events<&mut R>) -> Result<Package, SyncError>
 where
     R: Read,
 {
@@ -214,9 +334,16 @@ where
         path: "".to_string(),
         hash: Hash::None,
         size: 0,
+        file_time: 0,
+        build_time: 0,
     };
 
     let mut last_tag = "data".into();
+    let mut checksum_type = String::from("sha");
+    //other checksums, e.g. of the header, may be listed too: pkgid="YES" marks the package one,
+    //a checksum without pkgid is only used when none is marked
+    let mut checksum_priority = 0;
+    let mut selected_priority = 0;
     loop {
         let event = next_event(iterator)?;
         if event.is_none() {
@@ -229,15 +356,26 @@ where
             } => {
                 last_tag = name.local_name.clone();
                 match name.local_name.as_str() {
+                    "checksum" => {
+                        checksum_type = checksum_type_of(&attributes);
+                        checksum_priority = match attributes
+                            .iter()
+                            .find(|x| x.name.local_name == "pkgid")
+                            .map(|x| x.value.as_str())
+                        {
+                            Some("YES") => 2,
+                            None => 1,
+                            Some(_) => 0,
+                        };
+                    }
                     "location" => {
                         let location = attributes.iter().find(|x| x.name.local_name == "href");
                         if let Some(location) = location {
                             data.path = location.value.clone();
                         } else {
-                            return Result::Err(std::io::Error::new(
-                                ErrorKind::InvalidData,
-                                format!("missing href from location"),
-                            ));
+                            return Result::Err(SyncError::Parse(format!(
+                                "missing href from location"
+                            )));
                         }
                     }
                     "size" => {
@@ -247,20 +385,21 @@ where
                             .map(|x| x.value.as_str());
 
                         if size.is_none() {
-                            return Result::Err(std::io::Error::new(
-                                ErrorKind::InvalidData,
-                                format!("invalid size tag"),
-                            ));
+                            return Result::Err(SyncError::Parse(format!("invalid size tag")));
                         }
                         let parsed = u64::from_str(size.unwrap());
                         if parsed.is_err() {
-                            return Result::Err(std::io::Error::new(
-                                ErrorKind::InvalidData,
-                                format!("invalid size: {}", &parsed.err().unwrap().to_string()),
-                            ));
+                            return Result::Err(SyncError::Parse(format!(
+                                "invalid size: {}",
+                                &parsed.err().unwrap().to_string()
+                            )));
                         }
                         data.size = parsed.unwrap();
                     }
+                    "time" => {
+                        data.file_time = parse_time(&attributes, "file")?;
+                        data.build_time = parse_time(&attributes, "build")?;
+                    }
                     "version" => {
                         let epoch = attributes
                             .iter()
@@ -276,10 +415,7 @@ where
                             .map(|x| x.value.as_str());
 
                         if epoch.is_none() || ver.is_none() || rel.is_none() {
-                            return Result::Err(std::io::Error::new(
-                                ErrorKind::InvalidData,
-                                format!("invalid version tag"),
-                            ));
+                            return Result::Err(SyncError::Parse(format!("invalid version tag")));
                         }
                         data.version =
                             format!("{}-{}-{}", ver.unwrap(), rel.unwrap(), epoch.unwrap());
@@ -290,7 +426,10 @@ where
             XmlEvent::Characters(text) => match last_tag.as_str() {
                 "name" => data.name = text,
                 "arch" => data.architecture = text,
-                "checksum" => data.hash = Hash::Sha1 { hex: text },
+                "checksum" if checksum_priority > selected_priority => {
+                    data.hash = parse_checksum(&checksum_type, text)?;
+                    selected_priority = checksum_priority;
+                }
                 _ => {}
             },
             XmlEvent::EndElement { name } => {
@@ -305,7 +444,7 @@ where
     Result::Ok(data)
 }
 
-fn parse_repomod<R>(source: &mut R) -> Result<Vec<RepomodData>, std::io::Error>
+fn parse_repomod<R>(source: &mut R) -> Result<Vec<RepomodData>, SyncError>
 where
     R: Read,
 {
@@ -343,7 +482,7 @@ where
 fn parse_repomod_data<R>(
     iterator: &mut Events<&mut R>,
     type_: &str,
-) -> Result<RepomodData, std::io::Error>
+) -> Result<RepomodData, SyncError>
 where
     R: Read,
 {
@@ -355,6 +494,7 @@ where
     };
 
     let mut last_tag = "data".into();
+    let mut checksum_type = String::from("sha");
     loop {
         let event = next_event(iterator)?;
         if event.is_none() {
@@ -366,27 +506,28 @@ where
                 name, attributes, ..
             } => {
                 last_tag = name.local_name.clone();
-                if name.local_name == "location" {
+                if name.local_name == "checksum" {
+                    checksum_type = checksum_type_of(&attributes);
+                } else if name.local_name == "location" {
                     let location = attributes.iter().find(|x| x.name.local_name == "href");
                     if let Some(location) = location {
                         data.location = location.value.clone();
                     } else {
-                        return Result::Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            format!("missing href from location"),
-                        ));
+                        return Result::Err(SyncError::Parse(format!(
+                            "missing href from location"
+                        )));
                     }
                 }
             }
             XmlEvent::Characters(text) => match last_tag.as_str() {
-                "checksum" => data.hash = Hash::Sha1 { hex: text },
+                "checksum" => data.hash = parse_checksum(&checksum_type, text)?,
                 "size" => {
                     let parsed = usize::from_str(&text);
                     if parsed.is_err() {
-                        return Result::Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            format!("invalid size: {}", &parsed.err().unwrap().to_string()),
-                        ));
+                        return Result::Err(SyncError::Parse(format!(
+                            "invalid size: {}",
+                            &parsed.err().unwrap().to_string()
+                        )));
                     }
                     data.size = parsed.unwrap()
                 }
@@ -408,9 +549,253 @@ where
 
 #[cfg(test)]
 pub mod tests {
-    use crate::packages::{Hash, Package};
-    use crate::redhat::{parse_packages, parse_repomod, RepomodData};
+    use crate::config::{DestinationConfig, RepositoryConfig, SourceConfig};
+    use crate::fetcher::{FetchError, MockFetcher};
+    use crate::packages::{Hash, Package, Signature};
+    use crate::redhat::{
+        entry_points, fetch_repository_internal, parse_packages, parse_repomod, RepomodData,
+    };
+    use crate::state;
+    use crate::state::{LiveRepoMetadataStore, MemoryRepoMetadataStore, RepoMetadataStore};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::collections::BTreeMap;
+    use std::fs;
     use std::fs::File;
+    use std::io::{Cursor, Read, Write};
+    use std::sync::Arc;
+
+    fn create_config() -> RepositoryConfig {
+        RepositoryConfig {
+            name: "test-repo".to_string(),
+            source: SourceConfig {
+                endpoint: "http://fake-url".to_string(),
+                kind: "redhat".to_string(),
+                public_pgp_key: None,
+                username: None,
+                password: None,
+                authorization_file: None,
+                packages_base: None,
+                strict_signatures: false,
+                signature_hash_algorithms: vec![],
+                metadata_path: None,
+                strip_prefix: None,
+                required_indexes: vec![],
+                headers: BTreeMap::new(),
+                channels: vec![],
+            },
+            destination: DestinationConfig {
+                s3: None,
+                local: None,
+                arch_prefix: false,
+            },
+            versions: vec![],
+            tmp_path: None,
+            pin: false,
+            best_effort: false,
+            metadata_only: false,
+            publish_public_key: None,
+            manifest_path: None,
+            publish_variants: None,
+            architectures: None,
+            max_requests_per_sec: None,
+            max_download_bytes_per_sec: None,
+            max_concurrent_index_fetches: None,
+            wildcard_invalidation: false,
+            append_only: false,
+            skip_unchanged: false,
+            verify_indexes: false,
+            allow_pool_conflicts: false,
+            max_total_size_bytes: None,
+            delete_grace_secs: 0,
+            extra_files: vec![],
+        }
+    }
+
+    #[test]
+    fn repomod_signature_is_published_verbatim() {
+        let mut primary = vec![];
+        let mut encoder = GzEncoder::new(&mut primary, Compression::default());
+        encoder
+            .write_all(&fs::read("samples/redhat/primary.xml").unwrap())
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .times(5)
+            .returning(move |url: &str| match url {
+                "http://fake-url/rc/repodata/repomd.xml" => Ok(Box::new(
+                    File::open("samples/redhat/repomod.xml").unwrap(),
+                )),
+                "http://fake-url/rc/repodata/repomd.xml.asc" => {
+                    Ok(Box::new(File::open("samples/fake-signature").unwrap()))
+                }
+                "http://fake-url/rc/repodata/repomd.xml.key" => {
+                    Ok(Box::new(File::open("samples/public-key").unwrap()))
+                }
+                "http://fake-url/rc/repodata/2e1eb1fb69a2ca7fbd6d8723ce7d3cd91e9a9f13-primary.xml.gz" => {
+                    Ok(Box::new(Cursor::new(primary.clone())))
+                }
+                "http://fake-url/rc/repodata/16b72c920dbd5d48e8aceb383b4b74664eb079ba-other.xml.gz" => {
+                    Ok(Box::new(File::open("samples/fake-package").unwrap()))
+                }
+                _ => panic!("unexpected url: {}", url),
+            });
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            tmp_dir.path().to_str().unwrap(),
+            Arc::new(mock_fetcher),
+        )
+        .unwrap();
+
+        let repository = fetch_repository_internal(&state, &create_config()).unwrap();
+        let indexes = &repository.collections.first().unwrap().indexes;
+
+        for (path, sample) in &[
+            ("repodata/repomd.xml.asc", "samples/fake-signature"),
+            ("repodata/repomd.xml.key", "samples/public-key"),
+        ] {
+            let index = indexes.iter().find(|index| &index.path == path).unwrap();
+            assert_eq!(Signature::None, index.signature);
+            let mut stored = vec![];
+            state::open_stored(&index.file_path)
+                .unwrap()
+                .read_to_end(&mut stored)
+                .unwrap();
+            assert_eq!(fs::read(sample).unwrap(), stored);
+        }
+
+        let repomod = indexes
+            .iter()
+            .find(|index| index.path == "repodata/repomd.xml")
+            .unwrap();
+        assert_eq!(
+            Signature::PGPExternal {
+                signature: fs::read_to_string("samples/fake-signature").unwrap()
+            },
+            repomod.signature
+        );
+    }
+
+    #[test]
+    fn custom_metadata_path() {
+        let mut primary = vec![];
+        let mut encoder = GzEncoder::new(&mut primary, Compression::default());
+        encoder
+            .write_all(&fs::read("samples/redhat/primary.xml").unwrap())
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let mut state = MemoryRepoMetadataStore::from_samples(&[
+            (
+                "modules/repodata/modules-repomd.xml",
+                "samples/redhat/repomod.xml",
+            ),
+            (
+                "repodata/16b72c920dbd5d48e8aceb383b4b74664eb079ba-other.xml.gz",
+                "samples/fake-package",
+            ),
+        ]);
+        //data files are still relative to the endpoint
+        state.insert(
+            "repodata/2e1eb1fb69a2ca7fbd6d8723ce7d3cd91e9a9f13-primary.xml.gz",
+            primary,
+        );
+
+        let mut config = create_config();
+        config.source.metadata_path = Some("modules/repodata/modules-repomd.xml".into());
+        let repository = fetch_repository_internal(&state, &config).unwrap();
+        let indexes = &repository.collections.first().unwrap().indexes;
+        assert!(indexes
+            .iter()
+            .any(|index| index.path == "modules/repodata/modules-repomd.xml"));
+        assert_eq!(2, repository.collections.first().unwrap().packages.len());
+        let fetched = state.fetched();
+        assert_eq!(5, fetched.len());
+        assert!(fetched.contains(
+            &"repodata/2e1eb1fb69a2ca7fbd6d8723ce7d3cd91e9a9f13-primary.xml.gz".to_string()
+        ));
+        assert!(!fetched
+            .iter()
+            .any(|path| path.starts_with("modules/repodata/2e1eb1fb")));
+
+        //every package is still mirrored
+        config.architectures = Some(vec!["x86_64".into(), "aarch64".into()]);
+        let repository = fetch_repository_internal(&state, &config).unwrap();
+        let collection = repository.collections.first().unwrap();
+        assert_eq!(vec!["x86_64", "aarch64"], collection.target.architectures);
+        assert_eq!(2, collection.packages.len());
+    }
+
+    #[test]
+    fn refetch_repomd_when_referenced_files_are_gone() {
+        let mut primary = vec![];
+        let mut encoder = GzEncoder::new(&mut primary, Compression::default());
+        encoder
+            .write_all(&fs::read("samples/redhat/primary.xml").unwrap())
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let create_fetcher = move |primary_failures: u32| {
+            let primary = primary.clone();
+            let mut primary_requests = 0;
+            let mut mock_fetcher = MockFetcher::new();
+            mock_fetcher
+                .expect_fetch()
+                .returning(move |url: &str| match url {
+                    "http://fake-url/rc/repodata/repomd.xml" => Ok(Box::new(
+                        File::open("samples/redhat/repomod.xml").unwrap(),
+                    )),
+                    "http://fake-url/rc/repodata/2e1eb1fb69a2ca7fbd6d8723ce7d3cd91e9a9f13-primary.xml.gz" => {
+                        primary_requests += 1;
+                        if primary_requests <= primary_failures {
+                            Err(FetchError {
+                                code: 404,
+                                error: "not found".into(),
+                            })
+                        } else {
+                            Ok(Box::new(Cursor::new(primary.clone())))
+                        }
+                    }
+                    "http://fake-url/rc/repodata/16b72c920dbd5d48e8aceb383b4b74664eb079ba-other.xml.gz" => {
+                        Ok(Box::new(File::open("samples/fake-package").unwrap()))
+                    }
+                    _ => Err(FetchError {
+                        code: 404,
+                        error: "not found".into(),
+                    }),
+                });
+            mock_fetcher
+        };
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            tmp_dir.path().to_str().unwrap(),
+            Arc::new(create_fetcher(2)),
+        )
+        .unwrap();
+        let repository = fetch_repository_internal(&state, &create_config()).unwrap();
+        assert_eq!(2, repository.collections.first().unwrap().packages.len());
+
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            tmp_dir.path().to_str().unwrap(),
+            Arc::new(create_fetcher(3)),
+        )
+        .unwrap();
+        let err = fetch_repository_internal(&state, &create_config())
+            .err()
+            .unwrap();
+        assert_eq!(
+            "repodata/repomd.xml kept referencing missing files after 3 attempts",
+            err.to_string()
+        );
+    }
 
     #[test]
     fn parse_repomod_successful() {
@@ -457,7 +842,9 @@ pub mod tests {
                     hash: Hash::Sha1 {
                         hex: "d331abce6e2300fc3a6e6d8d04849a7c58d20c00".into()
                     },
-                    size: 1089320
+                    size: 1089320,
+                    file_time: 1613143933633,
+                    build_time: 1613143913,
                 },
                 Package {
                     name: "service-discover-daemon".to_string(),
@@ -469,10 +856,170 @@ pub mod tests {
                     hash: Hash::Sha1 {
                         hex: "46530a9bd48e887301d3de5fbdb7634b9c2ac299".into()
                     },
-                    size: 1469912
+                    size: 1469912,
+                    file_time: 1613057547780,
+                    build_time: 1613057537,
                 }
             ],
             entries
         );
     }
+
+    #[test]
+    fn parse_checksum_types() {
+        let primary = |checksum_type: &str| {
+            format!(
+                "<metadata><package type=\"rpm\"><name>foo</name><arch>x86_64</arch>
+<version epoch=\"0\" ver=\"1.0\" rel=\"1\"/>
+<checksum type=\"{}\" pkgid=\"YES\">abcd</checksum>
+<size package=\"10\"/><location href=\"foo-1.0.rpm\"/></package></metadata>",
+                checksum_type
+            )
+        };
+        let hash = |checksum_type: &str| {
+            parse_packages(&mut primary(checksum_type).as_bytes())
+                .map(|packages| packages.first().unwrap().hash.clone())
+        };
+        assert_eq!(Hash::Sha256 { hex: "abcd".into() }, hash("sha256").unwrap());
+        assert_eq!(Hash::Sha1 { hex: "abcd".into() }, hash("sha").unwrap());
+        assert_eq!(Hash::Md5 { hex: "abcd".into() }, hash("md5").unwrap());
+        assert_eq!(
+            "unsupported checksum type 'sha512'",
+            hash("sha512").err().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn package_checksum_is_selected_by_pkgid() {
+        let hashes: Vec<Hash> =
+            parse_packages(&mut File::open("samples/redhat/primary-header-checksum.xml").unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|package| package.hash)
+                .collect();
+        assert_eq!(
+            vec![
+                Hash::Sha256 {
+                    hex: "1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b".into()
+                },
+                Hash::Sha256 {
+                    hex: "2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c".into()
+                },
+                Hash::Sha256 {
+                    hex: "3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d5e6f1a2b3c4d".into()
+                },
+            ],
+            hashes
+        );
+    }
+
+    #[test]
+    fn every_channel_is_a_collection() {
+        let mut primary = vec![];
+        let mut encoder = GzEncoder::new(&mut primary, Compression::default());
+        encoder
+            .write_all(&fs::read("samples/redhat/primary.xml").unwrap())
+            .unwrap();
+        encoder.finish().unwrap();
+
+        let mut state = MemoryRepoMetadataStore::new();
+        for channel in &["os", "updates"] {
+            state.insert(
+                &format!("{}/repodata/repomd.xml", channel),
+                fs::read("samples/redhat/repomod.xml").unwrap(),
+            );
+            state.insert(
+                &format!(
+                    "{}/repodata/2e1eb1fb69a2ca7fbd6d8723ce7d3cd91e9a9f13-primary.xml.gz",
+                    channel
+                ),
+                primary.clone(),
+            );
+            state.insert(
+                &format!(
+                    "{}/repodata/16b72c920dbd5d48e8aceb383b4b74664eb079ba-other.xml.gz",
+                    channel
+                ),
+                fs::read("samples/fake-package").unwrap(),
+            );
+        }
+
+        let mut config = create_config();
+        config.source.channels = vec!["os".into(), "updates/".into()];
+        assert_eq!(
+            vec!["os/repodata/repomd.xml", "updates/repodata/repomd.xml"],
+            entry_points(&config)
+        );
+        let repository = fetch_repository_internal(&state, &config).unwrap();
+        assert_eq!(2, repository.collections.len());
+        for (collection, channel) in repository.collections.iter().zip(&["os", "updates"]) {
+            assert_eq!(*channel, collection.target.release_name);
+            assert!(collection
+                .indexes
+                .iter()
+                .all(|index| index.path.starts_with(&format!("{}/repodata/", channel))));
+            assert_eq!(2, collection.packages.len());
+            assert!(collection
+                .packages
+                .iter()
+                .all(|package| package.path.starts_with(&format!("{}/zextras/", channel))));
+        }
+    }
+
+    #[test]
+    fn data_file_signatures_are_verified() {
+        let mut primary = vec![];
+        let mut encoder = GzEncoder::new(&mut primary, Compression::default());
+        encoder
+            .write_all(&fs::read("samples/redhat/primary.xml").unwrap())
+            .unwrap();
+        encoder.finish().unwrap();
+        let primary_path = "repodata/2e1eb1fb69a2ca7fbd6d8723ce7d3cd91e9a9f13-primary.xml.gz";
+        let other_path = "repodata/16b72c920dbd5d48e8aceb383b4b74664eb079ba-other.xml.gz";
+
+        let mut config = create_config();
+        config.source.public_pgp_key =
+            Some(fs::read_to_string("samples/signing-public-key").unwrap());
+        let public_key = config.source.parse_public_key().unwrap().unwrap();
+        let verify = |state: &MemoryRepoMetadataStore, path: &str| {
+            let repository = fetch_repository_internal(state, &config).unwrap();
+            let index = repository.collections[0]
+                .indexes
+                .iter()
+                .find(|index| index.path == path)
+                .unwrap()
+                .clone();
+            let mut reader = state.read(path).unwrap().unwrap();
+            index.signature.matches(&public_key, &[], &mut reader)
+        };
+
+        //other is signed, primary carries the signature of other
+        let mut state = MemoryRepoMetadataStore::from_samples(&[
+            ("repodata/repomd.xml", "samples/redhat/repomod.xml"),
+            (other_path, "samples/signed-data"),
+            (
+                &format!("{}.asc", other_path),
+                "samples/signed-data.sha256.asc",
+            ),
+            (
+                &format!("{}.asc", primary_path),
+                "samples/signed-data.sha256.asc",
+            ),
+        ]);
+        state.insert(primary_path, primary.clone());
+        assert_eq!(
+            Some(Signature::fingerprint(&public_key)),
+            verify(&state, other_path).unwrap()
+        );
+        assert!(verify(&state, primary_path).is_err());
+
+        //without a signature there is nothing to verify
+        let mut state = MemoryRepoMetadataStore::from_samples(&[
+            ("repodata/repomd.xml", "samples/redhat/repomod.xml"),
+            (other_path, "samples/signed-data"),
+        ]);
+        state.insert(primary_path, primary);
+        assert_eq!(None, verify(&state, other_path).unwrap());
+        assert!(state.fetched().contains(&format!("{}.asc", other_path)));
+    }
 }
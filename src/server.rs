@@ -1,37 +1,241 @@
 use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use hyper::{Body, Method, Request, Response, StatusCode};
 use reposync_lib::server::MakeService;
 use reposync_lib::{
-    Api, HealthGetResponse, RepositoryRepoGetResponse, RepositoryRepoSyncPostResponse,
+    Api, HealthGetResponse, ReloadPostResponse, RepositoryRepoGetResponse,
+    RepositoryRepoSyncPostResponse,
 };
 use std::marker::PhantomData;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use swagger::auth::MakeAllowAllAuthenticator;
 use swagger::ApiError;
 use swagger::EmptyContext;
 use swagger::{Has, XSpanIdString};
+use tokio::io::AsyncReadExt;
+use tokio::net::UnixListener;
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::sync::SyncManager;
-use reposync_lib::models::Status;
+use crate::config::{load_config, Config};
+use crate::destination::validate_path;
+use crate::error::SyncError;
+use crate::sync::{SyncManager, SyncProgress};
+use reposync_lib::models::{Health, PathStatus, Status};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub async fn create(sync_manager: SyncManager, addr: &str) -> hyper::Result<()> {
-    let addr = addr.parse().expect("Failed to parse bind address");
-    let server = Server::new(sync_manager);
+pub async fn create(sync_manager: SyncManager, addr: &str, config_path: &str) -> hyper::Result<()> {
+    let server = Server::new(sync_manager, config_path);
     server.start_scheduler();
+    let sync_manager = server.sync_manager.clone();
 
     let service = MakeService::new(server);
     let service = MakeAllowAllAuthenticator::new(service, "cosmo");
     let service = reposync_lib::server::context::MakeAddContext::<_, EmptyContext>::new(service);
+    let service = MakeServeFiles {
+        inner: service,
+        sync_manager,
+    };
 
-    let server_future = hyper::server::Server::bind(&addr).serve(service);
-    println!("starting http server, listening on  {}", &addr);
-    server_future.await
+    if let Some(path) = addr.strip_prefix("unix:") {
+        remove_socket(path);
+        let mut listener = UnixListener::bind(path).expect("Failed to bind unix socket");
+        let server_future =
+            hyper::server::Server::builder(hyper::server::accept::from_stream(listener.incoming()))
+                .serve(service)
+                .with_graceful_shutdown(shutdown_signal());
+        println!("starting http server, listening on {}", addr);
+        let result = server_future.await;
+        remove_socket(path);
+        result
+    } else {
+        let addr = addr.parse().expect("Failed to parse bind address");
+        let server_future = hyper::server::Server::bind(&addr).serve(service);
+        println!("starting http server, listening on  {}", &addr);
+        server_future.await
+    }
+}
+
+///removes the socket file, refusing to touch anything which is not a socket
+fn remove_socket(path: &str) {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            if let Err(err) = std::fs::remove_file(path) {
+                println!("cannot remove socket {}: {}", path, err);
+            }
+        }
+    }
+}
+
+///completes on either SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("cannot listen to SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("cannot listen to SIGINT");
+    futures::future::select(terminate.recv().boxed(), interrupt.recv().boxed()).await;
+    println!("shutting down http server");
+}
+
+///serves the local destinations with a serve_prefix, any other request goes to the apis
+struct MakeServeFiles<T> {
+    inner: T,
+    sync_manager: Arc<SyncManager>,
+}
+
+impl<T, Target> hyper::service::Service<Target> for MakeServeFiles<T>
+where
+    T: hyper::service::Service<Target>,
+    T::Future: Send + 'static,
+{
+    type Response = ServeFiles<T::Response>;
+    type Error = T::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: Target) -> Self::Future {
+        let sync_manager = self.sync_manager.clone();
+        Box::pin(self.inner.call(target).map(|inner| {
+            Ok(ServeFiles {
+                inner: inner?,
+                sync_manager,
+            })
+        }))
+    }
+}
+
+struct ServeFiles<T> {
+    inner: T,
+    sync_manager: Arc<SyncManager>,
+}
+
+impl<T> hyper::service::Service<Request<Body>> for ServeFiles<T>
+where
+    T: hyper::service::Service<Request<Body>, Response = Response<Body>>,
+    T::Error: 'static,
+    T::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = T::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        if request.method() == Method::GET || request.method() == Method::HEAD {
+            //read on every request, so a reload applies to prefixes too
+            let config = self.sync_manager.config();
+            if let Some(served) = served_file(&config, request.uri().path()) {
+                let head = request.method() == Method::HEAD;
+                return Box::pin(serve_file(served, head).map(Ok));
+            }
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+///maps a request path to a file of a local destination, None when no serve_prefix matches
+fn served_file(config: &Config, request_path: &str) -> Option<Result<PathBuf, ()>> {
+    for repo in &config.repo {
+        let local = match &repo.destination.local {
+            Some(local) => local,
+            None => continue,
+        };
+        let serve_prefix = match &local.serve_prefix {
+            Some(serve_prefix) => serve_prefix,
+            None => continue,
+        };
+        let path = match request_path.strip_prefix(serve_prefix.as_str()) {
+            Some(path) if path.is_empty() || path.starts_with('/') => path,
+            _ => continue,
+        };
+        let path = match percent_encoding::percent_decode_str(path).decode_utf8() {
+            Ok(path) => path.trim_start_matches('/').to_string(),
+            Err(_) => return Some(Err(())),
+        };
+        if path.is_empty() || validate_path(&path).is_err() {
+            return Some(Err(()));
+        }
+        return Some(Ok(PathBuf::from(format!("{}/{}", local.path, path))));
+    }
+    None
+}
+
+///streams the file, directories and invalid paths are not found
+async fn serve_file(path: Result<PathBuf, ()>, head: bool) -> Response<Body> {
+    let path = match path {
+        Ok(path) => path,
+        Err(()) => return status_response(StatusCode::NOT_FOUND),
+    };
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return status_response(StatusCode::NOT_FOUND)
+        }
+        Err(err) => {
+            println!("cannot serve {}: {}", path.display(), err);
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let length = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        Ok(_) => return status_response(StatusCode::NOT_FOUND),
+        Err(err) => {
+            println!("cannot serve {}: {}", path.display(), err);
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let body = if head {
+        Body::empty()
+    } else {
+        Body::wrap_stream(futures::stream::unfold(file, |mut file| async move {
+            let mut buffer = vec![0u8; 64 * 1024];
+            match file.read(&mut buffer).await {
+                Ok(0) => None,
+                Ok(size) => {
+                    buffer.truncate(size);
+                    Some((Ok(buffer), file))
+                }
+                Err(err) => Some((Err(err), file)),
+            }
+        }))
+    };
+    Response::builder()
+        .header(hyper::header::CONTENT_LENGTH, length)
+        .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+        .body(body)
+        .unwrap()
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
 }
 
 #[derive(Clone)]
 pub struct Server<C> {
     marker: PhantomData<C>,
     sync_manager: Arc<SyncManager>,
+    //read again on reload
+    config_path: String,
+}
+
+fn load(
+    progress: &Option<Arc<SyncProgress>>,
+    counter: fn(&SyncProgress) -> &AtomicU64,
+) -> Option<i64> {
+    progress
+        .as_ref()
+        .map(|progress| counter(progress).load(Ordering::SeqCst) as i64)
 }
 
 fn normalize(system_time: &SystemTime) -> i64 {
@@ -39,24 +243,32 @@ fn normalize(system_time: &SystemTime) -> i64 {
 }
 
 impl<C> Server<C> {
-    pub fn new(sync_manager: SyncManager) -> Self {
+    pub fn new(sync_manager: SyncManager, config_path: &str) -> Self {
         Server {
             marker: PhantomData,
             sync_manager: Arc::new(sync_manager),
+            config_path: config_path.into(),
         }
     }
 
+    ///the http server keeps running without the scheduler, so the health reports it
     pub fn start_scheduler(&self) {
-        SyncManager::start_scheduler(self.sync_manager.clone());
+        if let Err(err) = SyncManager::start_scheduler(self.sync_manager.clone()) {
+            println!("cannot start the scheduler: {}", err);
+        }
     }
 
-    ///return None when repo is not found
-    fn get_repo_status(&self, repo_name: &str) -> Option<Status> {
+    ///fails with NotFound when repo is not configured, or why its state cannot be loaded
+    fn get_repo_status(&self, repo_name: &str) -> Result<Status, SyncError> {
         let result = self.sync_manager.load_current_by_name(&repo_name);
-        if let Ok(Some(result)) = result {
+        if let Err(err) = &result {
+            println!("cannot load status of {}: {}", repo_name, err);
+        }
+        if let Some(result) = result? {
             let (repo, _metadata) = result;
             if let Some(sync_state) = self.sync_manager.get_status(&repo.name) {
-                Some(Status {
+                let progress = sync_state.progress;
+                Ok(Status {
                     status: sync_state.current.to_string(),
                     next_sync: normalize(&sync_state.next_sync),
                     last_sync: normalize(&sync_state.last_sync),
@@ -64,12 +276,16 @@ impl<C> Server<C> {
                     name: repo.name.clone(),
                     size: repo.size() as i64,
                     packages: repo.count_packages() as isize,
+                    files_done: load(&progress, |x| &x.files_done),
+                    files_total: load(&progress, |x| &x.files_total),
+                    bytes_done: load(&progress, |x| &x.bytes_done),
+                    bytes_total: load(&progress, |x| &x.bytes_total),
                 })
             } else {
-                None
+                Err(SyncError::NotFound(repo_name.into()))
             }
         } else {
-            None
+            Err(SyncError::NotFound(repo_name.into()))
         }
     }
 }
@@ -81,11 +297,65 @@ where
 {
     /// Simple health-check
     async fn health_get(&self, _context: &C) -> Result<HealthGetResponse, ApiError> {
-        if let Err(err) = self.sync_manager.check_permissions() {
-            println!("health-check failed: {}", err.to_string());
-            Ok(HealthGetResponse::ServiceUnavailableWhenServiceHasKnownIssues)
+        let mut healthy = true;
+        let mut paths = vec![];
+        for (path, result) in self.sync_manager.check_paths() {
+            let status = match result {
+                Ok(()) => "ok".to_string(),
+                Err(err) => {
+                    println!("health-check failed: {}", err);
+                    healthy = false;
+                    err.to_string()
+                }
+            };
+            paths.push(PathStatus { path, status });
+        }
+        //reported only, the next sync of the repository replaces it
+        for (path, content) in self.sync_manager.stale_sync_markers() {
+            let status = format!(
+                "stale lock file of an interrupted sync: {}",
+                content.trim().replace('\n', ", ")
+            );
+            println!("health-check warning: {} {}", path, status);
+            paths.push(PathStatus { path, status });
+        }
+        let scheduler_alive = self.sync_manager.is_scheduler_alive();
+        if !scheduler_alive {
+            println!("health-check failed: scheduler is not running");
+            healthy = false;
+        }
+        let health = Health {
+            paths,
+            scheduler_alive,
+            last_tick: match self.sync_manager.last_tick() {
+                Some(last_tick) => normalize(&last_tick),
+                None => 0,
+            },
+        };
+        if healthy {
+            Ok(HealthGetResponse::EverythingIsOk(health))
         } else {
-            Ok(HealthGetResponse::EmptyResponseWhenEverythingIsOk)
+            Ok(HealthGetResponse::ServiceUnavailableWhenServiceHasKnownIssues(health))
+        }
+    }
+
+    /// Reload the configuration
+    async fn reload_post(&self, _context: &C) -> Result<ReloadPostResponse, ApiError> {
+        match load_config(&self.config_path) {
+            Ok(config) => match self.sync_manager.reload(config) {
+                Ok(names) => {
+                    println!("configuration reloaded from {}", &self.config_path);
+                    Ok(ReloadPostResponse::TheConfigurationHasBeenReloaded(names))
+                }
+                Err(err) => {
+                    println!("cannot reload configuration: {}", err);
+                    Ok(ReloadPostResponse::InvalidConfiguration(err.to_string()))
+                }
+            },
+            Err(err) => {
+                println!("cannot reload configuration: {}", err);
+                Ok(ReloadPostResponse::InvalidConfiguration(err))
+            }
         }
     }
 
@@ -95,10 +365,20 @@ where
         repo: String,
         _context: &C,
     ) -> Result<RepositoryRepoGetResponse, ApiError> {
-        if let Some(status) = self.get_repo_status(&repo) {
-            Ok(reposync_lib::RepositoryRepoGetResponse::TheStatusOfTheRepository { 0: status })
-        } else {
-            Ok(reposync_lib::RepositoryRepoGetResponse::RepositoryNotFound {})
+        match self.get_repo_status(&repo) {
+            Ok(status) => {
+                Ok(reposync_lib::RepositoryRepoGetResponse::TheStatusOfTheRepository { 0: status })
+            }
+            Err(SyncError::NotFound(_)) => {
+                Ok(reposync_lib::RepositoryRepoGetResponse::RepositoryNotFound {})
+            }
+            Err(SyncError::Busy(_)) => Ok(
+                reposync_lib::RepositoryRepoGetResponse::TheRepositoryIsLockedByASynchronization {},
+            ),
+            Err(SyncError::Refused(reason)) => {
+                Ok(reposync_lib::RepositoryRepoGetResponse::TheRequestHasBeenRefused(reason))
+            }
+            Err(err) => Err(ApiError(err.to_string())),
         }
     }
 
@@ -109,14 +389,87 @@ where
         _context: &C,
     ) -> Result<RepositoryRepoSyncPostResponse, ApiError> {
         self.sync_manager.queue_sync(&repo);
-        if let Some(status) = self.get_repo_status(&repo) {
-            Ok(
+        match self.get_repo_status(&repo) {
+            Ok(status) => Ok(
                 reposync_lib::RepositoryRepoSyncPostResponse::TheSynchronizationHasBeenQueuedCorrectly {
                     0: status,
                 },
-            )
-        } else {
-            Ok(reposync_lib::RepositoryRepoSyncPostResponse::RepositoryNotFound {})
+            ),
+            Err(SyncError::NotFound(_)) => {
+                Ok(reposync_lib::RepositoryRepoSyncPostResponse::RepositoryNotFound {})
+            }
+            Err(SyncError::Busy(_)) => Ok(
+                reposync_lib::RepositoryRepoSyncPostResponse::TheRepositoryIsLockedByASynchronization {},
+            ),
+            Err(SyncError::Refused(reason)) => Ok(
+                reposync_lib::RepositoryRepoSyncPostResponse::TheRequestHasBeenRefused(reason),
+            ),
+            Err(err) => Err(ApiError(err.to_string())),
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use crate::config::LocalDestination;
+    use crate::server::{serve_file, served_file};
+    use crate::sync::tests::create_config;
+    use hyper::StatusCode;
+    use std::path::PathBuf;
+
+    #[tokio::test]
+    async fn serve_local_destination() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mirror_path = format!("{}/mirror", tmp_dir.path().to_str().unwrap());
+        std::fs::create_dir_all(format!("{}/pool/main", &mirror_path)).unwrap();
+        std::fs::write(
+            format!("{}/pool/main/libfoo_1.0+dfsg.deb", &mirror_path),
+            "deb",
+        )
+        .unwrap();
+
+        let mut config = create_config(&tmp_dir);
+        assert!(served_file(&config, "/ubuntu/pool/main/libfoo_1.0+dfsg.deb").is_none());
+        config.repo[0].destination.local = Some(LocalDestination {
+            path: mirror_path.clone(),
+            gzip_indexes: false,
+            verify_uploads: false,
+            serve_prefix: Some("/ubuntu".into()),
+        });
+
+        let file = PathBuf::from(format!("{}/pool/main/libfoo_1.0+dfsg.deb", &mirror_path));
+        assert_eq!(
+            Some(Ok(file.clone())),
+            served_file(&config, "/ubuntu/pool/main/libfoo_1.0%2Bdfsg.deb")
+        );
+        assert_eq!(None, served_file(&config, "/ubuntu-other/pool"));
+        assert_eq!(None, served_file(&config, "/health"));
+        assert_eq!(
+            Some(Err(())),
+            served_file(&config, "/ubuntu/pool/../../secret")
+        );
+        assert_eq!(Some(Err(())), served_file(&config, "/ubuntu/%2E%2E/secret"));
+
+        let response = serve_file(Ok(file.clone()), false).await;
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("3", response.headers()["content-length"]);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&b"deb"[..], &body[..]);
+
+        let response = serve_file(Ok(file), true).await;
+        assert_eq!("3", response.headers()["content-length"]);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.is_empty());
+
+        let directory = PathBuf::from(format!("{}/pool", &mirror_path));
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            serve_file(Ok(directory), false).await.status()
+        );
+        let missing = PathBuf::from(format!("{}/missing", &mirror_path));
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            serve_file(Ok(missing), false).await.status()
+        );
+    }
+}
@@ -1,13 +1,60 @@
+use crate::error::SyncError;
 use crate::fetcher::Fetcher;
+use crate::utils::human_println;
 use data_encoding::BASE32_NOPAD;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::fs;
 use std::fs::File;
-use std::io::{Error, ErrorKind, Read};
-use std::rc::Rc;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//metadata is stored gzip compressed, states saved before are still readable
+const COMPRESSED_SUFFIX: &str = ".gz";
+
+pub fn is_compressed(file_path: &str) -> bool {
+    file_path.ends_with(COMPRESSED_SUFFIX)
+}
+
+///reads a stored metadata file, decompressing it when needed
+pub fn open_stored(file_path: &str) -> Result<Box<dyn Read>, std::io::Error> {
+    let file = File::open(file_path)?;
+    if is_compressed(file_path) {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+///uncompressed size from the gzip trailer, metadata files are far below its 4GB limit
+fn uncompressed_size(file: &mut File) -> Result<u64, std::io::Error> {
+    file.seek(SeekFrom::End(-4))?;
+    let mut trailer = [0u8; 4];
+    file.read_exact(&mut trailer)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(u32::from_le_bytes(trailer) as u64)
+}
 
 pub trait RepoMetadataStore {
-    fn fetch(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), std::io::Error>;
-    fn read(&self, path: &str) -> Result<Option<Box<dyn Read>>, std::io::Error>;
+    fn fetch(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), SyncError>;
+    fn read(&self, path: &str) -> Result<Option<Box<dyn Read>>, SyncError>;
+
+    ///like fetch, for the file every other metadata file is found from
+    fn fetch_entry_point(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), SyncError> {
+        self.fetch(path)
+    }
+}
+
+///other urls a mirror may serve a missing entry point at, e.g. a metadata_path with the wrong case,
+///only the entry point itself changes, so the files it lists are still found next to it
+fn entry_point_fallbacks(repo_base_url: &str, path: &str) -> Vec<String> {
+    let url = format!("{}/{}", repo_base_url, path);
+    let mut fallbacks = vec![format!("{}/{}", repo_base_url, path.to_lowercase())];
+    fallbacks.retain(|x| *x != url);
+    fallbacks
 }
 
 pub struct SavedRepoMetadataStore {
@@ -23,15 +70,25 @@ impl SavedRepoMetadataStore {
 }
 
 impl RepoMetadataStore for SavedRepoMetadataStore {
-    fn fetch(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), Error> {
+    fn fetch(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), SyncError> {
         let base32 = BASE32_NOPAD.encode(path.as_bytes());
-        let file_path = format!("{}/{}", self.directory, base32);
-        let file = File::open(&file_path)?;
-        let size = file.metadata()?.len();
-        Ok((file_path, Box::new(Box::new(file)), size))
+        let file_path = format!("{}/{}{}", self.directory, base32, COMPRESSED_SUFFIX);
+        match File::open(&file_path) {
+            Ok(mut file) => {
+                let size = uncompressed_size(&mut file)?;
+                Ok((file_path, Box::new(GzDecoder::new(file)), size))
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let file_path = format!("{}/{}", self.directory, base32);
+                let file = File::open(&file_path)?;
+                let size = file.metadata()?.len();
+                Ok((file_path, Box::new(file), size))
+            }
+            Err(err) => Err(err.into()),
+        }
     }
 
-    fn read(&self, path: &str) -> Result<Option<Box<dyn Read>>, std::io::Error> {
+    fn read(&self, path: &str) -> Result<Option<Box<dyn Read>>, SyncError> {
         let (_, reader, _) = self.fetch(path)?;
         Ok(Some(reader))
     }
@@ -40,14 +97,14 @@ impl RepoMetadataStore for SavedRepoMetadataStore {
 pub struct LiveRepoMetadataStore {
     repo_base_url: String,
     tmp_directory: String,
-    fetcher: Rc<dyn Fetcher>,
+    fetcher: Arc<dyn Fetcher>,
 }
 
 impl LiveRepoMetadataStore {
     pub fn new(
         repo_base_url: &str,
         tmp_directory: &str,
-        fetcher: Rc<dyn Fetcher>,
+        fetcher: Arc<dyn Fetcher>,
     ) -> Result<Self, std::io::Error> {
         //just a safeguard in case something goes wrong
         //the directory should be {repo_name}_tmp
@@ -65,62 +122,367 @@ impl LiveRepoMetadataStore {
         })
     }
 
-    pub fn replace(&self, path: &str) -> Result<(), std::io::Error> {
+    ///the replaced state is kept as a generation, up to generations - 1 of them
+    pub fn replace(&self, path: &str, generations: usize) -> Result<(), std::io::Error> {
         let tmp_dir = &format!("{}__", path);
         let existed = File::open(path).is_ok();
         if existed {
             std::fs::rename(&path, tmp_dir)?;
         }
-        std::fs::rename(&self.tmp_directory, path)?;
+        if let Err(err) = move_directory(&self.tmp_directory, path) {
+            //keep the current state
+            if existed {
+                std::fs::rename(tmp_dir, path)?;
+            }
+            return Err(err);
+        }
         if existed {
-            std::fs::remove_dir_all(tmp_dir)?;
+            if generations > 1 {
+                keep_generation(tmp_dir, path)?;
+            } else {
+                std::fs::remove_dir_all(tmp_dir)?;
+            }
         }
 
+        let saved = list_generations(path)?;
+        let excess = saved.len().saturating_sub(generations.saturating_sub(1));
+        for generation in &saved[..excess] {
+            fs::remove_dir_all(format!("{}/{}", generations_path(path), generation))?;
+        }
         Ok(())
     }
 }
 
-impl RepoMetadataStore for LiveRepoMetadataStore {
-    fn fetch(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), std::io::Error> {
+///renames from to to, copying it when they are on different filesystems, e.g. when the
+///repository tmp_path is on another disk than data_path
+fn move_directory(from: &str, to: &str) -> Result<(), std::io::Error> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => move_across(from, to),
+        result => result,
+    }
+}
+
+fn move_across(from: &str, to: &str) -> Result<(), std::io::Error> {
+    //copied next to the target first, so the target is replaced at once
+    let copy_path = format!("{}_copy", to);
+    let _ = fs::remove_dir_all(&copy_path);
+    if let Err(err) = copy_directory(Path::new(from), Path::new(&copy_path)) {
+        let _ = fs::remove_dir_all(&copy_path);
+        return Err(std::io::Error::new(
+            err.kind(),
+            format!(
+                "cannot move '{}' to '{}', which is on another filesystem: {}",
+                from, to, err
+            ),
+        ));
+    }
+    fs::rename(&copy_path, to)?;
+    fs::remove_dir_all(from)
+}
+
+fn copy_directory(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_directory(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+///moves the state in from to the generations of path, named by the current time
+pub fn keep_generation(from: &str, path: &str) -> Result<(), std::io::Error> {
+    let generations_path = generations_path(path);
+    fs::create_dir_all(&generations_path)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    fs::rename(from, format!("{}/{}", generations_path, millis))
+}
+
+pub fn generations_path(path: &str) -> String {
+    format!("{}.generations", path)
+}
+
+///previous states of the state in path, named by their replacement time, oldest first
+pub fn list_generations(path: &str) -> Result<Vec<String>, std::io::Error> {
+    let entries = match fs::read_dir(generations_path(path)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut generations: Vec<u128> = vec![];
+    for entry in entries {
+        if let Some(millis) = entry?.file_name().to_str().and_then(|x| x.parse().ok()) {
+            generations.push(millis);
+        }
+    }
+    generations.sort_unstable();
+    Ok(generations.iter().map(|x| x.to_string()).collect())
+}
+
+impl LiveRepoMetadataStore {
+    ///stores the file at url as path
+    fn fetch_url(&self, path: &str, url: &str) -> Result<(String, Box<dyn Read>, u64), SyncError> {
         let base32 = BASE32_NOPAD.encode(path.as_bytes());
-        let file_path = format!("{}/{}", self.tmp_directory, base32);
+        let file_path = format!("{}/{}{}", self.tmp_directory, base32, COMPRESSED_SUFFIX);
 
         std::fs::create_dir_all(&self.tmp_directory)?;
 
-        let fetch_result = self
+        let mut reader = self
             .fetcher
-            .fetch(&format!("{}/{}", &self.repo_base_url, path));
-
-        if fetch_result.is_err() {
-            let err = fetch_result.err().unwrap();
-            if err.code == 404 {
-                return Err(std::io::Error::new(
-                    ErrorKind::NotFound,
-                    format!("file not found '{}'", path),
-                ));
-            }
-            return Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("cannot fetch file '{}': {}", path, err.error),
-            ));
-        }
-
-        let mut reader = fetch_result.unwrap();
-        let mut output = File::create(&file_path)?;
+            .fetch(url)
+            .map_err(|err| SyncError::fetch(path, err))?;
+        let mut output = GzEncoder::new(File::create(&file_path)?, Compression::default());
         let size = std::io::copy(&mut reader, &mut output)?;
-        let file_reader =
-            Box::new(File::open(&file_path).expect("cannot open a just created file"));
+        output.finish()?;
+        let file_reader = open_stored(&file_path).expect("cannot open a just created file");
 
         Ok((file_path, file_reader, size))
     }
+}
 
-    fn read(&self, path: &str) -> Result<Option<Box<dyn Read>>, std::io::Error> {
+impl RepoMetadataStore for LiveRepoMetadataStore {
+    fn fetch(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), SyncError> {
+        self.fetch_url(path, &format!("{}/{}", &self.repo_base_url, path))
+    }
+
+    fn fetch_entry_point(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), SyncError> {
+        let result = self.fetch(path);
+        match &result {
+            Err(err) if err.is_not_found() => {}
+            _ => return result,
+        }
+        for url in entry_point_fallbacks(&self.repo_base_url, path) {
+            match self.fetch_url(path, &url) {
+                Ok(fetched) => {
+                    human_println!("{} not found, fetched it from {} instead", path, url);
+                    return Ok(fetched);
+                }
+                Err(err) if err.is_not_found() => {}
+                Err(err) => return Err(err),
+            }
+        }
+        result
+    }
+
+    fn read(&self, path: &str) -> Result<Option<Box<dyn Read>>, SyncError> {
         let base32 = BASE32_NOPAD.encode(path.as_bytes());
-        let file = File::open(&format!("{}/{}", self.tmp_directory, base32));
-        if let Ok(file) = file {
-            Ok(Some(Box::new(file)))
+        let file_path = format!("{}/{}{}", self.tmp_directory, base32, COMPRESSED_SUFFIX);
+        if let Ok(reader) = open_stored(&file_path) {
+            Ok(Some(reader))
         } else {
             Ok(None)
         }
     }
 }
+
+///keeps metadata files in memory, for tests which don't need a fetcher nor a tmp directory
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryRepoMetadataStore {
+    files: std::collections::BTreeMap<String, Vec<u8>>,
+    //paths in fetch order, only those can be read back
+    fetched: std::sync::Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl MemoryRepoMetadataStore {
+    pub fn new() -> Self {
+        MemoryRepoMetadataStore {
+            files: std::collections::BTreeMap::new(),
+            fetched: std::sync::Mutex::new(vec![]),
+        }
+    }
+
+    ///every fetched path, relative to the endpoint, including the failed fetches
+    pub fn fetched(&self) -> Vec<String> {
+        self.fetched.lock().unwrap().clone()
+    }
+
+    pub fn insert(&mut self, path: &str, content: Vec<u8>) {
+        self.files.insert(path.into(), content);
+    }
+
+    ///reads every (path, sample file) pair from disk
+    pub fn from_samples(samples: &[(&str, &str)]) -> Self {
+        let mut store = Self::new();
+        for (path, sample) in samples {
+            store.insert(path, fs::read(sample).unwrap());
+        }
+        store
+    }
+}
+
+#[cfg(test)]
+impl RepoMetadataStore for MemoryRepoMetadataStore {
+    fn fetch(&self, path: &str) -> Result<(String, Box<dyn Read>, u64), SyncError> {
+        self.fetched.lock().unwrap().push(path.into());
+        match self.files.get(path) {
+            Some(content) => Ok((
+                format!("memory://{}", path),
+                Box::new(std::io::Cursor::new(content.clone())),
+                content.len() as u64,
+            )),
+            None => Err(SyncError::Io(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found", path),
+            ))),
+        }
+    }
+
+    fn read(&self, path: &str) -> Result<Option<Box<dyn Read>>, SyncError> {
+        if !self.fetched.lock().unwrap().iter().any(|x| x == path) {
+            return Ok(None);
+        }
+        Ok(self
+            .files
+            .get(path)
+            .map(|content| Box::new(std::io::Cursor::new(content.clone())) as Box<dyn Read>))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::fetcher::{FetchError, MockFetcher};
+    use crate::state::{
+        entry_point_fallbacks, is_compressed, move_across, LiveRepoMetadataStore,
+        RepoMetadataStore, SavedRepoMetadataStore,
+    };
+    use data_encoding::BASE32_NOPAD;
+    use mockall::predicate;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Read;
+    use std::sync::Arc;
+
+    #[test]
+    fn compressed_state_round_trip() {
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .with(predicate::eq("http://fake-url/rc/dists/focal/Packages"))
+            .returning(|_| Result::Ok(Box::new(File::open("samples/debian/Packages").unwrap())));
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let data_path = format!("{}/repo", tmp_dir.path().to_str().unwrap());
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            &format!("{}/repo_tmp", tmp_dir.path().to_str().unwrap()),
+            Arc::new(mock_fetcher),
+        )
+        .unwrap();
+        let original = fs::read("samples/debian/Packages").unwrap();
+
+        let (file_path, _, size) = state.fetch("dists/focal/Packages").unwrap();
+        assert!(is_compressed(&file_path));
+        assert!(fs::metadata(&file_path).unwrap().len() < original.len() as u64);
+        assert_eq!(original.len() as u64, size);
+        state.replace(&data_path, 1).unwrap();
+
+        let saved = SavedRepoMetadataStore::new(&data_path);
+        let (_, mut reader, size) = saved.fetch("dists/focal/Packages").unwrap();
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(original, content);
+        assert_eq!(original.len() as u64, size);
+
+        //states saved uncompressed are still readable
+        let base32 = BASE32_NOPAD.encode("dists/focal/Release".as_bytes());
+        fs::copy(
+            "samples/debian/Release",
+            format!("{}/{}", data_path, base32),
+        )
+        .unwrap();
+        let (file_path, mut reader, size) = saved.fetch("dists/focal/Release").unwrap();
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+        assert!(!is_compressed(&file_path));
+        assert_eq!(fs::read("samples/debian/Release").unwrap(), content);
+        assert_eq!(content.len() as u64, size);
+    }
+
+    #[test]
+    fn entry_point_falls_back_to_normalized_urls() {
+        assert_eq!(
+            vec!["http://fake-url/rc/repodata/repomd.xml"],
+            entry_point_fallbacks("http://fake-url/rc", "repodata/REPOMD.xml")
+        );
+        //a duplicated slash in the endpoint would break every other request too
+        assert!(entry_point_fallbacks("http://fake-url//rc", "repodata/repomd.xml").is_empty());
+        assert!(entry_point_fallbacks("http://fake-url/rc", "repodata/repomd.xml").is_empty());
+
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher.expect_fetch().returning(|url| match url {
+            "http://fake-url/rc/repodata/repomd.xml" => {
+                Result::Ok(Box::new(File::open("samples/redhat/repomod.xml").unwrap()))
+            }
+            "http://fake-url/rc/broken/repomd.xml" => Err(FetchError {
+                code: 500,
+                error: "internal server error".into(),
+            }),
+            _ => Err(FetchError {
+                code: 404,
+                error: "not found".into(),
+            }),
+        });
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let state = LiveRepoMetadataStore::new(
+            "http://fake-url/rc",
+            &format!("{}/repo_tmp", tmp_dir.path().to_str().unwrap()),
+            Arc::new(mock_fetcher),
+        )
+        .unwrap();
+
+        let (_, mut reader, _) = state.fetch_entry_point("repodata/REPOMD.xml").unwrap();
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(fs::read("samples/redhat/repomod.xml").unwrap(), content);
+        //stored with the requested path
+        assert!(state.read("repodata/REPOMD.xml").unwrap().is_some());
+
+        assert!(state
+            .fetch("repodata/REPOMD.xml")
+            .err()
+            .unwrap()
+            .is_not_found());
+        assert!(state
+            .fetch_entry_point("missing.xml")
+            .err()
+            .unwrap()
+            .is_not_found());
+        assert!(!state
+            .fetch_entry_point("broken/REPOMD.xml")
+            .err()
+            .unwrap()
+            .is_not_found());
+    }
+
+    #[test]
+    fn state_is_copied_across_filesystems() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let from = format!("{}/repo_tmp", tmp_dir.path().to_str().unwrap());
+        let to = format!("{}/repo", tmp_dir.path().to_str().unwrap());
+        fs::create_dir_all(format!("{}/nested", from)).unwrap();
+        fs::write(format!("{}/file", from), "first").unwrap();
+        fs::write(format!("{}/nested/file", from), "second").unwrap();
+
+        move_across(&from, &to).unwrap();
+        assert_eq!("first", fs::read_to_string(format!("{}/file", to)).unwrap());
+        assert_eq!(
+            "second",
+            fs::read_to_string(format!("{}/nested/file", to)).unwrap()
+        );
+        assert!(File::open(&from).is_err());
+        assert!(File::open(format!("{}_copy", to)).is_err());
+
+        //nothing is left behind when the copy fails
+        let err = move_across(&from, &format!("{}/other", to)).err().unwrap();
+        assert!(err.to_string().contains("on another filesystem"));
+        assert!(File::open(format!("{}/other_copy", to)).is_err());
+    }
+}
@@ -1,24 +1,42 @@
-use crate::config::{Config, RepositoryConfig};
-use crate::destination::{create_destination, Destination};
+use crate::config::{Config, GeneralConfig, RepositoryConfig, SourceConfig};
+use crate::destination::{
+    create_destination, Destination, EventDestination, InvalidationsInProgress, PrefixDestination,
+    StripPrefixDestination,
+};
+use crate::error::SyncError;
 use crate::fetcher::Fetcher;
+use crate::locks;
 use crate::locks::Lock;
 use crate::packages::{Collection, Hash, IndexFile, Package, Repository};
-use crate::state::SavedRepoMetadataStore;
+use crate::state;
+use crate::state::{RepoMetadataStore, SavedRepoMetadataStore};
+use crate::utils;
+use crate::utils::human_println;
 use crate::{debian, fetcher, redhat};
 use core::fmt;
+use flate2::read::GzDecoder;
 #[cfg(test)]
 use mockall::automock;
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::CString;
 use std::fmt::Formatter;
 use std::fs::File;
 use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
 use std::ops::{Add, Sub};
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, SystemTime};
 
+//below this size starting a reading thread costs more than it saves
+const PARALLEL_HASHING_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
 /*
 Steps:
  - lock repository
@@ -29,6 +47,7 @@ Steps:
  - execute "copy" operations for packages
  -- BEGIN POSSIBLE INCONSISTENCIES
  - execute "copy" operations for metadata
+ - optionally read back the top level metadata and check its hash
  - send CDN cache invalidation
  -- END POSSIBLE INCONSISTENCIES
  - execute "delete" operations for packages and metadata of all collection
@@ -43,11 +62,256 @@ struct CopyOperation {
     hash: Hash,
     size: u64,
     local_file: Option<String>,
+    //of packages, empty for indexes
+    architecture: String,
 }
 
 #[derive(Clone, Eq, PartialEq)]
 struct DeleteOperation {
     path: String,
+    architecture: String,
+    size: u64,
+}
+
+///Release or repomd.xml files, every other index is found from them
+fn entry_points(repo_config: &RepositoryConfig) -> Vec<String> {
+    match repo_config.source.kind.as_str() {
+        "debian" | "debian-flat" => debian::entry_points(repo_config),
+        "redhat" => redhat::entry_points(repo_config),
+        _ => panic!("unknown repo of type {}", &repo_config.source.kind),
+    }
+}
+
+///http client settings of a repository
+fn client_options(
+    general: &GeneralConfig,
+    repo_config: &RepositoryConfig,
+) -> fetcher::ClientOptions {
+    fetcher::ClientOptions {
+        timeout: Duration::from_secs(general.timeout as u64),
+        connect_timeout: general
+            .connect_timeout
+            .map(|connect_timeout| Duration::from_secs(connect_timeout as u64)),
+        http2_prior_knowledge: general.http2_prior_knowledge,
+        pool_idle_timeout: general.pool_idle_timeout.map(Duration::from_secs),
+        pool_max_idle_per_host: general.pool_max_idle_per_host,
+        headers: repo_config.source.headers.clone(),
+    }
+}
+
+///where the download of url is resumed from, the file name stays unique and short enough for
+///any filesystem, whatever the length of the url
+fn partial_path(tmp_path: &str, url: &str) -> String {
+    let file_name: String = url
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .replace(|c: char| !c.is_ascii_alphanumeric() && c != '.', "_")
+        .chars()
+        .take(64)
+        .collect();
+    let digest = format!("{:x}", Sha256::digest(url.as_bytes()));
+    format!("{}/{}.{}.partial", tmp_path, file_name, &digest[..16])
+}
+
+///fails when the filesystem of path has fewer free inodes than min_free_inodes, filesystems
+///allocating inodes dynamically report none and are not checked
+fn check_free_inodes(path: &str, min_free_inodes: u64) -> Result<(), Error> {
+    let c_path = CString::new(path).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    let free_inodes = stat.f_favail as u64;
+    if stat.f_files > 0 && free_inodes < min_free_inodes {
+        return Err(std::io::Error::other(format!(
+            "only {} free inodes left on '{}', below min_free_inodes {}, though there may be free space",
+            free_inodes, path, min_free_inodes
+        )));
+    }
+    Ok(())
+}
+
+///where a file is stored when the destination path ends with {arch}: packages under a
+///directory per architecture, indexes and other files at the root
+fn arch_path(path: &str, architecture: &str, arch_prefix: bool) -> String {
+    if arch_prefix && !architecture.is_empty() {
+        format!("{}/{}", architecture, path)
+    } else {
+        path.into()
+    }
+}
+
+///what has actually been transferred by a copy
+#[derive(Default)]
+struct CopyResult {
+    files: usize,
+    bytes: u64,
+    invalidations: Vec<String>,
+    //path and reason of every skipped file, only in best effort mode
+    failures: Vec<(String, String)>,
+}
+
+///how a copy handles every file
+struct CopyOptions<'a> {
+    //skip failing files instead of aborting
+    best_effort: bool,
+    verify_size: bool,
+    parallel_hashing: bool,
+    //downloads failing validation are fetched again, they are often transient
+    copy_retries: u32,
+    retry_sleep: Duration,
+    //packages are stored under their architecture
+    arch_prefix: bool,
+    progress: &'a SyncProgress,
+}
+
+impl CopyResult {
+    fn append(&mut self, other: &mut CopyResult) {
+        self.files += other.files;
+        self.bytes += other.bytes;
+        self.invalidations.append(&mut other.invalidations);
+        self.failures.append(&mut other.failures);
+    }
+}
+
+///removes duplicates, optionally replacing indexes with one wildcard per top-most directory,
+///e.g. 'repodata/*' instead of every file in repodata, files at the root are kept as they are
+fn invalidation_paths(
+    packages: Vec<String>,
+    indexes: Vec<String>,
+    wildcard_indexes: bool,
+) -> Vec<String> {
+    let indexes = if wildcard_indexes {
+        let (nested, root): (Vec<&String>, Vec<&String>) =
+            indexes.iter().partition(|path| path.contains('/'));
+        let mut directories: Vec<&str> = nested
+            .iter()
+            .map(|path| &path[..path.rfind('/').unwrap()])
+            .collect();
+        directories.sort_unstable();
+        let mut top_directories: Vec<&str> = vec![];
+        for directory in directories {
+            //a wildcard matches nested directories as well
+            if !top_directories
+                .iter()
+                .any(|x| *x == directory || directory.starts_with(&format!("{}/", x)))
+            {
+                top_directories.push(directory);
+            }
+        }
+        top_directories
+            .iter()
+            .map(|directory| format!("{}/*", directory))
+            .chain(root.into_iter().cloned())
+            .collect()
+    } else {
+        indexes
+    };
+
+    let mut seen = HashSet::new();
+    packages
+        .into_iter()
+        .chain(indexes)
+        .filter(|path| seen.insert(path.clone()))
+        .collect()
+}
+
+///outcome of a completed synchronization
+#[derive(Default)]
+pub struct SyncReport {
+    ///packages skipped in best effort mode, formatted as 'path: reason'
+    pub failures: Vec<String>,
+    ///metadata files downloaded from upstream
+    pub metadata_fetched: usize,
+    ///metadata files identical to the last synchronization, which are not published again
+    pub metadata_unchanged: usize,
+    ///planned operations, only when printing the diff instead of synchronizing
+    pub plan: Option<SyncPlan>,
+    ///files copied to the destination and their total size
+    pub files_copied: usize,
+    pub bytes_copied: u64,
+}
+
+///what a synchronization would do, with paths as stored in the destination
+#[derive(Serialize)]
+pub struct SyncPlan {
+    pub repository: String,
+    pub copy: Vec<PlannedCopy>,
+    pub delete: Vec<String>,
+    ///sizes of the delete paths, in the same order, only printed by --print-deletes
+    #[serde(skip)]
+    pub delete_sizes: Vec<u64>,
+    pub invalidate: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct PlannedCopy {
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+    pub is_replace: bool,
+}
+
+impl SyncPlan {
+    fn new(
+        repo_config: &RepositoryConfig,
+        copy_lists: &[&Vec<CopyOperation>],
+        delete_lists: &[&Vec<DeleteOperation>],
+        invalidate: Vec<String>,
+    ) -> Self {
+        let source = &repo_config.source;
+        let arch_prefix = repo_config.destination.arch_prefix;
+        SyncPlan {
+            repository: repo_config.name.clone(),
+            copy: copy_lists
+                .iter()
+                .flat_map(|x| x.iter())
+                .map(|x| PlannedCopy {
+                    path: arch_path(
+                        source.destination_path(&x.path),
+                        &x.architecture,
+                        arch_prefix,
+                    ),
+                    size: x.size,
+                    hash: x.hash.to_string(),
+                    is_replace: x.is_replace,
+                })
+                .collect(),
+            delete: delete_lists
+                .iter()
+                .flat_map(|x| x.iter())
+                .map(|x| {
+                    arch_path(
+                        source.destination_path(&x.path),
+                        &x.architecture,
+                        arch_prefix,
+                    )
+                })
+                .collect(),
+            delete_sizes: delete_lists
+                .iter()
+                .flat_map(|x| x.iter())
+                .map(|x| x.size)
+                .collect(),
+            invalidate: invalidate
+                .iter()
+                .map(|x| source.destination_path(x).into())
+                .collect(),
+        }
+    }
+}
+
+///written after every synchronization
+#[derive(Serialize)]
+struct SyncReportFile<'a> {
+    repository: &'a str,
+    //milliseconds since the epoch
+    completed: u128,
+    result: String,
+    metadata_fetched: usize,
+    metadata_unchanged: usize,
+    failures: &'a [String],
 }
 
 #[derive(Clone)]
@@ -71,6 +335,44 @@ pub struct SyncStatus {
     pub next_sync: SystemTime,
     pub last_sync: SystemTime,
     pub last_result: Option<String>,
+    ///only while a synchronization is copying files
+    pub progress: Option<Arc<SyncProgress>>,
+}
+
+///counters of the files being copied, updated by the sync while the status api reads them
+#[derive(Default)]
+pub struct SyncProgress {
+    pub files_done: AtomicU64,
+    pub files_total: AtomicU64,
+    pub bytes_done: AtomicU64,
+    pub bytes_total: AtomicU64,
+}
+
+impl SyncProgress {
+    fn new(files_total: usize, bytes_total: u64) -> Self {
+        SyncProgress {
+            files_total: AtomicU64::new(files_total as u64),
+            bytes_total: AtomicU64::new(bytes_total),
+            ..Default::default()
+        }
+    }
+
+    ///a file was either copied or skipped
+    fn file_done(&self, size: u64) {
+        self.files_done.fetch_add(1, Ordering::SeqCst);
+        self.bytes_done.fetch_add(size, Ordering::SeqCst);
+    }
+}
+
+struct ProgressGuard<'a> {
+    sync_manager: &'a SyncManager,
+    repo_name: String,
+}
+
+impl Drop for ProgressGuard<'_> {
+    fn drop(&mut self) {
+        self.sync_manager.set_progress(&self.repo_name, None);
+    }
 }
 
 #[cfg_attr(test, automock)]
@@ -86,10 +388,21 @@ impl TimeProvider for RealTimeProvider {
 }
 
 pub struct SyncManager {
-    config: Config,
+    //replaced as a whole on reload, a running sync keeps the repository settings it started
+    //with while general ones, e.g. report_path, apply right away, so data_path cannot change
+    config: RwLock<Arc<Config>>,
     lock: Lock,
     time_provider: Arc<dyn TimeProvider>,
     sync_map: Arc<Mutex<BTreeMap<String, SyncStatus>>>,
+    allow_mass_delete: bool,
+    verbose: bool,
+    //plan the synchronization without performing anything
+    print_diff: bool,
+    //packages built before are left pending
+    since: Option<SystemTime>,
+    last_tick: Arc<Mutex<Option<SystemTime>>>,
+    //shared by every repository, cloudfront limits the invalidations in progress
+    invalidations: Arc<InvalidationsInProgress>,
 }
 
 impl SyncManager {
@@ -98,6 +411,14 @@ impl SyncManager {
     }
 
     fn new_internal(config: Config, lock: Lock, time_provider: Arc<dyn TimeProvider>) -> Self {
+        let max_delay_secs = config.general.max_sync_delay as u64 * 60;
+        //spread the first synchronizations, so all repositories are not due at the same time
+        let min_delay_secs = if config.general.initial_sync_jitter {
+            (config.general.min_sync_delay as u64 * 60).min(max_delay_secs)
+        } else {
+            max_delay_secs
+        };
+        let mut rng = rand::thread_rng();
         let mut map = BTreeMap::new();
         config.repo.iter().for_each(|r| {
             map.insert(
@@ -105,49 +426,199 @@ impl SyncManager {
                 SyncStatus {
                     current: RepoStatus::Waiting,
                     next_sync: time_provider.now().add(Duration::from_secs(
-                        config.general.max_sync_delay as u64 * 60,
+                        rng.gen_range(min_delay_secs, max_delay_secs + 1),
                     )),
                     last_sync: SystemTime::UNIX_EPOCH,
                     last_result: None,
+                    progress: None,
                 },
             );
         });
         SyncManager {
-            config,
             lock,
             time_provider,
             sync_map: Arc::new(Mutex::new(map)),
+            allow_mass_delete: false,
+            verbose: false,
+            print_diff: false,
+            since: None,
+            last_tick: Arc::new(Mutex::new(None)),
+            invalidations: Arc::new(InvalidationsInProgress::new(
+                config.general.max_concurrent_invalidations,
+            )),
+            config: RwLock::new(Arc::new(config)),
         }
     }
 
-    pub fn start_scheduler(self: Arc<Self>) {
-        thread::spawn(move || loop {
-            let now = self.time_provider.now();
-            if let Some((name, time)) = self.next_repo_to_sync() {
-                if let Ok(sleep_time) = time.duration_since(now) {
-                    thread::sleep(sleep_time.min(Duration::from_secs(10)));
-                } else {
-                    //negative time
-                    let result = self.sync_repo(&name);
-                    if let Err(err) = result {
-                        println!("failed to synchronize {}: {}", &name, &err.to_string());
-                        self.sync_completed(&name, &err.to_string());
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    ///applies a new configuration and returns the configured repositories, new ones are
+    ///synchronized right away while removed ones are forgotten. A new data_path is refused,
+    ///running syncs keep writing their state there
+    pub fn reload(&self, config: Config) -> Result<Vec<String>, SyncError> {
+        if config.general.data_path != self.config().general.data_path {
+            return Err(SyncError::Config(
+                "data_path cannot be changed by a reload, restart reposync instead".into(),
+            ));
+        }
+        let now = self.time_provider.now();
+        let names: Vec<String> = config.repo.iter().map(|r| r.name.clone()).collect();
+        {
+            let mut map = self.sync_map.lock().unwrap();
+            map.retain(|name, _| names.contains(name));
+            for name in &names {
+                map.entry(name.clone()).or_insert(SyncStatus {
+                    current: RepoStatus::Waiting,
+                    next_sync: now,
+                    last_sync: SystemTime::UNIX_EPOCH,
+                    last_result: None,
+                    progress: None,
+                });
+            }
+        }
+        self.invalidations
+            .set_limit(config.general.max_concurrent_invalidations);
+        *self.config.write().unwrap() = Arc::new(config);
+        Ok(names)
+    }
+
+    ///disables the max_delete_ratio safety check
+    pub fn set_allow_mass_delete(&mut self, allow_mass_delete: bool) {
+        self.allow_mass_delete = allow_mass_delete;
+    }
+
+    ///prints the reason of every copy and delete decision
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    ///copies only packages built after since, older ones are copied by a later sync without it
+    pub fn set_since(&mut self, since: Option<SystemTime>) {
+        self.since = since;
+    }
+
+    ///returns the planned operations in the report instead of performing them
+    pub fn set_print_diff(&mut self, print_diff: bool) {
+        self.print_diff = print_diff;
+    }
+
+    ///the scheduler stops ticking only if its thread cannot be started, which the health reports
+    pub fn start_scheduler(self: Arc<Self>) -> Result<(), Error> {
+        thread::Builder::new()
+            .name("scheduler".into())
+            .spawn(move || loop {
+                //read on every tick, a reload may change it
+                let tick = Duration::from_secs(self.config().general.scheduler_tick_secs);
+                let now = self.time_provider.now();
+                *self.last_tick.lock().unwrap() = Some(now);
+                if let Some((name, time)) = self.next_repo_to_sync() {
+                    if let Ok(sleep_time) = time.duration_since(now) {
+                        thread::sleep(sleep_time.min(tick));
                     } else {
-                        println!("{} fully synchronized", &name);
-                        self.sync_completed(&name, "successful");
+                        //negative time
+                        self.run_scheduled(&name, || self.sync_repo(&name));
                     }
+                } else {
+                    thread::sleep(tick);
                 }
-            } else {
-                thread::sleep(Duration::from_secs(10));
+            })?;
+        Ok(())
+    }
+
+    ///runs a scheduled sync and records its result, a panic fails only this sync
+    fn run_scheduled<F>(&self, name: &str, sync: F)
+    where
+        F: FnOnce() -> Result<SyncReport, SyncError>,
+    {
+        let result = match panic::catch_unwind(AssertUnwindSafe(sync)) {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|x| x.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                human_println!("synchronization of {} panicked: {}", name, message);
+                self.sync_completed(name, &format!("panicked: {}", message));
+                return;
             }
-        });
+        };
+        match result {
+            Err(err) => {
+                human_println!("failed to synchronize {}: {}", name, &err.to_string());
+                self.sync_completed(name, &err.to_string());
+            }
+            Ok(report) if !report.failures.is_empty() => {
+                human_println!(
+                    "{} synchronized, {} packages skipped",
+                    name,
+                    report.failures.len()
+                );
+                self.sync_completed(name, &format!("{} packages skipped", report.failures.len()));
+            }
+            Ok(report) => {
+                human_println!(
+                    "{} fully synchronized, {} of {} metadata files identical and not re-published",
+                    name,
+                    report.metadata_unchanged,
+                    report.metadata_fetched
+                );
+                self.sync_completed(name, "successful");
+            }
+        }
     }
 
-    ///returns true if all paths in the configuration are accessible
-    pub fn check_permissions(&self) -> Result<(), std::io::Error> {
-        Self::check_writable(&self.config.general.data_path)?;
-        Self::check_writable(&self.config.general.tmp_path)?;
-        Ok(())
+    ///returns every path in the configuration with the result of its check
+    pub fn check_paths(&self) -> Vec<(String, Result<(), Error>)> {
+        let mut paths = vec![
+            self.config().general.data_path.clone(),
+            self.config().general.tmp_path.clone(),
+        ];
+        if let Some(report_path) = &self.config().general.report_path {
+            paths.push(report_path.clone());
+        }
+        for repo_config in &self.config().repo {
+            if let Some(tmp_path) = &repo_config.tmp_path {
+                paths.push(tmp_path.clone());
+            }
+        }
+        let min_free_inodes = self.config().general.min_free_inodes;
+        paths
+            .into_iter()
+            .map(|path| {
+                let result = Self::check_writable(&path).and_then(|_| match min_free_inodes {
+                    Some(min_free_inodes) => check_free_inodes(&path, min_free_inodes),
+                    None => Ok(()),
+                });
+                (path, result)
+            })
+            .collect()
+    }
+
+    ///last time the scheduler checked for due repositories, None if it never did
+    pub fn last_tick(&self) -> Option<SystemTime> {
+        *self.last_tick.lock().unwrap()
+    }
+
+    ///returns false when the scheduler did not tick for 3 ticks, unless it's synchronizing
+    pub fn is_scheduler_alive(&self) -> bool {
+        let last_tick = match self.last_tick() {
+            Some(last_tick) => last_tick,
+            None => return false,
+        };
+        let max_silence = Duration::from_secs(self.config().general.scheduler_tick_secs * 3);
+        match self.time_provider.now().duration_since(last_tick) {
+            Ok(elapsed) if elapsed > max_silence => {}
+            _ => return true,
+        }
+        //the scheduler synchronizes in its own thread, so it doesn't tick while syncing
+        self.sync_map
+            .lock()
+            .unwrap()
+            .values()
+            .any(|status| matches!(status.current, RepoStatus::Syncing))
     }
 
     fn check_writable(path: &str) -> Result<(), Error> {
@@ -175,13 +646,29 @@ impl SyncManager {
         if let Some(status) = map.get_mut(repo_name) {
             let tmp_next_sync = now
                 .add(Duration::from_secs(
-                    self.config.general.min_sync_delay as u64 * 60,
+                    self.config().general.min_sync_delay as u64 * 60,
                 ))
                 .sub(now.duration_since(status.last_sync).unwrap());
             status.next_sync = tmp_next_sync.min(status.next_sync);
         }
     }
 
+    ///publishes the progress in the status until the returned guard is dropped, so it's cleared
+    ///whether the sync completes or fails
+    fn track_progress(&self, repo_name: &str, progress: Arc<SyncProgress>) -> ProgressGuard<'_> {
+        self.set_progress(repo_name, Some(progress));
+        ProgressGuard {
+            sync_manager: self,
+            repo_name: repo_name.into(),
+        }
+    }
+
+    fn set_progress(&self, repo_name: &str, progress: Option<Arc<SyncProgress>>) {
+        if let Some(status) = self.sync_map.lock().unwrap().get_mut(repo_name) {
+            status.progress = progress;
+        }
+    }
+
     fn sync_completed(&self, repo_name: &str, result: &str) {
         let now = self.time_provider.now();
         let mut map = self.sync_map.lock().unwrap();
@@ -189,7 +676,7 @@ impl SyncManager {
         if let Some(status) = map.get_mut(repo_name) {
             status.last_sync = now;
             status.next_sync = now.add(Duration::from_secs(
-                self.config.general.max_sync_delay as u64 * 60,
+                self.config().general.max_sync_delay as u64 * 60,
             ));
             status.last_result = Some(result.into());
         }
@@ -236,10 +723,10 @@ impl SyncManager {
     pub fn load_current_by_name(
         &self,
         repo_name: &str,
-    ) -> Result<Option<(Repository, SavedRepoMetadataStore)>, std::io::Error> {
+    ) -> Result<Option<(Repository, SavedRepoMetadataStore)>, SyncError> {
         let repo_config = self.get_repo_config(repo_name);
         if let Some(repo_config) = repo_config {
-            let result = self.load_current(repo_config);
+            let result = self.load_current(&repo_config);
             if let Ok(result) = result {
                 Ok(Some(result))
             } else {
@@ -250,16 +737,20 @@ impl SyncManager {
         }
     }
 
-    fn get_repo_config(&self, repo_name: &str) -> Option<&RepositoryConfig> {
-        self.config.repo.iter().find(|x| x.name == repo_name)
+    fn get_repo_config(&self, repo_name: &str) -> Option<RepositoryConfig> {
+        self.config()
+            .repo
+            .iter()
+            .find(|x| x.name == repo_name)
+            .cloned()
     }
 
     pub fn load_current(
         &self,
         repo_config: &RepositoryConfig,
-    ) -> Result<(Repository, SavedRepoMetadataStore), std::io::Error> {
+    ) -> Result<(Repository, SavedRepoMetadataStore), SyncError> {
         let _write_lock = self.lock.lock_write(&repo_config.name);
-        let data_path = format!("{}/{}", self.config.general.data_path, repo_config.name);
+        let data_path = format!("{}/{}", self.config().general.data_path, repo_config.name);
 
         let result = File::open(&data_path);
         if result.is_err() {
@@ -273,7 +764,7 @@ impl SyncManager {
         }
 
         match repo_config.source.kind.as_str() {
-            "debian" => {
+            "debian" | "debian-flat" => {
                 let (repo, store) = debian::load_repository(&data_path, &repo_config)?;
                 Ok((repo, store))
             }
@@ -287,36 +778,76 @@ impl SyncManager {
         }
     }
 
-    pub fn sync_repo(&self, repo_name: &str) -> Result<(), std::io::Error> {
-        println!("starting synchronization of {}", repo_name);
+    pub fn sync_repo(&self, repo_name: &str) -> Result<SyncReport, SyncError> {
+        human_println!("starting synchronization of {}", repo_name);
         let repo_config = self.get_repo_config(repo_name);
         if repo_config.is_none() {
-            return Err(std::io::Error::new(
-                ErrorKind::NotFound,
-                format!("repository {} not found", repo_name),
-            ));
+            return Err(SyncError::NotFound(repo_name.into()));
         }
-        let repo_config = repo_config.unwrap();
+        let repo_config = &repo_config.unwrap();
+        let result = self.sync_repo_with_config(repo_config);
+        if !self.print_diff {
+            if let Err(err) = self.write_report(repo_config, &result) {
+                human_println!(
+                    "warning: cannot write the sync report of {}: {}",
+                    repo_name,
+                    err
+                );
+            }
+        }
+        result
+    }
+
+    fn sync_repo_with_config(
+        &self,
+        repo_config: &RepositoryConfig,
+    ) -> Result<SyncReport, SyncError> {
+        let config = self.config();
 
         let fetcher = fetcher::create_chain(
-            self.config.general.max_retries,
-            Duration::from_secs(self.config.general.retry_sleep),
+            config.general.max_retries,
+            Duration::from_secs(config.general.retry_sleep),
             repo_config
                 .source
                 .get_authorization_secret()
-                .expect("cannot read authorization secret"),
-            Duration::from_secs(self.config.general.timeout as u64),
+                .map_err(|err| {
+                    SyncError::Config(format!("cannot read authorization secret: {}", err))
+                })?,
+            client_options(&config.general, repo_config),
+            config.general.rebase_redirects,
+            fetcher::RateLimit {
+                max_requests_per_sec: repo_config.max_requests_per_sec,
+                max_download_bytes_per_sec: repo_config.max_download_bytes_per_sec,
+            },
         )?;
 
-        let mut destination = create_destination(&self.config.general, &repo_config.destination)?;
+        let fetcher: Box<dyn Fetcher> = if utils::is_json_log() {
+            Box::new(fetcher::EventFetcher::new(fetcher, &repo_config.name))
+        } else {
+            fetcher
+        };
 
-        return if let Some(_lock) = self.lock.lock_sync(&repo_config.name) {
-            self.sync_repo_internal(fetcher, destination.as_mut(), repo_config)
+        let mut destination = create_destination(
+            &config.general,
+            &repo_config.destination,
+            &self.invalidations,
+        )?;
+        let mut events;
+        let destination: &mut dyn Destination = if utils::is_json_log() {
+            events = EventDestination::new(destination.as_mut(), &repo_config.name);
+            &mut events
         } else {
-            Result::Err(std::io::Error::new(
-                ErrorKind::WouldBlock,
-                "sync already in progress",
-            ))
+            destination.as_mut()
+        };
+
+        return if let Some(_lock) = self
+            .lock
+            .lock_sync(&repo_config.name)
+            .map(|x| x.with_marker(&self.syncing_marker_path(&repo_config.name)))
+        {
+            self.sync_repo_internal(fetcher, destination, repo_config)
+        } else {
+            Result::Err(SyncError::Busy(repo_config.name.clone()))
         };
     }
 
@@ -325,62 +856,335 @@ impl SyncManager {
         fetcher: Box<dyn Fetcher>,
         destination: &mut dyn Destination,
         repo_config: &RepositoryConfig,
-    ) -> Result<(), std::io::Error> {
-        let fetcher: Rc<dyn Fetcher> = Rc::from(fetcher);
+    ) -> Result<SyncReport, SyncError> {
+        let config = self.config();
+        let fetcher: Arc<dyn Fetcher> = Arc::from(fetcher);
+        let tmp_path = repo_config.get_tmp_path(&config.general);
+
+        if let Some(min_free_inodes) = config.general.min_free_inodes {
+            for path in [&config.general.data_path, &tmp_path] {
+                std::fs::create_dir_all(path)?;
+                check_free_inodes(path, min_free_inodes)
+                    .map_err(|err| SyncError::Refused(err.to_string()))?;
+            }
+        }
+
+        let mut stripped;
+        let destination: &mut dyn Destination = if repo_config.source.strip_prefix.is_some() {
+            stripped = StripPrefixDestination::new(destination, &repo_config.source);
+            &mut stripped
+        } else {
+            destination
+        };
+
+        if repo_config.skip_unchanged
+            && self.load_pending_packages(repo_config)?.is_empty()
+            && !self.deletes_due(repo_config)?
+            && self.upstream_unchanged(fetcher.borrow(), repo_config)
+        {
+            human_println!(
+                "{} entry points unchanged upstream, nothing to synchronize",
+                repo_config.name
+            );
+            //a planning run uploads nothing
+            if !self.print_diff {
+                self.publish_public_key(repo_config, destination, &tmp_path)?;
+                //the upstream indexes didn't change, so the saved ones still list the same checksums
+                let (repo, _) = self.load_current(repo_config)?;
+                self.publish_extra_files(
+                    repo_config,
+                    &repo,
+                    fetcher.borrow(),
+                    destination,
+                    &tmp_path,
+                )?;
+            }
+            return Ok(SyncReport {
+                plan: if self.print_diff {
+                    Some(SyncPlan::new(repo_config, &[], &[], vec![]))
+                } else {
+                    None
+                },
+                ..Default::default()
+            });
+        }
+
+        //metadata is stored next to the data unless the repo has its own tmp_path
+        let metadata_tmp_path = format!(
+            "{}/tmp_{}/",
+            repo_config
+                .tmp_path
+                .as_ref()
+                .unwrap_or(&config.general.data_path),
+            &repo_config.name
+        );
 
         let (repo, metadata_store) = match repo_config.source.kind.as_str() {
-            "debian" => debian::fetch_repository(
-                fetcher.clone(),
-                &format!(
-                    "{}/tmp_{}/",
-                    &self.config.general.data_path, &repo_config.name
-                ),
-                &repo_config,
-            )?,
-
-            "redhat" => redhat::fetch_repository(
-                fetcher.clone(),
-                &format!(
-                    "{}/tmp_{}/",
-                    &self.config.general.data_path, repo_config.name
-                ),
-                &repo_config,
-            )?,
+            "debian" | "debian-flat" => {
+                debian::fetch_repository(fetcher.clone(), &metadata_tmp_path, &repo_config)?
+            }
+
+            "redhat" => {
+                redhat::fetch_repository(fetcher.clone(), &metadata_tmp_path, &repo_config)?
+            }
 
             _ => panic!("unknown repo of type {}", &repo_config.source.kind),
         };
 
         let public_key = repo_config.source.parse_public_key()?;
         if let Some(public_key) = public_key {
+            let mut verified: HashSet<&str> = HashSet::new();
             for index in repo.collections.iter().map(|c| &c.indexes).flatten() {
-                let mut reader = File::open(&index.file_path).expect("cannot open stored index");
-                let result = index.signature.matches(&public_key, &mut reader);
-                if result.is_err() {
-                    let err = result.err().unwrap();
-                    return Err(std::io::Error::new(
-                        ErrorKind::InvalidData,
-                        format!(
-                            "cannot validate signature of '{}': {}",
-                            &index.path,
-                            err.to_string()
-                        ),
-                    ));
+                let mut reader = state::open_stored(&index.file_path)?;
+                let result = index.signature.matches(
+                    &public_key,
+                    &repo_config.source.signature_hash_algorithms,
+                    &mut reader,
+                );
+                match result {
+                    Ok(Some(fingerprint)) => {
+                        human_println!("verified {} with key {}", &index.path, fingerprint);
+                        verified.insert(&index.path);
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        return Err(SyncError::SignatureInvalid {
+                            path: index.path.clone(),
+                            reason: err.to_string(),
+                        });
+                    }
                 }
             }
+            if repo_config.source.strict_signatures {
+                SyncManager::require_signed_indexes(&repo, &verified, &entry_points(repo_config))?;
+            }
         } else {
-            println!("no public pgp key provided, skipping metadata signature validation")
+            human_println!("no public pgp key provided, skipping metadata signature validation")
+        }
+
+        SyncManager::require_indexes(
+            &repo,
+            &repo_config.source.required_indexes,
+            &entry_points(repo_config),
+        )?;
+        if let Some(min_hash_strength) = &config.general.min_hash_strength {
+            SyncManager::require_hash_strength(&repo, min_hash_strength)?;
+        }
+        if repo_config.source.strip_prefix.is_some() {
+            SyncManager::require_stripped_paths(&repo, &repo_config.source)?;
+        }
+        if repo_config.destination.arch_prefix {
+            SyncManager::require_arch_prefixes(&repo, repo_config)?;
         }
 
+        let metadata_fetched = repo.collections.iter().map(|c| c.indexes.len()).sum();
         let (current_repo, _) = self.load_current(repo_config)?;
 
-        let (packages_copy_list, packages_delete_list, index_copy_list, index_delete_list) =
-            SyncManager::repo_diff(&repo, current_repo);
+        //a pinned repository never advances past the last synchronized metadata
+        if repo_config.pin
+            && !current_repo.collections.is_empty()
+            && !repo.same_indexes(&current_repo)
+        {
+            human_println!(
+                "{} is pinned, ignoring upstream metadata changes",
+                repo_config.name
+            );
+            return Ok(SyncReport {
+                metadata_fetched,
+                plan: if self.print_diff {
+                    Some(SyncPlan::new(repo_config, &[], &[], vec![]))
+                } else {
+                    None
+                },
+                ..Default::default()
+            });
+        }
+
+        let mut current_packages_count = current_repo
+            .collections
+            .iter()
+            .flat_map(|c| &c.packages)
+            .map(|p| &p.path)
+            .collect::<HashSet<&String>>()
+            .len();
+
+        let (
+            mut packages_copy_list,
+            mut packages_delete_list,
+            mut index_copy_list,
+            mut index_delete_list,
+        ) = SyncManager::repo_diff(&repo, current_repo, self.verbose);
+        let metadata_unchanged = metadata_fetched.saturating_sub(index_copy_list.len());
+        human_println!(
+            "fetched {} metadata files, {} identical to the published ones are not re-published",
+            metadata_fetched,
+            metadata_unchanged
+        );
+
+        //packages skipped by a previous sync, or files restored by a rollback, must be copied again
+        for path in self.load_pending_packages(repo_config)? {
+            if packages_copy_list
+                .iter()
+                .chain(&index_copy_list)
+                .any(|x| x.path == path)
+            {
+                continue;
+            }
+            let index = repo
+                .collections
+                .iter()
+                .flat_map(|c| &c.indexes)
+                .find(|x| x.path == path);
+            if let Some(index) = index {
+                index_copy_list.push(CopyOperation {
+                    path: index.path.clone(),
+                    hash: index.hash.clone(),
+                    is_replace: true,
+                    local_file: Some(index.file_path.clone()),
+                    size: index.size,
+                    architecture: String::new(),
+                });
+                continue;
+            }
+            let package = repo
+                .collections
+                .iter()
+                .flat_map(|c| &c.packages)
+                .find(|p| p.path == path);
+            if let Some(package) = package {
+                packages_copy_list.push(CopyOperation {
+                    path: package.path.clone(),
+                    hash: package.hash.clone(),
+                    is_replace: false,
+                    local_file: None,
+                    size: package.size,
+                    architecture: package.architecture.clone(),
+                });
+            }
+        }
+
+        //packages are recorded as pending, so they will be copied once metadata_only is disabled
+        let mut pending_paths: Vec<String> = Vec::new();
+        if repo_config.metadata_only {
+            human_println!(
+                "metadata only, skipping {} packages to copy and {} to delete",
+                packages_copy_list.len(),
+                packages_delete_list.len()
+            );
+            pending_paths = packages_copy_list.drain(..).map(|x| x.path).collect();
+        }
+
+        //an incremental bootstrap, packages without a build time are always copied
+        if let Some(since) = self.since {
+            let cutoff = since
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let (recent, older) = SyncManager::split_older(&repo, packages_copy_list, cutoff);
+            human_println!(
+                "leaving {} packages built before the cutoff for a later sync",
+                older.len()
+            );
+            pending_paths.extend(older.into_iter().map(|x| x.path));
+            packages_copy_list = recent;
+        }
+
+        //files dropped upstream are recorded, so prune keeps them too
+        let mut retained_paths = self.load_retained_paths(repo_config)?;
+        if repo_config.append_only {
+            for operation in packages_delete_list
+                .drain(..)
+                .chain(index_delete_list.drain(..))
+            {
+                if !retained_paths.contains(&operation.path) {
+                    retained_paths.push(operation.path);
+                }
+            }
+            let upstream: HashSet<&String> = repo
+                .collections
+                .iter()
+                .flat_map(|c| {
+                    c.packages
+                        .iter()
+                        .map(|x| &x.path)
+                        .chain(c.indexes.iter().map(|x| &x.path))
+                })
+                .collect();
+            retained_paths.retain(|x| !upstream.contains(x));
+        }
+
+        //the delete of a package is delayed while its grace period lasts, files left by a
+        //rollback are deleted the same way
+        let mut missing_packages = self.load_missing_packages(repo_config)?;
+        //still stored, though no longer in the saved state
+        current_packages_count += missing_packages.len();
+        if repo_config.metadata_only {
+            //deleted once metadata_only is disabled, the new state no longer lists them
+            self.record_missing(packages_delete_list.drain(..), &mut missing_packages);
+        } else {
+            packages_delete_list = self.delay_deletes(
+                repo_config,
+                &repo,
+                packages_delete_list,
+                &mut missing_packages,
+            );
+        }
+
+        //nothing is performed nor saved, max_delete_ratio applies to the real sync only
+        if self.print_diff {
+            let arch_prefix = repo_config.destination.arch_prefix;
+            let replaced = |list: &Vec<CopyOperation>| {
+                list.iter()
+                    .filter(|x| x.is_replace)
+                    .map(|x| arch_path(&x.path, &x.architecture, arch_prefix))
+                    .collect()
+            };
+            let invalidate = invalidation_paths(
+                replaced(&packages_copy_list),
+                replaced(&index_copy_list),
+                repo_config.wildcard_invalidation,
+            );
+            return Ok(SyncReport {
+                metadata_fetched,
+                metadata_unchanged,
+                plan: Some(SyncPlan::new(
+                    repo_config,
+                    &[&packages_copy_list, &index_copy_list],
+                    &[&packages_delete_list, &index_delete_list],
+                    invalidate,
+                )),
+                ..Default::default()
+            });
+        }
+
+        //an upstream glitch returning empty metadata must not wipe the mirror
+        if !self.allow_mass_delete
+            && packages_delete_list.len() as f64
+                > current_packages_count as f64 * config.general.max_delete_ratio
+        {
+            return Err(SyncError::Refused(format!(
+                "refusing to delete {} of {} packages, use --allow-mass-delete to proceed",
+                packages_delete_list.len(),
+                current_packages_count
+            )));
+        }
+
+        self.publish_public_key(repo_config, destination, &tmp_path)?;
+        self.publish_extra_files(repo_config, &repo, fetcher.borrow(), destination, &tmp_path)?;
 
         if packages_copy_list.is_empty() && index_copy_list.is_empty() {
-            return Ok(());
+            self.publish_manifest(repo_config, &repo, &pending_paths, destination, &tmp_path)?;
+            //grace periods end even when nothing changed upstream
+            SyncManager::delete_operations(destination, repo_config, packages_delete_list)?;
+            self.save_missing_packages(repo_config, &missing_packages)?;
+            return Ok(SyncReport {
+                metadata_fetched,
+                metadata_unchanged,
+                ..Default::default()
+            });
         }
 
-        println!(
+        human_println!(
             "{} packages and {} indexes to copy or update for a total of {:.2} MB.",
             packages_copy_list.len(),
             index_copy_list.len(),
@@ -389,592 +1193,3795 @@ impl SyncManager {
                 / (1024f64 * 1024f64)
         );
 
-        println!(
+        human_println!(
             "{} packages and {} indexes to delete.",
             packages_delete_list.len(),
             index_delete_list.len()
         );
 
-        println!("sync operation is atomic, either it's fully completed or will be performed from scratch");
+        human_println!("sync operation is atomic, either it's fully completed or will be performed from scratch");
 
-        let mut invalidation_paths: Vec<String> = Vec::new();
-        invalidation_paths.append(&mut SyncManager::copy(
-            &self.config.general.tmp_path,
-            &repo_config.source.endpoint,
+        let progress = Arc::new(SyncProgress::new(
+            packages_copy_list.len() + index_copy_list.len(),
+            packages_copy_list
+                .iter()
+                .chain(index_copy_list.iter())
+                .map(|x| x.size)
+                .sum(),
+        ));
+        let _progress = self.track_progress(&repo_config.name, progress.clone());
+
+        let mut copy_result = CopyResult::default();
+        copy_result.append(&mut SyncManager::copy(
+            &tmp_path,
+            repo_config.source.get_packages_endpoint(),
             fetcher.borrow(),
             destination,
             packages_copy_list,
+            &CopyOptions {
+                best_effort: repo_config.best_effort,
+                verify_size: config.general.verify_size,
+                parallel_hashing: config.general.parallel_hashing,
+                copy_retries: config.general.copy_retries,
+                retry_sleep: Duration::from_secs(config.general.retry_sleep),
+                arch_prefix: repo_config.destination.arch_prefix,
+                progress: &progress,
+            },
         )?);
+        let package_invalidations = copy_result.invalidations.len();
 
-        invalidation_paths.append(&mut SyncManager::copy(
-            &self.config.general.tmp_path,
+        let top_level_indexes: Vec<(String, Hash)> = if repo_config.verify_indexes {
+            let entry_points = entry_points(repo_config);
+            index_copy_list
+                .iter()
+                .filter(|x| entry_points.contains(&x.path))
+                .map(|x| (x.path.clone(), x.hash.clone()))
+                .collect()
+        } else {
+            vec![]
+        };
+        copy_result.append(&mut SyncManager::copy(
+            &tmp_path,
             &repo_config.source.endpoint,
             fetcher.borrow(),
             destination,
             index_copy_list,
+            &CopyOptions {
+                best_effort: false,
+                verify_size: config.general.verify_size,
+                parallel_hashing: config.general.parallel_hashing,
+                copy_retries: config.general.copy_retries,
+                retry_sleep: Duration::from_secs(config.general.retry_sleep),
+                arch_prefix: repo_config.destination.arch_prefix,
+                progress: &progress,
+            },
         )?);
 
-        destination.invalidate(invalidation_paths)?;
+        human_println!(
+            "{} files transferred for a total of {:.2} MB.",
+            copy_result.files,
+            copy_result.bytes as f64 / (1024f64 * 1024f64)
+        );
 
-        for operation in packages_delete_list {
-            destination.delete(&operation.path)?;
+        //a corrupted index must be neither served nor trusted to delete the old packages
+        for (path, hash) in top_level_indexes {
+            SyncManager::verify_uploaded(destination, &path, &hash)?;
         }
 
-        for operation in index_delete_list {
-            destination.delete(&operation.path)?;
-        }
+        let index_invalidations = copy_result.invalidations.split_off(package_invalidations);
+        destination.invalidate(invalidation_paths(
+            std::mem::take(&mut copy_result.invalidations),
+            index_invalidations,
+            repo_config.wildcard_invalidation,
+        ))?;
+
+        packages_delete_list.append(&mut index_delete_list);
+        SyncManager::delete_operations(destination, repo_config, packages_delete_list)?;
+
+        pending_paths.extend(copy_result.failures.iter().map(|(path, _)| path.clone()));
+        self.publish_manifest(repo_config, &repo, &pending_paths, destination, &tmp_path)?;
 
         let _write_lock = self.lock.lock_write(&repo_config.name);
-        metadata_store.replace(&format!(
-            "{}/{}",
-            self.config.general.data_path, repo_config.name
-        ))?;
+        metadata_store.replace(
+            &format!("{}/{}", config.general.data_path, repo_config.name),
+            config.general.metadata_generations,
+        )?;
 
-        Ok(())
+        self.save_pending_packages(repo_config, &pending_paths)?;
+        if repo_config.append_only {
+            self.save_retained_paths(repo_config, &retained_paths)?;
+        }
+        self.save_missing_packages(repo_config, &missing_packages)?;
+        if let Some(max_total_size) = repo_config.max_total_size_bytes {
+            self.evict(destination, repo_config, &repo, max_total_size)?;
+        }
+
+        Ok(SyncReport {
+            failures: copy_result
+                .failures
+                .iter()
+                .map(|(path, reason)| format!("{}: {}", path, reason))
+                .collect(),
+            metadata_fetched,
+            metadata_unchanged,
+            plan: None,
+            files_copied: copy_result.files,
+            bytes_copied: copy_result.bytes,
+        })
     }
 
-    fn copy(
-        tmp_path: &str,
-        source_endpoint: &str,
-        fetcher: &dyn Fetcher,
-        destination: &mut dyn Destination,
-        copy_list: Vec<CopyOperation>,
-    ) -> Result<Vec<String>, std::io::Error> {
-        let result =
-            SyncManager::copy_internal(tmp_path, source_endpoint, fetcher, destination, copy_list);
-        if result.is_err() {
-            let err = result.err().unwrap();
-            return Err(std::io::Error::new(
-                err.kind(),
-                format!(
-                    "failed to copy {} to {}: {}",
-                    source_endpoint,
-                    destination.name(),
-                    &err.to_string()
-                ),
-            ));
+    ///true when every entry point upstream is identical to the saved one, any error lets the
+    ///regular sync run and report it
+    fn upstream_unchanged(&self, fetcher: &dyn Fetcher, repo_config: &RepositoryConfig) -> bool {
+        let entry_points = entry_points(repo_config);
+        let saved = SavedRepoMetadataStore::new(&format!(
+            "{}/{}",
+            self.config().general.data_path,
+            repo_config.name
+        ));
+        let mut any_saved = false;
+        for path in &entry_points {
+            let saved_hash = match saved.fetch(path) {
+                Ok((_, mut reader, _)) => match Hash::create_sha256_hash(&mut reader) {
+                    Ok(hash) => Some(hash),
+                    Err(_) => return false,
+                },
+                Err(err) if err.is_not_found() => None,
+                Err(_) => return false,
+            };
+            let url = format!("{}/{}", repo_config.source.endpoint, path);
+            let upstream_hash = match fetcher.fetch(&url) {
+                Ok(mut reader) => match Hash::create_sha256_hash(&mut reader) {
+                    Ok(hash) => Some(hash),
+                    Err(_) => return false,
+                },
+                Err(err) if err.code == 404 => None,
+                Err(_) => return false,
+            };
+            if saved_hash != upstream_hash {
+                return false;
+            }
+            any_saved |= saved_hash.is_some();
         }
-        result
+        any_saved
     }
 
-    fn copy_internal(
-        tmp_path: &str,
-        source_endpoint: &str,
-        fetcher: &dyn Fetcher,
-        destination: &mut dyn Destination,
-        copy_list: Vec<CopyOperation>,
-    ) -> Result<Vec<String>, std::io::Error> {
-        let mut invalidation_paths: Vec<String> = Vec::new();
-        std::fs::create_dir_all(tmp_path).expect("unable to create tmp_path");
-
-        for operation in copy_list {
-            let mut tmp_file;
-            if operation.local_file.is_some() {
-                let result = File::open(operation.local_file.clone().unwrap());
-                if let Err(err) = result {
-                    return Err(std::io::Error::new(
-                        err.kind(),
-                        format!(
-                            "cannot copy file '{}': {}",
-                            &operation.local_file.clone().unwrap(),
-                            err.to_string()
-                        ),
-                    ));
-                }
-                tmp_file = result.unwrap();
-            } else {
-                let fetch_result =
-                    fetcher.fetch(&format!("{}/{}", source_endpoint, operation.path));
-                if fetch_result.is_err() {
-                    return Err(std::io::Error::new(
-                        ErrorKind::Other,
-                        format!(
-                            "cannot copy file '{}': {}",
-                            operation.path,
-                            fetch_result.err().unwrap().error
-                        ),
-                    ));
-                }
-                let mut reader = fetch_result.unwrap();
-                tmp_file = tempfile::tempfile_in(tmp_path).expect("cannot create tmp file");
-                let _ = std::io::copy(&mut reader, &mut tmp_file)?;
-                tmp_file.flush()?;
-                tmp_file.seek(SeekFrom::Start(0))?;
-            }
+    ///restores a saved generation of the state, the latest when none is given. Files a sync from
+    ///the discarded state to the restored one would copy are marked pending, so the next sync
+    ///uploads them again even when upstream matches the restored state
+    pub fn rollback(&self, repo_name: &str, generation: Option<&str>) -> Result<String, SyncError> {
+        let repo_config = match self.get_repo_config(repo_name) {
+            Some(repo_config) => repo_config,
+            None => return Err(SyncError::NotFound(repo_name.into())),
+        };
 
-            if !operation.hash.matches(&mut tmp_file)? {
-                return Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!("failed hash validation for '{}'", operation.path),
-                ));
-            }
+        if let Some(_lock) = self.lock.lock_sync(&repo_config.name) {
+            self.rollback_internal(&repo_config, generation)
+        } else {
+            Result::Err(SyncError::Busy(repo_config.name.clone()))
+        }
+    }
 
-            let tmp_file_size = tmp_file.metadata()?.len();
-            if operation.size != tmp_file_size {
-                return Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    format!(
-                        "invalid file size for '{}', expected {} found {}",
-                        operation.path, operation.size, tmp_file_size
-                    ),
-                ));
+    fn rollback_internal(
+        &self,
+        repo_config: &RepositoryConfig,
+        generation: Option<&str>,
+    ) -> Result<String, SyncError> {
+        let data_path = format!("{}/{}", self.config().general.data_path, repo_config.name);
+        let generations = state::list_generations(&data_path)?;
+        let generation = match generation {
+            Some(generation) => generations.iter().find(|x| *x == generation),
+            None => generations.last(),
+        };
+        let generation = match generation {
+            Some(generation) => generation.clone(),
+            None => {
+                return Err(SyncError::Refused(format!(
+                    "no such saved generation of '{}', available: {}",
+                    repo_config.name,
+                    generations.join(", ")
+                )))
             }
+        };
+        let generation_path = format!("{}/{}", state::generations_path(&data_path), generation);
 
-            tmp_file.seek(SeekFrom::Start(0))?;
-            if operation.is_replace {
-                invalidation_paths.push(operation.path.clone());
+        let (current_repo, _) = self.load_current(repo_config)?;
+        let (restored_repo, _) = match repo_config.source.kind.as_str() {
+            "debian" | "debian-flat" => debian::load_repository(&generation_path, repo_config)?,
+            "redhat" => redhat::load_repository(&generation_path, repo_config)?,
+            _ => panic!("unknown repo of type {}", &repo_config.source.kind),
+        };
+        let (packages_copy_list, packages_delete_list, index_copy_list, index_delete_list) =
+            SyncManager::repo_diff(&restored_repo, current_repo, false);
+        let mut pending_paths = self.load_pending_packages(repo_config)?;
+        for operation in packages_copy_list.iter().chain(&index_copy_list) {
+            if !pending_paths.contains(&operation.path) {
+                pending_paths.push(operation.path.clone());
             }
+        }
+        //files of the discarded state only are still stored, the next sync deletes them
+        let mut missing_packages = self.load_missing_packages(repo_config)?;
+        self.record_missing(
+            packages_delete_list.into_iter().chain(index_delete_list),
+            &mut missing_packages,
+        );
 
-            destination.upload(&operation.path, tmp_file)?;
+        let _write_lock = self.lock.lock_write(&repo_config.name);
+        if File::open(&data_path).is_ok() {
+            state::keep_generation(&data_path, &data_path)?;
         }
+        std::fs::rename(&generation_path, &data_path)?;
+        self.save_pending_packages(repo_config, &pending_paths)?;
+        self.save_missing_packages(repo_config, &missing_packages)?;
+        Ok(generation)
+    }
+
+    ///lists, and deletes when confirmed, the objects of the destination which are not referenced
+    ///by the last synchronization, e.g. manual uploads or leftovers of interrupted old syncs
+    pub fn prune(&self, repo_name: &str, confirm: bool) -> Result<Vec<String>, SyncError> {
+        let repo_config = match self.get_repo_config(repo_name) {
+            Some(repo_config) => repo_config,
+            None => return Err(SyncError::NotFound(repo_name.into())),
+        };
+        let mut destination = create_destination(
+            &self.config().general,
+            &repo_config.destination,
+            &self.invalidations,
+        )?;
 
-        Ok(invalidation_paths)
+        if let Some(_lock) = self.lock.lock_sync(&repo_config.name) {
+            self.prune_internal(destination.as_mut(), &repo_config, confirm)
+        } else {
+            Result::Err(SyncError::Busy(repo_config.name.clone()))
+        }
     }
 
-    fn repo_diff(
-        repo: &Repository,
-        current_repo: Repository,
-    ) -> (
-        Vec<CopyOperation>,
-        Vec<DeleteOperation>,
-        Vec<CopyOperation>,
-        Vec<DeleteOperation>,
-    ) {
-        let mut packages_copy_list: Vec<CopyOperation> = Vec::new();
-        let mut packages_delete_list: Vec<DeleteOperation> = Vec::new();
+    fn prune_internal(
+        &self,
+        destination: &mut dyn Destination,
+        repo_config: &RepositoryConfig,
+        confirm: bool,
+    ) -> Result<Vec<String>, SyncError> {
+        let config = self.config();
+        let location = repo_config.destination.location();
+        let mut referenced: HashSet<String> = HashSet::new();
+        //destinations of other repositories nested in this one
+        let mut nested: Vec<String> = vec![];
+        for other in &config.repo {
+            let other_location = other.destination.location();
+            if other.name != repo_config.name && other_location != location {
+                if let Some(path) = other_location.strip_prefix(&format!("{}/", location)) {
+                    nested.push(format!("{}/", path));
+                }
+                continue;
+            }
+            let (current_repo, _) = self.load_current(other)?;
+            if current_repo.collections.is_empty() {
+                return Err(SyncError::Refused(format!(
+                    "{} was never synchronized, refusing to prune {}",
+                    other.name, location
+                )));
+            }
+            let stored = |path: &str| other.source.destination_path(path).to_string();
+            for collection in &current_repo.collections {
+                referenced.extend(collection.packages.iter().map(|x| {
+                    arch_path(
+                        &stored(&x.path),
+                        &x.architecture,
+                        other.destination.arch_prefix,
+                    )
+                }));
+                for index in &collection.indexes {
+                    //written next to indexes by gzip_indexes
+                    referenced.insert(format!("{}.gz", stored(&index.path)));
+                    referenced.insert(stored(&index.path));
+                }
+            }
+            if let Some(path) = &other.publish_public_key {
+                referenced.insert(stored(path));
+            }
+            if let Some(path) = &other.manifest_path {
+                referenced.insert(stored(path));
+            }
+            referenced.extend(other.extra_files.iter().map(|x| stored(x)));
+            for path in self.load_retained_paths(other)? {
+                referenced.insert(format!("{}.gz", stored(&path)));
+                referenced.insert(stored(&path));
+            }
+            //still in their grace period, or left by a rollback
+            for (path, (_, _, architecture)) in self.load_missing_packages(other)? {
+                referenced.insert(arch_path(
+                    &stored(&path),
+                    &architecture,
+                    other.destination.arch_prefix,
+                ));
+            }
+        }
 
-        let mut index_copy_list: Vec<CopyOperation> = Vec::new();
-        let mut index_delete_list: Vec<DeleteOperation> = Vec::new();
+        let mut orphans: Vec<String> = destination
+            .list("")?
+            .into_iter()
+            .filter(|x| !referenced.contains(x) && !nested.iter().any(|n| x.starts_with(n)))
+            .collect();
+        orphans.sort();
+        if confirm {
+            destination.delete_many(&orphans)?;
+        }
+        Ok(orphans)
+    }
 
+    ///every collection must have at least one verified top level index, either Release,
+    ///InRelease or the redhat metadata path, as they carry the hashes of every other index
+    fn require_signed_indexes(
+        repo: &Repository,
+        verified: &HashSet<&str>,
+        entry_points: &[String],
+    ) -> Result<(), SyncError> {
+        //the InRelease next to a Release is signed in place of it
+        let in_releases: Vec<String> = entry_points
+            .iter()
+            .filter_map(|x| x.strip_suffix("Release"))
+            .map(|directory| format!("{}InRelease", directory))
+            .collect();
         for collection in &repo.collections {
-            let empty_collection = Collection::empty(&collection.target);
-            let current_collection = current_repo
-                .collections
+            let top_level: Vec<&str> = collection
+                .indexes
                 .iter()
-                .find(|x| x.target == collection.target)
-                .unwrap_or(&empty_collection);
+                .map(|index| index.path.as_str())
+                .filter(|path| {
+                    entry_points.iter().any(|x| x == path) || in_releases.iter().any(|x| x == path)
+                })
+                .collect();
+            if !top_level.iter().any(|path| verified.contains(path)) {
+                return Err(SyncError::Refused(format!(
+                    "no verified signature for '{}', required by strict_signatures",
+                    top_level.join("', '")
+                )));
+            }
+        }
+        Ok(())
+    }
 
-            let new_packages: BTreeMap<String, &Package> = collection
-                .packages
+    ///an optional index missing upstream, e.g. a signature, usually means a broken publish
+    fn require_indexes(
+        repo: &Repository,
+        required_indexes: &[String],
+        entry_points: &[String],
+    ) -> Result<(), SyncError> {
+        for collection in &repo.collections {
+            let file_names: HashSet<&str> = collection
+                .indexes
                 .iter()
-                .map(|x| (x.path.clone(), x))
+                .map(|index| index.path.rsplit('/').next().unwrap_or(&index.path))
                 .collect();
-
-            let current_packages: BTreeMap<String, &Package> = current_collection
-                .packages
+            let missing: Vec<&str> = required_indexes
                 .iter()
-                .map(|x| (x.path.clone(), x))
+                .map(|x| x.as_str())
+                .filter(|x| !file_names.contains(x))
                 .collect();
-
-            packages_copy_list.append(
-                &mut new_packages
+            if !missing.is_empty() {
+                let top_level = collection
+                    .indexes
                     .iter()
-                    .filter(|&(key, new_package)| {
-                        if let Some(current_package) = current_packages.get(key) {
-                            //updated package or same old?
-                            current_package != new_package
-                        } else {
-                            //brand new package/version
-                            true
-                        }
-                    })
-                    .map(|(key, new_package)| {
-                        if current_packages.contains_key(key) {
-                            CopyOperation {
-                                path: new_package.path.clone(),
-                                hash: new_package.hash.clone(),
-                                is_replace: true,
-                                local_file: None,
-                                size: new_package.size,
-                            }
-                        } else {
-                            CopyOperation {
-                                path: new_package.path.clone(),
-                                hash: new_package.hash.clone(),
-                                is_replace: false,
-                                local_file: None,
-                                size: new_package.size,
-                            }
-                        }
-                    })
-                    .collect(),
-            );
+                    .map(|index| index.path.as_str())
+                    .find(|path| entry_points.iter().any(|x| x == path))
+                    .unwrap_or_default();
+                return Err(SyncError::Refused(format!(
+                    "'{}' missing next to '{}', required by required_indexes",
+                    missing.join("', '"),
+                    top_level
+                )));
+            }
+        }
+        Ok(())
+    }
 
-            packages_delete_list.append(
-                &mut current_packages
-                    .iter()
-                    //skip every path still in use
-                    .filter(|&(key, _)| !new_packages.contains_key(key))
-                    .map(|(_key, current_package)| DeleteOperation {
-                        path: current_package.path.clone(),
-                    })
-                    .collect(),
-            );
+    ///refuses architectures which are not a single directory name or which would be the
+    ///directory of an index, so every prefix only holds the packages of one architecture
+    fn require_arch_prefixes(
+        repo: &Repository,
+        repo_config: &RepositoryConfig,
+    ) -> Result<(), SyncError> {
+        let root_directories: HashSet<&str> = repo
+            .collections
+            .iter()
+            .flat_map(|c| c.indexes.iter().map(|x| x.path.as_str()))
+            .chain(repo_config.publish_public_key.iter().map(|x| x.as_str()))
+            .chain(repo_config.manifest_path.iter().map(|x| x.as_str()))
+            .chain(repo_config.extra_files.iter().map(|x| x.as_str()))
+            .filter_map(|x| x.split('/').next())
+            .collect();
+        for package in repo.collections.iter().flat_map(|c| &c.packages) {
+            let architecture = package.architecture.as_str();
+            if architecture.is_empty()
+                || architecture == "."
+                || architecture == ".."
+                || architecture.contains(&['/', '\\'][..])
+            {
+                return Err(SyncError::Refused(format!(
+                    "architecture '{}' of '{}' cannot be a directory of {{arch}}",
+                    architecture, package.path
+                )));
+            }
+            if root_directories.contains(architecture) {
+                return Err(SyncError::Refused(format!(
+                    "packages of architecture '{}' would be stored together with the indexes in '{}/'",
+                    architecture, architecture
+                )));
+            }
+        }
+        Ok(())
+    }
 
-            let new_indexes: BTreeMap<String, IndexFile> = collection
-                .indexes
+    ///refuses a strip_prefix which doesn't apply to every file, the indexes are published as
+    ///they are, so the paths they reference only stay valid when every file moves the same way
+    fn require_stripped_paths(repo: &Repository, source: &SourceConfig) -> Result<(), SyncError> {
+        for collection in &repo.collections {
+            let paths = collection
+                .packages
                 .iter()
-                .map(|x| (x.path.clone(), x.clone()))
-                .collect();
+                .map(|x| &x.path)
+                .chain(collection.indexes.iter().map(|x| &x.path));
+            for path in paths {
+                if source.destination_path(path) == path {
+                    return Err(SyncError::Refused(format!(
+                        "'{}' is not under strip_prefix '{}', the published indexes would reference missing files",
+                        path,
+                        source.strip_prefix.as_deref().unwrap_or_default()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
 
-            let current_indexes: BTreeMap<String, IndexFile> = current_collection
+    ///refuses files whose strongest checksum is weaker than the policy, top level indexes have
+    ///no checksum as they are covered by signatures
+    fn require_hash_strength(repo: &Repository, min_hash_strength: &str) -> Result<(), SyncError> {
+        let min_strength = Hash::strength_of(min_hash_strength).unwrap_or_default();
+        for collection in &repo.collections {
+            let hashes = collection
                 .indexes
                 .iter()
-                .map(|x| (x.path.clone(), x.clone()))
-                .collect();
+                .map(|x| (&x.path, &x.hash))
+                .chain(collection.packages.iter().map(|x| (&x.path, &x.hash)));
+            for (path, hash) in hashes {
+                if *hash != Hash::None && hash.strength() < min_strength {
+                    return Err(SyncError::Refused(format!(
+                        "'{}' only has a {} checksum, weaker than min_hash_strength {}",
+                        path,
+                        hash.algorithm(),
+                        min_hash_strength
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
 
-            index_copy_list.append(
-                &mut collection
-                    .indexes
-                    .iter()
-                    //skip unchanged indexes
-                    .filter(|new_index| {
-                        if let Some(current_index) = current_indexes.get(&new_index.path) {
-                            //updated index
-                            !new_index.same_content(current_index)
-                        } else {
-                            //brand new
-                            true
-                        }
-                    })
-                    .map(|new_index| {
-                        if current_indexes.contains_key(&new_index.path) {
-                            CopyOperation {
-                                path: new_index.path.clone(),
-                                hash: new_index.hash.clone(),
-                                is_replace: true,
-                                local_file: Some(new_index.file_path.clone()),
-                                size: new_index.size,
-                            }
-                        } else {
-                            CopyOperation {
-                                path: new_index.path.clone(),
-                                hash: new_index.hash.clone(),
-                                is_replace: false,
-                                local_file: Some(new_index.file_path.clone()),
-                                size: new_index.size,
-                            }
-                        }
-                    })
-                    .collect(),
-            );
+    ///visible while a sync of the repository is running
+    fn syncing_marker_path(&self, repo_name: &str) -> String {
+        format!("{}/{}.syncing", self.config().general.data_path, repo_name)
+    }
 
-            index_delete_list.append(
-                &mut current_collection
-                    .indexes
-                    .iter()
-                    //skip still used paths
-                    .filter(|x| !new_indexes.contains_key(&x.path))
-                    .map(|x| DeleteOperation {
-                        path: x.path.clone(),
-                    })
-                    .collect(),
-            );
+    ///lock files of interrupted syncs, with their content
+    pub fn stale_sync_markers(&self) -> Vec<(String, String)> {
+        self.config()
+            .repo
+            .iter()
+            .filter_map(|repo_config| {
+                let path = self.syncing_marker_path(&repo_config.name);
+                let held = self.lock.is_sync_held(&repo_config.name);
+                locks::stale_marker(&path, held).map(|content| (path, content))
+            })
+            .collect()
+    }
+
+    fn report_directory(&self, repo_config: &RepositoryConfig) -> String {
+        let config = self.config();
+        match &config.general.report_path {
+            Some(report_path) => report_path.clone(),
+            None => format!("{}/{}.reports", config.general.data_path, repo_config.name),
         }
+    }
 
-        (
-            SyncManager::deduplicate_list(packages_copy_list),
-            SyncManager::deduplicate_list(packages_delete_list),
-            SyncManager::deduplicate_list(index_copy_list),
-            SyncManager::deduplicate_list(index_delete_list),
+    ///writes {name}-{millis}.json, keeping the latest report_retention reports of the repository
+    fn write_report(
+        &self,
+        repo_config: &RepositoryConfig,
+        result: &Result<SyncReport, SyncError>,
+    ) -> Result<(), std::io::Error> {
+        let directory = self.report_directory(repo_config);
+        std::fs::create_dir_all(&directory)?;
+        let completed = self
+            .time_provider
+            .now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let empty = SyncReport::default();
+        let report = result.as_ref().unwrap_or(&empty);
+        let file = SyncReportFile {
+            repository: &repo_config.name,
+            completed,
+            result: match result {
+                Ok(_) => "successful".into(),
+                Err(err) => err.to_string(),
+            },
+            metadata_fetched: report.metadata_fetched,
+            metadata_unchanged: report.metadata_unchanged,
+            failures: &report.failures,
+        };
+        std::fs::write(
+            format!("{}/{}-{}.json", directory, repo_config.name, completed),
+            serde_json::to_string_pretty(&file)?,
+        )?;
+
+        let prefix = format!("{}-", repo_config.name);
+        let mut reports: Vec<u128> = vec![];
+        for entry in std::fs::read_dir(&directory)? {
+            let file_name = entry?.file_name();
+            let millis = file_name
+                .to_str()
+                .and_then(|x| x.strip_prefix(&prefix))
+                .and_then(|x| x.strip_suffix(".json"))
+                .and_then(|x| x.parse().ok());
+            if let Some(millis) = millis {
+                reports.push(millis);
+            }
+        }
+        reports.sort_unstable();
+        let excess = reports
+            .len()
+            .saturating_sub(self.config().general.report_retention);
+        for millis in &reports[..excess] {
+            std::fs::remove_file(format!("{}/{}{}.json", directory, prefix, millis))?;
+        }
+        Ok(())
+    }
+
+    fn pending_packages_path(&self, repo_config: &RepositoryConfig) -> String {
+        format!(
+            "{}/{}.pending",
+            self.config().general.data_path,
+            repo_config.name
         )
     }
 
-    fn deduplicate_list<T>(list: Vec<T>) -> Vec<T>
-    where
-        T: Eq + Clone,
-    {
-        let mut tmp: Vec<T> = Vec::new();
-        list.into_iter().for_each(|x| {
-            if !tmp.contains(&x) {
-                tmp.push(x);
-            }
-        });
-        tmp
+    fn load_pending_packages(
+        &self,
+        repo_config: &RepositoryConfig,
+    ) -> Result<Vec<String>, std::io::Error> {
+        SyncManager::load_paths(&self.pending_packages_path(repo_config))
     }
-}
 
-#[cfg(test)]
-pub mod tests {
-    use crate::config::{Config, DestinationConfig, GeneralConfig, RepositoryConfig, SourceConfig};
-    use crate::destination::MemoryDestination;
-    use crate::fetcher::MockFetcher;
-    use crate::sync::{Lock, MockTimeProvider, RealTimeProvider, SyncManager};
-    use std::fs::File;
-    use std::ops::Add;
-    use std::sync::atomic::{AtomicU64, Ordering};
-    use std::sync::{Arc, Mutex};
-    use std::time::{Duration, UNIX_EPOCH};
-    use tempfile::TempDir;
+    fn save_pending_packages(
+        &self,
+        repo_config: &RepositoryConfig,
+        paths: &[String],
+    ) -> Result<(), std::io::Error> {
+        SyncManager::save_paths(&self.pending_packages_path(repo_config), paths)
+    }
 
-    fn create_config(tmp_dir: &TempDir) -> Config {
-        let config = Config {
-            general: GeneralConfig {
-                data_path: format!("{}/data", tmp_dir.path().to_str().unwrap()),
-                tmp_path: format!("{}/tmp", tmp_dir.path().to_str().unwrap()),
-                bind_address: "".to_string(),
-                timeout: 0,
-                max_retries: 0,
-                retry_sleep: 0,
-                min_sync_delay: 10,
-                max_sync_delay: 30,
-            },
-            repo: vec![RepositoryConfig {
-                name: "test-ubuntu".to_string(),
-                source: SourceConfig {
-                    endpoint: "http://fake-url/rc".to_string(),
-                    kind: "debian".to_string(),
-                    public_pgp_key: None,
-                    username: None,
+    ///files an append_only repository keeps, although they are no longer upstream
+    fn retained_paths_path(&self, repo_config: &RepositoryConfig) -> String {
+        format!(
+            "{}/{}.retained",
+            self.config().general.data_path,
+            repo_config.name
+        )
+    }
+
+    fn load_retained_paths(
+        &self,
+        repo_config: &RepositoryConfig,
+    ) -> Result<Vec<String>, std::io::Error> {
+        SyncManager::load_paths(&self.retained_paths_path(repo_config))
+    }
+
+    fn save_retained_paths(
+        &self,
+        repo_config: &RepositoryConfig,
+        paths: &[String],
+    ) -> Result<(), std::io::Error> {
+        SyncManager::save_paths(&self.retained_paths_path(repo_config), paths)
+    }
+
+    ///packages missing upstream which are not deleted yet, and since when they are missing
+    fn missing_packages_path(&self, repo_config: &RepositoryConfig) -> String {
+        format!(
+            "{}/{}.missing",
+            self.config().general.data_path,
+            repo_config.name
+        )
+    }
+
+    ///lines of 'seconds size architecture path', keyed by path, the architecture may be empty
+    fn load_missing_packages(
+        &self,
+        repo_config: &RepositoryConfig,
+    ) -> Result<BTreeMap<String, (u64, u64, String)>, std::io::Error> {
+        let mut missing = BTreeMap::new();
+        for line in SyncManager::load_paths(&self.missing_packages_path(repo_config))? {
+            let mut parts = line.splitn(4, ' ');
+            if let (Some(Ok(time)), Some(Ok(size)), Some(architecture), Some(path)) = (
+                parts.next().map(|x| x.parse()),
+                parts.next().map(|x| x.parse()),
+                parts.next(),
+                parts.next(),
+            ) {
+                missing.insert(path.to_string(), (time, size, architecture.to_string()));
+            }
+        }
+        Ok(missing)
+    }
+
+    fn save_missing_packages(
+        &self,
+        repo_config: &RepositoryConfig,
+        missing: &BTreeMap<String, (u64, u64, String)>,
+    ) -> Result<(), std::io::Error> {
+        let lines: Vec<String> = missing
+            .iter()
+            .map(|(path, (time, size, architecture))| {
+                format!("{} {} {} {}", time, size, architecture, path)
+            })
+            .collect();
+        SyncManager::save_paths(&self.missing_packages_path(repo_config), &lines)
+    }
+
+    fn now_secs(&self) -> u64 {
+        self.time_provider
+            .now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    ///whether packages missing upstream are past delete_grace_secs, only a full sync deletes them
+    fn deletes_due(&self, repo_config: &RepositoryConfig) -> Result<bool, std::io::Error> {
+        //metadata_only records the deletes without ever performing them
+        if repo_config.metadata_only {
+            return Ok(false);
+        }
+        let now = self.now_secs();
+        Ok(self
+            .load_missing_packages(repo_config)?
+            .values()
+            .any(|(since, _, _)| now.saturating_sub(*since) >= repo_config.delete_grace_secs))
+    }
+
+    ///records the files to delete as missing since now, unless they already are, returns now
+    fn record_missing<I>(
+        &self,
+        operations: I,
+        missing: &mut BTreeMap<String, (u64, u64, String)>,
+    ) -> u64
+    where
+        I: IntoIterator<Item = DeleteOperation>,
+    {
+        let now = self.now_secs();
+        for operation in operations {
+            missing
+                .entry(operation.path)
+                .or_insert((now, operation.size, operation.architecture));
+        }
+        now
+    }
+
+    ///records the packages to delete as missing, and returns the ones missing for at least
+    ///delete_grace_secs, packages listed upstream again are forgotten
+    fn delay_deletes(
+        &self,
+        repo_config: &RepositoryConfig,
+        repo: &Repository,
+        candidates: Vec<DeleteOperation>,
+        missing: &mut BTreeMap<String, (u64, u64, String)>,
+    ) -> Vec<DeleteOperation> {
+        let now = self.record_missing(candidates, missing);
+        let upstream: HashSet<&String> = repo
+            .collections
+            .iter()
+            .flat_map(|c| {
+                c.packages
+                    .iter()
+                    .map(|x| &x.path)
+                    .chain(c.indexes.iter().map(|x| &x.path))
+            })
+            .collect();
+        missing.retain(|path, _| !upstream.contains(path));
+
+        let due: Vec<String> = missing
+            .iter()
+            .filter(|(_, (since, _, _))| {
+                now.saturating_sub(*since) >= repo_config.delete_grace_secs
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        if missing.len() > due.len() {
+            human_println!(
+                "{} packages missing upstream are kept until their grace period ends",
+                missing.len() - due.len()
+            );
+        }
+        due.into_iter()
+            .map(|path| {
+                let (_, size, architecture) = missing.remove(&path).unwrap();
+                DeleteOperation {
+                    path,
+                    architecture,
+                    size,
+                }
+            })
+            .collect()
+    }
+
+    ///size and last time upstream listed them of packages stored by a size-capped repository
+    fn references_path(&self, repo_config: &RepositoryConfig) -> String {
+        format!(
+            "{}/{}.references",
+            self.config().general.data_path,
+            repo_config.name
+        )
+    }
+
+    ///lines of 'seconds size path', keyed by path
+    fn load_references(
+        &self,
+        repo_config: &RepositoryConfig,
+    ) -> Result<BTreeMap<String, (u64, u64)>, std::io::Error> {
+        let mut references = BTreeMap::new();
+        for line in SyncManager::load_paths(&self.references_path(repo_config))? {
+            let mut parts = line.splitn(3, ' ');
+            if let (Some(Ok(time)), Some(Ok(size)), Some(path)) = (
+                parts.next().map(|x| x.parse()),
+                parts.next().map(|x| x.parse()),
+                parts.next(),
+            ) {
+                references.insert(path.to_string(), (time, size));
+            }
+        }
+        Ok(references)
+    }
+
+    ///deletes the least recently referenced packages no longer listed upstream until the total
+    ///size is within max_total_size, returns the evicted paths. Listed packages are never evicted,
+    ///the published indexes reference them
+    fn evict(
+        &self,
+        destination: &mut dyn Destination,
+        repo_config: &RepositoryConfig,
+        repo: &Repository,
+        max_total_size: u64,
+    ) -> Result<Vec<String>, std::io::Error> {
+        let now = self
+            .time_provider
+            .now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        //upstream paths, the destination strips them like on upload
+        let mut references = self.load_references(repo_config)?;
+        let mut listed: HashSet<&str> = HashSet::new();
+        for package in repo.collections.iter().flat_map(|x| &x.packages) {
+            references.insert(package.path.clone(), (now, package.size));
+            listed.insert(&package.path);
+        }
+        //forget files which are gone, e.g. deleted by the sync
+        let stored: HashSet<String> = destination.list("")?.into_iter().collect();
+        references.retain(|path, _| stored.contains(repo_config.source.destination_path(path)));
+
+        let mut total: u64 = references.values().map(|(_, size)| size).sum();
+        let mut by_age: Vec<(u64, String)> = references
+            .iter()
+            .filter(|(path, _)| !listed.contains(path.as_str()))
+            .map(|(path, (time, _))| (*time, path.clone()))
+            .collect();
+        by_age.sort();
+        let mut evicted = vec![];
+        for (_, path) in by_age {
+            if total <= max_total_size {
+                break;
+            }
+            total -= references.remove(&path).unwrap().1;
+            evicted.push(path);
+        }
+        if !evicted.is_empty() {
+            human_println!(
+                "evicting {} least recently referenced packages, {:.2} MB are kept",
+                evicted.len(),
+                total as f64 / (1024f64 * 1024f64)
+            );
+            destination.delete_many(&evicted)?;
+        }
+        if total > max_total_size {
+            human_println!(
+                "{} packages listed upstream take {:.2} MB, more than max_total_size_bytes, none is evicted",
+                listed.len(),
+                total as f64 / (1024f64 * 1024f64)
+            );
+        }
+
+        let lines: Vec<String> = references
+            .iter()
+            .map(|(path, (time, size))| format!("{} {} {}", time, size, path))
+            .collect();
+        SyncManager::save_paths(&self.references_path(repo_config), &lines)?;
+        Ok(evicted)
+    }
+
+    ///reads back an uploaded file and compares its hash with the expected one
+    fn verify_uploaded(
+        destination: &mut dyn Destination,
+        path: &str,
+        hash: &Hash,
+    ) -> Result<(), SyncError> {
+        let content = destination.read(path)?;
+        let found = hash.compute(&mut content.as_slice())?;
+        if &found != hash {
+            human_println!(
+                "uploaded {} doesn't match, nothing is deleted nor invalidated",
+                path
+            );
+            return Err(SyncError::HashMismatch {
+                path: path.into(),
+                expected: hash.to_string(),
+                found: found.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    ///deletes from the destination, packages stored by architecture included
+    fn delete_operations(
+        destination: &mut dyn Destination,
+        repo_config: &RepositoryConfig,
+        operations: Vec<DeleteOperation>,
+    ) -> Result<(), std::io::Error> {
+        let paths: Vec<String> = operations
+            .into_iter()
+            .map(|x| {
+                arch_path(
+                    &x.path,
+                    &x.architecture,
+                    repo_config.destination.arch_prefix,
+                )
+            })
+            .collect();
+        destination.delete_many(&paths)
+    }
+
+    fn load_paths(path: &str) -> Result<Vec<String>, std::io::Error> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(text.lines().map(|x| x.into()).collect()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save_paths(path: &str, paths: &[String]) -> Result<(), std::io::Error> {
+        if paths.is_empty() {
+            if File::open(path).is_ok() {
+                std::fs::remove_file(path)?;
+            }
+            Ok(())
+        } else {
+            std::fs::write(path, paths.join("\n"))
+        }
+    }
+
+    fn public_key_path(&self, repo_config: &RepositoryConfig) -> String {
+        format!(
+            "{}/{}.key",
+            self.config().general.data_path,
+            repo_config.name
+        )
+    }
+
+    ///uploads the armored public key verbatim, only when it differs from the published one
+    fn publish_public_key(
+        &self,
+        repo_config: &RepositoryConfig,
+        destination: &mut dyn Destination,
+        tmp_path: &str,
+    ) -> Result<(), std::io::Error> {
+        let (path, public_key) = match (
+            &repo_config.publish_public_key,
+            &repo_config.source.public_pgp_key,
+        ) {
+            (Some(path), Some(public_key)) => (path, public_key),
+            _ => return Ok(()),
+        };
+
+        let published_path = self.public_key_path(repo_config);
+        let published = match std::fs::read_to_string(&published_path) {
+            Ok(text) => Some(text),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        if published.as_ref() == Some(public_key) {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(tmp_path)?;
+        let mut file = tempfile::tempfile_in(tmp_path)?;
+        file.write_all(public_key.as_bytes())?;
+        file.seek(SeekFrom::Start(0))?;
+        destination.upload(path, file)?;
+        if published.is_some() {
+            destination.invalidate(vec![path.clone()])?;
+        }
+
+        std::fs::write(&published_path, public_key)
+    }
+
+    fn manifest_path(&self, repo_config: &RepositoryConfig) -> String {
+        format!(
+            "{}/{}.manifest",
+            self.config().general.data_path,
+            repo_config.name
+        )
+    }
+
+    ///'hash size path' of every published file, sorted by path, pending packages are not
+    ///published yet so they are left out
+    fn manifest(repo_config: &RepositoryConfig, repo: &Repository, pending: &[String]) -> String {
+        let source = &repo_config.source;
+        let pending: HashSet<&String> = pending.iter().collect();
+        let mut lines: BTreeMap<String, String> = BTreeMap::new();
+        for collection in &repo.collections {
+            let packages = collection
+                .packages
+                .iter()
+                .filter(|x| !pending.contains(&x.path))
+                .map(|x| (&x.path, x.architecture.as_str(), &x.hash, x.size));
+            let indexes = collection
+                .indexes
+                .iter()
+                .map(|x| (&x.path, "", &x.hash, x.size));
+            for (path, architecture, hash, size) in packages.chain(indexes) {
+                let path = arch_path(
+                    source.destination_path(path),
+                    architecture,
+                    repo_config.destination.arch_prefix,
+                );
+                lines.insert(path.clone(), format!("{} {} {}\n", hash, size, path));
+            }
+        }
+        lines.into_values().collect()
+    }
+
+    ///uploads the manifest, only when it differs from the published one
+    fn publish_manifest(
+        &self,
+        repo_config: &RepositoryConfig,
+        repo: &Repository,
+        pending: &[String],
+        destination: &mut dyn Destination,
+        tmp_path: &str,
+    ) -> Result<(), std::io::Error> {
+        let path = match &repo_config.manifest_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let manifest = SyncManager::manifest(repo_config, repo, pending);
+
+        let published_path = self.manifest_path(repo_config);
+        let published = match std::fs::read_to_string(&published_path) {
+            Ok(text) => Some(text),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        if published.as_ref() == Some(&manifest) {
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(tmp_path)?;
+        let mut file = tempfile::tempfile_in(tmp_path)?;
+        file.write_all(manifest.as_bytes())?;
+        file.seek(SeekFrom::Start(0))?;
+        destination.upload(path, file)?;
+        if published.is_some() {
+            destination.invalidate(vec![path.clone()])?;
+        }
+
+        std::fs::write(&published_path, manifest)
+    }
+
+    fn extra_files_path(&self, repo_config: &RepositoryConfig) -> String {
+        format!(
+            "{}/{}.extra",
+            self.config().general.data_path,
+            repo_config.name
+        )
+    }
+
+    ///uploads the extra files on every sync, missing ones are skipped. They are not part of the
+    ///metadata, so they are never deleted, and only changed ones are invalidated. Files the
+    ///upstream indexes list are checked against their checksum
+    fn publish_extra_files(
+        &self,
+        repo_config: &RepositoryConfig,
+        repo: &Repository,
+        fetcher: &dyn Fetcher,
+        destination: &mut dyn Destination,
+        tmp_path: &str,
+    ) -> Result<(), SyncError> {
+        if repo_config.extra_files.is_empty() {
+            return Ok(());
+        }
+        //'hash path' of the last published files
+        let extra_files_path = self.extra_files_path(repo_config);
+        let published = match std::fs::read_to_string(&extra_files_path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let published: BTreeMap<&str, &str> = published
+            .lines()
+            .filter_map(|x| x.split_once(' '))
+            .map(|(hash, path)| (path, hash))
+            .collect();
+
+        std::fs::create_dir_all(tmp_path)?;
+        let listed: BTreeMap<&str, &IndexFile> = repo
+            .collections
+            .iter()
+            .flat_map(|c| &c.indexes)
+            .map(|x| (x.path.as_str(), x))
+            .collect();
+        let mut hashes: Vec<String> = vec![];
+        let mut changed: Vec<String> = vec![];
+        for path in &repo_config.extra_files {
+            let url = format!("{}/{}", repo_config.source.endpoint, path);
+            let mut reader = match fetcher.fetch(&url) {
+                Ok(reader) => reader,
+                Err(err) if err.code == 404 => {
+                    human_println!("skipping extra file '{}': {}", path, err.error);
+                    continue;
+                }
+                Err(err) => return Err(SyncError::fetch(path, err)),
+            };
+            let mut file = tempfile::tempfile_in(tmp_path)?;
+            std::io::copy(&mut reader, &mut file)?;
+            if let Some(index) = listed.get(path.as_str()) {
+                file.seek(SeekFrom::Start(0))?;
+                let found = index.hash.compute(&mut file)?;
+                if found != index.hash {
+                    return Err(SyncError::HashMismatch {
+                        path: url,
+                        expected: index.hash.to_string(),
+                        found: found.to_string(),
+                    });
+                }
+            }
+            file.seek(SeekFrom::Start(0))?;
+            let hash = Hash::create_sha256_hash(&mut file)?.to_string();
+            file.seek(SeekFrom::Start(0))?;
+            destination.upload(path, file)?;
+            if published.get(path.as_str()) != Some(&hash.as_str()) {
+                changed.push(path.clone());
+            }
+            hashes.push(format!("{} {}", hash, path));
+        }
+        destination.invalidate(changed)?;
+
+        std::fs::write(&extra_files_path, hashes.join("\n"))?;
+        Ok(())
+    }
+
+    fn copy(
+        tmp_path: &str,
+        source_endpoint: &str,
+        fetcher: &dyn Fetcher,
+        destination: &mut dyn Destination,
+        copy_list: Vec<CopyOperation>,
+        options: &CopyOptions,
+    ) -> Result<CopyResult, SyncError> {
+        let destination_name = destination.name();
+        SyncManager::copy_internal(
+            tmp_path,
+            source_endpoint,
+            fetcher,
+            destination,
+            copy_list,
+            options,
+        )
+        .map_err(|err| {
+            err.context(&format!(
+                "failed to copy {} to {}",
+                source_endpoint, destination_name
+            ))
+        })
+    }
+
+    fn copy_internal(
+        tmp_path: &str,
+        source_endpoint: &str,
+        fetcher: &dyn Fetcher,
+        destination: &mut dyn Destination,
+        copy_list: Vec<CopyOperation>,
+        options: &CopyOptions,
+    ) -> Result<CopyResult, SyncError> {
+        let mut copy_result = CopyResult::default();
+        std::fs::create_dir_all(tmp_path).expect("unable to create tmp_path");
+
+        for operation in copy_list {
+            let mut prefixed;
+            let destination: &mut dyn Destination =
+                if options.arch_prefix && !operation.architecture.is_empty() {
+                    prefixed = PrefixDestination::new(&mut *destination, &operation.architecture);
+                    &mut prefixed
+                } else {
+                    &mut *destination
+                };
+            let copy_operation = |destination: &mut dyn Destination| {
+                SyncManager::copy_operation(
+                    tmp_path,
+                    source_endpoint,
+                    fetcher,
+                    destination,
+                    &operation,
+                    options.verify_size,
+                    options.parallel_hashing,
+                )
+            };
+            let mut result = copy_operation(destination);
+            //a local file would fail the same way again
+            let mut attempt = 0;
+            while attempt < options.copy_retries && operation.local_file.is_none() {
+                match &result {
+                    Err(err @ SyncError::HashMismatch { .. })
+                    | Err(err @ SyncError::SizeMismatch { .. }) => {
+                        attempt += 1;
+                        human_println!(
+                            "{}, downloading again in {}s ({}/{})...",
+                            err,
+                            options.retry_sleep.as_secs(),
+                            attempt,
+                            options.copy_retries
+                        );
+                    }
+                    _ => break,
+                }
+                thread::sleep(options.retry_sleep);
+                result = copy_operation(destination);
+            }
+            if result.is_ok() || options.best_effort {
+                options.progress.file_done(operation.size);
+            }
+            match result {
+                Ok(size) => {
+                    if operation.is_replace {
+                        copy_result.invalidations.push(arch_path(
+                            &operation.path,
+                            &operation.architecture,
+                            options.arch_prefix,
+                        ));
+                    }
+                    copy_result.files += 1;
+                    copy_result.bytes += size;
+                }
+                Err(err) if options.best_effort => {
+                    human_println!("skipping '{}': {}", operation.path, err);
+                    copy_result
+                        .failures
+                        .push((operation.path.clone(), err.to_string()));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(copy_result)
+    }
+
+    ///validates and uploads a single file, returns the copied size
+    fn copy_operation(
+        tmp_path: &str,
+        source_endpoint: &str,
+        fetcher: &dyn Fetcher,
+        destination: &mut dyn Destination,
+        operation: &CopyOperation,
+        verify_size: bool,
+        parallel_hashing: bool,
+    ) -> Result<u64, SyncError> {
+        let mut tmp_file;
+        if let Some(local_file) = &operation.local_file {
+            let result = File::open(local_file);
+            if let Err(err) = result {
+                return Err(SyncError::Io(std::io::Error::new(
+                    err.kind(),
+                    format!("cannot copy file '{}': {}", local_file, err),
+                )));
+            }
+            tmp_file = result.unwrap();
+            if state::is_compressed(local_file) {
+                //only the stored state is compressed, the upload is the original file
+                let file_name = Path::new(local_file).file_name().unwrap().to_string_lossy();
+                let decompressed_path = format!("{}/{}.decompressed", tmp_path, file_name);
+                let mut output = File::create(&decompressed_path)?;
+                std::io::copy(&mut GzDecoder::new(tmp_file), &mut output)?;
+                tmp_file = File::open(&decompressed_path)?;
+                std::fs::remove_file(&decompressed_path)?;
+            }
+        } else {
+            let url = format!("{}/{}", source_endpoint, operation.path);
+            //a named file, so an interrupted download is resumed by the next retry
+            let partial_path = partial_path(tmp_path, &url);
+            let result = fetcher.download(&url, Path::new(&partial_path));
+            if let Err(err) = result {
+                let _ = std::fs::remove_file(&partial_path);
+                return Err(SyncError::fetch(&operation.path, err));
+            }
+            tmp_file = File::open(&partial_path)?;
+            //the open handle keeps the content readable until the upload completes
+            std::fs::remove_file(&partial_path)?;
+        }
+
+        let computed_hash = if parallel_hashing && operation.size >= PARALLEL_HASHING_MIN_SIZE {
+            operation.hash.compute_read_ahead(&tmp_file)?
+        } else {
+            operation.hash.compute(&mut tmp_file)?
+        };
+        if computed_hash != operation.hash {
+            return Err(SyncError::HashMismatch {
+                path: format!("{}/{}", source_endpoint, operation.path),
+                expected: operation.hash.to_string(),
+                found: computed_hash.to_string(),
+            });
+        }
+
+        let tmp_file_size = tmp_file.metadata()?.len();
+        if operation.size != tmp_file_size {
+            let err = SyncError::SizeMismatch {
+                path: operation.path.clone(),
+                expected: operation.size,
+                found: tmp_file_size,
+            };
+            if verify_size {
+                return Err(err);
+            }
+            human_println!("warning: {}, accepted as the hash matches", err);
+        }
+
+        tmp_file.seek(SeekFrom::Start(0))?;
+        destination.upload(&operation.path, tmp_file)?;
+        Ok(tmp_file_size)
+    }
+
+    ///splits packages built before cutoff, in seconds since the epoch, from the others
+    fn split_older(
+        repo: &Repository,
+        copy_list: Vec<CopyOperation>,
+        cutoff: u64,
+    ) -> (Vec<CopyOperation>, Vec<CopyOperation>) {
+        let build_times: BTreeMap<&str, u64> = repo
+            .collections
+            .iter()
+            .flat_map(|c| &c.packages)
+            .filter(|p| p.build_time > 0)
+            .map(|p| (p.path.as_str(), p.build_time))
+            .collect();
+        copy_list
+            .into_iter()
+            .partition(|x| !matches!(build_times.get(x.path.as_str()), Some(t) if *t < cutoff))
+    }
+
+    fn repo_diff(
+        repo: &Repository,
+        current_repo: Repository,
+        verbose: bool,
+    ) -> (
+        Vec<CopyOperation>,
+        Vec<DeleteOperation>,
+        Vec<CopyOperation>,
+        Vec<DeleteOperation>,
+    ) {
+        let mut packages_copy_list: Vec<CopyOperation> = Vec::new();
+        let mut packages_delete_list: Vec<DeleteOperation> = Vec::new();
+
+        let mut index_copy_list: Vec<CopyOperation> = Vec::new();
+        let mut index_delete_list: Vec<DeleteOperation> = Vec::new();
+
+        for collection in &repo.collections {
+            let empty_collection = Collection::empty(&collection.target);
+            let current_collection = current_repo
+                .collections
+                .iter()
+                .find(|x| x.target == collection.target)
+                .unwrap_or(&empty_collection);
+
+            let new_packages: BTreeMap<String, &Package> = collection
+                .packages
+                .iter()
+                .map(|x| (x.path.clone(), x))
+                .collect();
+
+            let current_packages: BTreeMap<String, &Package> = current_collection
+                .packages
+                .iter()
+                .map(|x| (x.path.clone(), x))
+                .collect();
+
+            packages_copy_list.append(
+                &mut new_packages
+                    .iter()
+                    .filter(|&(key, new_package)| {
+                        if let Some(current_package) = current_packages.get(key) {
+                            //updated package or same old?
+                            let changed = !current_package.same_file(new_package);
+                            if verbose && changed {
+                                human_println!(
+                                    "package {} changed hash from {} to {}, size from {} to {}",
+                                    key,
+                                    current_package.hash,
+                                    new_package.hash,
+                                    current_package.size,
+                                    new_package.size
+                                );
+                            }
+                            changed
+                        } else {
+                            //brand new package/version
+                            if verbose {
+                                human_println!("package {} is new", key);
+                            }
+                            true
+                        }
+                    })
+                    .map(|(key, new_package)| CopyOperation {
+                        path: new_package.path.clone(),
+                        hash: new_package.hash.clone(),
+                        is_replace: current_packages.contains_key(key),
+                        local_file: None,
+                        size: new_package.size,
+                        architecture: new_package.architecture.clone(),
+                    })
+                    .collect(),
+            );
+
+            packages_delete_list.append(
+                &mut current_packages
+                    .iter()
+                    //skip every path still in use
+                    .filter(|&(key, _)| {
+                        let removed = !new_packages.contains_key(key);
+                        if verbose && removed {
+                            human_println!("package {} is no longer referenced", key);
+                        }
+                        removed
+                    })
+                    .map(|(_key, current_package)| DeleteOperation {
+                        path: current_package.path.clone(),
+                        architecture: current_package.architecture.clone(),
+                        size: current_package.size,
+                    })
+                    .collect(),
+            );
+
+            let new_indexes: BTreeMap<String, IndexFile> = collection
+                .indexes
+                .iter()
+                .map(|x| (x.path.clone(), x.clone()))
+                .collect();
+
+            let current_indexes: BTreeMap<String, IndexFile> = current_collection
+                .indexes
+                .iter()
+                .map(|x| (x.path.clone(), x.clone()))
+                .collect();
+
+            index_copy_list.append(
+                &mut collection
+                    .indexes
+                    .iter()
+                    //skip unchanged indexes
+                    .filter(|new_index| {
+                        if let Some(current_index) = current_indexes.get(&new_index.path) {
+                            //updated index
+                            let changed = !new_index.same_content(current_index);
+                            if verbose {
+                                if changed {
+                                    human_println!(
+                                        "index {} changed hash from {} to {}",
+                                        new_index.path,
+                                        current_index.hash,
+                                        new_index.hash
+                                    );
+                                } else {
+                                    human_println!("index {} unchanged", new_index.path);
+                                }
+                            }
+                            changed
+                        } else {
+                            //brand new
+                            if verbose {
+                                human_println!("index {} is new", new_index.path);
+                            }
+                            true
+                        }
+                    })
+                    .map(|new_index| {
+                        if current_indexes.contains_key(&new_index.path) {
+                            CopyOperation {
+                                path: new_index.path.clone(),
+                                hash: new_index.hash.clone(),
+                                is_replace: true,
+                                local_file: Some(new_index.file_path.clone()),
+                                size: new_index.size,
+                                architecture: String::new(),
+                            }
+                        } else {
+                            CopyOperation {
+                                path: new_index.path.clone(),
+                                hash: new_index.hash.clone(),
+                                is_replace: false,
+                                local_file: Some(new_index.file_path.clone()),
+                                size: new_index.size,
+                                architecture: String::new(),
+                            }
+                        }
+                    })
+                    .collect(),
+            );
+
+            index_delete_list.append(
+                &mut current_collection
+                    .indexes
+                    .iter()
+                    //skip still used paths
+                    .filter(|x| {
+                        let removed = !new_indexes.contains_key(&x.path);
+                        if verbose && removed {
+                            human_println!("index {} is no longer referenced", x.path);
+                        }
+                        removed
+                    })
+                    .map(|x| DeleteOperation {
+                        path: x.path.clone(),
+                        architecture: String::new(),
+                        size: x.size,
+                    })
+                    .collect(),
+            );
+        }
+
+        (
+            SyncManager::deduplicate_list(packages_copy_list),
+            SyncManager::deduplicate_list(packages_delete_list),
+            SyncManager::deduplicate_list(index_copy_list),
+            SyncManager::deduplicate_list(index_delete_list),
+        )
+    }
+
+    fn deduplicate_list<T>(list: Vec<T>) -> Vec<T>
+    where
+        T: Eq + Clone,
+    {
+        let mut tmp: Vec<T> = Vec::new();
+        list.into_iter().for_each(|x| {
+            if !tmp.contains(&x) {
+                tmp.push(x);
+            }
+        });
+        tmp
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::config::{
+        Config, DestinationConfig, GeneralConfig, LocalDestination, RepositoryConfig, SourceConfig,
+    };
+    use crate::destination::InvalidationsInProgress;
+    use crate::destination::{Destination, MemoryDestination};
+    use crate::error::SyncError;
+    use crate::fetcher::{FetchError, MockFetcher};
+    use crate::packages::{Collection, Hash, IndexFile, Package, Repository, Signature, Target};
+    use crate::state;
+    use crate::sync::{
+        check_free_inodes, client_options, entry_points, invalidation_paths, partial_path,
+        CopyOperation, CopyOptions, Lock, MockTimeProvider, RealTimeProvider, RepoStatus,
+        SyncManager, SyncProgress, SyncReport,
+    };
+    use mockall::predicate;
+    use std::collections::{BTreeMap, HashSet};
+    use std::fs::File;
+    use std::ops::Add;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    pub fn create_config(tmp_dir: &TempDir) -> Config {
+        let config = Config {
+            general: GeneralConfig {
+                data_path: format!("{}/data", tmp_dir.path().to_str().unwrap()),
+                tmp_path: format!("{}/tmp", tmp_dir.path().to_str().unwrap()),
+                bind_address: "".to_string(),
+                timeout: 0,
+                connect_timeout: None,
+                rebase_redirects: false,
+                max_retries: 0,
+                retry_sleep: 0,
+                min_sync_delay: 10,
+                max_sync_delay: 30,
+                initial_sync_jitter: false,
+                scheduler_tick_secs: 10,
+                max_delete_ratio: 0.5,
+                max_concurrent_invalidations: 15,
+                verify_size: true,
+                min_hash_strength: None,
+                parallel_hashing: false,
+                key_expiry_warning_days: 30,
+                copy_retries: 2,
+                http2_prior_knowledge: false,
+                pool_idle_timeout: None,
+                pool_max_idle_per_host: None,
+                metadata_generations: 1,
+                report_path: None,
+                report_retention: 10,
+                min_free_inodes: None,
+            },
+            repo: vec![RepositoryConfig {
+                name: "test-ubuntu".to_string(),
+                source: SourceConfig {
+                    endpoint: "http://fake-url/rc".to_string(),
+                    kind: "debian".to_string(),
+                    public_pgp_key: None,
+                    username: None,
                     password: None,
                     authorization_file: None,
+                    packages_base: None,
+                    strict_signatures: false,
+                    signature_hash_algorithms: vec![],
+                    metadata_path: None,
+                    strip_prefix: None,
+                    required_indexes: vec![],
+                    headers: BTreeMap::new(),
+                    channels: vec![],
+                },
+                destination: DestinationConfig {
+                    s3: None,
+                    local: None,
+                    arch_prefix: false,
+                },
+                versions: vec!["focal".into()],
+                tmp_path: None,
+                pin: false,
+                best_effort: false,
+                metadata_only: false,
+                publish_public_key: None,
+                manifest_path: None,
+                publish_variants: None,
+                architectures: None,
+                max_requests_per_sec: None,
+                max_download_bytes_per_sec: None,
+                max_concurrent_index_fetches: None,
+                wildcard_invalidation: false,
+                append_only: false,
+                skip_unchanged: false,
+                verify_indexes: false,
+                allow_pool_conflicts: false,
+                max_total_size_bytes: None,
+                delete_grace_secs: 0,
+                extra_files: vec![],
+            }],
+        };
+
+        std::fs::create_dir_all(&config.general.data_path).unwrap();
+        std::fs::create_dir_all(&config.general.tmp_path).unwrap();
+
+        config
+    }
+
+    #[test]
+    fn load_current_returns_empty_when_missing() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = create_config(&tmp_dir);
+
+        let sync_manager = SyncManager {
+            config: RwLock::new(Arc::new(config.clone())),
+            lock: Lock::new(),
+            time_provider: Arc::new(RealTimeProvider {}),
+            sync_map: Arc::new(Mutex::new(Default::default())),
+            allow_mass_delete: false,
+            verbose: false,
+            print_diff: false,
+            since: None,
+            last_tick: Arc::new(Mutex::new(None)),
+            invalidations: Arc::new(InvalidationsInProgress::new(1)),
+        };
+        let (repository, _saved_metadata_store) = sync_manager
+            .load_current(&config.repo.get(0).unwrap())
+            .unwrap();
+
+        assert_eq!("test-ubuntu", repository.name);
+        assert_eq!(0, repository.collections.len());
+    }
+
+    #[test]
+    fn mass_delete_is_refused() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.general.max_delete_ratio = 0.4;
+        let repo_config = config.repo.first().unwrap().clone();
+
+        let mut sync_manager = SyncManager {
+            config: RwLock::new(Arc::new(config.clone())),
+            lock: Lock::new(),
+            sync_map: Arc::new(Mutex::new(Default::default())),
+            time_provider: Arc::new(RealTimeProvider {}),
+            allow_mass_delete: false,
+            verbose: false,
+            print_diff: false,
+            since: None,
+            last_tick: Arc::new(Mutex::new(None)),
+            invalidations: Arc::new(InvalidationsInProgress::new(1)),
+        };
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+
+        //one of the two packages disappears upstream
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let result =
+            sync_manager.sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config);
+        assert!(result.is_err());
+        let (contents, deletions, _) = destination.explode();
+        assert_eq!(0, contents.len());
+        assert_eq!(0, deletions.len());
+
+        sync_manager.set_allow_mass_delete(true);
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+        let (_, deletions, _) = destination.explode();
+        assert_eq!(1, deletions.len());
+    }
+
+    #[test]
+    fn public_key_is_published_once() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        let public_key = std::fs::read_to_string("samples/public-key").unwrap();
+        let repo_config = config.repo.first_mut().unwrap();
+        repo_config.source.public_pgp_key = Some(public_key.clone());
+        repo_config.publish_public_key = Some("Release.key".into());
+        let repo_config = repo_config.clone();
+
+        let sync_manager = SyncManager {
+            config: RwLock::new(Arc::new(config.clone())),
+            lock: Lock::new(),
+            sync_map: Arc::new(Mutex::new(Default::default())),
+            time_provider: Arc::new(RealTimeProvider {}),
+            allow_mass_delete: false,
+            verbose: false,
+            print_diff: false,
+            since: None,
+            last_tick: Arc::new(Mutex::new(None)),
+            invalidations: Arc::new(InvalidationsInProgress::new(1)),
+        };
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .publish_public_key(&repo_config, &mut destination, &config.general.tmp_path)
+            .unwrap();
+        let (contents, _, invalidations) = destination.explode();
+        assert_eq!(
+            public_key.as_bytes(),
+            contents.get("ubuntu/Release.key").unwrap().as_slice()
+        );
+        assert!(invalidations.is_empty());
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .publish_public_key(&repo_config, &mut destination, &config.general.tmp_path)
+            .unwrap();
+        let (contents, _, _) = destination.explode();
+        assert!(contents.is_empty());
+    }
+
+    #[test]
+    fn sync_debian_repo_from_scratch() {
+        let mut mock_fetcher = MockFetcher::new();
+
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = create_config(&tmp_dir);
+
+        let repo_config = config.repo.get(0).unwrap();
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+
+        let sync_manager = SyncManager {
+            config: RwLock::new(Arc::new(config.clone())),
+            lock: Lock::new(),
+            sync_map: Arc::new(Mutex::new(Default::default())),
+            allow_mass_delete: false,
+            verbose: false,
+            print_diff: false,
+            since: None,
+            last_tick: Arc::new(Mutex::new(None)),
+            invalidations: Arc::new(InvalidationsInProgress::new(1)),
+            time_provider: Arc::new(RealTimeProvider {}),
+        };
+        let report = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, repo_config)
+            .unwrap();
+        assert_eq!(9, report.metadata_fetched);
+        assert_eq!(0, report.metadata_unchanged);
+
+        destination.print();
+
+        let (contents, deletions, invalidations) = destination.explode();
+
+        assert_eq!(
+            1868,
+            contents.get("ubuntu/dists/focal/Release").unwrap().len()
+        );
+        assert_eq!(
+            14,
+            contents
+                .get("ubuntu/dists/focal/Release.gpg")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            1868,
+            contents.get("ubuntu/dists/focal/InRelease").unwrap().len()
+        );
+        assert_eq!(
+            1075,
+            contents
+                .get("ubuntu/dists/focal/main/binary-amd64/Packages")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            1075,
+            contents
+                .get("ubuntu/dists/focal/main/binary-amd64/Packages.bz2")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            1075,
+            contents
+                .get("ubuntu/dists/focal/main/binary-amd64/Packages.gz")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            1075,
+            contents
+                .get("ubuntu/dists/focal/main/binary-i386/Packages")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            1075,
+            contents
+                .get("ubuntu/dists/focal/main/binary-i386/Packages.bz2")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            1075,
+            contents
+                .get("ubuntu/dists/focal/main/binary-i386/Packages.gz")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            20,
+            contents
+                .get("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb")
+                .unwrap()
+                .len()
+        );
+        assert_eq!(
+            20,
+            contents
+                .get("ubuntu/pool/service-discover-daemon_0.1.0_amd64.deb")
+                .unwrap()
+                .len()
+        );
+
+        assert_eq!(0, deletions.len());
+        assert_eq!(0, invalidations.len());
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let report = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, repo_config)
+            .unwrap();
+        //only Release.gpg is identical
+        assert_eq!(9, report.metadata_fetched);
+        assert_eq!(1, report.metadata_unchanged);
+
+        destination.print();
+
+        let (contents, deletions, invalidations) = destination.explode();
+        assert_eq!(8, contents.len());
+        assert_eq!(1, deletions.len());
+        assert!(deletions.contains("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb"));
+        assert_eq!(8, invalidations.len());
+        assert!(invalidations.contains("ubuntu/dists/focal/Release"));
+        assert!(!invalidations.contains("ubuntu/dists/focal/Release.gpg"));
+        assert!(invalidations.contains("ubuntu/dists/focal/InRelease"));
+        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-amd64/Packages"));
+        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-amd64/Packages.gz"));
+        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-amd64/Packages.bz2"));
+        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-i386/Packages"));
+        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-i386/Packages.gz"));
+        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-i386/Packages.bz2"));
+    }
+
+    #[test]
+    fn invalidation_paths_are_deduplicated() {
+        assert_eq!(
+            vec![
+                "pool/a.rpm",
+                "repodata/repomd.xml",
+                "repodata/primary.xml.gz"
+            ],
+            invalidation_paths(
+                vec!["pool/a.rpm".into(), "pool/a.rpm".into()],
+                vec![
+                    "repodata/repomd.xml".into(),
+                    "repodata/primary.xml.gz".into(),
+                    "repodata/repomd.xml".into(),
+                ],
+                false,
+            )
+        );
+        assert_eq!(
+            vec!["pool/a.rpm", "repodata/*"],
+            invalidation_paths(
+                vec!["pool/a.rpm".into()],
+                vec![
+                    "repodata/repomd.xml".into(),
+                    "repodata/primary.xml.gz".into()
+                ],
+                true,
+            )
+        );
+        assert_eq!(
+            vec!["dists/focal/*", "dists/focal-updates/*"],
+            invalidation_paths(
+                vec![],
+                vec![
+                    "dists/focal/main/binary-amd64/Packages".into(),
+                    "dists/focal/Release".into(),
+                    "dists/focal-updates/Release".into(),
+                ],
+                true,
+            )
+        );
+        //e.g. a debian-flat repository
+        assert_eq!(
+            vec!["repodata/*", "Release", "Packages"],
+            invalidation_paths(
+                vec![],
+                vec![
+                    "Release".into(),
+                    "repodata/repomd.xml".into(),
+                    "Packages".into(),
+                ],
+                true,
+            )
+        );
+    }
+
+    #[test]
+    fn strict_signatures_reject_unsigned_release() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        let repo_config = config.repo.first_mut().unwrap();
+        repo_config.source.public_pgp_key =
+            Some(std::fs::read_to_string("samples/public-key").unwrap());
+        repo_config.source.strict_signatures = true;
+        let repo_config = repo_config.clone();
+        let sync_manager =
+            SyncManager::new_internal(config, Lock::new(), Arc::new(RealTimeProvider {}));
+
+        //neither InRelease nor Release.gpg are available
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .returning(|url: &str| match url {
+                "http://fake-url/rc/dists/focal/Release" => {
+                    Ok(Box::new(File::open("samples/debian/Release").unwrap()))
+                }
+                _ if url.contains("/Packages") => {
+                    Ok(Box::new(File::open("samples/debian/Packages").unwrap()))
+                }
+                _ => Err(FetchError {
+                    code: 404,
+                    error: "not found".into(),
+                }),
+            });
+
+        let mut destination = MemoryDestination::new("ubuntu");
+        let err = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .err()
+            .unwrap();
+        assert_eq!(
+            "no verified signature for 'dists/focal/Release', required by strict_signatures",
+            err.to_string()
+        );
+        let (contents, _, _) = destination.explode();
+        assert!(contents.is_empty());
+    }
+
+    #[test]
+    fn sync_errors_are_typed() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let sync_manager = SyncManager::new(create_config(&tmp_dir));
+        let err = sync_manager.sync_repo("missing").err().unwrap();
+        assert!(matches!(err, SyncError::NotFound(_)));
+
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .with(predicate::eq("http://fake-url/rc/pool/a.deb"))
+            .returning(|_| Result::Ok(Box::new("corrupted".as_bytes())));
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let err = SyncManager::copy_operation(
+            tmp_dir.path().to_str().unwrap(),
+            "http://fake-url/rc",
+            &mock_fetcher,
+            &mut destination,
+            &CopyOperation {
+                is_replace: false,
+                path: "pool/a.deb".into(),
+                hash: Hash::Sha256 { hex: "0000".into() },
+                size: 9,
+                local_file: None,
+                architecture: String::new(),
+            },
+            true,
+            false,
+        )
+        .err()
+        .unwrap();
+        match err {
+            SyncError::HashMismatch { path, expected, .. } => {
+                assert_eq!("http://fake-url/rc/pool/a.deb", path);
+                assert_eq!("sha256:0000", expected);
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        let (contents, _, _) = destination.explode();
+        assert!(contents.is_empty());
+    }
+
+    #[test]
+    fn size_mismatch_tolerated_unless_verified() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let hash = Hash::Sha256 { hex: "".into() }
+            .compute(&mut File::open("samples/fake-package").unwrap())
+            .unwrap();
+        let operation = CopyOperation {
+            is_replace: false,
+            path: "pool/a.deb".into(),
+            hash,
+            size: 1,
+            local_file: Some("samples/fake-package".into()),
+            architecture: String::new(),
+        };
+        let mock_fetcher = MockFetcher::new();
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let err = SyncManager::copy_operation(
+            tmp_dir.path().to_str().unwrap(),
+            "http://fake-url/rc",
+            &mock_fetcher,
+            &mut destination,
+            &operation,
+            true,
+            false,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(err, SyncError::SizeMismatch { expected: 1, .. }));
+
+        let size = SyncManager::copy_operation(
+            tmp_dir.path().to_str().unwrap(),
+            "http://fake-url/rc",
+            &mock_fetcher,
+            &mut destination,
+            &operation,
+            false,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::metadata("samples/fake-package").unwrap().len(),
+            size
+        );
+        let (contents, _, _) = destination.explode();
+        assert!(contents.contains_key("ubuntu/pool/a.deb"));
+    }
+
+    #[test]
+    fn progress_is_tracked_while_copying() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let hash = Hash::Sha256 { hex: "".into() }
+            .compute(&mut File::open("samples/fake-package").unwrap())
+            .unwrap();
+        let size = std::fs::metadata("samples/fake-package").unwrap().len();
+        let operation = |path: &str, local_file: &str| CopyOperation {
+            is_replace: false,
+            path: path.into(),
+            hash: hash.clone(),
+            size,
+            local_file: Some(local_file.into()),
+            architecture: String::new(),
+        };
+        let copy_list = || {
+            vec![
+                operation("pool/a.deb", "samples/fake-package"),
+                operation("pool/b.deb", "samples/missing-package"),
+                operation("pool/c.deb", "samples/fake-package"),
+            ]
+        };
+        let mock_fetcher = MockFetcher::new();
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+
+        let progress = SyncProgress::new(3, 3 * size);
+        SyncManager::copy(
+            tmp_dir.path().to_str().unwrap(),
+            "http://fake-url/rc",
+            &mock_fetcher,
+            &mut destination,
+            copy_list(),
+            &CopyOptions {
+                best_effort: true,
+                verify_size: true,
+                parallel_hashing: false,
+                copy_retries: 0,
+                retry_sleep: Duration::from_secs(0),
+                arch_prefix: false,
+                progress: &progress,
+            },
+        )
+        .unwrap();
+        //skipped files count as done
+        assert_eq!(3, progress.files_done.load(Ordering::SeqCst));
+        assert_eq!(3 * size, progress.bytes_done.load(Ordering::SeqCst));
+
+        let progress = SyncProgress::new(3, 3 * size);
+        SyncManager::copy(
+            tmp_dir.path().to_str().unwrap(),
+            "http://fake-url/rc",
+            &mock_fetcher,
+            &mut destination,
+            copy_list(),
+            &CopyOptions {
+                best_effort: false,
+                verify_size: true,
+                parallel_hashing: false,
+                copy_retries: 0,
+                retry_sleep: Duration::from_secs(0),
+                arch_prefix: false,
+                progress: &progress,
+            },
+        )
+        .err()
+        .unwrap();
+        assert_eq!(1, progress.files_done.load(Ordering::SeqCst));
+        assert_eq!(size, progress.bytes_done.load(Ordering::SeqCst));
+
+        let config = create_config(&tmp_dir);
+        let sync_manager = SyncManager::new(config);
+        let guard = sync_manager.track_progress("test-ubuntu", Arc::new(progress));
+        let status = sync_manager.get_status("test-ubuntu").unwrap();
+        assert_eq!(
+            3,
+            status.progress.unwrap().files_total.load(Ordering::SeqCst)
+        );
+        drop(guard);
+        assert!(sync_manager
+            .get_status("test-ubuntu")
+            .unwrap()
+            .progress
+            .is_none());
+    }
+
+    #[test]
+    fn prune_deletes_only_unreferenced_objects() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].destination.local = Some(LocalDestination {
+            path: "/mirror".into(),
+            gzip_indexes: false,
+            verify_uploads: false,
+            serve_prefix: None,
+        });
+        let mut nested = config.repo[0].clone();
+        nested.name = "nested".into();
+        nested.destination.local.as_mut().unwrap().path = "/mirror/nested".into();
+        config.repo.push(nested);
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let err = sync_manager
+            .prune_internal(&mut destination, &repo_config, true)
+            .err()
+            .unwrap();
+        assert!(matches!(err, SyncError::Refused(_)));
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+        for path in &["pool/orphan.deb", "nested/pool/other.deb"] {
+            destination
+                .upload(path, File::open("samples/fake-package").unwrap())
+                .unwrap();
+        }
+
+        let orphans = sync_manager
+            .prune_internal(&mut destination, &repo_config, false)
+            .unwrap();
+        assert_eq!(vec!["pool/orphan.deb"], orphans);
+        let (_, deletions, _) = destination.explode();
+        assert!(deletions.is_empty());
+
+        sync_manager
+            .prune_internal(&mut destination, &repo_config, true)
+            .unwrap();
+        let (_, deletions, _) = destination.explode();
+        assert_eq!(1, deletions.len());
+        assert!(deletions.contains("ubuntu/pool/orphan.deb"));
+    }
+
+    #[test]
+    fn strict_signatures_of_channels() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].source.kind = "redhat".into();
+        config.repo[0].source.channels = vec!["os".into(), "updates".into()];
+        let repo_config = config.repo.first().unwrap().clone();
+
+        let collection = |channel: &str| {
+            let index = |file_name: &str| IndexFile {
+                file_path: "".into(),
+                path: format!("{}/repodata/{}", channel, file_name),
+                size: 1,
+                hash: Hash::Sha256 { hex: "00".into() },
+                signature: Signature::None,
+            };
+            Collection {
+                target: Target {
+                    release_name: channel.into(),
+                    architectures: vec![],
+                },
+                indexes: vec![index("repomd.xml"), index("primary.xml.gz")],
+                packages: vec![],
+            }
+        };
+        let repository = Repository {
+            name: repo_config.name.clone(),
+            collections: vec![collection("os"), collection("updates")],
+        };
+        let entry_points = entry_points(&repo_config);
+
+        let mut verified: HashSet<&str> = HashSet::new();
+        verified.insert("os/repodata/repomd.xml");
+        let err = SyncManager::require_signed_indexes(&repository, &verified, &entry_points)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("'updates/repodata/repomd.xml'"));
+        verified.insert("updates/repodata/repomd.xml");
+        SyncManager::require_signed_indexes(&repository, &verified, &entry_points).unwrap();
+
+        let err = SyncManager::require_indexes(
+            &repository,
+            &["repomd.xml.asc".to_string()],
+            &entry_points,
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("next to 'os/repodata/repomd.xml'"));
+    }
+
+    #[test]
+    fn weak_hashes_are_refused_by_policy() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.general.min_hash_strength = Some("sha256".into());
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        //the debian sample has sha256 checksums only
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+
+        let mut repository = sync_manager.load_current(&repo_config).unwrap().0;
+        assert!(SyncManager::require_hash_strength(&repository, "sha256").is_ok());
+        repository.collections[0].packages[0].hash = Hash::Sha1 { hex: "00".into() };
+        let path = repository.collections[0].packages[0].path.clone();
+        let err = SyncManager::require_hash_strength(&repository, "sha256")
+            .err()
+            .unwrap();
+        assert_eq!(
+            format!(
+                "'{}' only has a sha1 checksum, weaker than min_hash_strength sha256",
+                path
+            ),
+            err.to_string()
+        );
+        assert!(SyncManager::require_hash_strength(&repository, "sha1").is_ok());
+    }
+
+    #[test]
+    fn zero_byte_index_is_copied() {
+        //empty components list a zero-length Packages, e.g. '0 main/binary-i386/Packages'
+        assert!(crate::debian::parse_packages(&b""[..]).unwrap().is_empty());
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let empty_path = tmp_dir.path().join("Packages");
+        File::create(&empty_path).unwrap();
+        let hash = Hash::Sha256 {
+            hex: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".into(),
+        };
+        assert_eq!(
+            hash,
+            hash.compute(&mut File::open(&empty_path).unwrap()).unwrap()
+        );
+
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .with(predicate::eq(
+                "http://fake-url/rc/dists/focal/main/binary-i386/Packages",
+            ))
+            .returning(|_| Result::Ok(Box::new(&b""[..])));
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        for local_file in &[None, Some(empty_path.to_str().unwrap().to_string())] {
+            let size = SyncManager::copy_operation(
+                tmp_dir.path().to_str().unwrap(),
+                "http://fake-url/rc",
+                &mock_fetcher,
+                &mut destination,
+                &CopyOperation {
+                    is_replace: false,
+                    path: "dists/focal/main/binary-i386/Packages".into(),
+                    hash: hash.clone(),
+                    size: 0,
+                    local_file: local_file.clone(),
+                    architecture: String::new(),
+                },
+                true,
+                false,
+            )
+            .unwrap();
+            assert_eq!(0, size);
+        }
+        let (contents, _, _) = destination.explode();
+        assert!(contents["ubuntu/dists/focal/main/binary-i386/Packages"].is_empty());
+    }
+
+    #[test]
+    fn extra_files_are_published() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].extra_files = vec!["keyring.gpg".into(), "README".into()];
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        let not_found = |_: &str| -> Result<Box<dyn std::io::Read>, FetchError> {
+            Result::Err(FetchError {
+                code: 404,
+                error: "not found".to_string(),
+            })
+        };
+        let sync = |destination: &mut MemoryDestination| {
+            let mut mock_fetcher = MockFetcher::new();
+            mock_fetcher
+                .expect_fetch()
+                .with(predicate::eq("http://fake-url/rc/keyring.gpg"))
+                .returning(|_| Result::Ok(Box::new(File::open("samples/public-key").unwrap())));
+            mock_fetcher
+                .expect_fetch()
+                .with(predicate::eq("http://fake-url/rc/README"))
+                .returning(not_found);
+            setup_fetcher(
+                &mut mock_fetcher,
+                "samples/debian/Release",
+                "samples/debian/Packages",
+            );
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), destination, &repo_config)
+                .unwrap();
+        };
+
+        //a missing file is skipped
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync(&mut destination);
+        let (contents, _, invalidations) = destination.explode();
+        assert_eq!(
+            std::fs::read("samples/public-key").unwrap(),
+            contents["ubuntu/keyring.gpg"]
+        );
+        assert!(!contents.contains_key("ubuntu/README"));
+        assert!(invalidations.iter().any(|x| x.ends_with("keyring.gpg")));
+
+        //uploaded again, even if the metadata didn't change, but only invalidated when changed
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync(&mut destination);
+        let (contents, deletions, invalidations) = destination.explode();
+        assert!(contents.contains_key("ubuntu/keyring.gpg"));
+        assert!(deletions.is_empty());
+        assert!(!invalidations.iter().any(|x| x.ends_with("keyring.gpg")));
+
+        //files listed by the upstream indexes must match their checksum
+        let mut repo_config = repo_config.clone();
+        repo_config.extra_files = vec!["dists/focal/main/binary-amd64/Packages".into()];
+        let (repo, _) = sync_manager.load_current(&repo_config).unwrap();
+        let tmp_path = format!("{}/tmp", tmp_dir.path().to_str().unwrap());
+        let publish = |packages: &'static str| {
+            let mut mock_fetcher = MockFetcher::new();
+            mock_fetcher
+                .expect_fetch()
+                .returning(move |_| Result::Ok(Box::new(File::open(packages).unwrap())));
+            let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+            sync_manager.publish_extra_files(
+                &repo_config,
+                &repo,
+                &mock_fetcher,
+                &mut destination,
+                &tmp_path,
+            )
+        };
+        publish("samples/debian/Packages").unwrap();
+        match publish("samples/debian/Packages.2").err().unwrap() {
+            SyncError::HashMismatch { path, .. } => assert_eq!(
+                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages",
+                path
+            ),
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn pinned_repo_ignores_upstream_changes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo.first_mut().unwrap().pin = true;
+        let repo_config = config.repo.first().unwrap();
+
+        let sync_manager = SyncManager {
+            config: RwLock::new(Arc::new(config.clone())),
+            lock: Lock::new(),
+            sync_map: Arc::new(Mutex::new(Default::default())),
+            allow_mass_delete: false,
+            verbose: false,
+            print_diff: false,
+            since: None,
+            last_tick: Arc::new(Mutex::new(None)),
+            invalidations: Arc::new(InvalidationsInProgress::new(1)),
+            time_provider: Arc::new(RealTimeProvider {}),
+        };
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, repo_config)
+            .unwrap();
+        let (contents, _, _) = destination.explode();
+        assert_eq!(11, contents.len());
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, repo_config)
+            .unwrap();
+
+        let (contents, deletions, invalidations) = destination.explode();
+        assert_eq!(0, contents.len());
+        assert_eq!(0, deletions.len());
+        assert_eq!(0, invalidations.len());
+    }
+
+    #[test]
+    fn best_effort_skips_and_retries_failed_packages() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo.first_mut().unwrap().best_effort = true;
+        let repo_config = config.repo.first().unwrap();
+
+        let sync_manager = SyncManager {
+            config: RwLock::new(Arc::new(config.clone())),
+            lock: Lock::new(),
+            sync_map: Arc::new(Mutex::new(Default::default())),
+            allow_mass_delete: false,
+            verbose: false,
+            print_diff: false,
+            since: None,
+            last_tick: Arc::new(Mutex::new(None)),
+            invalidations: Arc::new(InvalidationsInProgress::new(1)),
+            time_provider: Arc::new(RealTimeProvider {}),
+        };
+
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .with(predicate::eq(
+                "http://fake-url/rc/pool/service-discover-agent_0.1.0_amd64.deb",
+            ))
+            .returning(|_| {
+                Result::Err(FetchError {
+                    code: 500,
+                    error: "broken".to_string(),
+                })
+            });
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let report = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, repo_config)
+            .unwrap();
+
+        assert_eq!(1, report.failures.len());
+        let (contents, _, _) = destination.explode();
+        assert_eq!(10, contents.len());
+        assert!(!contents.contains_key("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb"));
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let report = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, repo_config)
+            .unwrap();
+
+        assert!(report.failures.is_empty());
+        let (contents, _, _) = destination.explode();
+        assert_eq!(1, contents.len());
+        assert!(contents.contains_key("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb"));
+    }
+
+    #[test]
+    fn metadata_only_skips_packages_until_disabled() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo.first_mut().unwrap().metadata_only = true;
+
+        let sync_manager = SyncManager {
+            config: RwLock::new(Arc::new(config.clone())),
+            lock: Lock::new(),
+            sync_map: Arc::new(Mutex::new(Default::default())),
+            allow_mass_delete: false,
+            verbose: false,
+            print_diff: false,
+            since: None,
+            last_tick: Arc::new(Mutex::new(None)),
+            invalidations: Arc::new(InvalidationsInProgress::new(1)),
+            time_provider: Arc::new(RealTimeProvider {}),
+        };
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .sync_repo_internal(
+                Box::new(mock_fetcher),
+                &mut destination,
+                config.repo.first().unwrap(),
+            )
+            .unwrap();
+
+        let (contents, _, _) = destination.explode();
+        assert_eq!(9, contents.len());
+        assert!(!contents.contains_key("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb"));
+
+        config.repo.first_mut().unwrap().metadata_only = false;
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .sync_repo_internal(
+                Box::new(mock_fetcher),
+                &mut destination,
+                config.repo.first().unwrap(),
+            )
+            .unwrap();
+
+        let (contents, _, _) = destination.explode();
+        assert_eq!(2, contents.len());
+        assert!(contents.contains_key("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb"));
+        assert!(contents.contains_key("ubuntu/pool/service-discover-daemon_0.1.0_amd64.deb"));
+    }
+
+    #[test]
+    fn repository_tmp_path_on_another_filesystem() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        //a tmpfs is usually another filesystem than the data_path, so the state is copied
+        let repo_tmp_dir = if Path::new("/dev/shm").is_dir() {
+            tempfile::tempdir_in("/dev/shm").unwrap()
+        } else {
+            tempfile::tempdir().unwrap()
+        };
+        config.repo[0].tmp_path = Some(repo_tmp_dir.path().to_str().unwrap().into());
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        //the second sync replaces the saved state
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        for (release, packages) in &[
+            ("samples/debian/Release", "samples/debian/Packages"),
+            ("samples/debian/Release.2", "samples/debian/Packages.2"),
+        ] {
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+                .unwrap();
+        }
+        let (repo, _) = sync_manager.load_current(&repo_config).unwrap();
+        assert_eq!(1, repo.count_packages());
+    }
+
+    #[test]
+    fn metadata_only_defers_deletes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = create_config(&tmp_dir);
+        let mut repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let agent = "ubuntu/pool/service-discover-agent_0.1.0_amd64.deb";
+        for (release, packages, metadata_only) in &[
+            ("samples/debian/Release", "samples/debian/Packages", false),
+            (
+                "samples/debian/Release.2",
+                "samples/debian/Packages.2",
+                true,
+            ),
+        ] {
+            repo_config.metadata_only = *metadata_only;
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+                .unwrap();
+        }
+        let (contents, deletions, _) = destination.explode();
+        assert!(contents.contains_key(agent));
+        assert!(!deletions.contains(agent));
+        assert!(sync_manager
+            .load_missing_packages(&repo_config)
+            .unwrap()
+            .contains_key("pool/service-discover-agent_0.1.0_amd64.deb"));
+
+        //the package dropped upstream in the meantime is deleted by the next full sync
+        repo_config.metadata_only = false;
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+        let (_, deletions, _) = destination.explode();
+        assert!(deletions.contains(agent));
+        assert!(sync_manager
+            .load_missing_packages(&repo_config)
+            .unwrap()
+            .is_empty());
+    }
+
+    fn setup_fetcher(mock_fetcher: &mut MockFetcher, release: &str, packages: &str) {
+        let packages: String = packages.into();
+        let release: String = release.into();
+        mock_fetcher.expect_fetch().returning(move |url: &str| {
+            Result::Ok(Box::new(match url {
+                "http://fake-url/rc/dists/focal/Release" => File::open(&release).unwrap(),
+                "http://fake-url/rc/dists/focal/Release.gpg" => {
+                    File::open("samples/fake-signature").unwrap()
+                }
+                "http://fake-url/rc/dists/focal/InRelease" => File::open(&release).unwrap(),
+                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages" => {
+                    File::open(&packages).unwrap()
+                }
+                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.bz2" => {
+                    File::open(&packages).unwrap()
+                }
+                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.gz" => {
+                    File::open(&packages).unwrap()
+                }
+                "http://fake-url/rc/dists/focal/main/binary-i386/Packages" => {
+                    File::open(&packages).unwrap()
+                }
+                "http://fake-url/rc/dists/focal/main/binary-i386/Packages.bz2" => {
+                    File::open(&packages).unwrap()
+                }
+                "http://fake-url/rc/dists/focal/main/binary-i386/Packages.gz" => {
+                    File::open(&packages).unwrap()
+                }
+                "http://fake-url/rc/pool/service-discover-agent_0.1.0_amd64.deb" => {
+                    File::open("samples/fake-package").unwrap()
+                }
+                "http://fake-url/rc/pool/service-discover-daemon_0.1.0_amd64.deb" => {
+                    File::open("samples/fake-package").unwrap()
+                }
+                _ => panic!("unexpected url: {}", url),
+            }))
+        });
+    }
+
+    #[test]
+    fn scheduler() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = create_config(&tmp_dir);
+        let secs_offset = Arc::new(AtomicU64::new(0));
+
+        let mut mock = MockTimeProvider::new();
+        {
+            let secs_offset = secs_offset.clone();
+            mock.expect_now().returning(move || {
+                UNIX_EPOCH.add(Duration::from_secs(secs_offset.load(Ordering::SeqCst)))
+            });
+        }
+
+        let sync_manager = SyncManager::new_internal(config.clone(), Lock::new(), Arc::new(mock));
+
+        secs_offset.store(0, Ordering::SeqCst);
+        {
+            let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
+            assert_eq!("test-ubuntu", next_name);
+            assert_eq!(UNIX_EPOCH.add(Duration::from_secs(30 * 60)), next_time);
+        }
+        {
+            sync_manager.queue_sync("test-ubuntu");
+            let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
+            assert_eq!("test-ubuntu", next_name);
+            assert_eq!(UNIX_EPOCH.add(Duration::from_secs(10 * 60)), next_time);
+        }
+        secs_offset.store(60, Ordering::SeqCst);
+        {
+            sync_manager.sync_completed("test-ubuntu", "success");
+            let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
+            assert_eq!("test-ubuntu", next_name);
+            assert_eq!(UNIX_EPOCH.add(Duration::from_secs(31 * 60)), next_time);
+        }
+        {
+            sync_manager.queue_sync("test-ubuntu");
+            let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
+            assert_eq!("test-ubuntu", next_name);
+            assert_eq!(UNIX_EPOCH.add(Duration::from_secs(11 * 60)), next_time);
+        }
+    }
+
+    #[test]
+    fn reload_replaces_repositories() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = create_config(&tmp_dir);
+        let mut mock = MockTimeProvider::new();
+        mock.expect_now()
+            .returning(|| UNIX_EPOCH.add(Duration::from_secs(60)));
+        let sync_manager = SyncManager::new_internal(config.clone(), Lock::new(), Arc::new(mock));
+        assert!(sync_manager.get_status("test-ubuntu").is_some());
+
+        let mut new_config = config.clone();
+        new_config.repo.first_mut().unwrap().name = "test-focal".into();
+        new_config.general.max_delete_ratio = 0.1;
+        assert_eq!(
+            vec!["test-focal"],
+            sync_manager.reload(new_config.clone()).unwrap()
+        );
+
+        assert!(sync_manager.get_status("test-ubuntu").is_none());
+        assert!(sync_manager.get_repo_config("test-ubuntu").is_none());
+        assert_eq!(0.1, sync_manager.config().general.max_delete_ratio);
+        //a new repository is due right away
+        let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
+        assert_eq!("test-focal", next_name);
+        assert_eq!(UNIX_EPOCH.add(Duration::from_secs(60)), next_time);
+
+        //running syncs would write their state to both
+        new_config.general.data_path = format!("{}/other", new_config.general.data_path);
+        new_config.general.max_delete_ratio = 0.2;
+        assert!(sync_manager.reload(new_config).is_err());
+        assert_eq!(0.1, sync_manager.config().general.max_delete_ratio);
+    }
+
+    #[test]
+    fn initial_sync_jitter() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.general.initial_sync_jitter = true;
+
+        let mut mock = MockTimeProvider::new();
+        mock.expect_now().returning(|| UNIX_EPOCH);
+
+        let sync_manager = SyncManager::new_internal(config, Lock::new(), Arc::new(mock));
+        let (_, next_time) = sync_manager.next_repo_to_sync().unwrap();
+        assert!(next_time >= UNIX_EPOCH.add(Duration::from_secs(10 * 60)));
+        assert!(next_time <= UNIX_EPOCH.add(Duration::from_secs(30 * 60)));
+    }
+
+    #[test]
+    fn scheduler_liveness() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = create_config(&tmp_dir);
+
+        let mut mock = MockTimeProvider::new();
+        mock.expect_now()
+            .returning(|| UNIX_EPOCH.add(Duration::from_secs(100)));
+
+        let sync_manager = SyncManager::new_internal(config, Lock::new(), Arc::new(mock));
+        assert!(!sync_manager.is_scheduler_alive());
+
+        *sync_manager.last_tick.lock().unwrap() = Some(UNIX_EPOCH.add(Duration::from_secs(80)));
+        assert!(sync_manager.is_scheduler_alive());
+
+        *sync_manager.last_tick.lock().unwrap() = Some(UNIX_EPOCH.add(Duration::from_secs(60)));
+        assert!(!sync_manager.is_scheduler_alive());
+
+        sync_manager
+            .sync_map
+            .lock()
+            .unwrap()
+            .get_mut("test-ubuntu")
+            .unwrap()
+            .current = RepoStatus::Syncing;
+        assert!(sync_manager.is_scheduler_alive());
+    }
+
+    #[test]
+    fn rollback_restores_previous_generation() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.general.metadata_generations = 2;
+        let repo_config = config.repo.first().unwrap().clone();
+        let data_path = format!("{}/{}", config.general.data_path, repo_config.name);
+        let mut sync_manager = SyncManager::new(config);
+        sync_manager.set_allow_mass_delete(true);
+
+        let sync = |release: &str, packages: &str| {
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+                .unwrap();
+            destination
+        };
+        let release_of = |sync_manager: &SyncManager| {
+            let (repo, _) = sync_manager.load_current(&repo_config).unwrap();
+            let index = repo.collections[0]
+                .indexes
+                .iter()
+                .find(|x| x.path == "dists/focal/Release")
+                .cloned();
+            index.unwrap().hash
+        };
+
+        sync("samples/debian/Release", "samples/debian/Packages");
+        let good_release = release_of(&sync_manager);
+        sync("samples/debian/Release.2", "samples/debian/Packages.2");
+        sync("samples/debian/Release", "samples/debian/Packages");
+        sync("samples/debian/Release.2", "samples/debian/Packages.2");
+        //only one generation besides the current one is kept
+        assert_eq!(1, state::list_generations(&data_path).unwrap().len());
+        assert_ne!(good_release, release_of(&sync_manager));
+
+        let generation = sync_manager.rollback("test-ubuntu", None).unwrap();
+        assert_eq!(good_release, release_of(&sync_manager));
+        //the discarded state is kept as a generation
+        let generations = state::list_generations(&data_path).unwrap();
+        assert_eq!(1, generations.len());
+        assert_ne!(generation, generations[0]);
+        assert!(matches!(
+            sync_manager.rollback("test-ubuntu", Some(&generation)),
+            Err(SyncError::Refused(_))
+        ));
+
+        //upstream matches the restored state, but the destination still has the discarded one
+        let destination = sync("samples/debian/Release", "samples/debian/Packages");
+        let (contents, deletions, _) = destination.explode();
+        assert!(contents.contains_key("ubuntu/dists/focal/Release"));
+        assert!(contents.contains_key("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb"));
+        assert!(deletions.is_empty());
+
+        //nothing is pending anymore
+        let destination = sync("samples/debian/Release", "samples/debian/Packages");
+        let (contents, _, _) = destination.explode();
+        assert!(contents.is_empty());
+
+        //the agent is stored only because of the discarded state, it's deleted by the next sync
+        sync("samples/debian/Release.2", "samples/debian/Packages.2");
+        sync("samples/debian/Release", "samples/debian/Packages");
+        sync_manager.rollback("test-ubuntu", None).unwrap();
+        let destination = sync("samples/debian/Release.2", "samples/debian/Packages.2");
+        let (_, deletions, _) = destination.explode();
+        assert!(deletions.contains("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb"));
+    }
+
+    #[test]
+    fn strip_prefix_applies_to_every_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].source.strip_prefix = Some("pool".into());
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        //the published Packages would still reference pool/
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let err = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("'dists/focal/Release' is not under"));
+        let (contents, _, _) = destination.explode();
+        assert!(contents.is_empty());
+
+        //e.g. a single redhat channel stored at the root of the destination
+        let mut source = repo_config.source.clone();
+        source.strip_prefix = Some("os".into());
+        let index = |path: &str| IndexFile {
+            file_path: "".into(),
+            path: path.into(),
+            size: 1,
+            hash: Hash::Sha256 { hex: "00".into() },
+            signature: Signature::None,
+        };
+        let mut repository = Repository {
+            name: repo_config.name.clone(),
+            collections: vec![Collection {
+                target: Target {
+                    release_name: "os".into(),
+                    architectures: vec![],
                 },
-                destination: DestinationConfig {
-                    s3: None,
-                    local: None,
+                indexes: vec![index("os/repodata/repomd.xml")],
+                packages: vec![],
+            }],
+        };
+        SyncManager::require_stripped_paths(&repository, &source).unwrap();
+        assert_eq!(
+            "repodata/repomd.xml",
+            source.destination_path("os/repodata/repomd.xml")
+        );
+        repository.collections[0]
+            .indexes
+            .push(index("updates/repodata/repomd.xml"));
+        let err = SyncManager::require_stripped_paths(&repository, &source)
+            .err()
+            .unwrap();
+        assert!(matches!(err, SyncError::Refused(_)));
+    }
+
+    #[test]
+    fn append_only_never_deletes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].append_only = true;
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        for (release, packages) in &[
+            ("samples/debian/Release", "samples/debian/Packages"),
+            ("samples/debian/Release.2", "samples/debian/Packages.2"),
+        ] {
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+                .unwrap();
+        }
+        //even beyond max_delete_ratio, nothing is deleted
+        let (_, deletions, _) = destination.explode();
+        assert!(deletions.is_empty());
+
+        let orphans = sync_manager
+            .prune_internal(&mut destination, &repo_config, false)
+            .unwrap();
+        assert!(orphans.is_empty());
+    }
+
+    #[test]
+    fn corrupted_downloads_are_fetched_again() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let hash = Hash::Sha256 { hex: "".into() }
+            .compute(&mut File::open("samples/fake-package").unwrap())
+            .unwrap();
+        let size = std::fs::metadata("samples/fake-package").unwrap().len();
+        let copy_list = vec![CopyOperation {
+            is_replace: false,
+            path: "pool/a.deb".into(),
+            hash,
+            size,
+            local_file: None,
+            architecture: String::new(),
+        }];
+        let copy = |corrupted: u64, copy_retries: u32| {
+            let calls = Arc::new(AtomicU64::new(0));
+            let mut mock_fetcher = MockFetcher::new();
+            let counter = calls.clone();
+            mock_fetcher
+                .expect_fetch()
+                .with(predicate::eq("http://fake-url/rc/pool/a.deb"))
+                .returning(move |_| {
+                    if counter.fetch_add(1, Ordering::SeqCst) < corrupted {
+                        Result::Ok(Box::new("corrupted".as_bytes()))
+                    } else {
+                        Result::Ok(Box::new(File::open("samples/fake-package").unwrap()))
+                    }
+                });
+            let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+            let progress = SyncProgress::new(1, size);
+            let result = SyncManager::copy(
+                tmp_dir.path().to_str().unwrap(),
+                "http://fake-url/rc",
+                &mock_fetcher,
+                &mut destination,
+                copy_list.clone(),
+                &CopyOptions {
+                    best_effort: false,
+                    verify_size: true,
+                    parallel_hashing: false,
+                    copy_retries,
+                    retry_sleep: Duration::from_secs(0),
+                    arch_prefix: false,
+                    progress: &progress,
                 },
-                versions: vec!["focal".into()],
+            );
+            (result.is_ok(), calls.load(Ordering::SeqCst))
+        };
+
+        assert_eq!((true, 3), copy(2, 2));
+        assert_eq!((false, 3), copy(3, 2));
+        assert_eq!((false, 1), copy(1, 0));
+    }
+
+    #[test]
+    fn print_diff_plans_without_performing() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = create_config(&tmp_dir);
+        let repo_config = config.repo.first().unwrap().clone();
+        let mut sync_manager = SyncManager::new(config);
+        let sync = |sync_manager: &SyncManager, release: &str, packages: &str| {
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+            let report = sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+                .unwrap();
+            (report, destination.explode())
+        };
+
+        sync_manager.set_print_diff(true);
+        let (report, (contents, deletions, invalidations)) = sync(
+            &sync_manager,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let plan = report.plan.unwrap();
+        assert_eq!("test-ubuntu", plan.repository);
+        assert!(plan.copy.iter().any(|x| x.path.ends_with(".deb")));
+        assert!(plan.copy.iter().all(|x| !x.is_replace));
+        assert!(plan.delete.is_empty() && plan.invalidate.is_empty());
+        assert!(contents.is_empty() && deletions.is_empty() && invalidations.is_empty());
+        let (current, _) = sync_manager.load_current(&repo_config).unwrap();
+        assert!(current.collections.is_empty());
+
+        sync_manager.set_print_diff(false);
+        let (report, _) = sync(
+            &sync_manager,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        assert!(report.plan.is_none());
+
+        //beyond max_delete_ratio, still planned
+        sync_manager.set_print_diff(true);
+        let (report, (contents, deletions, _)) = sync(
+            &sync_manager,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        let plan = report.plan.unwrap();
+        assert_eq!(1, plan.delete.len());
+        assert_eq!(vec![20], plan.delete_sizes);
+        assert!(!plan.invalidate.is_empty());
+        for path in &plan.invalidate {
+            assert!(plan.copy.iter().any(|x| &x.path == path && x.is_replace));
+        }
+        assert!(contents.is_empty() && deletions.is_empty());
+        let json = serde_json::to_string(&plan).unwrap();
+        assert!(json.contains("\"is_replace\":true"));
+        assert!(!json.contains("delete_sizes"));
+    }
+
+    #[test]
+    fn missing_required_indexes_fail_the_sync() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].source.required_indexes = vec!["Release.gpg".into(), "InRelease".into()];
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        //Release.gpg is available, InRelease is not
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .returning(|url: &str| match url {
+                "http://fake-url/rc/dists/focal/Release" => {
+                    Ok(Box::new(File::open("samples/debian/Release").unwrap()))
+                }
+                "http://fake-url/rc/dists/focal/Release.gpg" => {
+                    Ok(Box::new(File::open("samples/fake-signature").unwrap()))
+                }
+                _ if url.contains("/Packages") => {
+                    Ok(Box::new(File::open("samples/debian/Packages").unwrap()))
+                }
+                _ => Err(FetchError {
+                    code: 404,
+                    error: "not found".into(),
+                }),
+            });
+        let mut destination = MemoryDestination::new("ubuntu");
+        let err = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .err()
+            .unwrap();
+        assert_eq!(
+            "'InRelease' missing next to 'dists/focal/Release', required by required_indexes",
+            err.to_string()
+        );
+        let (contents, _, _) = destination.explode();
+        assert!(contents.is_empty());
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+    }
+
+    #[test]
+    fn sync_reports_are_written_and_retained() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.general.report_retention = 2;
+        let repo_config = config.repo.first().unwrap().clone();
+        let millis = Arc::new(AtomicU64::new(0));
+        let mut mock = MockTimeProvider::new();
+        {
+            let millis = millis.clone();
+            mock.expect_now().returning(move || {
+                UNIX_EPOCH.add(Duration::from_millis(millis.fetch_add(1, Ordering::SeqCst)))
+            });
+        }
+        let sync_manager = SyncManager::new_internal(config.clone(), Lock::new(), Arc::new(mock));
+        millis.store(1000, Ordering::SeqCst);
+
+        let write = |result: Result<SyncReport, SyncError>| {
+            sync_manager.write_report(&repo_config, &result).unwrap();
+        };
+        write(Ok(SyncReport::default()));
+        write(Err(SyncError::Busy("test-ubuntu".into())));
+        write(Ok(SyncReport {
+            failures: vec!["pool/a.deb: not found".into()],
+            metadata_fetched: 3,
+            ..Default::default()
+        }));
+
+        let directory = format!("{}/test-ubuntu.reports", config.general.data_path);
+        let mut reports: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_str().unwrap().to_string())
+            .collect();
+        reports.sort();
+        assert_eq!(
+            vec!["test-ubuntu-1001.json", "test-ubuntu-1002.json"],
+            reports
+        );
+        let report: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(format!("{}/test-ubuntu-1002.json", directory)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!("test-ubuntu", report["repository"]);
+        assert_eq!(3, report["metadata_fetched"]);
+        assert_eq!("pool/a.deb: not found", report["failures"][0]);
+
+        let report_path = format!("{}/reports", tmp_dir.path().to_str().unwrap());
+        config.general.report_path = Some(report_path.clone());
+        sync_manager.reload(config).unwrap();
+        write(Ok(SyncReport::default()));
+        assert_eq!(1, std::fs::read_dir(&report_path).unwrap().count());
+    }
+
+    #[test]
+    fn unchanged_entry_points_are_not_published_when_planning() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].skip_unchanged = true;
+        let repo_config = config.repo.first().unwrap().clone();
+        let mut sync_manager = SyncManager::new(config);
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+
+        //a regular sync would request and upload the extra file
+        let mut repo_config = repo_config;
+        repo_config.extra_files = vec!["keyring.gpg".into()];
+        sync_manager.set_print_diff(true);
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .with(predicate::eq("http://fake-url/rc/dists/focal/Release"))
+            .times(1)
+            .returning(|_| Ok(Box::new(File::open("samples/debian/Release").unwrap())));
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let report = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+        assert!(report.plan.is_some());
+        let (contents, deletions, invalidations) = destination.explode();
+        assert!(contents.is_empty() && deletions.is_empty() && invalidations.is_empty());
+    }
+
+    #[test]
+    fn unchanged_entry_points_skip_the_sync() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].skip_unchanged = true;
+        config.general.max_delete_ratio = 1.0;
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        let report = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+        assert!(report.metadata_fetched > 0);
+
+        //only the Release is requested
+        let mut mock_fetcher = MockFetcher::new();
+        mock_fetcher
+            .expect_fetch()
+            .with(predicate::eq("http://fake-url/rc/dists/focal/Release"))
+            .times(1)
+            .returning(|_| Ok(Box::new(File::open("samples/debian/Release").unwrap())));
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let report = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+        assert_eq!(0, report.metadata_fetched);
+        let (contents, deletions, _) = destination.explode();
+        assert!(contents.is_empty() && deletions.is_empty());
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+        let (_, deletions, _) = destination.explode();
+        assert_eq!(1, deletions.len());
+    }
+
+    #[test]
+    fn packages_built_before_since_are_split() {
+        let package = |path: &str, build_time: u64| Package {
+            path: path.into(),
+            build_time,
+            ..Package::empty()
+        };
+        let repo = Repository {
+            name: "test-redhat".into(),
+            collections: vec![Collection {
+                target: Target {
+                    release_name: "".into(),
+                    architectures: vec![],
+                },
+                indexes: vec![],
+                packages: vec![
+                    package("old.rpm", 1000),
+                    package("new.rpm", 2000),
+                    package("unknown.rpm", 0),
+                ],
+            }],
+        };
+        let copy_list = ["old.rpm", "new.rpm", "unknown.rpm"]
+            .iter()
+            .map(|path| CopyOperation {
+                is_replace: false,
+                path: path.to_string(),
+                hash: Hash::None,
+                size: 0,
+                local_file: None,
+                architecture: String::new(),
+            })
+            .collect();
+
+        let (recent, older) = SyncManager::split_older(&repo, copy_list, 1500);
+        assert_eq!(
+            vec!["new.rpm", "unknown.rpm"],
+            recent
+                .iter()
+                .map(|x| x.path.as_str())
+                .collect::<Vec<&str>>()
+        );
+        assert_eq!(
+            vec!["old.rpm"],
+            older.iter().map(|x| x.path.as_str()).collect::<Vec<&str>>()
+        );
+    }
+
+    #[test]
+    fn package_times_do_not_trigger_uploads() {
+        let repo = |file_time: u64, build_time: u64, hex: &str| Repository {
+            name: "test-redhat".into(),
+            collections: vec![Collection {
+                target: Target {
+                    release_name: "".into(),
+                    architectures: vec![],
+                },
+                indexes: vec![],
+                packages: vec![Package {
+                    path: "foo.rpm".into(),
+                    hash: Hash::Sha256 { hex: hex.into() },
+                    size: 10,
+                    file_time,
+                    build_time,
+                    ..Package::empty()
+                }],
             }],
         };
 
-        std::fs::create_dir_all(&config.general.data_path).unwrap();
-        std::fs::create_dir_all(&config.general.tmp_path).unwrap();
+        let (copy_list, delete_list, _, _) =
+            SyncManager::repo_diff(&repo(2000, 1500, "abcd"), repo(1000, 900, "abcd"), false);
+        assert!(copy_list.is_empty() && delete_list.is_empty());
+
+        let (copy_list, _, _, _) =
+            SyncManager::repo_diff(&repo(1000, 900, "dcba"), repo(1000, 900, "abcd"), false);
+        assert_eq!(1, copy_list.len());
+        assert!(copy_list[0].is_replace);
+    }
+
+    #[test]
+    fn least_recently_referenced_packages_are_evicted() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        //both packages upstream are 20 bytes
+        config.repo[0].max_total_size_bytes = Some(40);
+        let repo_config = config.repo.first().unwrap().clone();
+        let mut mock = MockTimeProvider::new();
+        mock.expect_now()
+            .returning(|| UNIX_EPOCH.add(Duration::from_secs(100)));
+        let sync_manager = SyncManager::new_internal(config, Lock::new(), Arc::new(mock));
+
+        //cached by a previous sync, no longer listed upstream
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        destination
+            .upload(
+                "pool/zz-old_1.0_amd64.deb",
+                File::open("samples/fake-package").unwrap(),
+            )
+            .unwrap();
+        std::fs::write(
+            sync_manager.references_path(&repo_config),
+            "50 20 pool/zz-old_1.0_amd64.deb\n10 20 pool/deleted_1.0_amd64.deb",
+        )
+        .unwrap();
+
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+
+        let (_, deletions, _) = destination.explode();
+        assert_eq!(
+            vec!["ubuntu/pool/zz-old_1.0_amd64.deb".to_string()],
+            deletions.into_iter().collect::<Vec<String>>()
+        );
+        assert_eq!(
+            "100 20 pool/service-discover-agent_0.1.0_amd64.deb\n\
+             100 20 pool/service-discover-daemon_0.1.0_amd64.deb",
+            std::fs::read_to_string(sync_manager.references_path(&repo_config)).unwrap()
+        );
+
+        //packages listed upstream are kept, even beyond the limit
+        let mut repo_config = repo_config.clone();
+        repo_config.max_total_size_bytes = Some(30);
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .unwrap();
+        //only the package evicted before
+        let (_, deletions, _) = destination.explode();
+        assert_eq!(1, deletions.len());
+    }
+
+    #[test]
+    fn packages_are_stored_by_architecture() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].destination.arch_prefix = true;
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        for (release, packages) in &[
+            ("samples/debian/Release", "samples/debian/Packages"),
+            ("samples/debian/Release.2", "samples/debian/Packages.2"),
+        ] {
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+                .unwrap();
+        }
+
+        let (contents, deletions, _) = destination.explode();
+        assert!(contents.contains_key("ubuntu/amd64/pool/service-discover-daemon_0.1.0_amd64.deb"));
+        assert!(!contents.contains_key("ubuntu/pool/service-discover-daemon_0.1.0_amd64.deb"));
+        assert!(contents.contains_key("ubuntu/dists/focal/Release"));
+        assert!(deletions.contains("ubuntu/amd64/pool/service-discover-agent_0.1.0_amd64.deb"));
+
+        let orphans = sync_manager
+            .prune_internal(&mut destination, &repo_config, false)
+            .unwrap();
+        assert!(orphans.is_empty());
+    }
+
+    #[test]
+    fn deletes_wait_for_the_grace_period() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].delete_grace_secs = 3600;
+        let repo_config = config.repo.first().unwrap().clone();
+        let now = Arc::new(AtomicU64::new(100));
+        let mut mock = MockTimeProvider::new();
+        let clock = now.clone();
+        mock.expect_now()
+            .returning(move || UNIX_EPOCH.add(Duration::from_secs(clock.load(Ordering::SeqCst))));
+        let sync_manager = SyncManager::new_internal(config, Lock::new(), Arc::new(mock));
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let sync = |destination: &mut MemoryDestination, release: &str, packages: &str| {
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), destination, &repo_config)
+                .unwrap();
+        };
+        sync(
+            &mut destination,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
+        //the agent is dropped upstream
+        sync(
+            &mut destination,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        now.store(3000, Ordering::SeqCst);
+        sync(
+            &mut destination,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        assert!(destination.explode().1.is_empty());
+        assert_eq!(
+            "100 20 amd64 pool/service-discover-agent_0.1.0_amd64.deb",
+            std::fs::read_to_string(sync_manager.missing_packages_path(&repo_config)).unwrap()
+        );
+        let orphans = sync_manager
+            .prune_internal(&mut destination, &repo_config, false)
+            .unwrap();
+        assert!(orphans.is_empty());
+
+        now.store(3700, Ordering::SeqCst);
+        sync(
+            &mut destination,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
+        );
+        assert_eq!(
+            vec!["ubuntu/pool/service-discover-agent_0.1.0_amd64.deb".to_string()],
+            destination.explode().1.into_iter().collect::<Vec<String>>()
+        );
+        assert!(!std::path::Path::new(&sync_manager.missing_packages_path(&repo_config)).exists());
+    }
+
+    #[test]
+    fn unchanged_entry_points_do_not_delay_expired_deletes() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].delete_grace_secs = 3600;
+        config.repo[0].skip_unchanged = true;
+        let repo_config = config.repo.first().unwrap().clone();
+        let now = Arc::new(AtomicU64::new(100));
+        let mut mock = MockTimeProvider::new();
+        let clock = now.clone();
+        mock.expect_now()
+            .returning(move || UNIX_EPOCH.add(Duration::from_secs(clock.load(Ordering::SeqCst))));
+        let sync_manager = SyncManager::new_internal(config, Lock::new(), Arc::new(mock));
+
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let sync = |destination: &mut MemoryDestination, mock_fetcher: MockFetcher| {
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), destination, &repo_config)
+                .unwrap();
+        };
+        let full_fetcher = |release: &str, packages: &str| {
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            mock_fetcher
+        };
+        let release_fetcher = || {
+            let mut mock_fetcher = MockFetcher::new();
+            mock_fetcher
+                .expect_fetch()
+                .with(predicate::eq("http://fake-url/rc/dists/focal/Release"))
+                .times(1)
+                .returning(|_| Ok(Box::new(File::open("samples/debian/Release.2").unwrap())));
+            mock_fetcher
+        };
+        sync(
+            &mut destination,
+            full_fetcher("samples/debian/Release", "samples/debian/Packages"),
+        );
+        //the agent is dropped upstream
+        sync(
+            &mut destination,
+            full_fetcher("samples/debian/Release.2", "samples/debian/Packages.2"),
+        );
+
+        //within the grace period an unchanged upstream is still skipped
+        now.store(3000, Ordering::SeqCst);
+        sync(&mut destination, release_fetcher());
+        assert!(destination.explode().1.is_empty());
 
-        config
+        //once it's over, the sync runs to delete the agent
+        now.store(3700, Ordering::SeqCst);
+        sync(
+            &mut destination,
+            full_fetcher("samples/debian/Release.2", "samples/debian/Packages.2"),
+        );
+        assert_eq!(
+            vec!["ubuntu/pool/service-discover-agent_0.1.0_amd64.deb".to_string()],
+            destination.explode().1.into_iter().collect::<Vec<String>>()
+        );
+
+        now.store(3800, Ordering::SeqCst);
+        sync(&mut destination, release_fetcher());
     }
 
     #[test]
-    fn load_current_returns_empty_when_missing() {
+    fn manifest_lists_published_files() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let config = create_config(&tmp_dir);
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].manifest_path = Some("MANIFEST".into());
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
 
-        let sync_manager = SyncManager {
-            config: config.clone(),
-            lock: Lock::new(),
-            time_provider: Arc::new(RealTimeProvider {}),
-            sync_map: Arc::new(Mutex::new(Default::default())),
+        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let manifest = |destination: &MemoryDestination| {
+            String::from_utf8(destination.explode().0["ubuntu/MANIFEST"].clone()).unwrap()
         };
-        let (repository, _saved_metadata_store) = sync_manager
-            .load_current(&config.repo.get(0).unwrap())
-            .unwrap();
+        for (release, packages) in &[
+            ("samples/debian/Release", "samples/debian/Packages"),
+            ("samples/debian/Release.2", "samples/debian/Packages.2"),
+        ] {
+            let mut mock_fetcher = MockFetcher::new();
+            setup_fetcher(&mut mock_fetcher, release, packages);
+            sync_manager
+                .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+                .unwrap();
+            if release.ends_with("Release") {
+                assert!(manifest(&destination).contains(
+                    "sha256:9ed5e5312df1aa047aa64799960b281e56b724bbbb457b5114bde9a829f17af2 20 \
+                     pool/service-discover-daemon_0.1.0_amd64.deb\n"
+                ));
+                assert!(manifest(&destination).contains("service-discover-agent"));
+            }
+        }
 
-        assert_eq!("test-ubuntu", repository.name);
-        assert_eq!(0, repository.collections.len());
+        let (contents, deletions, invalidations) = destination.explode();
+        let manifest = manifest(&destination);
+        assert!(!manifest.contains("service-discover-agent"));
+        assert!(manifest.contains(" dists/focal/Release\n"));
+        //every published file is listed, the manifest itself excluded
+        let stored = contents.keys().filter(|x| !deletions.contains(*x)).count();
+        assert_eq!(stored - 1, manifest.lines().count());
+        assert!(invalidations.contains("ubuntu/MANIFEST"));
     }
 
     #[test]
-    fn sync_debian_repo_from_scratch() {
-        let mut mock_fetcher = MockFetcher::new();
+    fn client_options_follow_the_config() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        let options = client_options(&config.general, &config.repo[0]);
+        assert!(!options.http2_prior_knowledge);
+        assert_eq!(None, options.pool_idle_timeout);
+        assert_eq!(None, options.pool_max_idle_per_host);
 
-        setup_fetcher(
-            &mut mock_fetcher,
-            "samples/debian/Release",
-            "samples/debian/Packages",
+        config.general.http2_prior_knowledge = true;
+        config.general.pool_idle_timeout = Some(30);
+        config.general.pool_max_idle_per_host = Some(4);
+        config.repo[0]
+            .source
+            .headers
+            .insert("X-Mirror".into(), "reposync".into());
+        let options = client_options(&config.general, &config.repo[0]);
+        assert!(options.http2_prior_knowledge);
+        assert_eq!(Some(Duration::from_secs(30)), options.pool_idle_timeout);
+        assert_eq!(Some(4), options.pool_max_idle_per_host);
+        assert_eq!(
+            Some(&"reposync".to_string()),
+            options.headers.get("X-Mirror")
         );
+    }
 
+    #[test]
+    fn partial_path_of_a_long_url() {
         let tmp_dir = tempfile::tempdir().unwrap();
-        let config = create_config(&tmp_dir);
+        let tmp_path = tmp_dir.path().to_str().unwrap();
+        let url = format!("http://fake-url/rc/pool/{}/package.deb", "a".repeat(300));
+        let path = partial_path(tmp_path, &url);
+        let file_name = Path::new(&path).file_name().unwrap().to_str().unwrap();
+        assert!(file_name.starts_with("package.deb."));
+        assert!(file_name.len() < 255);
+        File::create(&path).unwrap();
 
-        let repo_config = config.repo.get(0).unwrap();
+        //the same file name in another directory doesn't resume the same download
+        let other = partial_path(tmp_path, "http://fake-url/rc/pool/b/package.deb");
+        assert_ne!(path, other);
+        let long_name = partial_path(tmp_path, &format!("http://fake-url/rc/{}", "x".repeat(300)));
+        File::create(&long_name).unwrap();
+    }
+
+    #[test]
+    fn free_inodes_are_checked() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        let path = tmp_dir.path().to_str().unwrap();
+        check_free_inodes(path, 1).unwrap();
+        let err = check_free_inodes(path, u64::MAX).err().unwrap();
+        assert!(err.to_string().contains("free inodes left on"));
+
+        config.general.min_free_inodes = Some(u64::MAX);
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
         let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
+        let result = sync_manager.sync_repo_internal(
+            Box::new(MockFetcher::new()),
+            &mut destination,
+            &repo_config,
+        );
+        assert!(matches!(result, Err(SyncError::Refused(_))));
+    }
 
-        let sync_manager = SyncManager {
-            config: config.clone(),
-            lock: Lock::new(),
-            sync_map: Arc::new(Mutex::new(Default::default())),
-            time_provider: Arc::new(RealTimeProvider {}),
+    struct CorruptingDestination {
+        inner: MemoryDestination,
+        corrupt: bool,
+    }
+    impl Destination for CorruptingDestination {
+        fn upload(&mut self, path: &str, file: File) -> Result<(), std::io::Error> {
+            self.inner.upload(path, file)
+        }
+        fn delete(&mut self, path: &str) -> Result<(), std::io::Error> {
+            self.inner.delete(path)
+        }
+        fn invalidate(&mut self, paths: Vec<String>) -> Result<(), std::io::Error> {
+            self.inner.invalidate(paths)
+        }
+        fn list(&mut self, prefix: &str) -> Result<Vec<String>, std::io::Error> {
+            self.inner.list(prefix)
+        }
+        fn read(&mut self, path: &str) -> Result<Vec<u8>, std::io::Error> {
+            let mut content = self.inner.read(path)?;
+            if self.corrupt {
+                content.truncate(content.len() / 2);
+            }
+            Ok(content)
+        }
+        fn name(&self) -> String {
+            self.inner.name()
+        }
+    }
+
+    #[test]
+    fn uploaded_indexes_are_verified_before_deleting() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut config = create_config(&tmp_dir);
+        config.repo[0].verify_indexes = true;
+        let repo_config = config.repo.first().unwrap().clone();
+        let sync_manager = SyncManager::new(config);
+
+        let mut destination = CorruptingDestination {
+            inner: MemoryDestination::new("ubuntu"),
+            corrupt: false,
         };
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release",
+            "samples/debian/Packages",
+        );
         sync_manager
-            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, repo_config)
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
             .unwrap();
 
-        destination.print();
-
-        let (contents, deletions, invalidations) = destination.explode();
-
-        assert_eq!(
-            1868,
-            contents.get("ubuntu/dists/focal/Release").unwrap().len()
-        );
-        assert_eq!(
-            14,
-            contents
-                .get("ubuntu/dists/focal/Release.gpg")
-                .unwrap()
-                .len()
-        );
-        assert_eq!(
-            1868,
-            contents.get("ubuntu/dists/focal/InRelease").unwrap().len()
-        );
-        assert_eq!(
-            1075,
-            contents
-                .get("ubuntu/dists/focal/main/binary-amd64/Packages")
-                .unwrap()
-                .len()
-        );
-        assert_eq!(
-            1075,
-            contents
-                .get("ubuntu/dists/focal/main/binary-amd64/Packages.bz2")
-                .unwrap()
-                .len()
-        );
-        assert_eq!(
-            1075,
-            contents
-                .get("ubuntu/dists/focal/main/binary-amd64/Packages.gz")
-                .unwrap()
-                .len()
-        );
-        assert_eq!(
-            1075,
-            contents
-                .get("ubuntu/dists/focal/main/binary-i386/Packages")
-                .unwrap()
-                .len()
-        );
-        assert_eq!(
-            1075,
-            contents
-                .get("ubuntu/dists/focal/main/binary-i386/Packages.bz2")
-                .unwrap()
-                .len()
-        );
-        assert_eq!(
-            1075,
-            contents
-                .get("ubuntu/dists/focal/main/binary-i386/Packages.gz")
-                .unwrap()
-                .len()
-        );
-        assert_eq!(
-            20,
-            contents
-                .get("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb")
-                .unwrap()
-                .len()
-        );
-        assert_eq!(
-            20,
-            contents
-                .get("ubuntu/pool/service-discover-daemon_0.1.0_amd64.deb")
-                .unwrap()
-                .len()
+        destination.corrupt = true;
+        let mut mock_fetcher = MockFetcher::new();
+        setup_fetcher(
+            &mut mock_fetcher,
+            "samples/debian/Release.2",
+            "samples/debian/Packages.2",
         );
+        let err = sync_manager
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
+            .err()
+            .unwrap();
+        match err {
+            SyncError::HashMismatch { path, .. } => assert_eq!("dists/focal/Release", path),
+            _ => panic!("unexpected error {}", err),
+        }
+        let (_, deletions, _) = destination.inner.explode();
+        assert!(deletions.is_empty());
 
-        assert_eq!(0, deletions.len());
-        assert_eq!(0, invalidations.len());
-
+        destination.corrupt = false;
         let mut mock_fetcher = MockFetcher::new();
         setup_fetcher(
             &mut mock_fetcher,
             "samples/debian/Release.2",
             "samples/debian/Packages.2",
         );
-        let mut destination: MemoryDestination = MemoryDestination::new("ubuntu");
         sync_manager
-            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, repo_config)
+            .sync_repo_internal(Box::new(mock_fetcher), &mut destination, &repo_config)
             .unwrap();
-
-        destination.print();
-
-        let (contents, deletions, invalidations) = destination.explode();
-        assert_eq!(8, contents.len());
-        assert_eq!(1, deletions.len());
-        assert!(deletions.contains("ubuntu/pool/service-discover-agent_0.1.0_amd64.deb"));
-        assert_eq!(8, invalidations.len());
-        assert!(invalidations.contains("ubuntu/dists/focal/Release"));
-        assert!(!invalidations.contains("ubuntu/dists/focal/Release.gpg"));
-        assert!(invalidations.contains("ubuntu/dists/focal/InRelease"));
-        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-amd64/Packages"));
-        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-amd64/Packages.gz"));
-        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-amd64/Packages.bz2"));
-        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-i386/Packages"));
-        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-i386/Packages.gz"));
-        assert!(invalidations.contains("ubuntu/dists/focal/main/binary-i386/Packages.bz2"));
-    }
-
-    fn setup_fetcher(mock_fetcher: &mut MockFetcher, release: &str, packages: &str) {
-        let packages: String = packages.into();
-        let release: String = release.into();
-        mock_fetcher.expect_fetch().returning(move |url: &str| {
-            Result::Ok(Box::new(match url {
-                "http://fake-url/rc/dists/focal/Release" => File::open(&release).unwrap(),
-                "http://fake-url/rc/dists/focal/Release.gpg" => {
-                    File::open("samples/fake-signature").unwrap()
-                }
-                "http://fake-url/rc/dists/focal/InRelease" => File::open(&release).unwrap(),
-                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages" => {
-                    File::open(&packages).unwrap()
-                }
-                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.bz2" => {
-                    File::open(&packages).unwrap()
-                }
-                "http://fake-url/rc/dists/focal/main/binary-amd64/Packages.gz" => {
-                    File::open(&packages).unwrap()
-                }
-                "http://fake-url/rc/dists/focal/main/binary-i386/Packages" => {
-                    File::open(&packages).unwrap()
-                }
-                "http://fake-url/rc/dists/focal/main/binary-i386/Packages.bz2" => {
-                    File::open(&packages).unwrap()
-                }
-                "http://fake-url/rc/dists/focal/main/binary-i386/Packages.gz" => {
-                    File::open(&packages).unwrap()
-                }
-                "http://fake-url/rc/pool/service-discover-agent_0.1.0_amd64.deb" => {
-                    File::open("samples/fake-package").unwrap()
-                }
-                "http://fake-url/rc/pool/service-discover-daemon_0.1.0_amd64.deb" => {
-                    File::open("samples/fake-package").unwrap()
-                }
-                _ => panic!("unexpected url: {}", url),
-            }))
-        });
+        let (_, deletions, _) = destination.inner.explode();
+        assert!(!deletions.is_empty());
     }
 
     #[test]
-    fn scheduler() {
+    fn scheduled_panic_fails_only_that_sync() {
         let tmp_dir = tempfile::tempdir().unwrap();
         let config = create_config(&tmp_dir);
-        let secs_offset = Arc::new(AtomicU64::new(0));
-
-        let mut mock = MockTimeProvider::new();
-        {
-            let secs_offset = secs_offset.clone();
-            mock.expect_now().returning(move || {
-                UNIX_EPOCH.add(Duration::from_secs(secs_offset.load(Ordering::SeqCst)))
-            });
-        }
+        let sync_manager = SyncManager::new(config);
 
-        let sync_manager = SyncManager::new_internal(config.clone(), Lock::new(), Arc::new(mock));
+        sync_manager.run_scheduled("test-ubuntu", || {
+            let _write_lock = sync_manager.lock.lock_write("test-ubuntu");
+            panic!("unexpected state");
+        });
+        let status = sync_manager.get_status("test-ubuntu").unwrap();
+        assert_eq!(
+            Some("panicked: unexpected state".to_string()),
+            status.last_result
+        );
+        assert!(status.next_sync > status.last_sync);
 
-        secs_offset.store(0, Ordering::SeqCst);
-        {
-            let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
-            assert_eq!("test-ubuntu", next_name);
-            assert_eq!(UNIX_EPOCH.add(Duration::from_secs(30 * 60)), next_time);
-        }
-        {
-            sync_manager.queue_sync("test-ubuntu");
-            let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
-            assert_eq!("test-ubuntu", next_name);
-            assert_eq!(UNIX_EPOCH.add(Duration::from_secs(10 * 60)), next_time);
-        }
-        secs_offset.store(60, Ordering::SeqCst);
-        {
-            sync_manager.sync_completed("test-ubuntu", "success");
-            let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
-            assert_eq!("test-ubuntu", next_name);
-            assert_eq!(UNIX_EPOCH.add(Duration::from_secs(31 * 60)), next_time);
-        }
-        {
-            sync_manager.queue_sync("test-ubuntu");
-            let (next_name, next_time) = sync_manager.next_repo_to_sync().unwrap();
-            assert_eq!("test-ubuntu", next_name);
-            assert_eq!(UNIX_EPOCH.add(Duration::from_secs(11 * 60)), next_time);
-        }
+        //the lock released by the panic can be taken again
+        sync_manager.run_scheduled("test-ubuntu", || {
+            let _write_lock = sync_manager.lock.lock_write("test-ubuntu");
+            Err(SyncError::Busy("test-ubuntu".into()))
+        });
+        let status = sync_manager.get_status("test-ubuntu").unwrap();
+        assert!(!status.last_result.unwrap().starts_with("panicked"));
     }
 }
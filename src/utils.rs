@@ -1,20 +1,95 @@
+use crate::error::SyncError;
 use crate::packages::{Hash, IndexFile, Signature};
 use crate::state::RepoMetadataStore;
-use std::io::{ErrorKind, Read};
+use serde::Serialize;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+//set once by the cli, the human output is replaced by LogEvent lines
+static JSON_LOG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_json_log(enabled: bool) {
+    JSON_LOG.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_json_log() -> bool {
+    JSON_LOG.load(Ordering::SeqCst)
+}
+
+//set by the cli when stdout carries the planned operations
+static PROGRESS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_progress_to_stderr(enabled: bool) {
+    PROGRESS_TO_STDERR.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_progress_to_stderr() -> bool {
+    PROGRESS_TO_STDERR.load(Ordering::SeqCst)
+}
+
+///println, unless the json log format is enabled, on stderr when stdout carries the plan
+macro_rules! human_println {
+    ($($arg:tt)*) => {
+        if crate::utils::is_json_log() {
+        } else if crate::utils::is_progress_to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use human_println;
+
+///one line of the json log format, e.g. {"repo":"ubuntu","event":"upload","path":"pool/a.deb","bytes":20}
+#[derive(Serialize, Default)]
+pub struct LogEvent<'a> {
+    pub repo: &'a str,
+    ///fetch, upload, delete, invalidate or summary
+    pub event: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files: Option<usize>,
+    ///packages which failed in best effort mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl LogEvent<'_> {
+    pub fn line(&self) -> String {
+        serde_json::to_string(self).expect("cannot serialize log event")
+    }
+
+    ///printed only with the json log format, on stderr when stdout carries the plan
+    pub fn emit(&self) {
+        if !is_json_log() {
+            return;
+        }
+        if is_progress_to_stderr() {
+            eprintln!("{}", self.line());
+        } else {
+            println!("{}", self.line());
+        }
+    }
+}
 
 pub fn add_optional_index<T>(
     state: &T,
     path: &str,
     indexes: &mut Vec<IndexFile>,
     signature: Signature,
-) -> Result<Option<Box<dyn Read>>, std::io::Error>
+) -> Result<Option<Box<dyn Read>>, SyncError>
 where
     T: RepoMetadataStore,
 {
     let result = state.fetch(path);
     if result.is_err() {
         let err = result.err().unwrap();
-        if err.kind() == ErrorKind::NotFound {
+        if err.is_not_found() {
             Ok(None)
         } else {
             Err(err)
@@ -35,3 +110,34 @@ where
         Ok(Some(state.read(path).unwrap().unwrap()))
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use crate::utils::LogEvent;
+
+    #[test]
+    fn log_events_omit_missing_fields() {
+        assert_eq!(
+            r#"{"repo":"ubuntu","event":"upload","path":"pool/a.deb","bytes":20}"#,
+            LogEvent {
+                repo: "ubuntu",
+                event: "upload",
+                path: Some("pool/a.deb"),
+                bytes: Some(20),
+                ..Default::default()
+            }
+            .line()
+        );
+        assert_eq!(
+            r#"{"repo":"ubuntu","event":"summary","files":0,"error":"not found"}"#,
+            LogEvent {
+                repo: "ubuntu",
+                event: "summary",
+                files: Some(0),
+                error: Some("not found".into()),
+                ..Default::default()
+            }
+            .line()
+        );
+    }
+}